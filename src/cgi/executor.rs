@@ -1,16 +1,125 @@
 use crate::error::{Result, ServerError};
 use crate::http::{Request, Response, StatusCode};
 use std::collections::HashMap;
-use std::io::Write;
+use std::io::{Read, Write};
 use std::path::Path;
-use std::process::{Command, Stdio};
+use std::process::{Child, ChildStdout, Command, Output, Stdio};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
 
 /// CGI script executor
 pub struct CgiExecutor;
 
 impl CgiExecutor {
     /// Executes a CGI script and returns the response
-    pub fn execute(request: &Request, script_path: &str, interpreter: &str) -> Result<Response> {
+    /// `remote_user` is the authenticated username, if the route requires auth;
+    /// output beyond `max_output` bytes kills the script with `CgiOutputTooLarge`;
+    /// `working_dir` overrides the default of the script's own directory
+    pub fn execute(
+        request: &Request,
+        script_path: &str,
+        interpreter: &str,
+        remote_user: Option<&str>,
+        pass_authorization: bool,
+        max_output: Option<usize>,
+        working_dir: Option<&str>,
+    ) -> Result<Response> {
+        let child = Self::spawn(request, script_path, interpreter, remote_user, pass_authorization, working_dir)?;
+
+        // Wait for the process to complete
+        let output = match max_output {
+            Some(limit) => Self::wait_with_capped_output(child, limit)?,
+            None => child.wait_with_output()
+                .map_err(|e| ServerError::Cgi(format!("CGI process failed: {}", e)))?,
+        };
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            log_error!("CGI error: {}", stderr);
+            return Err(ServerError::Cgi(format!("CGI script failed: {}", stderr)));
+        }
+
+        // Parse CGI output
+        Self::parse_cgi_output(&output.stdout)
+    }
+
+    /// Executes a CGI script without buffering its output: once the headers
+    /// have been read, the rest of stdout is streamed to the client as it is
+    /// produced, delimited by closing the connection
+    /// Reading the script still blocks, one chunk at a time; `max_output`
+    /// cuts the stream off (the client sees a truncated body)
+    pub fn execute_streaming(
+        request: &Request,
+        script_path: &str,
+        interpreter: &str,
+        remote_user: Option<&str>,
+        pass_authorization: bool,
+        max_output: Option<usize>,
+        working_dir: Option<&str>,
+    ) -> Result<Response> {
+        let mut child = Self::spawn(request, script_path, interpreter, remote_user, pass_authorization, working_dir)?;
+        let mut stdout = child.stdout.take()
+            .ok_or_else(|| ServerError::Cgi("CGI stdout not captured".to_string()))?;
+        Self::log_stderr(&mut child);
+
+        let limit = max_output.unwrap_or(usize::MAX);
+        let mut output = Vec::new();
+        let mut buf = [0u8; 8192];
+        let (header_end, body_start) = loop {
+            if let Some(split) = Self::find_header_end(&output) {
+                break split;
+            }
+            if output.len() > MAX_CGI_HEADER_SIZE {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(ServerError::Cgi("CGI headers too large".to_string()));
+            }
+
+            match stdout.read(&mut buf) {
+                Ok(0) => {
+                    // The script finished before streaming began: answer as
+                    // if it had been buffered
+                    let status = child.wait()
+                        .map_err(|e| ServerError::Cgi(format!("CGI process failed: {}", e)))?;
+                    if !status.success() {
+                        return Err(ServerError::Cgi(format!("CGI script failed: {}", status)));
+                    }
+                    return Self::parse_cgi_output(&output);
+                }
+                Ok(n) if output.len() + n > limit => {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return Err(ServerError::CgiOutputTooLarge);
+                }
+                Ok(n) => output.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return Err(ServerError::Cgi(format!("Failed to read CGI output: {}", e)));
+                }
+            }
+        };
+
+        let response = Self::parse_cgi_headers(&String::from_utf8_lossy(&output[..header_end]));
+        let body = CgiStream {
+            remaining: limit.saturating_sub(output.len()),
+            pending: output.split_off(body_start),
+            chunks: CgiStream::read_in_background(stdout),
+            child,
+        };
+        Ok(response.stream_until_close(Box::new(body)))
+    }
+
+    /// Starts a CGI process with its environment and hands it the request body
+    fn spawn(
+        request: &Request,
+        script_path: &str,
+        interpreter: &str,
+        remote_user: Option<&str>,
+        pass_authorization: bool,
+        working_dir: Option<&str>,
+    ) -> Result<Child> {
         let path = Path::new(script_path);
 
         // Check if script exists
@@ -23,46 +132,152 @@ impl CgiExecutor {
             .map_err(|e| ServerError::Cgi(format!("Failed to resolve script path: {}", e)))?;
 
         // Build environment variables
-        let env_vars = Self::build_env(request, abs_path.to_str().unwrap_or(script_path));
+        let env_vars = Self::build_env(
+            request,
+            abs_path.to_str().unwrap_or(script_path),
+            remote_user,
+            pass_authorization,
+        );
 
-        // Get the script's directory for working directory
-        let working_dir = abs_path.parent().unwrap_or(Path::new("."));
+        // Run in the configured working directory, or the script's own
+        let working_dir = match working_dir {
+            Some(dir) if !Path::new(dir).is_dir() => {
+                log_error!("CGI working directory '{}' is not a directory", dir);
+                return Err(ServerError::Cgi(format!("Invalid CGI working directory: {}", dir)));
+            }
+            Some(dir) => Path::new(dir),
+            None => abs_path.parent().unwrap_or(Path::new(".")),
+        };
+
+        // Execute the CGI script, either through its interpreter or directly
+        // (relying on the shebang) when no interpreter is configured
+        let mut command = if Self::is_direct_exec(interpreter) {
+            if !Self::is_executable(&abs_path) {
+                return Err(ServerError::Forbidden);
+            }
+            Command::new(&abs_path)
+        } else {
+            let mut command = Command::new(interpreter);
+            command.arg(&abs_path);
+            command
+        };
+
+        // A spooled body is handed over as the file itself, so the script
+        // reads it at its own pace and nothing is held in a pipe
+        let stdin = match &request.body_file {
+            Some(file) => file.open()
+                .map(Stdio::from)
+                .map_err(|e| ServerError::Cgi(format!("Failed to open spooled body: {}", e)))?,
+            None => Stdio::piped(),
+        };
 
-        // Execute the CGI script
-        let mut child = Command::new(interpreter)
-            .arg(&abs_path)
+        let mut child = command
             .envs(env_vars)
             .current_dir(working_dir)
-            .stdin(Stdio::piped())
+            .stdin(stdin)
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
             .spawn()
             .map_err(|e| ServerError::Cgi(format!("Failed to spawn CGI process: {}", e)))?;
 
-        // Write request body to stdin
-        if !request.body.is_empty() {
-            if let Some(mut stdin) = child.stdin.take() {
+        // Write request body to stdin, then close it so the script sees EOF
+        if let Some(mut stdin) = child.stdin.take() {
+            if !request.body.is_empty() {
                 stdin.write_all(&request.body)
                     .map_err(|e| ServerError::Cgi(format!("Failed to write to CGI stdin: {}", e)))?;
             }
         }
 
-        // Wait for the process to complete
-        let output = child.wait_with_output()
-            .map_err(|e| ServerError::Cgi(format!("CGI process failed: {}", e)))?;
+        Ok(child)
+    }
 
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            eprintln!("CGI error: {}", stderr);
-            return Err(ServerError::Cgi(format!("CGI script failed: {}", stderr)));
+    /// Drains a streaming script's stderr in the background, logging it
+    fn log_stderr(child: &mut Child) {
+        if let Some(mut stderr) = child.stderr.take() {
+            std::thread::spawn(move || {
+                let mut buf = Vec::new();
+                let _ = stderr.read_to_end(&mut buf);
+                if !buf.is_empty() {
+                    log_error!("CGI error: {}", String::from_utf8_lossy(&buf));
+                }
+            });
         }
+    }
 
-        // Parse CGI output
-        Self::parse_cgi_output(&output.stdout)
+    /// Collects a CGI process's output, killing it once stdout exceeds `limit`
+    /// bytes so a runaway script can't exhaust memory
+    fn wait_with_capped_output(mut child: Child, limit: usize) -> Result<Output> {
+        let result = Self::read_capped_output(&mut child, limit);
+        if result.is_err() {
+            // Whatever went wrong, the script must not outlive the request
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+        result
+    }
+
+    /// Reads a CGI process's output and waits for it; on error the caller
+    /// still has to stop the process
+    fn read_capped_output(child: &mut Child, limit: usize) -> Result<Output> {
+        let mut stdout = child.stdout.take()
+            .ok_or_else(|| ServerError::Cgi("CGI stdout not captured".to_string()))?;
+        let mut stderr = child.stderr.take()
+            .ok_or_else(|| ServerError::Cgi("CGI stderr not captured".to_string()))?;
+
+        // Drain stderr alongside so a chatty script can't block on a full pipe
+        let stderr_reader = std::thread::spawn(move || {
+            let mut buf = Vec::new();
+            let _ = stderr.read_to_end(&mut buf);
+            buf
+        });
+
+        let mut output = Vec::new();
+        let mut buf = [0u8; 8192];
+        loop {
+            match stdout.read(&mut buf) {
+                Ok(0) => break,
+                // The stderr thread ends once the caller kills the process
+                Ok(n) if output.len() + n > limit => return Err(ServerError::CgiOutputTooLarge),
+                Ok(n) => output.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(ServerError::Cgi(format!("Failed to read CGI output: {}", e))),
+            }
+        }
+
+        let status = child.wait()
+            .map_err(|e| ServerError::Cgi(format!("CGI process failed: {}", e)))?;
+        let stderr = stderr_reader.join().unwrap_or_default();
+
+        Ok(Output { status, stdout: output, stderr })
+    }
+
+    /// Checks if the script should be executed directly (empty or `*` interpreter)
+    fn is_direct_exec(interpreter: &str) -> bool {
+        let interpreter = interpreter.trim();
+        interpreter.is_empty() || interpreter == "*"
+    }
+
+    /// Checks if a script file has an executable bit set
+    #[cfg(unix)]
+    fn is_executable(path: &Path) -> bool {
+        use std::os::unix::fs::PermissionsExt;
+        path.metadata()
+            .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+            .unwrap_or(false)
+    }
+
+    #[cfg(not(unix))]
+    fn is_executable(path: &Path) -> bool {
+        path.is_file()
     }
 
     /// Builds CGI environment variables
-    fn build_env(request: &Request, script_path: &str) -> HashMap<String, String> {
+    fn build_env(
+        request: &Request,
+        script_path: &str,
+        remote_user: Option<&str>,
+        pass_authorization: bool,
+    ) -> HashMap<String, String> {
         let mut env = HashMap::new();
 
         // Standard CGI variables
@@ -70,7 +285,7 @@ impl CgiExecutor {
         env.insert("SCRIPT_FILENAME".to_string(), script_path.to_string());
         env.insert("SCRIPT_NAME".to_string(), request.path.clone());
         env.insert("PATH_INFO".to_string(), request.path.clone());
-        env.insert("QUERY_STRING".to_string(), Self::build_query_string(&request.query));
+        env.insert("QUERY_STRING".to_string(), request.raw_query.clone());
         env.insert("SERVER_PROTOCOL".to_string(), request.version.clone());
         env.insert("SERVER_SOFTWARE".to_string(), "localhost/0.1.0".to_string());
         env.insert("GATEWAY_INTERFACE".to_string(), "CGI/1.1".to_string());
@@ -79,12 +294,24 @@ impl CgiExecutor {
         if let Some(content_type) = request.content_type() {
             env.insert("CONTENT_TYPE".to_string(), content_type.to_string());
         }
-        if let Some(content_length) = request.content_length() {
+        // A decoded chunked body has no Content-Length header of its own
+        let content_length = request.content_length()
+            .or_else(|| if request.body_len() == 0 { None } else { Some(request.body_len()) });
+        if let Some(content_length) = content_length {
             env.insert("CONTENT_LENGTH".to_string(), content_length.to_string());
         }
 
-        // HTTP headers as environment variables
+        // Authentication information
+        if let Some(user) = remote_user {
+            env.insert("AUTH_TYPE".to_string(), "Basic".to_string());
+            env.insert("REMOTE_USER".to_string(), user.to_string());
+        }
+
+        // HTTP headers as environment variables (credentials only when allowed)
         for (name, values) in request.headers.iter() {
+            if name == "authorization" && !pass_authorization {
+                continue;
+            }
             let env_name = format!("HTTP_{}", name.to_uppercase().replace('-', "_"));
             if let Some(value) = values.first() {
                 env.insert(env_name, value.clone());
@@ -97,34 +324,49 @@ impl CgiExecutor {
             env.insert("SERVER_NAME".to_string(), host.split(':').next().unwrap_or(host).to_string());
         }
 
-        env
-    }
+        // Connection endpoints
+        if let Some(local) = request.local_addr {
+            env.insert("SERVER_ADDR".to_string(), local.ip().to_string());
+            env.insert("SERVER_PORT".to_string(), local.port().to_string());
+        }
+        if let Some(remote) = request.remote_addr {
+            env.insert("REMOTE_ADDR".to_string(), remote.ip().to_string());
+            env.insert("REMOTE_PORT".to_string(), remote.port().to_string());
+        }
 
-    /// Builds query string from parameters
-    fn build_query_string(params: &HashMap<String, String>) -> String {
-        params
-            .iter()
-            .map(|(k, v)| format!("{}={}", k, v))
-            .collect::<Vec<_>>()
-            .join("&")
+        env
     }
 
     /// Parses CGI output into an HTTP response
     fn parse_cgi_output(output: &[u8]) -> Result<Response> {
-        let output_str = String::from_utf8_lossy(output);
-
-        // Find the header/body separator
-        let (headers_part, body) = if let Some(pos) = output_str.find("\r\n\r\n") {
-            (&output_str[..pos], &output[pos + 4..])
-        } else if let Some(pos) = output_str.find("\n\n") {
-            (&output_str[..pos], &output[pos + 2..])
-        } else {
-            // No headers, treat entire output as body
-            return Ok(Response::ok()
-                .content_type("text/html")
-                .body(output.to_vec()));
+        let (header_end, body_start) = match Self::find_header_end(output) {
+            Some(split) => split,
+            None => {
+                // No headers, treat entire output as body
+                return Ok(Response::ok()
+                    .content_type("text/html")
+                    .body(output.to_vec()));
+            }
         };
 
+        let mut response = Self::parse_cgi_headers(&String::from_utf8_lossy(&output[..header_end]));
+        response.body = output[body_start..].to_vec();
+        response.headers.set("Content-Length", &response.body.len().to_string());
+
+        Ok(response)
+    }
+
+    /// Locates the blank line ending a script's headers, returning where the
+    /// headers end and the body starts
+    fn find_header_end(output: &[u8]) -> Option<(usize, usize)> {
+        let find = |separator: &[u8]| output.windows(separator.len()).position(|w| w == separator);
+        find(b"\r\n\r\n")
+            .map(|pos| (pos, pos + 4))
+            .or_else(|| find(b"\n\n").map(|pos| (pos, pos + 2)))
+    }
+
+    /// Builds a response from a script's header lines
+    fn parse_cgi_headers(headers_part: &str) -> Response {
         // Parse headers
         let mut response = Response::new(StatusCode::Ok);
         let mut has_content_type = false;
@@ -136,12 +378,22 @@ impl CgiExecutor {
 
                 match name.to_lowercase().as_str() {
                     "status" => {
-                        // Parse status code from "Status: 200 OK" format
-                        if let Some(code) = value.split_whitespace().next() {
-                            if let Ok(code_num) = code.parse::<u16>() {
-                                if let Some(status) = StatusCode::from_code(code_num) {
-                                    response.status = status;
-                                }
+                        // Parse status code from "Status: 200 OK" format, keeping
+                        // the script's reason phrase verbatim
+                        let (code, reason) = match value.find(' ') {
+                            Some(pos) => (&value[..pos], value[pos + 1..].trim()),
+                            None => (value, ""),
+                        };
+                        if let Ok(code_num) = code.parse::<u16>() {
+                            if (100..1000).contains(&code_num) {
+                                let reason = if reason.is_empty() {
+                                    StatusCode::from_code(code_num)
+                                        .map(|s| s.reason())
+                                        .unwrap_or("Unknown")
+                                } else {
+                                    reason
+                                };
+                                response = response.raw_status(code_num, reason);
                             }
                         }
                     }
@@ -151,10 +403,15 @@ impl CgiExecutor {
                     }
                     "location" => {
                         response.headers.set("Location", value);
-                        if response.status == StatusCode::Ok {
+                        if response.status_code() == 200 {
                             response.status = StatusCode::Found;
+                            response.raw_status = None;
                         }
                     }
+                    // A script may set several cookies
+                    "set-cookie" => {
+                        response.headers.add("Set-Cookie", value);
+                    }
                     _ => {
                         response.headers.set(name, value);
                     }
@@ -167,10 +424,106 @@ impl CgiExecutor {
             response.headers.set("Content-Type", "text/html");
         }
 
-        // Set body
-        response.body = body.to_vec();
-        response.headers.set("Content-Length", &response.body.len().to_string());
+        response
+    }
+}
 
-        Ok(response)
+/// Largest header block accepted from a streaming CGI script
+const MAX_CGI_HEADER_SIZE: usize = 64 * 1024;
+
+/// The body of a streaming CGI response: whatever followed the headers in the
+/// first read, then the script's stdout until it exits
+/// The pipe is read on its own thread so a slow script never blocks the event
+/// loop; `read` reports `WouldBlock` until the next chunk arrives
+struct CgiStream {
+    pending: Vec<u8>,
+    chunks: Receiver<std::io::Result<Vec<u8>>>,
+    child: Child,
+    remaining: usize,
+}
+
+/// Chunks read ahead of the client before the reader thread waits
+const CGI_STREAM_BACKLOG: usize = 4;
+
+impl CgiStream {
+    /// Reads `stdout` on a new thread, handing chunks over until EOF, a read
+    /// error, or the stream being dropped
+    fn read_in_background(mut stdout: ChildStdout) -> Receiver<std::io::Result<Vec<u8>>> {
+        let (sender, receiver) = mpsc::sync_channel(CGI_STREAM_BACKLOG);
+        thread::spawn(move || {
+            let mut buf = [0u8; 8192];
+            loop {
+                let chunk = match stdout.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => Ok(buf[..n].to_vec()),
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                    Err(e) => Err(e),
+                };
+                let failed = chunk.is_err();
+                if sender.send(chunk).is_err() || failed {
+                    break;
+                }
+            }
+        });
+        receiver
+    }
+}
+
+impl Read for CgiStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pending.is_empty() {
+            let chunk = match self.chunks.try_recv() {
+                Ok(chunk) => chunk?,
+                Err(TryRecvError::Empty) => return Err(std::io::ErrorKind::WouldBlock.into()),
+                Err(TryRecvError::Disconnected) => return Ok(0),
+            };
+            if chunk.len() > self.remaining {
+                log_error!("CGI output exceeded the limit; closing the response");
+                return Err(std::io::Error::other("CGI output too large"));
+            }
+            self.remaining -= chunk.len();
+            self.pending = chunk;
+        }
+
+        let n = buf.len().min(self.pending.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        Ok(n)
+    }
+}
+
+impl Drop for CgiStream {
+    fn drop(&mut self) {
+        // A client that went away leaves the script running; stop it, which
+        // also ends the reader thread at EOF
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status_line(output: &[u8]) -> String {
+        let bytes = CgiExecutor::parse_cgi_output(output).unwrap().to_bytes();
+        let text = String::from_utf8_lossy(&bytes);
+        text.lines().next().unwrap().to_string()
+    }
+
+    #[test]
+    fn script_status_line() {
+        assert_eq!(status_line(b"Status: 418 I'm a teapot\r\n\r\nshort and stout"), "HTTP/1.1 418 I'm a teapot");
+        assert_eq!(status_line(b"Status: 404\nContent-Type: text/plain\n\nnope"), "HTTP/1.1 404 Not Found");
+        assert_eq!(status_line(b"Content-Type: text/plain\r\n\r\nok"), "HTTP/1.1 200 OK");
+        assert_eq!(status_line(b"Location: /elsewhere\r\n\r\n"), "HTTP/1.1 302 Found");
+    }
+
+    #[test]
+    fn script_body_is_kept() {
+        let response = CgiExecutor::parse_cgi_output(b"Status: 418 I'm a teapot\r\n\r\nshort and stout").unwrap();
+        assert_eq!(response.status_code(), 418);
+        assert_eq!(response.body, b"short and stout");
+        assert_eq!(response.headers.get("Content-Length"), Some("15"));
     }
 }
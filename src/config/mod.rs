@@ -4,11 +4,30 @@ pub mod server_config;
 
 pub use parser::ConfigParser;
 pub use route::Route;
-pub use server_config::{Config, ServerConfig};
+pub use server_config::{Config, CookiePolicy, RedirectPolicy, ServerConfig, SessionStorage, TcpKeepalive};
+
+use std::io::Read;
 
 impl Config {
-    /// Loads configuration from a file path
+    /// Loads configuration from a file path, or from stdin when the path is `-`
     pub fn load(path: &str) -> crate::error::Result<Self> {
-        ConfigParser::parse(path)
+        if path == "-" {
+            let mut content = String::new();
+            std::io::stdin().read_to_string(&mut content).map_err(|e| {
+                crate::error::ServerError::Config(format!("Failed to read config from stdin: {}", e))
+            })?;
+            return Self::load_string(&content);
+        }
+
+        let mut config = ConfigParser::parse(path)?;
+        config.finalize();
+        Ok(config)
+    }
+
+    /// Loads configuration from its text
+    pub fn load_string(content: &str) -> crate::error::Result<Self> {
+        let mut config = ConfigParser::parse_string(content)?;
+        config.finalize();
+        Ok(config)
     }
 }
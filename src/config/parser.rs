@@ -1,7 +1,8 @@
-use super::route::Route;
-use super::server_config::{Config, ServerConfig};
+use super::route::{Disposition, Route};
+use super::server_config::{Config, CookiePolicy, IpRange, RedirectPolicy, ServerConfig, SessionStorage, TcpKeepalive};
 use crate::error::{Result, ServerError};
-use crate::http::Method;
+use crate::http::{Method, StatusCode};
+use std::collections::HashMap;
 use std::fs;
 
 /// Configuration file parser
@@ -25,6 +26,10 @@ impl ConfigParser {
             if c.is_whitespace() {
                 continue;
             }
+            if c == '#' {
+                Self::skip_comment(&mut chars);
+                continue;
+            }
 
             // Read directive name
             let mut directive = String::new();
@@ -45,6 +50,26 @@ impl ConfigParser {
 
                 let server = Self::parse_server_block(&mut chars)?;
                 config.servers.push(server);
+            } else if directive == "log_level" {
+                Self::skip_whitespace(&mut chars);
+                let value = Self::read_value(&mut chars)?;
+                let level = value
+                    .parse()
+                    .map_err(|_| ServerError::Config(format!("Invalid log_level: {}", value)))?;
+                config.log_level = Some(level);
+            } else if directive == "lenient" {
+                Self::skip_whitespace(&mut chars);
+                let value = Self::read_value(&mut chars)?;
+                config.lenient = value == "on" || value == "true";
+            } else if directive == "session_store" {
+                Self::skip_whitespace(&mut chars);
+                let value = Self::read_value(&mut chars)?;
+                let mut parts = value.split_whitespace();
+                config.session_store = match (parts.next(), parts.next(), parts.next()) {
+                    (Some("memory"), None, None) => SessionStorage::Memory,
+                    (Some("file"), Some(dir), None) => SessionStorage::File(dir.to_string()),
+                    _ => return Err(ServerError::Config(format!("Invalid session_store: {}", value))),
+                };
             }
         }
 
@@ -75,40 +100,388 @@ impl ConfigParser {
 
                     match directive.as_str() {
                         "listen" => {
-                            let value = Self::read_value(chars);
-                            if let Ok(port) = value.parse::<u16>() {
+                            let value = Self::read_value(chars)?;
+                            let mut params = value.split_whitespace();
+                            let address = params.next().unwrap_or("");
+                            let flags: Vec<&str> = params.collect();
+                            let is_default = flags.contains(&"default_server");
+                            if let Some(path) = address.strip_prefix("unix:") {
+                                server.unix_sockets.push(path.to_string());
+                            } else if let Ok(port) = address.parse::<u16>() {
                                 if !server.ports.contains(&port) {
                                     server.ports.push(port);
                                 }
+                                if is_default && !server.default_ports.contains(&port) {
+                                    server.default_ports.push(port);
+                                }
+                                if flags.contains(&"ssl") && !server.ssl_ports.contains(&port) {
+                                    server.ssl_ports.push(port);
+                                }
                             }
                         }
+                        "upgrade_insecure_requests" => {
+                            let value = Self::read_value(chars)?;
+                            server.upgrade_insecure_requests = value == "on" || value == "true";
+                        }
+                        "ssl_certificate" => {
+                            server.ssl_certificate = Some(Self::read_value(chars)?);
+                        }
+                        "ssl_certificate_key" => {
+                            server.ssl_certificate_key = Some(Self::read_value(chars)?);
+                        }
+                        "default_server" => {
+                            let value = Self::read_value(chars)?;
+                            server.default_server = value == "on" || value == "true";
+                        }
+                        "unix_socket_mode" => {
+                            let value = Self::read_value(chars)?;
+                            let mode = u32::from_str_radix(&value, 8)
+                                .map_err(|_| ServerError::Config(format!("Invalid unix_socket_mode: {}", value)))?;
+                            server.unix_socket_mode = Some(mode);
+                        }
                         "server_name" => {
-                            server.server_name = Self::read_value(chars);
+                            server.server_name = Self::read_value(chars)?;
                         }
                         "host" => {
-                            server.host = Self::read_value(chars);
+                            server.host = Self::read_value(chars)?;
                         }
                         "root" => {
-                            server.root = Self::read_value(chars);
+                            server.root = Self::read_value(chars)?;
+                        }
+                        "index" => {
+                            server.index = Some(Self::read_value(chars)?);
                         }
                         "client_max_body_size" => {
-                            let value = Self::read_value(chars);
+                            let value = Self::read_value(chars)?;
                             server.client_max_body_size = Self::parse_size(&value)?;
                         }
                         "timeout" => {
-                            let value = Self::read_value(chars);
+                            let value = Self::read_value(chars)?;
                             server.timeout = value.parse().unwrap_or(60);
                         }
+                        "keepalive" => {
+                            let value = Self::read_value(chars)?;
+                            server.keepalive = value == "on" || value == "true";
+                        }
+                        "keepalive_header" => {
+                            let value = Self::read_value(chars)?;
+                            server.keepalive_header = value == "on" || value == "true";
+                        }
+                        "open_file_cache" => {
+                            // open_file_cache max=<count> [size=<bytes>] | off
+                            let value = Self::read_value(chars)?;
+                            server.open_file_cache_max = 0;
+                            for param in value.split_whitespace().filter(|p| *p != "off") {
+                                match param.split_once('=') {
+                                    Some(("max", n)) => {
+                                        server.open_file_cache_max = n.parse().map_err(|_| {
+                                            ServerError::Config(format!("Invalid open_file_cache max: {}", n))
+                                        })?;
+                                    }
+                                    Some(("size", n)) => server.open_file_cache_size = Self::parse_size(n)?,
+                                    _ => {
+                                        return Err(ServerError::Config(format!(
+                                            "Invalid open_file_cache parameter: {}",
+                                            param
+                                        )))
+                                    }
+                                }
+                            }
+                        }
+                        "response_buffer_size" => {
+                            let value = Self::read_value(chars)?;
+                            server.response_buffer_size = Self::parse_size(&value)?;
+                        }
+                        "read_buffer_size" => {
+                            let value = Self::read_value(chars)?;
+                            server.read_buffer_size = Self::parse_size(&value)?;
+                        }
+                        "write_buffer_size" => {
+                            let value = Self::read_value(chars)?;
+                            server.write_buffer_size = Self::parse_size(&value)?;
+                        }
+                        "max_connections" => {
+                            let value = Self::read_value(chars)?;
+                            server.max_connections = value.parse().map_err(|_| {
+                                ServerError::Config(format!("Invalid max_connections: {}", value))
+                            })?;
+                        }
+                        "max_connections_soft" => {
+                            let value = Self::read_value(chars)?;
+                            server.max_connections_soft = value.parse().map_err(|_| {
+                                ServerError::Config(format!("Invalid max_connections_soft: {}", value))
+                            })?;
+                        }
+                        "so_keepalive" => {
+                            // on, off, or idle:interval:count with any part left empty
+                            let value = Self::read_value(chars)?;
+                            server.so_keepalive = match value.as_str() {
+                                "on" | "true" => Some(TcpKeepalive::default()),
+                                "off" | "false" => None,
+                                _ => {
+                                    let parts: Vec<&str> = value.split(':').collect();
+                                    if parts.len() != 3 {
+                                        return Err(ServerError::Config(format!("Invalid so_keepalive: {}", value)));
+                                    }
+                                    let field = |part: &str| -> Result<Option<u32>> {
+                                        if part.is_empty() {
+                                            return Ok(None);
+                                        }
+                                        part.parse::<u32>()
+                                            .ok()
+                                            .filter(|n| *n > 0)
+                                            .map(Some)
+                                            .ok_or_else(|| ServerError::Config(format!("Invalid so_keepalive: {}", value)))
+                                    };
+                                    Some(TcpKeepalive {
+                                        idle: field(parts[0])?,
+                                        interval: field(parts[1])?,
+                                        count: field(parts[2])?,
+                                    })
+                                }
+                            };
+                        }
+                        "reset_timedout_connection" => {
+                            let value = Self::read_value(chars)?;
+                            server.reset_timedout_connection = value == "on" || value == "true";
+                        }
+                        "watchdog_timeout" => {
+                            let value = Self::read_value(chars)?;
+                            server.watchdog_timeout = value.parse().map_err(|_| {
+                                ServerError::Config(format!("Invalid watchdog_timeout: {}", value))
+                            })?;
+                        }
+                        "max_headers" => {
+                            let value = Self::read_value(chars)?;
+                            server.max_headers = value.parse().map_err(|_| {
+                                ServerError::Config(format!("Invalid max_headers: {}", value))
+                            })?;
+                        }
+                        "access_log" => {
+                            let value = Self::read_value(chars)?;
+                            server.access_log = if value == "off" { None } else { Some(value) };
+                        }
+                        "log_format" => {
+                            let value = Self::read_value(chars)?;
+                            server.log_format = Some(value.trim_matches('"').to_string());
+                        }
+                        "access_log_exclude_status" => {
+                            let value = Self::read_value(chars)?;
+                            server.access_log_exclude_status =
+                                value.split_whitespace().map(|s| s.to_lowercase()).collect();
+                        }
+                        "access_log_exclude_ip" => {
+                            let value = Self::read_value(chars)?;
+                            server.access_log_exclude_ip =
+                                value.split_whitespace().map(|s| s.to_string()).collect();
+                        }
+                        "gzip" => {
+                            let value = Self::read_value(chars)?;
+                            server.gzip = value == "on" || value == "true";
+                        }
+                        "gzip_min_length" => {
+                            let value = Self::read_value(chars)?;
+                            server.gzip_min_length = Self::parse_size(&value)?;
+                        }
+                        "gzip_types" => {
+                            let value = Self::read_value(chars)?;
+                            server.gzip_types =
+                                value.split_whitespace().map(|s| s.to_lowercase()).collect();
+                        }
+                        "brotli" => {
+                            let value = Self::read_value(chars)?;
+                            server.brotli = value == "on" || value == "true";
+                        }
+                        "brotli_comp_level" => {
+                            let value = Self::read_value(chars)?;
+                            server.brotli_comp_level = value.parse::<u32>()
+                                .ok()
+                                .filter(|level| *level <= 11)
+                                .ok_or_else(|| ServerError::Config(format!("Invalid brotli_comp_level: {}", value)))?;
+                        }
+                        "maintenance" => {
+                            let value = Self::read_value(chars)?;
+                            server.maintenance = value == "on" || value == "true";
+                        }
+                        "maintenance_file" => {
+                            server.maintenance_file = Some(Self::read_value(chars)?);
+                        }
+                        "set_cookie_max_size" => {
+                            let value = Self::read_value(chars)?;
+                            server.set_cookie_max_size = Self::parse_size(&value)?;
+                        }
+                        "set_cookie_max_count" => {
+                            let value = Self::read_value(chars)?;
+                            server.set_cookie_max_count = value.parse().map_err(|_| {
+                                ServerError::Config(format!("Invalid set_cookie_max_count: {}", value))
+                            })?;
+                        }
+                        "set_cookie_policy" => {
+                            let value = Self::read_value(chars)?;
+                            server.set_cookie_policy = match value.as_str() {
+                                "warn" => CookiePolicy::Warn,
+                                "drop" => CookiePolicy::Drop,
+                                "reject" => CookiePolicy::Reject,
+                                _ => return Err(ServerError::Config(format!("Invalid set_cookie_policy: {}", value))),
+                            };
+                        }
+                        "session_secret" => {
+                            let value = Self::read_value(chars)?;
+                            if value.is_empty() {
+                                return Err(ServerError::Config("Empty session_secret".to_string()));
+                            }
+                            server.session_secret = Some(value);
+                        }
+                        "session_renew_threshold" => {
+                            // A percentage of the cookie's lifetime (1-100), or off
+                            let value = Self::read_value(chars)?;
+                            server.session_renew_threshold = match value.as_str() {
+                                "off" => None,
+                                _ => Some(
+                                    value
+                                        .trim_end_matches('%')
+                                        .parse::<u8>()
+                                        .ok()
+                                        .filter(|p| (1..=100).contains(p))
+                                        .ok_or_else(|| {
+                                            ServerError::Config(format!("Invalid session_renew_threshold: {}", value))
+                                        })?,
+                                ),
+                            };
+                        }
+                        "redirect_allow_hosts" => {
+                            let value = Self::read_value(chars)?;
+                            server.redirect_allow_hosts
+                                .extend(value.split_whitespace().map(|h| h.trim_end_matches('.').to_lowercase()));
+                        }
+                        "redirect_policy" => {
+                            let value = Self::read_value(chars)?;
+                            server.redirect_policy = match value.as_str() {
+                                "log" => RedirectPolicy::Log,
+                                "block" => RedirectPolicy::Block,
+                                _ => return Err(ServerError::Config(format!("Invalid redirect_policy: {}", value))),
+                            };
+                        }
+                        "maintenance_retry_after" => {
+                            let value = Self::read_value(chars)?;
+                            server.maintenance_retry_after = value.parse().map_err(|_| {
+                                ServerError::Config(format!("Invalid maintenance_retry_after: {}", value))
+                            })?;
+                        }
+                        "maintenance_allow" => {
+                            let value = Self::read_value(chars)?;
+                            server.maintenance_allow =
+                                value.split_whitespace().map(|s| s.to_string()).collect();
+                        }
+                        "set_real_ip_from" => {
+                            let value = Self::read_value(chars)?;
+                            let range = IpRange::parse(&value)
+                                .ok_or_else(|| ServerError::Config(format!("Invalid set_real_ip_from: {}", value)))?;
+                            server.set_real_ip_from.push(range);
+                        }
+                        "strict_bodies" => {
+                            let value = Self::read_value(chars)?;
+                            server.strict_bodies = value == "on" || value == "true";
+                        }
+                        "body_methods" => {
+                            let value = Self::read_value(chars)?;
+                            server.body_methods = value.split_whitespace()
+                                .map(|m| {
+                                    m.parse::<Method>()
+                                        .map_err(|_| ServerError::Config(format!("Invalid body_methods: {}", m)))
+                                })
+                                .collect::<Result<_>>()?;
+                        }
+                        "debug_errors" => {
+                            let value = Self::read_value(chars)?;
+                            server.debug_errors = value == "on" || value == "true";
+                        }
+                        "health_check" => {
+                            server.health_check_uri = Some(Self::read_value(chars)?);
+                        }
+                        "health_check_roots" => {
+                            let value = Self::read_value(chars)?;
+                            server.health_check_roots = value == "on" || value == "true";
+                        }
+                        "health_check_path" => {
+                            server.health_check_paths.push(Self::read_value(chars)?);
+                        }
+                        "session_admin" => {
+                            server.session_admin = Some(Self::read_value(chars)?.trim_end_matches('/').to_string());
+                        }
+                        "session_admin_allow" => {
+                            let value = Self::read_value(chars)?;
+                            for item in value.split_whitespace() {
+                                let range = IpRange::parse(item).ok_or_else(|| {
+                                    ServerError::Config(format!("Invalid session_admin_allow: {}", item))
+                                })?;
+                                server.session_admin_allow.push(range);
+                            }
+                        }
+                        "method_override" => {
+                            let value = Self::read_value(chars)?;
+                            server.method_override = value == "on" || value == "true";
+                        }
+                        "landing_page" => {
+                            let value = Self::read_value(chars)?;
+                            server.landing_page = value == "on" || value == "true";
+                        }
+                        "landing_page_template" => {
+                            server.landing_page_template = Some(Self::read_value(chars)?);
+                        }
+                        "status_page" => {
+                            server.status_page = Some(Self::read_value(chars)?);
+                        }
+                        "favicon" => {
+                            server.favicon = Some(Self::read_value(chars)?);
+                        }
+                        "robots" => {
+                            // A quoted value is the file content itself, with \n for newlines
+                            let value = Self::read_value(chars)?;
+                            match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+                                Some(text) => server.robots_text = Some(text.replace("\\n", "\n")),
+                                None => server.robots = Some(value),
+                            }
+                        }
                         "error_page" => {
-                            let code_str = Self::read_word(chars);
-                            Self::skip_whitespace(chars);
-                            let path = Self::read_value(chars);
-                            if let Ok(code) = code_str.parse::<u16>() {
-                                server.error_pages.insert(code, path);
+                            // error_page <code>... [=<response code>] <path>
+                            let value = Self::read_value(chars)?;
+                            let mut parts: Vec<&str> = value.split_whitespace().collect();
+                            let path = match parts.pop() {
+                                Some(p) if !parts.is_empty() => p.to_string(),
+                                _ => return Err(ServerError::Config(format!("Invalid error_page: {}", value))),
+                            };
+
+                            let mut codes = Vec::new();
+                            let mut response_code = None;
+                            for part in parts {
+                                if let Some(code) = part.strip_prefix('=') {
+                                    let code = code.parse::<u16>().ok().filter(|c| StatusCode::from_code(*c).is_some());
+                                    match code {
+                                        Some(code) => response_code = Some(code),
+                                        None => return Err(ServerError::Config(format!("Invalid error_page response code: {}", part))),
+                                    }
+                                } else {
+                                    match part.parse::<u16>() {
+                                        Ok(code) => codes.push(code),
+                                        Err(_) => return Err(ServerError::Config(format!("Invalid error_page code: {}", part))),
+                                    }
+                                }
                             }
+
+                            for code in codes {
+                                server.error_pages.insert(code, path.clone());
+                                match response_code {
+                                    Some(response_code) => server.error_page_status.insert(code, response_code),
+                                    None => server.error_page_status.remove(&code),
+                                };
+                            }
+                        }
+                        "types_disposition" => {
+                            Self::parse_disposition_block(chars, &mut server.types_disposition)?;
                         }
                         "location" => {
-                            let path = Self::read_word(chars);
+                            let path = Self::expand_env(&Self::read_word(chars))?;
                             Self::skip_whitespace(chars);
                             if chars.next() != Some('{') {
                                 return Err(ServerError::Config("Expected '{' after location path".to_string()));
@@ -120,7 +493,7 @@ impl ConfigParser {
                         "" => continue,
                         _ => {
                             // Skip unknown directive
-                            Self::read_value(chars);
+                            Self::read_raw_value(chars);
                         }
                     }
                 }
@@ -132,14 +505,10 @@ impl ConfigParser {
         if server.routes.is_empty() {
             let mut default_route = Route::new("/");
             default_route.root = Some(server.root.clone());
+            default_route.index = None;
             server.routes.push(default_route);
         }
 
-        // Clear default port if others were specified
-        if server.ports.len() > 1 && server.ports.contains(&8080) {
-            server.ports.retain(|&p| p != 8080);
-        }
-
         Ok(server)
     }
 
@@ -147,6 +516,7 @@ impl ConfigParser {
     fn parse_location_block(chars: &mut std::iter::Peekable<std::str::Chars>, path: &str) -> Result<Route> {
         let mut route = Route::new(path);
         route.methods.clear(); // Clear default methods
+        route.index = None;    // Inherited from the server unless set
 
         loop {
             Self::skip_whitespace(chars);
@@ -162,7 +532,7 @@ impl ConfigParser {
 
                     match directive.as_str() {
                         "methods" | "allow_methods" => {
-                            let methods_str = Self::read_value(chars);
+                            let methods_str = Self::read_value(chars)?;
                             for method in methods_str.split_whitespace() {
                                 if let Ok(m) = method.parse::<Method>() {
                                     if !route.methods.contains(&m) {
@@ -172,33 +542,151 @@ impl ConfigParser {
                             }
                         }
                         "root" => {
-                            route.root = Some(Self::read_value(chars));
+                            route.root = Some(Self::read_value(chars)?);
+                        }
+                        "alias" => {
+                            route.alias = Some(Self::read_value(chars)?);
                         }
                         "index" => {
-                            route.index = Some(Self::read_value(chars));
+                            route.index = Some(Self::read_value(chars)?);
                         }
                         "autoindex" => {
-                            let value = Self::read_value(chars);
+                            let value = Self::read_value(chars)?;
                             route.autoindex = value == "on" || value == "true";
                         }
+                        "autoindex_missing" => {
+                            let value = Self::read_value(chars)?;
+                            route.autoindex_missing = match value.as_str() {
+                                "403" => 403,
+                                "404" => 404,
+                                _ => return Err(ServerError::Config(format!("Invalid autoindex_missing: {}", value))),
+                            };
+                        }
+                        "follow_symlinks" => {
+                            let value = Self::read_value(chars)?;
+                            route.follow_symlinks = value != "off" && value != "false";
+                        }
+                        "empty_as_no_content" => {
+                            let value = Self::read_value(chars)?;
+                            route.empty_as_no_content = value == "on" || value == "true";
+                        }
+                        "preload" => {
+                            let value = Self::read_value(chars)?;
+                            let mut parts = value.split_whitespace();
+                            let url = parts.next()
+                                .ok_or_else(|| ServerError::Config("preload needs a URL".to_string()))?;
+                            route.preload.push((url.to_string(), parts.next().map(|t| t.to_string())));
+                        }
+                        "clean_urls" => {
+                            let value = Self::read_value(chars)?;
+                            route.clean_urls = value == "on" || value == "true";
+                        }
+                        "strict_accept" => {
+                            let value = Self::read_value(chars)?;
+                            route.strict_accept = value == "on" || value == "true";
+                        }
                         "return" | "redirect" => {
-                            let target = Self::read_value(chars);
+                            let target = Self::read_value(chars)?;
                             let permanent = directive == "redirect";
                             route.redirect = Some((target, permanent));
                         }
                         "cgi" => {
-                            let ext = Self::read_word(chars);
+                            let ext = Self::expand_env(&Self::read_word(chars))?;
                             Self::skip_whitespace(chars);
-                            let handler = Self::read_value(chars);
+                            let handler = Self::read_value(chars)?;
                             route.cgi.insert(ext, handler);
                         }
+                        "cgi_methods" => {
+                            let ext = Self::expand_env(&Self::read_word(chars))?;
+                            Self::skip_whitespace(chars);
+                            let value = Self::read_value(chars)?;
+                            let methods = value.split_whitespace()
+                                .map(|m| {
+                                    m.parse::<Method>()
+                                        .map_err(|_| ServerError::Config(format!("Invalid cgi_methods: {}", m)))
+                                })
+                                .collect::<Result<_>>()?;
+                            route.cgi_methods.insert(ext, methods);
+                        }
                         "upload_dir" => {
-                            route.upload_dir = Some(Self::read_value(chars));
+                            route.upload_dir = Some(Self::read_value(chars)?);
+                        }
+                        "cgi_max_output" => {
+                            let value = Self::read_value(chars)?;
+                            route.cgi_max_output = Some(Self::parse_size(&value)?);
+                        }
+                        "cgi_working_dir" => {
+                            route.cgi_working_dir = Some(Self::read_value(chars)?);
+                        }
+                        "cgi_buffering" => {
+                            let value = Self::read_value(chars)?;
+                            route.cgi_buffering = value == "on" || value == "true";
+                        }
+                        "cgi_dir" => {
+                            route.cgi_dir = Some(Self::read_value(chars)?);
+                        }
+                        "upload_tmp_dir" => {
+                            route.upload_tmp_dir = Some(Self::read_value(chars)?);
+                        }
+                        "upload_max_file_size" => {
+                            let value = Self::read_value(chars)?;
+                            route.upload_max_file_size = Some(Self::parse_size(&value)?);
+                        }
+                        "auth_basic" => {
+                            let realm = Self::read_value(chars)?;
+                            let realm = realm.trim_matches('"').to_string();
+                            route.auth_basic = if realm == "off" { None } else { Some(realm) };
+                        }
+                        "auth_basic_user_file" => {
+                            let path = Self::read_value(chars)?;
+                            route.auth_users = Self::parse_user_file(&path)?;
+                        }
+                        "access_log" => {
+                            let value = Self::read_value(chars)?;
+                            route.access_log = value != "off";
+                        }
+                        "client_max_body_size" => {
+                            let value = Self::read_value(chars)?;
+                            route.client_max_body_size = Some(Self::parse_size(&value)?);
+                        }
+                        "client_body_in_file_only" => {
+                            let value = Self::read_value(chars)?;
+                            route.client_body_in_file_only = value == "on" || value == "true";
+                        }
+                        "client_body_buffer_size" => {
+                            let value = Self::read_value(chars)?;
+                            route.client_body_buffer_size = Some(Self::parse_size(&value)?);
+                        }
+                        "client_body_temp_path" => {
+                            route.client_body_temp_path = Some(Self::read_value(chars)?);
+                        }
+                        "cgi_pass_authorization" => {
+                            let value = Self::read_value(chars)?;
+                            route.cgi_pass_authorization = value == "on" || value == "true";
+                        }
+                        "cors_allow_origin" => {
+                            route.cors_allow_origin = Some(Self::read_value(chars)?);
+                        }
+                        "cors_allow_headers" => {
+                            route.cors_allow_headers = Some(Self::read_value(chars)?);
+                        }
+                        "cors_max_age" => {
+                            let value = Self::read_value(chars)?;
+                            route.cors_max_age = Some(value.parse().map_err(|_| {
+                                ServerError::Config(format!("Invalid cors_max_age: {}", value))
+                            })?);
+                        }
+                        "cors_preflight" => {
+                            let value = Self::read_value(chars)?;
+                            route.cors_preflight = value == "on" || value == "true";
+                        }
+                        "types_disposition" => {
+                            Self::parse_disposition_block(chars, &mut route.types_disposition)?;
                         }
                         "" => continue,
                         _ => {
                             // Skip unknown directive
-                            Self::read_value(chars);
+                            Self::read_raw_value(chars);
                         }
                     }
                 }
@@ -214,21 +702,172 @@ impl ConfigParser {
         Ok(route)
     }
 
-    /// Skips whitespace characters
+    /// Parses a `types_disposition { attachment csv bin; inline pdf; }` block
+    /// into a map of extensions; `attachment: [csv, bin];` is read the same
+    fn parse_disposition_block(
+        chars: &mut std::iter::Peekable<std::str::Chars>,
+        types: &mut HashMap<String, Disposition>,
+    ) -> Result<()> {
+        if chars.next() != Some('{') {
+            return Err(ServerError::Config("Expected '{' after types_disposition".to_string()));
+        }
+
+        loop {
+            Self::skip_whitespace(chars);
+            match chars.peek() {
+                Some('}') => {
+                    chars.next();
+                    return Ok(());
+                }
+                Some(_) => {
+                    let kind = Self::read_word(chars);
+                    let disposition = match kind.trim_end_matches(':') {
+                        "attachment" => Disposition::Attachment,
+                        "inline" => Disposition::Inline,
+                        "" => continue,
+                        other => {
+                            return Err(ServerError::Config(format!("Invalid types_disposition: {}", other)));
+                        }
+                    };
+                    Self::skip_whitespace(chars);
+                    let value = Self::read_value(chars)?;
+                    let extensions = value
+                        .split(|c: char| c.is_whitespace() || c == ',')
+                        .map(|ext| ext.trim_matches(|c| c == '[' || c == ']' || c == ':').trim_start_matches('.'))
+                        .filter(|ext| !ext.is_empty());
+                    for ext in extensions {
+                        types.insert(ext.to_ascii_lowercase(), disposition);
+                    }
+                }
+                None => {
+                    return Err(ServerError::Config("Unterminated types_disposition block".to_string()));
+                }
+            }
+        }
+    }
+
+    /// Loads Basic auth credentials from a file of `user:password` lines
+    fn parse_user_file(path: &str) -> Result<Vec<(String, String)>> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| ServerError::Config(format!("Failed to read auth file '{}': {}", path, e)))?;
+
+        let mut users = Vec::new();
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once(':') {
+                Some((user, password)) => users.push((user.to_string(), password.to_string())),
+                None => {
+                    return Err(ServerError::Config(format!("Invalid line in auth file '{}'", path)));
+                }
+            }
+        }
+        Ok(users)
+    }
+
+    /// Expands environment variables in a value: `${VAR}`, `${VAR:-default}`
+    /// and `$VAR`; `$$` is a literal `$`
+    /// Bare `$VAR` needs an upper-case name, so lower-case `$variables`
+    /// (e.g. in log_format) are left untouched
+    fn expand_env(content: &str) -> Result<String> {
+        let mut result = String::with_capacity(content.len());
+        let mut chars = content.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            if c != '$' {
+                result.push(c);
+                continue;
+            }
+
+            if chars.peek() == Some(&'$') {
+                chars.next();
+                result.push('$');
+            } else if chars.peek() == Some(&'{') {
+                chars.next();
+                let mut expr = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(ch) => expr.push(ch),
+                        None => {
+                            return Err(ServerError::Config(format!("Unterminated '${{{}'", expr)));
+                        }
+                    }
+                }
+
+                let (name, default) = match expr.split_once(":-") {
+                    Some((name, default)) => (name, Some(default)),
+                    None => (expr.as_str(), None),
+                };
+
+                // Like the shell, the default also applies to empty values
+                match std::env::var(name) {
+                    Ok(value) if !value.is_empty() || default.is_none() => result.push_str(&value),
+                    _ => match default {
+                        Some(default) => result.push_str(default),
+                        None => {
+                            return Err(ServerError::Config(format!(
+                                "Undefined environment variable '{}'",
+                                name
+                            )));
+                        }
+                    },
+                }
+            } else if chars.peek().map(|ch| ch.is_ascii_uppercase()).unwrap_or(false) {
+                let mut name = String::new();
+                while let Some(&ch) = chars.peek() {
+                    if ch.is_ascii_uppercase() || ch.is_ascii_digit() || ch == '_' {
+                        name.push(ch);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+
+                let value = std::env::var(&name).map_err(|_| {
+                    ServerError::Config(format!("Undefined environment variable '{}'", name))
+                })?;
+                result.push_str(&value);
+            } else {
+                result.push(c);
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Skips whitespace characters and `#` comments
     fn skip_whitespace(chars: &mut std::iter::Peekable<std::str::Chars>) {
         while let Some(&c) = chars.peek() {
             if c.is_whitespace() {
                 chars.next();
+            } else if c == '#' {
+                Self::skip_comment(chars);
             } else {
                 break;
             }
         }
     }
 
+    /// Skips the rest of a `#` comment line
+    fn skip_comment(chars: &mut std::iter::Peekable<std::str::Chars>) {
+        for c in chars.by_ref() {
+            if c == '\n' {
+                break;
+            }
+        }
+    }
+
     /// Reads a word (until whitespace or special char)
     fn read_word(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
         let mut word = String::new();
         while let Some(&c) = chars.peek() {
+            if c == '{' && word.ends_with('$') {
+                Self::read_braced(chars, &mut word);
+                continue;
+            }
             if c.is_whitespace() || c == '{' || c == '}' || c == ';' {
                 break;
             }
@@ -237,10 +876,20 @@ impl ConfigParser {
         word
     }
 
-    /// Reads a value (until newline or semicolon)
-    fn read_value(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+    /// Reads a value (until newline or semicolon) and expands environment
+    /// variables in it
+    fn read_value(chars: &mut std::iter::Peekable<std::str::Chars>) -> Result<String> {
+        Self::expand_env(&Self::read_raw_value(chars))
+    }
+
+    /// Reads a value (until newline or semicolon) as written
+    fn read_raw_value(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
         let mut value = String::new();
         while let Some(&c) = chars.peek() {
+            if c == '{' && value.ends_with('$') {
+                Self::read_braced(chars, &mut value);
+                continue;
+            }
             if c == '\n' || c == '\r' || c == ';' || c == '{' || c == '}' {
                 if c == ';' {
                     chars.next();
@@ -252,6 +901,21 @@ impl ConfigParser {
         value.trim().to_string()
     }
 
+    /// Reads the `{...}` of a `${...}` reference whole, so its braces and any
+    /// `;` in a default don't end the word or value; it can't span lines
+    fn read_braced(chars: &mut std::iter::Peekable<std::str::Chars>, into: &mut String) {
+        while let Some(&c) = chars.peek() {
+            if c == '\n' {
+                break;
+            }
+            into.push(c);
+            chars.next();
+            if c == '}' {
+                break;
+            }
+        }
+    }
+
     /// Parses a size string (e.g., "10M", "1024K", "1G")
     fn parse_size(s: &str) -> Result<usize> {
         let s = s.trim();
@@ -275,3 +939,79 @@ impl ConfigParser {
             .map_err(|_| ServerError::Config(format!("Invalid size: {}", s)))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listen_8080_next_to_other_ports() {
+        let config = ConfigParser::parse_string("server {\n    listen 8080\n    listen 8443 ssl\n}\n").unwrap();
+        assert_eq!(config.servers[0].ports, [8080, 8443]);
+        assert_eq!(config.servers[0].ssl_ports, [8443]);
+    }
+
+    #[test]
+    fn environment_variables_in_values() {
+        std::env::set_var("LOCALHOST_TEST_PORT", "9191");
+        std::env::set_var("LOCALHOST_TEST_EMPTY", "");
+        let config = ConfigParser::parse_string(
+            "server {\n    listen $LOCALHOST_TEST_PORT\n    server_name ${LOCALHOST_TEST_UNSET:-example.com}\n    \
+             # never expanded: ${LOCALHOST_TEST_UNSET}\n    root /srv/${LOCALHOST_TEST_EMPTY:-www}\n    \
+             location / {\n        index cost$$.html\n    }\n}\n",
+        )
+        .unwrap();
+        let server = &config.servers[0];
+        assert_eq!(server.ports, [9191]);
+        assert_eq!(server.server_name, "example.com");
+        assert_eq!(server.root, "/srv/www");
+        assert_eq!(server.routes[0].index.as_deref(), Some("cost$.html"));
+
+        // Substituted text stays inside its value
+        std::env::set_var("LOCALHOST_TEST_NAME", "a; listen 1 }");
+        let config = ConfigParser::parse_string("server {\n    listen 8080\n    server_name $LOCALHOST_TEST_NAME\n}\n").unwrap();
+        assert_eq!(config.servers[0].server_name, "a; listen 1 }");
+        assert_eq!(config.servers[0].ports, [8080]);
+
+        for value in ["$LOCALHOST_TEST_UNSET", "${LOCALHOST_TEST_UNSET}", "${LOCALHOST_TEST_PORT"] {
+            let config = format!("server {{\n    listen 8080\n    root {}\n}}\n", value);
+            assert!(matches!(ConfigParser::parse_string(&config), Err(ServerError::Config(_))), "{}", value);
+        }
+    }
+
+    #[test]
+    fn error_page_codes_and_override() {
+        let config = ConfigParser::parse_string(
+            "server {\n    listen 8080\n    error_page 500 502 503 /50x.html\n    error_page 404 =200 /empty.html\n    \
+             error_page 502 /bad-gateway.html\n}\n",
+        )
+        .unwrap();
+        let server = &config.servers[0];
+        assert_eq!(server.get_error_page(500).map(String::as_str), Some("/50x.html"));
+        assert_eq!(server.get_error_page(503).map(String::as_str), Some("/50x.html"));
+        // A later directive takes a code over
+        assert_eq!(server.get_error_page(502).map(String::as_str), Some("/bad-gateway.html"));
+        assert_eq!(server.error_page_status(404), 200);
+        assert_eq!(server.error_page_status(500), 500);
+
+        for bad in ["error_page /x.html", "error_page 5xx /x.html", "error_page 404 =299 /x.html"] {
+            let config = format!("server {{\n    listen 8080\n    {}\n}}\n", bad);
+            assert!(ConfigParser::parse_string(&config).is_err(), "{}", bad);
+        }
+    }
+
+
+    #[test]
+    fn so_keepalive_forms() {
+        let keepalive = |value: &str| {
+            ConfigParser::parse_string(&format!("server {{\n    listen 8080\n    so_keepalive {}\n}}\n", value))
+                .map(|config| config.servers[0].so_keepalive)
+        };
+        assert_eq!(keepalive("off").unwrap(), None);
+        assert_eq!(keepalive("on").unwrap(), Some(TcpKeepalive::default()));
+        assert_eq!(keepalive("60::5").unwrap(), Some(TcpKeepalive { idle: Some(60), interval: None, count: Some(5) }));
+        for bad in ["60:10", "0:10:5", "a:b:c"] {
+            assert!(keepalive(bad).is_err(), "{}", bad);
+        }
+    }
+}
@@ -1,5 +1,29 @@
 use crate::http::Method;
 use std::collections::HashMap;
+use std::path::Path;
+
+/// How a served file is presented: rendered by the browser or downloaded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Disposition {
+    Inline,
+    Attachment,
+}
+
+impl Disposition {
+    /// Content-Disposition value for a file; the name is only included when
+    /// it is plain printable ASCII, else the browser falls back to the URL's
+    pub fn header_value(&self, filename: &str) -> String {
+        let kind = match self {
+            Disposition::Inline => "inline",
+            Disposition::Attachment => "attachment",
+        };
+        if filename.is_empty() || !filename.bytes().all(|b| (b' '..=b'~').contains(&b)) {
+            return kind.to_string();
+        }
+        let quoted = filename.replace('\\', "\\\\").replace('"', "\\\"");
+        format!("{}; filename=\"{}\"", kind, quoted)
+    }
+}
 
 /// Route configuration for a location block
 #[derive(Debug, Clone)]
@@ -10,16 +34,77 @@ pub struct Route {
     pub methods: Vec<Method>,
     /// Root directory for serving files
     pub root: Option<String>,
+    /// Directory replacing the matched location prefix (takes precedence over root)
+    pub alias: Option<String>,
     /// Default index file (e.g., "index.html")
     pub index: Option<String>,
     /// Enable directory listing
     pub autoindex: bool,
+    /// Status for a directory with no index file and listing off (403 or 404)
+    pub autoindex_missing: u16,
+    /// Follow symbolic links when serving and listing files
+    pub follow_symlinks: bool,
+    /// Answer GET for zero-byte files with 204 No Content instead of an empty 200
+    pub empty_as_no_content: bool,
+    /// Answer 406 Not Acceptable when the response's Content-Type isn't
+    /// admitted by the request's Accept header
+    pub strict_accept: bool,
+    /// Serve `<path>.html` for an extensionless path that doesn't exist
+    pub clean_urls: bool,
+    /// Content-Disposition of served files by extension (lowercase, no dot)
+    pub types_disposition: HashMap<String, Disposition>,
+    /// Assets announced in a 103 Early Hints response (URL, optional `as` type)
+    pub preload: Vec<(String, Option<String>)>,
     /// HTTP redirect (target URL, permanent flag)
     pub redirect: Option<(String, bool)>,
     /// CGI handlers by file extension (e.g., ".py" -> "/usr/bin/python3")
     pub cgi: HashMap<String, String>,
+    /// Methods a CGI extension's scripts take (e.g., ".py" -> GET POST);
+    /// extensions not listed take GET, HEAD and POST
+    pub cgi_methods: HashMap<String, Vec<Method>>,
+    /// Directory whose files all run as CGI scripts, replacing the location
+    /// prefix like an alias (classic cgi-bin / ScriptAlias)
+    pub cgi_dir: Option<String>,
+    /// Maximum bytes read from a CGI script's output (unlimited when None)
+    pub cgi_max_output: Option<usize>,
+    /// Working directory for CGI processes (default: the script's directory)
+    pub cgi_working_dir: Option<String>,
+    /// Collect a CGI script's whole output before responding; when off, the
+    /// body is streamed as it is produced and the connection closed after it
+    pub cgi_buffering: bool,
     /// Upload directory for file uploads
     pub upload_dir: Option<String>,
+    /// Directory for in-progress uploads (defaults to the upload directory;
+    /// must be on the same filesystem so finished files can be renamed in)
+    pub upload_tmp_dir: Option<String>,
+    /// Maximum size of a single multipart upload part (unlimited when None)
+    pub upload_max_file_size: Option<usize>,
+    /// Maximum request body size, overriding the server's limit
+    pub client_max_body_size: Option<usize>,
+    /// Spool every request body to a temp file
+    pub client_body_in_file_only: bool,
+    /// Bodies larger than this are spooled to a temp file (kept in memory
+    /// when None, unless `client_body_in_file_only` is set)
+    pub client_body_buffer_size: Option<usize>,
+    /// Directory for spooled request bodies (default: the system temp dir)
+    pub client_body_temp_path: Option<String>,
+    /// Basic authentication realm (enables auth when set)
+    pub auth_basic: Option<String>,
+    /// Basic authentication credentials (username, password)
+    pub auth_users: Vec<(String, String)>,
+    /// Pass the raw Authorization header to CGI scripts as HTTP_AUTHORIZATION
+    pub cgi_pass_authorization: bool,
+    /// Origins allowed cross-origin access ("*" or a space-separated list);
+    /// CORS headers are sent only when set
+    pub cors_allow_origin: Option<String>,
+    /// Access-Control-Allow-Headers for preflights (default: echo the request's)
+    pub cors_allow_headers: Option<String>,
+    /// Access-Control-Max-Age for preflights, in seconds
+    pub cors_max_age: Option<u64>,
+    /// Answer CORS preflights even when OPTIONS isn't among the route's methods
+    pub cors_preflight: bool,
+    /// Log requests to this location in the access log
+    pub access_log: bool,
 }
 
 impl Route {
@@ -29,14 +114,52 @@ impl Route {
             path: path.to_string(),
             methods: vec![Method::Get],
             root: None,
+            alias: None,
             index: Some("index.html".to_string()),
             autoindex: false,
+            autoindex_missing: 403,
+            follow_symlinks: true,
+            empty_as_no_content: false,
+            strict_accept: false,
+            clean_urls: false,
+            types_disposition: HashMap::new(),
+            preload: Vec::new(),
             redirect: None,
             cgi: HashMap::new(),
+            cgi_methods: HashMap::new(),
+            cgi_dir: None,
+            cgi_max_output: None,
+            cgi_working_dir: None,
+            cgi_buffering: true,
             upload_dir: None,
+            upload_tmp_dir: None,
+            upload_max_file_size: None,
+            client_max_body_size: None,
+            client_body_in_file_only: false,
+            client_body_buffer_size: None,
+            client_body_temp_path: None,
+            auth_basic: None,
+            auth_users: Vec::new(),
+            cgi_pass_authorization: false,
+            cors_allow_origin: None,
+            cors_allow_headers: None,
+            cors_max_age: None,
+            cors_preflight: false,
+            access_log: true,
         }
     }
 
+    /// Checks if a body of `len` bytes is spooled to a temp file
+    pub fn spools_body(&self, len: usize) -> bool {
+        len > 0 && (self.client_body_in_file_only || self.client_body_buffer_size.is_some_and(|max| len > max))
+    }
+
+    /// The configured disposition for a file, by its extension
+    pub fn disposition_for(&self, file_path: &str) -> Option<Disposition> {
+        let ext = Path::new(file_path).extension()?.to_str()?;
+        self.types_disposition.get(&ext.to_ascii_lowercase()).copied()
+    }
+
     /// Checks if a method is allowed for this route
     pub fn is_method_allowed(&self, method: &Method) -> bool {
         self.methods.contains(method)
@@ -69,6 +192,12 @@ impl Route {
 
     /// Returns the file path using provided root as fallback
     pub fn resolve_path_with_root(&self, request_path: &str, server_root: &str) -> Option<String> {
+        // If route has an alias (or CGI directory), replace the route prefix with it
+        if let Some(alias) = self.alias.as_ref().or(self.cgi_dir.as_ref()) {
+            let relative = request_path.strip_prefix(&self.path).unwrap_or(request_path);
+            return Some(Self::join_root(alias.trim_end_matches('/'), relative));
+        }
+
         // If route has custom root, use it and strip route prefix
         // Otherwise use server root and keep full request path
         if let Some(ref custom_root) = self.root {
@@ -78,21 +207,55 @@ impl Route {
             } else {
                 request_path.strip_prefix(&self.path).unwrap_or(request_path)
             };
-            let relative = relative.trim_start_matches('/');
-            
-            if relative.is_empty() {
-                Some(custom_root.clone())
-            } else {
-                Some(format!("{}/{}", custom_root, relative))
-            }
+            Some(Self::join_root(custom_root, relative))
         } else {
             // Using server root - map request path directly to server root
-            let clean_path = request_path.trim_start_matches('/');
-            if clean_path.is_empty() {
-                Some(server_root.to_string())
-            } else {
-                Some(format!("{}/{}", server_root, clean_path))
-            }
+            Some(Self::join_root(server_root, request_path))
+        }
+    }
+
+    /// Returns the directory requests on this route are served from
+    pub fn root_dir<'a>(&'a self, server_root: &'a str) -> &'a str {
+        self.alias.as_deref()
+            .or(self.cgi_dir.as_deref())
+            .or(self.root.as_deref())
+            .unwrap_or(server_root)
+    }
+
+    /// Joins a relative request path onto a root directory
+    /// A root that is a single file is served for every path (e.g. a maintenance page)
+    fn join_root(root: &str, relative: &str) -> String {
+        let relative = relative.trim_start_matches('/');
+        if relative.is_empty() || Path::new(root).is_file() {
+            root.to_string()
+        } else {
+            format!("{}/{}", root, relative)
+        }
+    }
+
+    /// Gets the CGI handler for a file: its extension's interpreter, or direct
+    /// execution (`*`) for any other file under a `cgi_dir`
+    pub fn cgi_handler_for(&self, path: &str) -> Option<&str> {
+        self.get_cgi_handler(path)
+            .map(|h| h.as_str())
+            .or_else(|| {
+                let in_cgi_dir = self.cgi_dir.is_some() && Path::new(path).is_file();
+                if in_cgi_dir { Some("*") } else { None }
+            })
+    }
+
+    /// Checks if a method may be handed to the CGI script at `path`: the
+    /// ones its extension's `cgi_methods` lists, or GET, HEAD and POST when
+    /// there is no list. HEAD goes wherever GET does
+    pub fn cgi_allows_method(&self, path: &str, method: &Method) -> bool {
+        let listed = self.cgi_methods
+            .iter()
+            .find(|(ext, _)| path.ends_with(ext.as_str()))
+            .map(|(_, methods)| methods.as_slice());
+
+        match listed {
+            Some(methods) => methods.contains(method) || (*method == Method::Head && methods.contains(&Method::Get)),
+            None => matches!(method, Method::Get | Method::Head | Method::Post),
         }
     }
 
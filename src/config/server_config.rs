@@ -1,5 +1,20 @@
-use super::route::Route;
+use super::route::{Disposition, Route};
+use crate::http::compression::{DEFAULT_BROTLI_QUALITY, DEFAULT_COMPRESSIBLE_TYPES};
+use crate::http::Method;
+use crate::log::Level;
 use std::collections::HashMap;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+
+/// Smallest accepted `read_buffer_size`
+const MIN_READ_BUFFER_SIZE: usize = 1024;
+
+/// Smallest accepted `write_buffer_size`
+const MIN_WRITE_BUFFER_SIZE: usize = 4096;
+
+/// Default `session_renew_threshold`: renew once the cookie is half spent
+const DEFAULT_SESSION_RENEW_THRESHOLD: u8 = 50;
 
 /// Configuration for a single virtual server
 #[derive(Debug, Clone)]
@@ -10,16 +25,223 @@ pub struct ServerConfig {
     pub host: String,
     /// Ports to listen on
     pub ports: Vec<u16>,
+    /// Answers requests on all its ports whose Host matches no server_name
+    pub default_server: bool,
+    /// Ports this server is the default for (`listen <port> default_server`)
+    pub default_ports: Vec<u16>,
+    /// Unix domain socket paths to listen on (`listen unix:<path>`)
+    pub unix_sockets: Vec<String>,
+    /// Permissions for created Unix socket files (e.g. 0o660)
+    pub unix_socket_mode: Option<u32>,
+    /// Port the Unix socket connections are matched to servers under: the
+    /// first listen port, or one reserved by `Config::finalize` for a server
+    /// listening on Unix sockets only
+    pub unix_port: u16,
+    /// Ports whose connections are TLS (`listen 443 ssl`)
+    pub ssl_ports: Vec<u16>,
+    /// PEM certificate chain presented on TLS ports
+    pub ssl_certificate: Option<String>,
+    /// PEM private key for the certificate
+    pub ssl_certificate_key: Option<String>,
+    /// Redirect plaintext requests carrying `Upgrade-Insecure-Requests: 1`
+    /// to https with 307
+    pub upgrade_insecure_requests: bool,
     /// Root directory for serving files
     pub root: String,
+    /// Index file for routes that don't set their own
+    pub index: Option<String>,
+    /// Content-Disposition by extension, for routes that don't map it themselves
+    pub types_disposition: HashMap<String, Disposition>,
     /// Maximum client body size in bytes
     pub client_max_body_size: usize,
     /// Custom error pages (status code -> file path)
     pub error_pages: HashMap<u16, String>,
+    /// Status codes custom error pages are sent with instead of the original
+    /// (`error_page 404 =200 /empty.html`)
+    pub error_page_status: HashMap<u16, u16>,
+    /// Show request context and internal error details on error pages
+    pub debug_errors: bool,
+    /// Reject request bodies on methods not listed in `body_methods` with 400
+    pub strict_bodies: bool,
+    /// Methods allowed to carry a body under `strict_bodies`
+    pub body_methods: Vec<Method>,
+    /// File served for /favicon.ico ahead of routing
+    pub favicon: Option<String>,
+    /// File served for /robots.txt ahead of routing
+    pub robots: Option<String>,
+    /// Inline text served for /robots.txt (takes precedence over `robots`)
+    pub robots_text: Option<String>,
+    /// Path of the health endpoint, answered ahead of routing (off when None)
+    pub health_check_uri: Option<String>,
+    /// Have the health endpoint check the server and location roots
+    pub health_check_roots: bool,
+    /// Extra files or directories the health endpoint checks
+    pub health_check_paths: Vec<String>,
+    /// Path of the status page with connection and cache counters, answered
+    /// ahead of routing (off when None)
+    pub status_page: Option<String>,
+    /// Let a POST stand in for PUT or DELETE via X-HTTP-Method-Override or
+    /// a `_method` form field
+    pub method_override: bool,
+    /// Answer the site root with a generated page linking the locations
+    /// when it has no index file and listing is off
+    pub landing_page: bool,
+    /// HTML file with `$server_name` and `$links` placeholders for the
+    /// landing page (built-in page when None)
+    pub landing_page_template: Option<String>,
+    /// Path of the endpoint that lists and destroys sessions, answered ahead
+    /// of routing (off when None)
+    pub session_admin: Option<String>,
+    /// Clients allowed to use the session admin endpoint (loopback when empty)
+    pub session_admin_allow: Vec<IpRange>,
     /// Route configurations
     pub routes: Vec<Route>,
     /// Request timeout in seconds
     pub timeout: u64,
+    /// Keep connections open between requests; when off, every response
+    /// carries `Connection: close` whatever the client asked for
+    pub keepalive: bool,
+    /// Advertise the idle timeout in a Keep-Alive header on persistent connections
+    pub keepalive_header: bool,
+    /// Responses up to this size are written as soon as they are ready,
+    /// in a single write, instead of waiting for the next poll iteration
+    pub response_buffer_size: usize,
+    /// Bytes taken in by each socket read
+    pub read_buffer_size: usize,
+    /// Bytes sent per chunk of a streamed response body
+    pub write_buffer_size: usize,
+    /// Maximum number of small static files kept in memory (0 disables the cache)
+    pub open_file_cache_max: usize,
+    /// Maximum total bytes of cached static files
+    pub open_file_cache_size: usize,
+    /// Maximum open connections; further connections are closed on accept (0 = unlimited)
+    pub max_connections: usize,
+    /// Open connections above which requests get 503 with Retry-After (0 = off)
+    pub max_connections_soft: usize,
+    /// Reset timed-out connections (TCP RST) instead of closing them gracefully
+    pub reset_timedout_connection: bool,
+    /// Seconds a connection may stay in one state before the watchdog closes
+    /// it; a response being written counts from its last progress (0 = off)
+    pub watchdog_timeout: u64,
+    /// Maximum number of header lines per request
+    pub max_headers: usize,
+    /// Access log destination (file path or "stdout"), None when disabled
+    pub access_log: Option<String>,
+    /// Access log line format with `$variable` placeholders
+    pub log_format: Option<String>,
+    /// Status classes (e.g. "2xx") excluded from the access log
+    pub access_log_exclude_status: Vec<String>,
+    /// Client IPs excluded from the access log
+    pub access_log_exclude_ip: Vec<String>,
+    /// Compress eligible responses with gzip
+    pub gzip: bool,
+    /// Smallest body size worth compressing
+    pub gzip_min_length: usize,
+    /// Content types to compress (`type/*` wildcards allowed)
+    pub gzip_types: Vec<String>,
+    /// Compress eligible responses with Brotli (same length and type rules as gzip)
+    pub brotli: bool,
+    /// Brotli quality, 0 (fastest) to 11 (smallest)
+    pub brotli_comp_level: u32,
+    /// Answer every request with 503 Service Unavailable
+    pub maintenance: bool,
+    /// Sentinel file enabling maintenance mode while it exists
+    pub maintenance_file: Option<String>,
+    /// Retry-After seconds sent with maintenance responses
+    pub maintenance_retry_after: u64,
+    /// Client IPs still served normally during maintenance
+    pub maintenance_allow: Vec<String>,
+    /// Maximum combined size of a response's Set-Cookie headers (0 = unlimited)
+    pub set_cookie_max_size: usize,
+    /// Maximum number of Set-Cookie headers in a response (0 = unlimited)
+    pub set_cookie_max_count: usize,
+    /// What to do with a response over the Set-Cookie limits
+    pub set_cookie_policy: CookiePolicy,
+    /// Proxies trusted to report the client address in X-Forwarded-For
+    pub set_real_ip_from: Vec<IpRange>,
+    /// TCP keepalive probes on accepted sockets (off when None)
+    pub so_keepalive: Option<TcpKeepalive>,
+    /// Key signing the session cookie, so a tampered cookie is ignored
+    pub session_secret: Option<String>,
+    /// Percent of the session cookie's lifetime after which a request
+    /// renews it (None = never renewed)
+    pub session_renew_threshold: Option<u8>,
+    /// Hosts redirects may point at besides `server_name`, `*.` prefixes
+    /// matching subdomains (empty = any host)
+    pub redirect_allow_hosts: Vec<String>,
+    /// What to do with a redirect to a host off `redirect_allow_hosts`
+    pub redirect_policy: RedirectPolicy,
+}
+
+/// TCP keepalive settings; unset values keep the system defaults
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TcpKeepalive {
+    /// Idle seconds before the first probe
+    pub idle: Option<u32>,
+    /// Seconds between probes
+    pub interval: Option<u32>,
+    /// Unanswered probes before the connection is dropped
+    pub count: Option<u32>,
+}
+
+/// Handling of responses whose Set-Cookie headers exceed the limits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CookiePolicy {
+    /// Send the response unchanged, logging a warning
+    Warn,
+    /// Drop the cookies past the limits, logging a warning
+    Drop,
+    /// Replace the response with 500 Internal Server Error
+    Reject,
+}
+
+/// Handling of redirects to hosts off the allowlist
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RedirectPolicy {
+    /// Send the redirect unchanged, logging a warning
+    Log,
+    /// Replace the response with 500 Internal Server Error
+    Block,
+}
+
+/// An address block in CIDR notation, or a single address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpRange {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpRange {
+    /// Parses "10.0.0.0/8", "2001:db8::/32" or a bare address
+    pub fn parse(value: &str) -> Option<Self> {
+        let (addr, prefix) = match value.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (value, None),
+        };
+        let addr: IpAddr = addr.parse().ok()?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u8>().ok().filter(|p| *p <= max)?,
+            None => max,
+        };
+        Some(IpRange { addr, prefix })
+    }
+
+    /// Checks if an address is inside the block; IPv4-mapped IPv6
+    /// addresses match their IPv4 form
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
 }
 
 impl ServerConfig {
@@ -29,21 +251,117 @@ impl ServerConfig {
             server_name: "localhost".to_string(),
             host: "127.0.0.1".to_string(),
             ports: vec![8080],
+            default_server: false,
+            default_ports: Vec::new(),
+            unix_sockets: Vec::new(),
+            unix_socket_mode: None,
+            unix_port: 0,
+            ssl_ports: Vec::new(),
+            ssl_certificate: None,
+            ssl_certificate_key: None,
+            upgrade_insecure_requests: false,
             root: "./www".to_string(),
+            index: Some("index.html".to_string()),
+            types_disposition: HashMap::new(),
             client_max_body_size: 10 * 1024 * 1024, // 10MB
             error_pages: HashMap::new(),
+            error_page_status: HashMap::new(),
+            debug_errors: false,
+            strict_bodies: false,
+            body_methods: vec![Method::Post, Method::Put],
+            favicon: None,
+            robots: None,
+            robots_text: None,
+            health_check_uri: None,
+            health_check_roots: true,
+            health_check_paths: Vec::new(),
+            status_page: None,
+            method_override: false,
+            landing_page: false,
+            landing_page_template: None,
+            session_admin: None,
+            session_admin_allow: Vec::new(),
             routes: vec![Route::default()],
             timeout: 60,
+            reset_timedout_connection: false,
+            watchdog_timeout: 0,
+            max_connections: 0,
+            max_connections_soft: 0,
+            keepalive: true,
+            keepalive_header: false,
+            response_buffer_size: 64 * 1024,
+            read_buffer_size: crate::server::connection::DEFAULT_READ_BUFFER_SIZE,
+            write_buffer_size: crate::server::connection::DEFAULT_WRITE_BUFFER_SIZE,
+            open_file_cache_max: 0,
+            open_file_cache_size: 10 * 1024 * 1024,
+            max_headers: crate::http::parser::DEFAULT_MAX_HEADERS,
+            access_log: None,
+            log_format: None,
+            access_log_exclude_status: Vec::new(),
+            access_log_exclude_ip: Vec::new(),
+            gzip: false,
+            gzip_min_length: 256,
+            gzip_types: DEFAULT_COMPRESSIBLE_TYPES.iter().map(|t| t.to_string()).collect(),
+            brotli: false,
+            brotli_comp_level: DEFAULT_BROTLI_QUALITY,
+            maintenance: false,
+            maintenance_file: None,
+            maintenance_retry_after: 60,
+            maintenance_allow: Vec::new(),
+            set_cookie_max_size: 0,
+            set_cookie_max_count: 0,
+            set_cookie_policy: CookiePolicy::Drop,
+            set_real_ip_from: Vec::new(),
+            so_keepalive: None,
+            session_secret: None,
+            session_renew_threshold: Some(DEFAULT_SESSION_RENEW_THRESHOLD),
+            redirect_allow_hosts: Vec::new(),
+            redirect_policy: RedirectPolicy::Block,
         }
     }
 
+    /// Checks if redirects may point at a host: the server's own name, any
+    /// host when no allowlist is set, or one on `redirect_allow_hosts`
+    pub fn is_redirect_host_allowed(&self, host: &str) -> bool {
+        if self.redirect_allow_hosts.is_empty() || host.eq_ignore_ascii_case(&self.server_name) {
+            return true;
+        }
+        self.redirect_allow_hosts.iter().any(|allowed| match allowed.strip_prefix("*.") {
+            Some(domain) => host.len() > domain.len() + 1
+                && host.ends_with(domain)
+                && host.as_bytes()[host.len() - domain.len() - 1] == b'.',
+            None => host == allowed,
+        })
+    }
+
+    /// Checks if a peer is a proxy trusted with X-Forwarded-For
+    pub fn is_trusted_proxy(&self, ip: IpAddr) -> bool {
+        self.set_real_ip_from.iter().any(|range| range.contains(ip))
+    }
+
+    /// Checks if maintenance mode is on, by directive or sentinel file
+    pub fn in_maintenance(&self) -> bool {
+        self.maintenance
+            || self.maintenance_file.as_ref().map(|f| Path::new(f).exists()).unwrap_or(false)
+    }
+
     /// Finds the best matching route for a request path
+    ///
+    /// Precedence: an exact path match wins, then the longest matching prefix;
+    /// among equally long prefixes the route declared first wins
     pub fn find_route(&self, path: &str) -> Option<&Route> {
-        // Find the most specific matching route (longest path)
-        self.routes
-            .iter()
-            .filter(|r| r.matches(path))
-            .max_by_key(|r| r.path.len())
+        if let Some(exact) = self.routes.iter().find(|r| r.path == path) {
+            return Some(exact);
+        }
+
+        let mut best: Option<&Route> = None;
+        for route in self.routes.iter().filter(|r| r.matches(path)) {
+            // Strictly longer only, so earlier routes keep ties
+            if best.map(|b| route.path.len() > b.path.len()).unwrap_or(true) {
+                best = Some(route);
+            }
+        }
+        best
     }
 
     /// Gets the error page path for a status code
@@ -51,9 +369,55 @@ impl ServerConfig {
         self.error_pages.get(&status_code)
     }
 
-    /// Checks if the body size is within limits
-    pub fn is_body_size_allowed(&self, size: usize) -> bool {
-        size <= self.client_max_body_size
+    /// Gets the status code a custom error page is sent with
+    pub fn error_page_status(&self, status_code: u16) -> u16 {
+        self.error_page_status.get(&status_code).copied().unwrap_or(status_code)
+    }
+
+    /// Checks if this server is the fallback for unmatched hosts on a port
+    pub fn is_default_for(&self, port: u16) -> bool {
+        self.listens_on(port) && (self.default_server || self.default_ports.contains(&port))
+    }
+
+    /// Checks if connections matched under `port` may reach this server,
+    /// over TCP or one of its Unix sockets
+    pub fn listens_on(&self, port: u16) -> bool {
+        self.ports.contains(&port) || (!self.unix_sockets.is_empty() && self.unix_port == port)
+    }
+
+    /// Copies server-level settings into every route that doesn't override
+    /// them, so request handling reads each setting from the route alone
+    ///
+    /// The root is not copied: a route's own root replaces the location
+    /// prefix, while the inherited server root maps the full request path
+    pub fn finalize(&mut self) {
+        for route in &mut self.routes {
+            if route.index.is_none() {
+                route.index = self.index.clone();
+            }
+            for (ext, disposition) in &self.types_disposition {
+                route.types_disposition.entry(ext.clone()).or_insert(*disposition);
+            }
+            if route.client_max_body_size.is_none() {
+                route.client_max_body_size = Some(self.client_max_body_size);
+            }
+            if route.upload_tmp_dir.is_none() {
+                route.upload_tmp_dir = route.upload_dir.clone();
+            }
+        }
+    }
+
+    /// Returns the body size limit for a request path: the matching route's
+    /// limit if set, else the server's
+    pub fn max_body_size_for(&self, path: &str) -> usize {
+        self.find_route(path)
+            .and_then(|r| r.client_max_body_size)
+            .unwrap_or(self.client_max_body_size)
+    }
+
+    /// Checks if a body size is within the limit for a request path
+    pub fn is_body_size_allowed(&self, path: &str, size: usize) -> bool {
+        size <= self.max_body_size_for(path)
     }
 }
 
@@ -68,6 +432,22 @@ impl Default for ServerConfig {
 pub struct Config {
     /// All server configurations
     pub servers: Vec<ServerConfig>,
+    /// Log level from the top-level `log_level` directive
+    pub log_level: Option<Level>,
+    /// Report missing or unreadable roots as warnings instead of errors
+    pub lenient: bool,
+    /// Where sessions are kept, from the top-level `session_store` directive
+    pub session_store: SessionStorage,
+}
+
+/// Session backend selected by the `session_store` directive
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum SessionStorage {
+    /// In process memory, lost on restart
+    #[default]
+    Memory,
+    /// One file per session in the given directory
+    File(String),
 }
 
 impl Config {
@@ -75,6 +455,36 @@ impl Config {
     pub fn new() -> Self {
         Config {
             servers: Vec::new(),
+            log_level: None,
+            lenient: false,
+            session_store: SessionStorage::Memory,
+        }
+    }
+
+    /// Resolves every route's inherited settings; run once after parsing
+    pub fn finalize(&mut self) {
+        for server in &mut self.servers {
+            server.finalize();
+        }
+
+        // A server on Unix sockets only gets a port of its own, taken from
+        // the top of the range past any port a server listens on, so its
+        // connections can't be matched to another server
+        let mut reserved = u16::MAX;
+        for i in 0..self.servers.len() {
+            if self.servers[i].unix_sockets.is_empty() {
+                continue;
+            }
+            self.servers[i].unix_port = match self.servers[i].ports.first() {
+                Some(&port) => port,
+                None => {
+                    while self.servers.iter().any(|s| s.ports.contains(&reserved)) {
+                        reserved -= 1;
+                    }
+                    reserved -= 1;
+                    reserved + 1
+                }
+            };
         }
     }
 
@@ -111,34 +521,203 @@ impl Config {
             }
         }
 
+        // At most one default server per host:port
+        for (i, server) in self.servers.iter().enumerate() {
+            for &port in &server.ports {
+                let clash = self.servers[..i].iter().find(|other| {
+                    other.host == server.host && other.is_default_for(port) && server.is_default_for(port)
+                });
+                if let Some(other) = clash {
+                    return Err(format!(
+                        "Servers '{}' and '{}' are both default_server for {}:{}",
+                        other.server_name, server.server_name, server.host, port
+                    ));
+                }
+            }
+        }
+
+        // A host:port is either TLS for every server on it or for none
+        for server in &self.servers {
+            for &port in &server.ports {
+                if server.ssl_ports.contains(&port) != self.is_ssl_address(&server.host, port) {
+                    return Err(format!("{}:{} is listed both with and without ssl", server.host, port));
+                }
+            }
+        }
+
+        if let SessionStorage::File(dir) = &self.session_store {
+            if !Path::new(dir).is_dir() {
+                return Err(format!("session_store directory '{}' does not exist", dir));
+            }
+        }
+
         // Validate each server
         for server in &self.servers {
-            if server.ports.is_empty() {
+            if server.ports.is_empty() && server.unix_sockets.is_empty() {
                 return Err(format!("Server '{}' has no ports configured", server.server_name));
             }
+            if !server.ssl_ports.is_empty()
+                && (server.ssl_certificate.is_none() || server.ssl_certificate_key.is_none())
+            {
+                return Err(format!(
+                    "Server '{}' listens with ssl but has no ssl_certificate and ssl_certificate_key",
+                    server.server_name
+                ));
+            }
             if server.root.is_empty() {
                 return Err(format!("Server '{}' has no root directory", server.server_name));
             }
+            if server.read_buffer_size < MIN_READ_BUFFER_SIZE {
+                return Err(format!(
+                    "Server '{}': read_buffer_size must be at least {} bytes",
+                    server.server_name, MIN_READ_BUFFER_SIZE
+                ));
+            }
+            if server.write_buffer_size < MIN_WRITE_BUFFER_SIZE {
+                return Err(format!(
+                    "Server '{}': write_buffer_size must be at least {} bytes",
+                    server.server_name, MIN_WRITE_BUFFER_SIZE
+                ));
+            }
+            for route in &server.routes {
+                if let Some(dir) = route.client_body_temp_path.as_deref().filter(|d| !Path::new(d).is_dir()) {
+                    return Err(format!(
+                        "Location '{}': client_body_temp_path '{}' does not exist",
+                        route.path, dir
+                    ));
+                }
+            }
+        }
+
+        let problems = self.root_problems();
+        if self.lenient {
+            for problem in &problems {
+                log_warn!("Configuration warning: {}", problem);
+            }
+        } else if !problems.is_empty() {
+            return Err(problems.join("; "));
         }
 
         Ok(())
     }
 
-    /// Finds the server config for a given host:port and server_name
-    pub fn find_server(&self, host: &str, port: u16, server_name: Option<&str>) -> Option<&ServerConfig> {
+    /// Lists every server and route whose resolved root is missing or unreadable
+    pub fn root_problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        for server in &self.servers {
+            // Servers sharing a name on several ports report once
+            for problem in Self::server_root_problems(server) {
+                if !problems.contains(&problem) {
+                    problems.push(problem);
+                }
+            }
+        }
+
+        problems
+    }
+
+    /// Lists the missing or unreadable roots of one server and its routes
+    fn server_root_problems(server: &ServerConfig) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if let Some(reason) = Self::check_root(&server.root) {
+            problems.push(format!("Server '{}': root '{}' {}", server.server_name, server.root, reason));
+        }
+
+        for route in &server.routes {
+            // Redirect-only routes never touch the filesystem
+            if route.redirect.is_some() {
+                continue;
+            }
+
+            // Routes without their own root inherit the server's, checked above
+            let root = match route.alias.as_ref().or(route.cgi_dir.as_ref()).or(route.root.as_ref()) {
+                Some(root) if *root != server.root => root,
+                _ => continue,
+            };
+
+            if let Some(reason) = Self::check_root(root) {
+                let problem = format!(
+                    "Server '{}' location '{}': root '{}' {}",
+                    server.server_name, route.path, root, reason
+                );
+                if !problems.contains(&problem) {
+                    problems.push(problem);
+                }
+            }
+        }
+
+        problems
+    }
+
+    /// Runs a server's health checks, listing what is failing
+    pub fn health_problems(server: &ServerConfig) -> Vec<String> {
+        let mut problems = if server.health_check_roots {
+            Self::server_root_problems(server)
+        } else {
+            Vec::new()
+        };
+
+        for path in &server.health_check_paths {
+            if let Some(reason) = Self::check_root(path) {
+                problems.push(format!("Path '{}' {}", path, reason));
+            }
+        }
+
+        problems
+    }
+
+    /// Checks that a root is a readable directory or file
+    /// Returns the reason it is unusable, if any
+    fn check_root(root: &str) -> Option<&'static str> {
+        let path = Path::new(root);
+        let readable = if path.is_dir() {
+            fs::read_dir(path).is_ok()
+        } else if path.exists() {
+            fs::File::open(path).is_ok()
+        } else {
+            return Some("does not exist");
+        };
+
+        if readable {
+            None
+        } else {
+            Some("is not readable")
+        }
+    }
+
+    /// Finds the server config for a port and server_name
+    pub fn find_server(&self, port: u16, server_name: Option<&str>) -> Option<&ServerConfig> {
         // First try to find exact match with server_name
         if let Some(name) = server_name {
             if let Some(server) = self.servers.iter().find(|s| {
-                s.ports.contains(&port) && s.server_name == name
+                s.listens_on(port) && s.server_name == name
             }) {
                 return Some(server);
             }
         }
 
-        // Fall back to first server matching port (default server)
+        self.default_server_for(port)
+    }
+
+    /// Returns the server marked default for a port, else the first one on it
+    pub fn default_server_for(&self, port: u16) -> Option<&ServerConfig> {
         self.servers
             .iter()
-            .find(|s| s.ports.contains(&port))
+            .find(|s| s.is_default_for(port))
+            .or_else(|| self.servers.iter().find(|s| s.listens_on(port)))
+    }
+
+    /// Largest body any server on a port accepts, by its own limit or a
+    /// location's: the cap on a body read before its route is known
+    pub fn max_body_size_on(&self, port: u16) -> usize {
+        self.servers
+            .iter()
+            .filter(|s| s.listens_on(port))
+            .flat_map(|s| s.routes.iter().filter_map(|r| r.client_max_body_size).chain([s.client_max_body_size]))
+            .max()
+            .unwrap_or(usize::MAX)
     }
 
     /// Finds server by Host header (for virtual hosting)
@@ -148,15 +727,37 @@ impl Config {
         
         // Try exact server_name match first
         if let Some(server) = self.servers.iter().find(|s| {
-            s.ports.contains(&port) && s.server_name == hostname
+            s.listens_on(port) && s.server_name == hostname
         }) {
             return Some(server);
         }
 
-        // Fall back to first server on this port (default)
+        // Fall back to the port's default server
+        self.default_server_for(port)
+    }
+
+    /// Finds the server for a request, preferring the name a TLS client sent
+    /// in SNI (which also picked the certificate) over the Host header
+    pub fn find_server_by_sni(&self, sni: Option<&str>, host_header: &str, port: u16) -> Option<&ServerConfig> {
+        let by_sni = sni.and_then(|name| {
+            self.servers.iter().find(|s| s.listens_on(port) && s.server_name.eq_ignore_ascii_case(name))
+        });
+        by_sni.or_else(|| self.find_server_by_host(host_header, port))
+    }
+
+    /// Port a server's name is served over TLS on: the first `ssl` port of
+    /// any server with that name, or 443
+    pub fn https_port_for(&self, server_name: &str) -> u16 {
         self.servers
             .iter()
-            .find(|s| s.ports.contains(&port))
+            .filter(|s| s.server_name == server_name)
+            .find_map(|s| s.ssl_ports.first().copied())
+            .unwrap_or(443)
+    }
+
+    /// Checks if connections to a host:port are TLS
+    pub fn is_ssl_address(&self, host: &str, port: u16) -> bool {
+        self.servers.iter().any(|s| s.host == host && s.ssl_ports.contains(&port))
     }
 
     /// Gets all unique host:port combinations
@@ -184,3 +785,145 @@ impl Default for Config {
         config
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Config, ConfigParser};
+
+    #[test]
+    fn route_precedence() {
+        let config = Config::load_string(
+            "server {\n    listen 8080\n    \
+             location / {\n        index root.html\n    }\n    \
+             location /docs {\n        index first.html\n    }\n    \
+             location /docs {\n        index second.html\n    }\n    \
+             location /docs/api {\n        index api.html\n    }\n    \
+             location /docs/api/v1.html {\n        index exact.html\n    }\n}\n",
+        )
+        .unwrap();
+        let server = &config.servers[0];
+        let index = |path: &str| server.find_route(path).unwrap().index.clone().unwrap();
+
+        assert_eq!(index("/docs/api/v1.html"), "exact.html");
+        assert_eq!(index("/docs/api/v2.html"), "api.html");
+        // Equally long prefixes: the first declared wins
+        assert_eq!(index("/docs"), "first.html");
+        assert_eq!(index("/docs/guide"), "first.html");
+        assert_eq!(index("/blog"), "root.html");
+    }
+
+    #[test]
+    fn missing_roots_are_named() {
+        let dir = std::env::temp_dir().to_string_lossy().into_owned();
+        let config = |lenient: &str, server_root: &str| {
+            Config::load_string(&format!(
+                "{}\nserver {{\n    listen 8080\n    server_name site\n    root {}\n    \
+                 location /docs {{\n        alias /nonexistent/docs\n    }}\n    \
+                 location /old {{\n        return 301 /new\n        root /nonexistent/old\n    }}\n    \
+                 location /inherit {{\n        index index.html\n    }}\n}}\n",
+                lenient, server_root
+            ))
+            .unwrap()
+        };
+
+        let err = config("", "/nonexistent/site").validate().unwrap_err();
+        assert!(err.contains("Server 'site': root '/nonexistent/site' "), "{}", err);
+        assert!(err.contains("Server 'site' location '/docs': root '/nonexistent/docs' "), "{}", err);
+        // Redirect-only routes never touch the filesystem
+        assert!(!err.contains("/old"), "{}", err);
+        assert_eq!(err.matches("; ").count(), 1);
+
+        assert!(config("", &dir).validate().unwrap_err().contains("/docs"));
+        assert!(config("lenient on", "/nonexistent/site").validate().is_ok());
+    }
+
+
+    #[test]
+    fn finalize_fills_inherited_settings() {
+        let source = "server {\n    listen 8080\n    index home.html\n    client_max_body_size 2k\n    \
+                      location / {\n        methods GET\n    }\n    \
+                      location /docs {\n        index readme.html\n        client_max_body_size 64\n    }\n}\n";
+
+        let mut config = ConfigParser::parse_string(source).unwrap();
+        assert_eq!(config.servers[0].find_route("/").unwrap().index, None);
+
+        config.finalize();
+        let server = &config.servers[0];
+        let root = server.find_route("/").unwrap();
+        assert_eq!(root.index.as_deref(), Some("home.html"));
+        assert_eq!(root.client_max_body_size, Some(2048));
+        let docs = server.find_route("/docs").unwrap();
+        assert_eq!(docs.index.as_deref(), Some("readme.html"));
+        assert_eq!(docs.client_max_body_size, Some(64));
+
+        // Config::load_string finalizes on its own
+        let loaded = Config::load_string(source).unwrap();
+        assert_eq!(loaded.servers[0].find_route("/").unwrap().index.as_deref(), Some("home.html"));
+    }
+
+
+    #[test]
+    fn default_server_takes_unknown_hosts() {
+        let dir = std::env::temp_dir().to_string_lossy().into_owned();
+        let config = |first: &str, second: &str| {
+            Config::load_string(&format!(
+                "server {{\n    listen 8080\n    listen 9090\n    server_name first\n    root {dir}\n    {}\n}}\n\
+                 server {{\n    listen 8080\n    listen 9090\n    server_name second\n    root {dir}\n    {}\n}}\n",
+                first,
+                second,
+                dir = dir
+            ))
+            .unwrap()
+        };
+        let name = |config: &Config, host: &str, port: u16| config.find_server_by_host(host, port).unwrap().server_name.clone();
+
+        // Without a marker the first server on the port is the fallback
+        let plain = config("", "");
+        assert_eq!(name(&plain, "unknown.test", 8080), "first");
+
+        let marked = config("", "default_server on");
+        assert_eq!(name(&marked, "unknown.test", 8080), "second");
+        assert_eq!(name(&marked, "first", 8080), "first");
+        assert!(marked.validate().is_ok());
+
+        // listen <port> default_server marks only that port
+        let per_port = config("", "listen 9090 default_server");
+        assert_eq!(name(&per_port, "unknown.test", 8080), "first");
+        assert_eq!(name(&per_port, "unknown.test:9090", 9090), "second");
+
+        let clash = config("default_server on", "listen 8080 default_server").validate().unwrap_err();
+        assert!(clash.contains("both default_server"), "{}", clash);
+    }
+
+
+    #[test]
+    fn buffer_size_minimums() {
+        let dir = std::env::temp_dir().to_string_lossy().into_owned();
+        let config = |sizes: &str| {
+            Config::load_string(&format!("server {{\n    listen 8080\n    root {}\n    {}\n}}\n", dir, sizes)).unwrap()
+        };
+        assert!(config("read_buffer_size 1k\n    write_buffer_size 4k").validate().is_ok());
+        assert!(config("read_buffer_size 512").validate().unwrap_err().contains("read_buffer_size"));
+        assert!(config("write_buffer_size 1k").validate().unwrap_err().contains("write_buffer_size"));
+    }
+
+
+    #[test]
+    fn ip_ranges() {
+        use super::IpRange;
+        let ip = |s: &str| s.parse::<std::net::IpAddr>().unwrap();
+
+        let block = IpRange::parse("10.0.0.0/8").unwrap();
+        assert!(block.contains(ip("10.255.0.1")));
+        assert!(block.contains(ip("::ffff:10.1.2.3")));
+        assert!(!block.contains(ip("11.0.0.1")));
+        let single = IpRange::parse("192.168.1.1").unwrap();
+        assert!(single.contains(ip("192.168.1.1")) && !single.contains(ip("192.168.1.2")));
+        assert!(IpRange::parse("2001:db8::/32").unwrap().contains(ip("2001:db8:ffff::1")));
+        assert!(IpRange::parse("0.0.0.0/0").unwrap().contains(ip("8.8.8.8")));
+
+        for bad in ["10.0.0.0/33", "10.0.0/8", "host.example", "::/129"] {
+            assert_eq!(IpRange::parse(bad), None, "{}", bad);
+        }
+    }
+}
@@ -12,10 +12,18 @@ pub enum ServerError {
     Config(String),
     /// HTTP parsing errors
     Parse(String),
+    /// Request data is valid so far but incomplete (more bytes needed)
+    Incomplete,
     /// Request timeout
     Timeout,
+    /// Body-carrying method without Content-Length or chunked encoding
+    LengthRequired,
     /// Client body too large
     BodyTooLarge,
+    /// Too many request header fields
+    TooManyHeaders,
+    /// HTTP version other than 1.0 or 1.1 (including the HTTP/2 preface)
+    UnsupportedVersion(String),
     /// Method not allowed
     MethodNotAllowed,
     /// Resource not found
@@ -28,6 +36,8 @@ pub enum ServerError {
     Internal(String),
     /// CGI execution error
     Cgi(String),
+    /// CGI script wrote more output than allowed
+    CgiOutputTooLarge,
 }
 
 impl fmt::Display for ServerError {
@@ -36,14 +46,19 @@ impl fmt::Display for ServerError {
             ServerError::Io(e) => write!(f, "I/O error: {}", e),
             ServerError::Config(msg) => write!(f, "Config error: {}", msg),
             ServerError::Parse(msg) => write!(f, "Parse error: {}", msg),
+            ServerError::Incomplete => write!(f, "Incomplete request"),
             ServerError::Timeout => write!(f, "Request timeout"),
+            ServerError::LengthRequired => write!(f, "Content-Length required"),
             ServerError::BodyTooLarge => write!(f, "Request body too large"),
+            ServerError::TooManyHeaders => write!(f, "Too many request headers"),
+            ServerError::UnsupportedVersion(v) => write!(f, "Unsupported HTTP version: {}", v),
             ServerError::MethodNotAllowed => write!(f, "Method not allowed"),
             ServerError::NotFound => write!(f, "Not found"),
             ServerError::Forbidden => write!(f, "Forbidden"),
             ServerError::BadRequest(msg) => write!(f, "Bad request: {}", msg),
             ServerError::Internal(msg) => write!(f, "Internal error: {}", msg),
             ServerError::Cgi(msg) => write!(f, "CGI error: {}", msg),
+            ServerError::CgiOutputTooLarge => write!(f, "CGI output exceeds the configured limit"),
         }
     }
 }
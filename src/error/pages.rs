@@ -0,0 +1,43 @@
+use crate::http::{format_http_date, Request};
+use std::time::SystemTime;
+
+/// Renders the built-in error page
+/// With `debug` set, the page also shows the request and any internal error
+/// detail; without it nothing about the request or the failure is revealed
+pub fn render(status_code: u16, message: &str, request: &Request, detail: Option<&str>, debug: bool) -> String {
+    let mut context = String::new();
+
+    if debug {
+        context.push_str("<hr>\n<pre>\n");
+        context.push_str(&format!("Method: {}\n", request.method));
+        context.push_str(&format!("Path: {}\n", escape_html(&request.path)));
+        if !request.raw_query.is_empty() {
+            context.push_str(&format!("Query: {}\n", escape_html(&request.raw_query)));
+        }
+        context.push_str(&format!("Time: {}\n", format_http_date(SystemTime::now())));
+        if let Some(detail) = detail {
+            context.push_str(&format!("Error: {}\n", escape_html(detail)));
+        }
+        context.push_str("</pre>\n");
+    }
+
+    format!(
+        "<!DOCTYPE html>\n\
+        <html>\n\
+        <head><title>{} {}</title></head>\n\
+        <body>\n\
+        <h1>{} {}</h1>\n\
+        {}\
+        </body>\n\
+        </html>",
+        status_code, message, status_code, message, context
+    )
+}
+
+/// Escapes text for inclusion in HTML
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,123 @@
+use super::headers::Headers;
+
+/// One entry of an Accept-style header: a media range, language or coding
+/// with its quality value
+#[derive(Debug, Clone, PartialEq)]
+pub struct AcceptItem {
+    /// Lowercased value, e.g. "text/html", "text/*", "en-us" or "gzip"
+    pub value: String,
+    /// Quality from the `q` parameter (default 1.0); 0 means "not acceptable"
+    pub q: f32,
+}
+
+/// Parses every line of an Accept-style header, or None if it is absent
+pub fn from_headers(headers: &Headers, name: &str) -> Option<Vec<AcceptItem>> {
+    if !headers.contains(name) {
+        return None;
+    }
+    Some(parse(&headers.get_list(name)))
+}
+
+/// Parses the elements of an Accept, Accept-Language or Accept-Encoding
+/// header into items ordered by preference: highest q first, then more
+/// specific ranges, then header order. Parameters other than `q` are ignored
+pub fn parse(entries: &[&str]) -> Vec<AcceptItem> {
+    let mut items: Vec<AcceptItem> = entries
+        .iter()
+        .filter_map(|entry| {
+            let mut params = entry.split(';');
+            let value = params.next()?.trim().to_lowercase();
+            if value.is_empty() {
+                return None;
+            }
+
+            let q = params
+                .filter_map(|p| p.trim().split_once('='))
+                .find(|(name, _)| name.trim().eq_ignore_ascii_case("q"))
+                .map(|(_, v)| v.trim().parse::<f32>().unwrap_or(0.0).clamp(0.0, 1.0))
+                .unwrap_or(1.0);
+
+            Some(AcceptItem { value, q })
+        })
+        .collect();
+
+    // Stable sort keeps header order among equals
+    items.sort_by(|a, b| {
+        b.q.partial_cmp(&a.q)
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then_with(|| specificity(&b.value).cmp(&specificity(&a.value)))
+    });
+    items
+}
+
+/// Quality the header gives a media type, from the most specific matching
+/// range; 0 when nothing matches
+pub fn quality(items: &[AcceptItem], media_type: &str) -> f32 {
+    let media_type = media_type.split(';').next().unwrap_or("").trim().to_lowercase();
+    items
+        .iter()
+        .filter(|item| matches(&item.value, &media_type))
+        .max_by_key(|item| specificity(&item.value))
+        .map(|item| item.q)
+        .unwrap_or(0.0)
+}
+
+/// Checks if the Accept header admits a media type; a missing header
+/// accepts anything
+pub fn accepts(headers: &Headers, media_type: &str) -> bool {
+    match from_headers(headers, "accept") {
+        Some(items) => quality(&items, media_type) > 0.0,
+        None => true,
+    }
+}
+
+/// Checks if a range ("*/*", "type/*", "*" or an exact value) covers a value
+pub fn matches(range: &str, value: &str) -> bool {
+    if range == "*" || range == "*/*" {
+        return true;
+    }
+    match range.strip_suffix("/*") {
+        Some(prefix) => value.split('/').next() == Some(prefix),
+        None => range == value,
+    }
+}
+
+/// Ranks how specific a range is: wildcards lose to type wildcards, which
+/// lose to exact values
+fn specificity(range: &str) -> u8 {
+    if range == "*" || range == "*/*" {
+        0
+    } else if range.ends_with("/*") {
+        1
+    } else {
+        2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges_ordered_by_preference() {
+        let items = parse(&["text/*;q=0.5", "application/json", "*/*;q=0.1", "text/html", "image/png;q=0"]);
+        let values: Vec<&str> = items.iter().map(|i| i.value.as_str()).collect();
+        assert_eq!(values, ["application/json", "text/html", "text/*", "*/*", "image/png"]);
+
+        assert_eq!(quality(&items, "text/html; charset=utf-8"), 1.0);
+        assert_eq!(quality(&items, "text/plain"), 0.5);
+        assert_eq!(quality(&items, "video/mp4"), 0.1);
+        // The most specific range wins, even over a looser one with a higher q
+        assert_eq!(quality(&items, "image/png"), 0.0);
+    }
+
+    #[test]
+    fn missing_or_mismatched_accept() {
+        let mut headers = Headers::new();
+        assert!(accepts(&headers, "text/html"));
+
+        headers.set("Accept", "application/json");
+        assert!(!accepts(&headers, "text/html"));
+        assert!(accepts(&headers, "application/json"));
+    }
+}
@@ -0,0 +1,92 @@
+use crate::session::store::random_hex;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// A request body spooled to a temp file instead of being kept in memory;
+/// the file is removed when the body is dropped
+#[derive(Debug)]
+pub struct SpooledBody {
+    path: PathBuf,
+    len: u64,
+}
+
+impl SpooledBody {
+    /// Writes a body to a new temp file in `dir`
+    pub fn create(dir: &Path, data: &[u8]) -> io::Result<Self> {
+        let mut writer = SpoolWriter::create(dir, data.len() as u64)?;
+        writer.write(data)?;
+        Ok(writer.finish())
+    }
+
+    /// Opens the spooled body for reading from the start
+    pub fn open(&self) -> io::Result<File> {
+        File::open(&self.path)
+    }
+
+    /// Size of the body in bytes
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Checks if the body is empty
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Path of the temp file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for SpooledBody {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// A request body being written to a temp file as it arrives from the client
+#[derive(Debug)]
+pub struct SpoolWriter {
+    body: SpooledBody,
+    file: File,
+    expected: u64,
+}
+
+impl SpoolWriter {
+    /// Creates an empty temp file in `dir` for a body of `expected` bytes
+    /// The name carries a random part so other local users can't predict it
+    pub fn create(dir: &Path, expected: u64) -> io::Result<Self> {
+        let path = dir.join(format!(".body-{}-{}.tmp", std::process::id(), random_hex(8)));
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+
+        Ok(SpoolWriter {
+            body: SpooledBody { path, len: 0 },
+            file,
+            expected,
+        })
+    }
+
+    /// Appends body bytes, up to the expected size; returns how many were
+    /// taken. Dropping the writer on error removes the partial file
+    pub fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let n = self.remaining().min(data.len() as u64) as usize;
+        self.file.write_all(&data[..n])?;
+        self.body.len += n as u64;
+        Ok(n)
+    }
+
+    /// Bytes still to come
+    pub fn remaining(&self) -> u64 {
+        self.expected - self.body.len
+    }
+
+    /// Closes the file and hands over the spooled body
+    pub fn finish(self) -> SpooledBody {
+        self.body
+    }
+}
@@ -0,0 +1,794 @@
+use super::accept;
+use super::request::Request;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+/// Content types compressed when no explicit list is configured
+pub const DEFAULT_COMPRESSIBLE_TYPES: &[&str] = &[
+    "text/*",
+    "application/json",
+    "application/javascript",
+    "application/xml",
+    "image/svg+xml",
+];
+
+/// Sliding window size for back-references
+const WINDOW_SIZE: usize = 32 * 1024;
+/// Shortest and longest back-reference DEFLATE can encode
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+/// Candidates examined per position when searching for a match
+const MAX_CHAIN: usize = 64;
+const HASH_SIZE: usize = 1 << 15;
+
+/// Base lengths for length codes 257..=285
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115,
+    131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+
+/// Base distances for distance codes 0..=29
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/// Brotli quality used when none is configured
+pub const DEFAULT_BROTLI_QUALITY: u32 = 6;
+
+/// Input bytes per Brotli meta-block
+const BROTLI_BLOCK_SIZE: usize = 1 << 18;
+
+/// Base values and extra bits of the Brotli insert length codes
+const INSERT_BASE: [u32; 24] = [
+    0, 1, 2, 3, 4, 5, 6, 8, 10, 14, 18, 26, 34, 50, 66, 98, 130, 194, 322, 578, 1090, 2114, 6210,
+    22594,
+];
+const INSERT_EXTRA: [u32; 24] = [
+    0, 0, 0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 7, 8, 9, 10, 12, 14, 24,
+];
+
+/// Base values and extra bits of the Brotli copy length codes
+const COPY_BASE: [u32; 24] = [
+    2, 3, 4, 5, 6, 7, 8, 9, 10, 12, 14, 18, 22, 30, 38, 54, 70, 102, 134, 198, 326, 582, 1094,
+    2118,
+];
+const COPY_EXTRA: [u32; 24] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 7, 8, 9, 10, 24,
+];
+
+/// Order in which the code length code lengths are stored
+const CODE_LENGTH_ORDER: [usize; 18] = [1, 2, 3, 4, 0, 5, 17, 6, 16, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+
+/// Fixed code (value, bits) for each code length code length 0..=5
+const CODE_LENGTH_LENGTH_CODES: [(u32, u32); 6] = [(0b00, 2), (0b0111, 4), (0b011, 3), (0b10, 2), (0b01, 2), (0b1111, 4)];
+
+/// Alphabet sizes of the literal, insert-and-copy and distance codes (the
+/// last without postfix bits or direct distance codes)
+const LITERAL_ALPHABET: usize = 256;
+const COMMAND_ALPHABET: usize = 704;
+const DISTANCE_ALPHABET: usize = 64;
+
+/// Result of negotiating a response's content coding
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EncodingChoice<'a> {
+    /// Send the body with this coding
+    Coding(&'a str),
+    /// Send the body as it is
+    Identity,
+    /// The client refuses identity and every supported coding (406)
+    NotAcceptable,
+}
+
+/// Picks a content coding from the request's Accept-Encoding: the supported
+/// coding with the highest q-value (ties going to the earlier one), unless
+/// identity is rated higher. A q-value of 0 rules a coding out, and
+/// `identity;q=0` (or `*;q=0` without an identity entry) forbids sending the
+/// body unencoded
+pub fn choose_encoding<'a>(request: &Request, supported: &[&'a str]) -> EncodingChoice<'a> {
+    let items = match accept::from_headers(&request.headers, "accept-encoding") {
+        Some(items) => items,
+        None => return EncodingChoice::Identity,
+    };
+
+    let mut best: Option<(&str, f32)> = None;
+    for &coding in supported {
+        let q = accept::quality(&items, coding);
+        if q > 0.0 && best.map(|(_, best_q)| q > best_q).unwrap_or(true) {
+            best = Some((coding, q));
+        }
+    }
+
+    // Identity is acceptable unless explicitly excluded
+    let identity_q = items
+        .iter()
+        .find(|item| item.value == "identity")
+        .or_else(|| items.iter().find(|item| item.value == "*"))
+        .map(|item| item.q);
+
+    match (best, identity_q) {
+        (Some((_, q)), Some(identity_q)) if identity_q > q => EncodingChoice::Identity,
+        (Some((coding, _)), _) => EncodingChoice::Coding(coding),
+        (None, Some(0.0)) => EncodingChoice::NotAcceptable,
+        (None, _) => EncodingChoice::Identity,
+    }
+}
+
+/// Checks if a Content-Type matches one of the compressible types
+/// Patterns are exact media types or `type/*` wildcards
+pub fn is_compressible<S: AsRef<str>>(content_type: &str, types: &[S]) -> bool {
+    let media_type = content_type.split(';').next().unwrap_or("").trim().to_lowercase();
+    types.iter().any(|pattern| {
+        let pattern = pattern.as_ref();
+        match pattern.strip_suffix("/*") {
+            Some(prefix) => media_type.split('/').next() == Some(prefix),
+            None => media_type == pattern,
+        }
+    })
+}
+
+/// Compresses data into the gzip format (RFC 1952)
+pub fn gzip(data: &[u8]) -> Vec<u8> {
+    // Magic, deflate method, no flags, no mtime, no extra flags, unknown OS
+    let mut out = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+    out.extend_from_slice(&deflate(data));
+    out.extend_from_slice(&crc32(data).to_le_bytes());
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out
+}
+
+/// Compresses data as a single raw DEFLATE block (RFC 1951) using the fixed
+/// Huffman codes and hash-chain LZ77 matching
+pub fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut writer = BitWriter::new();
+
+    // BFINAL = 1, BTYPE = 01 (fixed Huffman)
+    writer.write_bits(1, 1);
+    writer.write_bits(1, 2);
+
+    let mut head = vec![usize::MAX; HASH_SIZE];
+    let mut prev = vec![usize::MAX; WINDOW_SIZE];
+    let mut pos = 0;
+
+    while pos < data.len() {
+        let (length, distance) = find_match(data, pos, &head, &prev, MAX_CHAIN);
+
+        if length >= MIN_MATCH {
+            write_length(&mut writer, length);
+            write_distance(&mut writer, distance);
+            for p in pos..pos + length {
+                insert_hash(data, p, &mut head, &mut prev);
+            }
+            pos += length;
+        } else {
+            write_literal(&mut writer, data[pos] as u16);
+            insert_hash(data, pos, &mut head, &mut prev);
+            pos += 1;
+        }
+    }
+
+    // End of block
+    write_literal(&mut writer, 256);
+    writer.finish()
+}
+
+/// Compresses data into the Brotli format (RFC 7932)
+/// `quality` (0-11) sets how hard matches are searched for: 0 stores only
+/// literals, and each step up doubles the match candidates examined
+pub fn brotli(data: &[u8], quality: u32) -> Vec<u8> {
+    let max_chain = match quality.min(11) {
+        0 => 0,
+        q => 1 << (q - 1),
+    };
+
+    let mut writer = BitWriter::new();
+    // WBITS = 16: the 64K window covers every match distance
+    writer.write_bits(0, 1);
+
+    if data.is_empty() {
+        // ISLAST, ISLASTEMPTY
+        writer.write_bits(1, 1);
+        writer.write_bits(1, 1);
+        return writer.finish();
+    }
+
+    let mut head = vec![usize::MAX; HASH_SIZE];
+    let mut prev = vec![usize::MAX; WINDOW_SIZE];
+    let mut start = 0;
+
+    while start < data.len() {
+        // Matches may reach back into earlier meta-blocks but not past this one
+        let end = (start + BROTLI_BLOCK_SIZE).min(data.len());
+        let block = &data[..end];
+        let commands = brotli_commands(block, start, &mut head, &mut prev, max_chain);
+        write_meta_block(&mut writer, block, start, &commands, end == data.len());
+        start = end;
+    }
+
+    writer.finish()
+}
+
+/// Computes the CRC-32 (IEEE) checksum of data
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// Hashes the three bytes starting at a position
+fn hash3(data: &[u8], pos: usize) -> usize {
+    let value = (data[pos] as usize) << 16 | (data[pos + 1] as usize) << 8 | data[pos + 2] as usize;
+    (value.wrapping_mul(2654435761) >> 7) & (HASH_SIZE - 1)
+}
+
+/// Records a position in the hash chains
+fn insert_hash(data: &[u8], pos: usize, head: &mut [usize], prev: &mut [usize]) {
+    if pos + MIN_MATCH > data.len() {
+        return;
+    }
+    let hash = hash3(data, pos);
+    prev[pos % WINDOW_SIZE] = head[hash];
+    head[hash] = pos;
+}
+
+/// Finds the longest earlier match for the data at a position, following at
+/// most `max_chain` candidates
+/// Returns (length, distance), with a length of 0 when nothing matches
+fn find_match(data: &[u8], pos: usize, head: &[usize], prev: &[usize], max_chain: usize) -> (usize, usize) {
+    if pos + MIN_MATCH > data.len() {
+        return (0, 0);
+    }
+
+    let max_length = MAX_MATCH.min(data.len() - pos);
+    let mut best = (0, 0);
+    let mut candidate = head[hash3(data, pos)];
+    let mut chain = 0;
+
+    while candidate != usize::MAX && chain < max_chain {
+        let distance = pos - candidate;
+        if distance == 0 || distance > WINDOW_SIZE {
+            break;
+        }
+
+        let length = data[candidate..]
+            .iter()
+            .zip(&data[pos..pos + max_length])
+            .take_while(|(a, b)| a == b)
+            .count();
+
+        if length > best.0 {
+            best = (length, distance);
+            if length == max_length {
+                break;
+            }
+        }
+
+        let next = prev[candidate % WINDOW_SIZE];
+        // Stop once the chain wraps into positions overwritten by newer ones
+        if next == usize::MAX || next >= candidate {
+            break;
+        }
+        candidate = next;
+        chain += 1;
+    }
+
+    best
+}
+
+/// Writes a literal/length symbol with the fixed Huffman code
+fn write_literal(writer: &mut BitWriter, symbol: u16) {
+    let (code, bits) = match symbol {
+        0..=143 => (0x30 + symbol as u32, 8),
+        144..=255 => (0x190 + (symbol as u32 - 144), 9),
+        256..=279 => (symbol as u32 - 256, 7),
+        _ => (0xc0 + (symbol as u32 - 280), 8),
+    };
+    writer.write_code(code, bits);
+}
+
+/// Writes a match length as its length code plus extra bits
+fn write_length(writer: &mut BitWriter, length: usize) {
+    let index = LENGTH_BASE.iter().rposition(|&base| base as usize <= length).unwrap_or(0);
+    write_literal(writer, 257 + index as u16);
+    writer.write_bits((length - LENGTH_BASE[index] as usize) as u32, LENGTH_EXTRA[index] as u32);
+}
+
+/// Writes a match distance as its fixed 5-bit code plus extra bits
+fn write_distance(writer: &mut BitWriter, distance: usize) {
+    let index = DIST_BASE.iter().rposition(|&base| base as usize <= distance).unwrap_or(0);
+    writer.write_code(index as u32, 5);
+    writer.write_bits((distance - DIST_BASE[index] as usize) as u32, DIST_EXTRA[index] as u32);
+}
+
+/// A Brotli command: literals taken from the input, then a back-reference
+/// (a `copy_len` of 0 marks the trailing literals of a meta-block)
+struct Command {
+    insert_start: usize,
+    insert_len: usize,
+    copy_len: usize,
+    distance: usize,
+}
+
+impl Command {
+    fn insert_code(&self) -> usize {
+        INSERT_BASE.iter().rposition(|&base| base as usize <= self.insert_len).unwrap_or(0)
+    }
+
+    fn copy_code(&self) -> usize {
+        COPY_BASE.iter().rposition(|&base| base as usize <= self.copy_len).unwrap_or(0)
+    }
+
+    /// Insert-and-copy symbol, always one that reads an explicit distance
+    fn symbol(&self) -> usize {
+        const CELL_BASE: [[usize; 3]; 3] = [[128, 192, 384], [256, 320, 512], [448, 576, 640]];
+        let (insert_code, copy_code) = (self.insert_code(), self.copy_code());
+        CELL_BASE[insert_code >> 3][copy_code >> 3] + ((insert_code & 7) << 3) + (copy_code & 7)
+    }
+}
+
+/// Splits the data from `start` to the end of the slice into commands,
+/// using the shared hash chains to find back-references
+fn brotli_commands(
+    data: &[u8],
+    start: usize,
+    head: &mut [usize],
+    prev: &mut [usize],
+    max_chain: usize,
+) -> Vec<Command> {
+    let mut commands = Vec::new();
+    let mut literal_start = start;
+    let mut pos = start;
+
+    while pos < data.len() {
+        let (length, distance) = find_match(data, pos, head, prev, max_chain);
+
+        if length >= MIN_MATCH {
+            commands.push(Command {
+                insert_start: literal_start,
+                insert_len: pos - literal_start,
+                copy_len: length,
+                distance,
+            });
+            for p in pos..pos + length {
+                insert_hash(data, p, head, prev);
+            }
+            pos += length;
+            literal_start = pos;
+        } else {
+            insert_hash(data, pos, head, prev);
+            pos += 1;
+        }
+    }
+
+    if literal_start < data.len() {
+        commands.push(Command {
+            insert_start: literal_start,
+            insert_len: data.len() - literal_start,
+            copy_len: 0,
+            distance: 0,
+        });
+    }
+    commands
+}
+
+/// Returns the distance code and its extra bits (count, value) for a
+/// back-reference, with no postfix bits or direct distance codes
+fn distance_code(distance: usize) -> (usize, u32, u32) {
+    let value = distance + 3;
+    let extra_bits = (usize::BITS - 1 - value.leading_zeros()) as usize - 1;
+    let high = (value >> extra_bits) & 1;
+    (16 + 2 * (extra_bits - 1) + high, extra_bits as u32, (value & ((1 << extra_bits) - 1)) as u32)
+}
+
+/// Writes the meta-block holding `data[start..]`: a header with a single
+/// block type and prefix code per category, then the commands
+fn write_meta_block(writer: &mut BitWriter, data: &[u8], start: usize, commands: &[Command], last: bool) {
+    let len = data.len() - start;
+
+    // ISLAST, and ISLASTEMPTY when set
+    writer.write_bits(last as u32, 1);
+    if last {
+        writer.write_bits(0, 1);
+    }
+    let nibbles = match len - 1 {
+        n if n < 1 << 16 => 4,
+        n if n < 1 << 20 => 5,
+        _ => 6,
+    };
+    writer.write_bits(nibbles - 4, 2);
+    writer.write_bits((len - 1) as u32, nibbles * 4);
+    if !last {
+        // ISUNCOMPRESSED
+        writer.write_bits(0, 1);
+    }
+
+    // One block type each for literals, commands and distances
+    writer.write_bits(0, 1);
+    writer.write_bits(0, 1);
+    writer.write_bits(0, 1);
+    // NPOSTFIX = 0, NDIRECT = 0
+    writer.write_bits(0, 2);
+    writer.write_bits(0, 4);
+    // Literal context mode, irrelevant with a single literal code
+    writer.write_bits(0, 2);
+    // One literal and one distance prefix code, so no context maps
+    writer.write_bits(0, 1);
+    writer.write_bits(0, 1);
+
+    let mut literal_freqs = vec![0u32; LITERAL_ALPHABET];
+    let mut command_freqs = vec![0u32; COMMAND_ALPHABET];
+    let mut distance_freqs = vec![0u32; DISTANCE_ALPHABET];
+    for command in commands {
+        for &byte in &data[command.insert_start..command.insert_start + command.insert_len] {
+            literal_freqs[byte as usize] += 1;
+        }
+        command_freqs[command.symbol()] += 1;
+        if command.copy_len > 0 {
+            distance_freqs[distance_code(command.distance).0] += 1;
+        }
+    }
+
+    let literal_code = PrefixCode::new(&literal_freqs, 15);
+    let command_code = PrefixCode::new(&command_freqs, 15);
+    let distance_code_tree = PrefixCode::new(&distance_freqs, 15);
+    literal_code.write(writer, 8);
+    command_code.write(writer, 10);
+    distance_code_tree.write(writer, 6);
+
+    for command in commands {
+        let (insert_code, copy_code) = (command.insert_code(), command.copy_code());
+        command_code.write_symbol(writer, command.symbol());
+        writer.write_bits(command.insert_len as u32 - INSERT_BASE[insert_code], INSERT_EXTRA[insert_code]);
+        // The trailing literals' command carries the shortest copy, never used
+        let copy_len = (command.copy_len as u32).max(COPY_BASE[0]);
+        writer.write_bits(copy_len - COPY_BASE[copy_code], COPY_EXTRA[copy_code]);
+
+        for &byte in &data[command.insert_start..command.insert_start + command.insert_len] {
+            literal_code.write_symbol(writer, byte as usize);
+        }
+
+        if command.copy_len > 0 {
+            let (code, extra_bits, extra) = distance_code(command.distance);
+            distance_code_tree.write_symbol(writer, code);
+            writer.write_bits(extra, extra_bits);
+        }
+    }
+}
+
+/// A canonical prefix code over an alphabet, as stored in a Brotli meta-block
+struct PrefixCode {
+    lengths: Vec<u8>,
+    codes: Vec<u32>,
+    /// The only symbol in use, sent with zero bits, when at most one is
+    single: Option<usize>,
+}
+
+impl PrefixCode {
+    /// Builds a code for the symbol frequencies, no longer than `max_bits`
+    fn new(freqs: &[u32], max_bits: u8) -> Self {
+        let used: Vec<usize> = (0..freqs.len()).filter(|&s| freqs[s] > 0).collect();
+        if used.len() <= 1 {
+            return PrefixCode {
+                lengths: vec![0; freqs.len()],
+                codes: vec![0; freqs.len()],
+                single: Some(used.first().copied().unwrap_or(0)),
+            };
+        }
+
+        let lengths = huffman_lengths(freqs, max_bits);
+        let codes = canonical_codes(&lengths);
+        PrefixCode { lengths, codes, single: None }
+    }
+
+    fn write_symbol(&self, writer: &mut BitWriter, symbol: usize) {
+        if self.single.is_none() {
+            writer.write_code(self.codes[symbol], self.lengths[symbol] as u32);
+        }
+    }
+
+    /// Writes the code's description: a simple code for a single symbol,
+    /// otherwise the code lengths, themselves run-length and prefix coded
+    fn write(&self, writer: &mut BitWriter, alphabet_bits: u32) {
+        if let Some(symbol) = self.single {
+            // HSKIP = 1 (simple code), NSYM = 1
+            writer.write_bits(1, 2);
+            writer.write_bits(0, 2);
+            writer.write_bits(symbol as u32, alphabet_bits);
+            return;
+        }
+
+        // Code lengths up to the last used symbol, with runs of zeros as
+        // code 17; a second 17 straight after would extend the first, so
+        // longer runs are broken up by a literal zero
+        let last = self.lengths.iter().rposition(|&l| l > 0).unwrap_or(0);
+        let mut tokens: Vec<(usize, u32)> = Vec::new();
+        let mut i = 0;
+        while i <= last {
+            if self.lengths[i] != 0 {
+                tokens.push((self.lengths[i] as usize, 0));
+                i += 1;
+                continue;
+            }
+
+            let run = self.lengths[i..=last].iter().take_while(|&&l| l == 0).count();
+            let mut remaining = run;
+            while remaining > 0 {
+                if remaining >= 3 {
+                    let take = remaining.min(10);
+                    tokens.push((17, (take - 3) as u32));
+                    remaining -= take;
+                    if remaining > 0 {
+                        tokens.push((0, 0));
+                        remaining -= 1;
+                    }
+                } else {
+                    tokens.push((0, 0));
+                    remaining -= 1;
+                }
+            }
+            i += run;
+        }
+
+        let mut token_freqs = [0u32; 18];
+        for &(token, _) in &tokens {
+            token_freqs[token] += 1;
+        }
+        let length_code = PrefixCode::new(&token_freqs, 5);
+
+        // HSKIP = 0, then the code length code lengths in storage order; the
+        // list ends once the code is complete, or runs to the end when only
+        // one code length is used (sent with zero bits)
+        writer.write_bits(0, 2);
+        let (length_lengths, count) = match length_code.single {
+            Some(token) => {
+                let mut lengths = vec![0u8; 18];
+                lengths[token] = 1;
+                (lengths, CODE_LENGTH_ORDER.len())
+            }
+            None => {
+                let lengths = length_code.lengths.clone();
+                let count = CODE_LENGTH_ORDER.iter().rposition(|&s| lengths[s] > 0).unwrap_or(0) + 1;
+                (lengths, count)
+            }
+        };
+        for &symbol in &CODE_LENGTH_ORDER[..count] {
+            let (value, bits) = CODE_LENGTH_LENGTH_CODES[length_lengths[symbol] as usize];
+            writer.write_bits(value, bits);
+        }
+
+        for (token, extra) in tokens {
+            length_code.write_symbol(writer, token);
+            if token == 17 {
+                writer.write_bits(extra, 3);
+            }
+        }
+    }
+}
+
+/// Computes Huffman code lengths of at most `max_bits`, flattening the
+/// frequencies until the tree is shallow enough
+fn huffman_lengths(freqs: &[u32], max_bits: u8) -> Vec<u8> {
+    let mut freqs = freqs.to_vec();
+    loop {
+        let lengths = huffman_depths(&freqs);
+        if lengths.iter().all(|&l| l <= max_bits) {
+            return lengths;
+        }
+        for freq in freqs.iter_mut().filter(|f| **f > 0) {
+            *freq = (*freq >> 1).max(1);
+        }
+    }
+}
+
+/// Computes the depth of each used symbol in a Huffman tree
+fn huffman_depths(freqs: &[u32]) -> Vec<u8> {
+    let mut parent = vec![usize::MAX; freqs.len()];
+    let mut heap: BinaryHeap<Reverse<(u64, usize)>> = freqs
+        .iter()
+        .enumerate()
+        .filter(|(_, &f)| f > 0)
+        .map(|(symbol, &f)| Reverse((f as u64, symbol)))
+        .collect();
+
+    while heap.len() > 1 {
+        let (Some(Reverse((w1, a))), Some(Reverse((w2, b)))) = (heap.pop(), heap.pop()) else {
+            break;
+        };
+        let node = parent.len();
+        parent.push(usize::MAX);
+        parent[a] = node;
+        parent[b] = node;
+        heap.push(Reverse((w1 + w2, node)));
+    }
+
+    (0..freqs.len())
+        .map(|symbol| {
+            if freqs[symbol] == 0 {
+                return 0;
+            }
+            let mut depth = 0;
+            let mut node = symbol;
+            while parent[node] != usize::MAX {
+                node = parent[node];
+                depth += 1;
+            }
+            depth.min(u8::MAX as usize) as u8
+        })
+        .collect()
+}
+
+/// Assigns canonical codes to code lengths: shorter codes first, and in
+/// symbol order within a length
+fn canonical_codes(lengths: &[u8]) -> Vec<u32> {
+    let mut count = [0u32; 16];
+    for &length in lengths.iter().filter(|&&l| l > 0) {
+        count[length as usize] += 1;
+    }
+
+    let mut next = [0u32; 16];
+    let mut code = 0;
+    for bits in 1..16 {
+        code = (code + count[bits - 1]) << 1;
+        next[bits] = code;
+    }
+
+    lengths
+        .iter()
+        .map(|&length| {
+            if length == 0 {
+                return 0;
+            }
+            let code = next[length as usize];
+            next[length as usize] += 1;
+            code
+        })
+        .collect()
+}
+
+/// Packs bits least-significant first, as DEFLATE and Brotli require
+struct BitWriter {
+    out: Vec<u8>,
+    buffer: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        BitWriter { out: Vec::new(), buffer: 0, count: 0 }
+    }
+
+    /// Writes a value's low `bits` bits, least significant first
+    fn write_bits(&mut self, value: u32, bits: u32) {
+        self.buffer |= (value as u64) << self.count;
+        self.count += bits;
+        while self.count >= 8 {
+            self.out.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// Writes a Huffman code, which is packed most significant bit first
+    fn write_code(&mut self, code: u32, bits: u32) {
+        let reversed = code.reverse_bits() >> (32 - bits);
+        self.write_bits(reversed, bits);
+    }
+
+    /// Flushes the remaining bits, padding the last byte
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.out.push(self.buffer as u8);
+        }
+        self.out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(data: &[u8]) {
+        for quality in [0, 1, DEFAULT_BROTLI_QUALITY, 11] {
+            let compressed = brotli(data, quality);
+            let mut decoded = Vec::new();
+            brotli_decompressor::BrotliDecompress(&mut &compressed[..], &mut decoded)
+                .unwrap_or_else(|e| panic!("quality {}, {} bytes: {}", quality, data.len(), e));
+            assert!(decoded == data, "quality {}, {} bytes: output differs", quality, data.len());
+        }
+    }
+
+    fn gunzip(data: &[u8]) -> Vec<u8> {
+        let mut decoded = Vec::new();
+        std::io::Read::read_to_end(&mut flate2::read::GzDecoder::new(data), &mut decoded).unwrap();
+        decoded
+    }
+
+    #[test]
+    fn gzip_round_trip() {
+        let mut state = 0x9e37_79b9_7f4a_7c15_u64;
+        let noise: Vec<u8> = (0..100_000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                (state >> 24) as u8
+            })
+            .collect();
+        let inputs: [&[u8]; 5] = [
+            b"",
+            b"a",
+            &b"<tr><td>row</td></tr>\n".repeat(10_000),
+            &noise,
+            &[noise.as_slice(), b"<p>hello</p>".repeat(5_000).as_slice()].concat(),
+        ];
+        for data in inputs {
+            let compressed = gzip(data);
+            assert!(gunzip(&compressed) == data, "{} bytes: output differs", data.len());
+        }
+        assert!(gzip(inputs[2]).len() < inputs[2].len() / 10);
+    }
+
+    #[test]
+    fn brotli_round_trip() {
+        round_trip(b"");
+        round_trip(b"a");
+        round_trip(b"<html><body>hello hello hello</body></html>");
+        round_trip(&b"abcabcabd".repeat(20_000));
+
+        // Pseudo-random bytes, which barely compress, across several blocks
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let noise: Vec<u8> = (0..200_000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                (state >> 24) as u8
+            })
+            .collect();
+        round_trip(&noise);
+
+        // Text mixed with noise, so matches and literals interleave
+        let mixed: Vec<u8> = noise
+            .chunks(100)
+            .flat_map(|chunk| [chunk, b"Content-Type: text/html; charset=utf-8\r\n"].concat())
+            .collect();
+        round_trip(&mixed);
+    }
+
+
+    #[test]
+    fn encoding_choice_honours_q_values() {
+        let choose = |accept: Option<&str>, supported: &[&'static str]| {
+            let mut request = Request::new(crate::http::Method::Get, "/");
+            if let Some(accept) = accept {
+                request.headers.set("Accept-Encoding", accept);
+            }
+            choose_encoding(&request, supported)
+        };
+        let both = ["gzip", "br"];
+
+        assert_eq!(choose(None, &both), EncodingChoice::Identity);
+        assert_eq!(choose(Some("gzip, br"), &both), EncodingChoice::Coding("gzip"));
+        assert_eq!(choose(Some("gzip;q=0, br;q=1.0"), &both), EncodingChoice::Coding("br"));
+        assert_eq!(choose(Some("gzip;q=0, br;q=1.0"), &["gzip"]), EncodingChoice::Identity);
+        assert_eq!(choose(Some("gzip;q=0.5, identity"), &both), EncodingChoice::Identity);
+        assert_eq!(choose(Some("*"), &both), EncodingChoice::Coding("gzip"));
+        // Identity ruled out, and nothing else acceptable
+        assert_eq!(choose(Some("gzip;q=0, identity;q=0"), &both), EncodingChoice::NotAcceptable);
+        assert_eq!(choose(Some("*;q=0"), &both), EncodingChoice::NotAcceptable);
+        assert_eq!(choose(Some("br, identity;q=0"), &["gzip"]), EncodingChoice::NotAcceptable);
+        assert_eq!(choose(Some("br, identity;q=0"), &both), EncodingChoice::Coding("br"));
+    }
+}
@@ -27,6 +27,16 @@ impl Headers {
         self.inner.get(&name.to_lowercase())
     }
 
+    /// Gets the elements of a list-type header (e.g. Accept-Encoding), with
+    /// every line split at commas outside quoted strings, so `a, b` on one
+    /// line and `a` and `b` on two give the same list. Elements are trimmed
+    /// and empty ones dropped
+    pub fn get_list(&self, name: &str) -> Vec<&str> {
+        self.get_all(name)
+            .map(|values| values.iter().flat_map(|v| split_list(v)).collect())
+            .unwrap_or_default()
+    }
+
     /// Sets a header value, replacing any existing values
     pub fn set(&mut self, name: &str, value: &str) {
         self.inner
@@ -37,7 +47,7 @@ impl Headers {
     pub fn add(&mut self, name: &str, value: &str) {
         self.inner
             .entry(name.to_lowercase())
-            .or_insert_with(Vec::new)
+            .or_default()
             .push(value.to_string());
     }
 
@@ -96,6 +106,15 @@ impl Headers {
         self.inner.iter()
     }
 
+    /// Returns the size in bytes of the serialized headers, as
+    /// `to_http_string().len()` would, without building the string
+    pub fn byte_len(&self) -> usize {
+        self.inner
+            .iter()
+            .map(|(name, values)| values.iter().map(|v| name.len() + v.len() + 4).sum::<usize>())
+            .sum()
+    }
+
     /// Serializes headers to HTTP format
     pub fn to_http_string(&self) -> String {
         let mut result = String::new();
@@ -107,3 +126,52 @@ impl Headers {
         result
     }
 }
+
+/// Splits a header value at commas outside quoted strings (where `\`
+/// escapes the next character), trimming elements and dropping empty ones
+pub fn split_list(value: &str) -> Vec<&str> {
+    let mut elements = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for (i, b) in value.bytes().enumerate() {
+        if escaped {
+            escaped = false;
+        } else if quoted && b == b'\\' {
+            escaped = true;
+        } else if b == b'"' {
+            quoted = !quoted;
+        } else if b == b',' && !quoted {
+            elements.push(value[start..i].trim());
+            start = i + 1;
+        }
+    }
+    elements.push(value[start..].trim());
+
+    elements.retain(|e| !e.is_empty());
+    elements
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn list_headers_fold_across_lines() {
+        let mut one_line = Headers::new();
+        one_line.add("Accept", "a, b");
+        let mut two_lines = Headers::new();
+        two_lines.add("Accept", "a");
+        two_lines.add("accept", "b");
+        assert_eq!(one_line.get_list("accept"), ["a", "b"]);
+        assert_eq!(two_lines.get_list("ACCEPT"), ["a", "b"]);
+        assert!(one_line.get_list("accept-encoding").is_empty());
+
+        // Commas inside quoted strings don't split, empty elements are dropped
+        assert_eq!(
+            split_list(r#"text/html;q="0,5", , x;note="a \"b, c\"", y"#),
+            [r#"text/html;q="0,5""#, r#"x;note="a \"b, c\"""#, "y"]
+        );
+    }
+}
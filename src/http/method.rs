@@ -24,6 +24,12 @@ impl Method {
             Method::Options => "OPTIONS",
         }
     }
+
+    /// Checks if requests with this method are expected to carry a body,
+    /// so its length must be declared
+    pub fn expects_body(&self) -> bool {
+        matches!(self, Method::Post | Method::Put)
+    }
 }
 
 impl fmt::Display for Method {
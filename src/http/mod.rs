@@ -1,13 +1,21 @@
+pub mod accept;
+pub mod body;
+pub mod compression;
+pub mod date;
 pub mod headers;
 pub mod method;
+pub mod multipart;
 pub mod parser;
 pub mod request;
 pub mod response;
 pub mod status;
 
+pub use body::{SpoolWriter, SpooledBody};
+pub use date::{format_http_date, parse_http_date};
 pub use headers::Headers;
 pub use method::Method;
+pub use multipart::MultipartReader;
 pub use parser::RequestParser;
 pub use request::Request;
-pub use response::{mime_type, Response};
+pub use response::{mime_type, Response, StreamBody};
 pub use status::StatusCode;
@@ -0,0 +1,335 @@
+use crate::error::{Result, ServerError};
+use std::io::{Read, Write};
+
+/// Default size of the window scanned for boundaries
+pub const DEFAULT_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Headers of one multipart/form-data part
+#[derive(Debug, Clone, Default)]
+pub struct PartHeaders {
+    /// Form field name from Content-Disposition
+    pub name: Option<String>,
+    /// Client-supplied filename from Content-Disposition (unsanitized)
+    pub filename: Option<String>,
+    /// Part Content-Type
+    pub content_type: Option<String>,
+}
+
+/// Where the reader is within the multipart stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Before the first boundary (preamble)
+    Start,
+    /// Inside a part's body
+    InBody,
+    /// Just past a boundary delimiter
+    AfterDelimiter,
+    /// Past the closing boundary
+    Done,
+}
+
+/// Streaming multipart/form-data parser (RFC 7578)
+///
+/// Scans the source for boundaries incrementally, so part bodies of any
+/// size are copied out while holding only about one buffer in memory
+pub struct MultipartReader<R: Read> {
+    reader: R,
+    /// "\r\n--" followed by the boundary
+    delimiter: Vec<u8>,
+    /// Unconsumed bytes read from the source
+    buf: Vec<u8>,
+    buffer_size: usize,
+    eof: bool,
+    state: State,
+    /// Body bytes copied out so far, across all parts
+    total: u64,
+    total_limit: u64,
+}
+
+impl<R: Read> MultipartReader<R> {
+    /// Creates a reader for a body delimited by `boundary` (as given in the
+    /// Content-Type, without the leading dashes)
+    pub fn new(reader: R, boundary: &str, buffer_size: usize) -> Self {
+        let delimiter = format!("\r\n--{}", boundary).into_bytes();
+        let buffer_size = buffer_size.max(delimiter.len() * 2);
+
+        MultipartReader {
+            reader,
+            delimiter,
+            // The first boundary has no preceding line break; supply one so
+            // every boundary matches the same delimiter
+            buf: b"\r\n".to_vec(),
+            buffer_size,
+            eof: false,
+            state: State::Start,
+            total: 0,
+            total_limit: u64::MAX,
+        }
+    }
+
+    /// Limits the combined size of all part bodies
+    pub fn with_total_limit(mut self, limit: u64) -> Self {
+        self.total_limit = limit;
+        self
+    }
+
+    /// Extracts the boundary parameter from a multipart Content-Type
+    pub fn boundary(content_type: &str) -> Option<&str> {
+        content_type
+            .split(';')
+            .filter_map(|param| param.trim().split_once('='))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("boundary"))
+            .map(|(_, value)| value.trim().trim_matches('"'))
+            .filter(|b| !b.is_empty())
+    }
+
+    /// Advances to the next part and returns its headers, skipping whatever
+    /// is left of the current one; None after the closing boundary
+    pub fn next_part(&mut self) -> Result<Option<PartHeaders>> {
+        match self.state {
+            State::Done => return Ok(None),
+            State::Start => self.skip_preamble()?,
+            State::InBody => {
+                self.copy_part(&mut std::io::sink(), u64::MAX)?;
+            }
+            State::AfterDelimiter => {}
+        }
+
+        // "--" closes the body; otherwise the boundary line ends (after
+        // optional whitespace) with CRLF and part headers follow
+        self.fill_to(2)?;
+        if self.buf.starts_with(b"--") {
+            self.state = State::Done;
+            return Ok(None);
+        }
+
+        let line_end = self.find_buffered(b"\r\n")?
+            .ok_or_else(|| ServerError::BadRequest("Malformed multipart boundary".to_string()))?;
+        if self.buf[..line_end].iter().any(|b| *b != b' ' && *b != b'\t') {
+            return Err(ServerError::BadRequest("Malformed multipart boundary".to_string()));
+        }
+        self.buf.drain(..line_end + 2);
+
+        let headers = self.read_headers()?;
+        self.state = State::InBody;
+        Ok(Some(headers))
+    }
+
+    /// Copies the current part's body to `out`, returning its length
+    /// Fails with `BodyTooLarge` once the part exceeds `limit` bytes or all
+    /// parts together exceed the total limit
+    pub fn copy_part<W: Write>(&mut self, out: &mut W, limit: u64) -> Result<u64> {
+        if self.state != State::InBody {
+            return Ok(0);
+        }
+
+        let mut written = 0u64;
+        loop {
+            let (end, found) = match Self::find(&self.buf, &self.delimiter) {
+                Some(pos) => (pos, true),
+                // Hold back a tail that may be the start of a delimiter
+                None => (self.buf.len().saturating_sub(self.delimiter.len() - 1), false),
+            };
+
+            written += end as u64;
+            self.total += end as u64;
+            if written > limit || self.total > self.total_limit {
+                return Err(ServerError::BodyTooLarge);
+            }
+            out.write_all(&self.buf[..end])?;
+            self.buf.drain(..end);
+
+            if found {
+                self.buf.drain(..self.delimiter.len());
+                self.state = State::AfterDelimiter;
+                return Ok(written);
+            }
+
+            if self.eof {
+                return Err(ServerError::BadRequest("Multipart body ended inside a part".to_string()));
+            }
+            self.fill()?;
+        }
+    }
+
+    /// Discards everything up to and including the first delimiter
+    fn skip_preamble(&mut self) -> Result<()> {
+        self.state = State::InBody;
+        self.copy_part(&mut std::io::sink(), u64::MAX)?;
+        // The preamble doesn't count towards the body limits
+        self.total = 0;
+        Ok(())
+    }
+
+    /// Reads part header lines up to the blank line that ends them
+    fn read_headers(&mut self) -> Result<PartHeaders> {
+        let end = self.find_buffered(b"\r\n\r\n")?
+            .ok_or_else(|| ServerError::BadRequest("Multipart part headers too large".to_string()))?;
+        let block: Vec<u8> = self.buf.drain(..end + 4).collect();
+        let block = String::from_utf8_lossy(&block[..end]);
+
+        let mut headers = PartHeaders::default();
+        for line in block.split("\r\n") {
+            let (name, value) = match line.split_once(':') {
+                Some(pair) => pair,
+                None => continue,
+            };
+            let value = value.trim();
+
+            if name.trim().eq_ignore_ascii_case("content-disposition") {
+                headers.name = Self::disposition_param(value, "name");
+                headers.filename = Self::disposition_param(value, "filename");
+            } else if name.trim().eq_ignore_ascii_case("content-type") {
+                headers.content_type = Some(value.to_string());
+            }
+        }
+
+        Ok(headers)
+    }
+
+    /// Extracts a (possibly quoted) parameter from a Content-Disposition value
+    fn disposition_param(value: &str, param: &str) -> Option<String> {
+        Self::split_params(value)
+            .into_iter()
+            .skip(1)
+            .filter_map(|p| p.trim().split_once('='))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case(param))
+            .map(|(_, v)| {
+                let v = v.trim();
+                v.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(v).to_string()
+            })
+    }
+
+    /// Splits a header value on the `;`s outside quoted strings, so a quoted
+    /// filename may contain one. Browsers percent-encode quotes in filenames
+    /// rather than escaping them, so a backslash is an ordinary character
+    fn split_params(value: &str) -> Vec<&str> {
+        let mut params = Vec::new();
+        let mut start = 0;
+        let mut quoted = false;
+        for (i, c) in value.char_indices() {
+            match c {
+                '"' => quoted = !quoted,
+                ';' if !quoted => {
+                    params.push(&value[start..i]);
+                    start = i + 1;
+                }
+                _ => {}
+            }
+        }
+        params.push(&value[start..]);
+        params
+    }
+
+    /// Finds a pattern in the buffer, reading more as needed while it fits
+    /// within the buffer size
+    fn find_buffered(&mut self, pattern: &[u8]) -> Result<Option<usize>> {
+        loop {
+            if let Some(pos) = Self::find(&self.buf, pattern) {
+                return Ok(Some(pos));
+            }
+            if self.eof || self.buf.len() >= self.buffer_size {
+                return Ok(None);
+            }
+            self.fill()?;
+        }
+    }
+
+    /// Reads until at least `n` bytes are buffered or the source ends
+    fn fill_to(&mut self, n: usize) -> Result<()> {
+        while self.buf.len() < n && !self.eof {
+            self.fill()?;
+        }
+        if self.buf.len() < n {
+            return Err(ServerError::BadRequest("Multipart body ended early".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Reads the next chunk from the source, up to the buffer size
+    fn fill(&mut self) -> Result<()> {
+        let start = self.buf.len();
+        let want = self.buffer_size.saturating_sub(start).max(1);
+        self.buf.resize(start + want, 0);
+
+        let n = loop {
+            match self.reader.read(&mut self.buf[start..]) {
+                Ok(n) => break n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    self.buf.truncate(start);
+                    return Err(ServerError::Io(e));
+                }
+            }
+        };
+
+        self.buf.truncate(start + n);
+        if n == 0 {
+            self.eof = true;
+        }
+        Ok(())
+    }
+
+    /// Finds the first occurrence of a pattern
+    fn find(haystack: &[u8], pattern: &[u8]) -> Option<usize> {
+        haystack.windows(pattern.len()).position(|w| w == pattern)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn body(parts: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for (disposition, data) in parts {
+            out.extend_from_slice(b"--XyZ\r\nContent-Disposition: form-data; ");
+            out.extend_from_slice(disposition.as_bytes());
+            out.extend_from_slice(b"\r\n\r\n");
+            out.extend_from_slice(data);
+            out.extend_from_slice(b"\r\n");
+        }
+        out.extend_from_slice(b"--XyZ--\r\n");
+        out
+    }
+
+    #[test]
+    fn part_larger_than_buffer() {
+        // Boundary-like bytes inside the data must not end the part early
+        let data: Vec<u8> = (0..10_000u32)
+            .flat_map(|i| if i % 97 == 0 { b"\r\n--Xy".to_vec() } else { vec![(i % 251) as u8] })
+            .collect();
+        let input = body(&[("name=\"file\"; filename=\"big.bin\"", &data), ("name=\"note\"", b"tail")]);
+        // Part headers still have to fit in the buffer
+        let mut reader = MultipartReader::new(&input[..], "XyZ", 128);
+
+        let headers = reader.next_part().unwrap().unwrap();
+        assert_eq!(headers.filename.as_deref(), Some("big.bin"));
+        let mut out = Vec::new();
+        assert_eq!(reader.copy_part(&mut out, u64::MAX).unwrap(), data.len() as u64);
+        assert_eq!(out, data);
+
+        let headers = reader.next_part().unwrap().unwrap();
+        assert_eq!(headers.name.as_deref(), Some("note"));
+        let mut out = Vec::new();
+        reader.copy_part(&mut out, u64::MAX).unwrap();
+        assert_eq!(out, b"tail");
+
+        assert!(reader.next_part().unwrap().is_none());
+    }
+
+    #[test]
+    fn part_over_limit() {
+        let input = body(&[("name=\"file\"; filename=\"a\"", &[b'a'; 1000])]);
+        let mut reader = MultipartReader::new(&input[..], "XyZ", 64);
+        reader.next_part().unwrap().unwrap();
+        assert!(matches!(reader.copy_part(&mut Vec::new(), 999), Err(ServerError::BodyTooLarge)));
+    }
+
+    #[test]
+    fn quoted_semicolon_in_filename() {
+        let value = "form-data; name=\"upload\"; filename=\"we;ird.bin\"";
+        assert_eq!(MultipartReader::<&[u8]>::disposition_param(value, "filename").as_deref(), Some("we;ird.bin"));
+        assert_eq!(MultipartReader::<&[u8]>::disposition_param(value, "name").as_deref(), Some("upload"));
+    }
+}
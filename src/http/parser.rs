@@ -3,37 +3,129 @@ use super::method::Method;
 use super::request::Request;
 use crate::error::{Result, ServerError};
 
+/// Default maximum number of header lines per request
+pub const DEFAULT_MAX_HEADERS: usize = 100;
+
+/// Fields a chunked trailer may not carry, as they affect framing, routing,
+/// authentication or how the body is interpreted (RFC 7230 section 4.1.2)
+const FORBIDDEN_TRAILERS: &[&str] = &[
+    "transfer-encoding",
+    "content-length",
+    "host",
+    "authorization",
+    "content-type",
+    "content-encoding",
+    "content-range",
+    "cookie",
+    "expect",
+    "trailer",
+];
+
+/// A decoded chunked body and the trailer fields that followed it
+pub type DecodedChunked = (Vec<u8>, Vec<(String, String)>);
+
 /// HTTP request parser
 pub struct RequestParser;
 
 impl RequestParser {
     /// Parses a complete HTTP request from bytes
+    /// Only the header section must be text; the body is passed through as raw bytes
     pub fn parse(data: &[u8]) -> Result<Request> {
-        let data_str = std::str::from_utf8(data)
-            .map_err(|_| ServerError::Parse("Invalid UTF-8 in request".to_string()))?;
+        Self::parse_with_limits(data, DEFAULT_MAX_HEADERS, usize::MAX)
+    }
+
+    /// Parses a complete HTTP request, rejecting more than `max_headers` header
+    /// lines and chunked bodies over `max_body` bytes
+    pub fn parse_with_limits(data: &[u8], max_headers: usize, max_body: usize) -> Result<Request> {
+        // Ignore leading blank lines between pipelined requests
+        let data = Self::skip_leading_newlines(data);
 
         // Find the end of headers
-        let header_end = data_str
-            .find("\r\n\r\n")
-            .ok_or_else(|| ServerError::Parse("Incomplete headers".to_string()))?;
+        let header_end = Self::find_header_end(data)
+            .ok_or(ServerError::Incomplete)?;
 
-        let header_section = &data_str[..header_end];
         let body_start = header_end + 4;
 
         // Parse request line and headers
-        let mut lines = header_section.lines();
-        
+        let mut lines = data[..header_end].split(|&b| b == b'\n').map(Self::trim_cr);
+
         // Parse request line: METHOD PATH HTTP/VERSION
         let request_line = lines
             .next()
             .ok_or_else(|| ServerError::Parse("Missing request line".to_string()))?;
 
-        let mut parts = request_line.split_whitespace();
-        
+        let (method, path, version) = Self::parse_request_line(request_line)?;
+
+        // Parse headers
+        let mut headers = Headers::new();
+        for (count, line) in lines.enumerate() {
+            if count >= max_headers {
+                return Err(ServerError::TooManyHeaders);
+            }
+            let (name, value) = Self::parse_header_line(line)?;
+            headers.add(&name, &value);
+        }
+
+        Self::validate_host(&headers)?;
+        Self::validate_framing(&headers)?;
+        Self::validate_length(method, &headers)?;
+
+        // An absolute-form target carries the host itself, which takes the
+        // place of any Host header (RFC 7230 section 5.4)
+        let path = match Self::split_absolute_form(path)? {
+            Some((authority, path)) => {
+                headers.set("Host", authority);
+                path
+            }
+            None => path.to_string(),
+        };
+
+        Self::validate_path(&path)?;
+
+        // Create request
+        let mut request = Request::new(method, &path);
+        request.version = version.to_string();
+        request.headers = headers;
+
+        // Extract body, decoding chunked framing and merging any trailer fields
+        if request.headers.is_chunked() {
+            let (body, trailers) = Self::decode_chunked(&data[body_start..], max_body)?;
+            for (name, value) in trailers {
+                if !FORBIDDEN_TRAILERS.contains(&name.to_lowercase().as_str()) {
+                    request.headers.add(&name, &value);
+                }
+            }
+            request.body = body;
+        } else if body_start < data.len() {
+            request.body = data[body_start..].to_vec();
+        }
+
+        Ok(request)
+    }
+
+    /// Parses the request line into method, target and version
+    fn parse_request_line(line: &[u8]) -> Result<(Method, &str, &str)> {
+        let line = std::str::from_utf8(line)
+            .map_err(|_| ServerError::Parse("Invalid bytes in request line".to_string()))?;
+
+        if line.bytes().any(|b| b.is_ascii_control()) {
+            return Err(ServerError::Parse("Control character in request line".to_string()));
+        }
+
+        let mut parts = line.split_whitespace();
+
+        // Check the version first, so an HTTP/2 preface ("PRI * HTTP/2.0")
+        // gets a clear 505 rather than failing on its unknown method
+        if let Some(version) = line.split_whitespace().nth(2) {
+            if version.starts_with("HTTP/") && version != "HTTP/1.0" && version != "HTTP/1.1" {
+                return Err(ServerError::UnsupportedVersion(version.to_string()));
+            }
+        }
+
         let method_str = parts
             .next()
             .ok_or_else(|| ServerError::Parse("Missing method".to_string()))?;
-        
+
         let method: Method = method_str
             .parse()
             .map_err(|_| ServerError::Parse(format!("Invalid method: {}", method_str)))?;
@@ -44,138 +136,503 @@ impl RequestParser {
 
         let version = parts
             .next()
-            .ok_or_else(|| ServerError::Parse("Missing HTTP version".to_string()))?
-            .to_string();
+            .ok_or_else(|| ServerError::Parse("Missing HTTP version".to_string()))?;
 
-        // Parse headers
-        let mut headers = Headers::new();
-        for line in lines {
-            if let Some(colon_pos) = line.find(':') {
-                let name = line[..colon_pos].trim();
-                let value = line[colon_pos + 1..].trim();
-                headers.add(name, value);
+        if !version.starts_with("HTTP/") {
+            return Err(ServerError::Parse(format!("Invalid HTTP version: {}", version)));
+        }
+
+        if parts.next().is_some() {
+            return Err(ServerError::Parse("Malformed request line".to_string()));
+        }
+
+        // The asterisk-form target addresses the whole server, only for OPTIONS
+        if path == "*" && method != Method::Options {
+            return Err(ServerError::Parse(format!("Invalid target '*' for {}", method)));
+        }
+
+        Ok((method, path, version))
+    }
+
+    /// Splits an absolute-form target ("http://host/path") into its authority
+    /// and origin-form path; returns None for any other target form
+    fn split_absolute_form(target: &str) -> Result<Option<(&str, String)>> {
+        // Origin-form ("/path") and asterisk-form ("*") targets
+        if target.starts_with('/') || target == "*" {
+            return Ok(None);
+        }
+
+        let scheme_end = match target.find("://") {
+            Some(pos) => pos,
+            None => return Ok(None),
+        };
+
+        let scheme = &target[..scheme_end];
+        if !scheme.eq_ignore_ascii_case("http") && !scheme.eq_ignore_ascii_case("https") {
+            return Err(ServerError::BadRequest(format!("Unsupported URI scheme: {}", scheme)));
+        }
+
+        let rest = &target[scheme_end + 3..];
+        let authority_end = rest.find(['/', '?']).unwrap_or(rest.len());
+        let authority = &rest[..authority_end];
+        if authority.is_empty() || authority.contains('@') {
+            return Err(ServerError::BadRequest("Invalid URI authority".to_string()));
+        }
+
+        let path = &rest[authority_end..];
+        let path = if path.starts_with('/') { path.to_string() } else { format!("/{}", path) };
+        Ok(Some((authority, path)))
+    }
+
+    /// Checks partially received data for errors that more bytes cannot fix
+    /// Returns `Incomplete` while the data is still a plausible request prefix
+    pub fn check_partial(data: &[u8]) -> Result<()> {
+        let data = Self::skip_leading_newlines(data);
+
+        match data.iter().position(|&b| b == b'\n') {
+            Some(line_end) => {
+                Self::parse_request_line(Self::trim_cr(&data[..line_end]))?;
+                Err(ServerError::Incomplete)
             }
+            None => Err(ServerError::Incomplete),
         }
+    }
 
-        // Create request
-        let mut request = Request::new(method, path);
-        request.version = version;
-        request.headers = headers;
+    /// Rejects requests carrying more than one Host header
+    fn validate_host(headers: &Headers) -> Result<()> {
+        if headers.get_all("host").map(|v| v.len() > 1).unwrap_or(false) {
+            return Err(ServerError::BadRequest("Multiple Host headers".to_string()));
+        }
+        Ok(())
+    }
 
-        // Extract body
-        if body_start < data.len() {
-            request.body = data[body_start..].to_vec();
+    /// Rejects targets whose path, once percent-decoded (a single pass),
+    /// could escape the root: ".." segments, NUL bytes, invalid UTF-8, and
+    /// escapes left over from double encoding ("%252e" decodes to "%2e")
+    /// that a second decode anywhere downstream would turn into a dot or
+    /// separator. Decoded control characters are refused too, as CR and LF
+    /// would otherwise reach headers, logs and the CGI environment
+    fn validate_path(target: &str) -> Result<()> {
+        let raw_path = target.split('?').next().unwrap_or(target);
+        let path = Request::decode_path(raw_path)
+            .ok_or_else(|| ServerError::BadRequest("Path is not valid UTF-8".to_string()))?;
+
+        if path.contains('\0') {
+            return Err(ServerError::BadRequest("NUL byte in path".to_string()));
+        }
+        if path.chars().any(|c| c.is_ascii_control()) {
+            return Err(ServerError::BadRequest("Control character in path".to_string()));
+        }
+        if path.split(['/', '\\']).any(|segment| segment == "..") {
+            return Err(ServerError::BadRequest("Path traversal".to_string()));
         }
 
-        Ok(request)
+        let lower = path.to_ascii_lowercase();
+        if ["%2e", "%2f", "%5c", "%25", "%00"].iter().any(|escape| lower.contains(escape)) {
+            return Err(ServerError::BadRequest("Multiply encoded path".to_string()));
+        }
+
+        Ok(())
+    }
+
+    /// Rejects ambiguous body framing that enables request smuggling:
+    /// both Content-Length and Transfer-Encoding, or conflicting Content-Lengths
+    fn validate_framing(headers: &Headers) -> Result<()> {
+        if headers.contains("transfer-encoding") && headers.contains("content-length") {
+            return Err(ServerError::BadRequest(
+                "Both Content-Length and Transfer-Encoding present".to_string(),
+            ));
+        }
+
+        if let Some(values) = headers.get_all("content-length") {
+            let mut lengths = values.iter().flat_map(|v| v.split(',')).map(|v| v.trim());
+            let first = lengths.next().unwrap_or("");
+            if first.is_empty() || !first.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(ServerError::BadRequest("Invalid Content-Length".to_string()));
+            }
+            if lengths.any(|v| v != first) {
+                return Err(ServerError::BadRequest("Conflicting Content-Length values".to_string()));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Requires a body-carrying method to declare its body's length: without
+    /// Content-Length or Transfer-Encoding the body can't be told apart from
+    /// the next request, so it would be silently cut off
+    fn validate_length(method: Method, headers: &Headers) -> Result<()> {
+        if method.expects_body() && !headers.contains("content-length") && !headers.contains("transfer-encoding") {
+            return Err(ServerError::LengthRequired);
+        }
+        Ok(())
+    }
+
+    /// Parses a single header line into a name and value
+    fn parse_header_line(line: &[u8]) -> Result<(String, String)> {
+        // Obsolete line folding (continuation lines) is rejected, per RFC 7230
+        if line.first().map(|&b| b == b' ' || b == b'\t').unwrap_or(false) {
+            return Err(ServerError::Parse("Obsolete line folding in headers".to_string()));
+        }
+
+        let colon_pos = line
+            .iter()
+            .position(|&b| b == b':')
+            .ok_or_else(|| ServerError::Parse("Malformed header line".to_string()))?;
+
+        let name = &line[..colon_pos];
+        if name.is_empty() || !name.iter().all(|&b| Self::is_token_char(b)) {
+            return Err(ServerError::Parse("Invalid header name".to_string()));
+        }
+
+        let value = &line[colon_pos + 1..];
+        if value.iter().any(|&b| b.is_ascii_control() && b != b'\t') {
+            return Err(ServerError::Parse("Control character in header value".to_string()));
+        }
+
+        // Names are ASCII tokens; values may carry obs-text, decoded lossily
+        let name = String::from_utf8_lossy(name).to_string();
+        let value = String::from_utf8_lossy(value).trim().to_string();
+        Ok((name, value))
+    }
+
+    /// Checks if a byte is a valid header name (token) character
+    fn is_token_char(b: u8) -> bool {
+        b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+    }
+
+    /// Skips blank lines preceding the request line
+    fn skip_leading_newlines(data: &[u8]) -> &[u8] {
+        let start = data
+            .iter()
+            .position(|&b| b != b'\r' && b != b'\n')
+            .unwrap_or(data.len());
+        &data[start..]
+    }
+
+    /// Strips a trailing CR from a line
+    fn trim_cr(line: &[u8]) -> &[u8] {
+        line.strip_suffix(b"\r").unwrap_or(line)
+    }
+
+    /// Finds the position of the blank line ending the headers
+    fn find_header_end(data: &[u8]) -> Option<usize> {
+        data.windows(4).position(|w| w == b"\r\n\r\n")
+    }
+
+    /// Finds a header value in the raw header section (case-insensitive name)
+    fn find_raw_header<'a>(data: &'a [u8], name: &str) -> Option<&'a [u8]> {
+        let header_end = Self::find_header_end(data).unwrap_or(data.len());
+        data[..header_end]
+            .split(|&b| b == b'\n')
+            .map(Self::trim_cr)
+            .skip(1)
+            .find_map(|line| {
+                let colon_pos = line.iter().position(|&b| b == b':')?;
+                if line[..colon_pos].eq_ignore_ascii_case(name.as_bytes()) {
+                    Some(line[colon_pos + 1..].trim_ascii())
+                } else {
+                    None
+                }
+            })
+    }
+
+    /// Returns the offset where the body starts, once headers are complete
+    pub fn body_offset(data: &[u8]) -> Option<usize> {
+        Self::find_header_end(data).map(|pos| pos + 4)
     }
 
     /// Checks if we have received complete headers
     pub fn has_complete_headers(data: &[u8]) -> bool {
-        if let Ok(s) = std::str::from_utf8(data) {
-            s.contains("\r\n\r\n")
-        } else {
-            false
-        }
+        Self::find_header_end(data).is_some()
     }
 
     /// Gets the expected body length from headers
     pub fn get_content_length(data: &[u8]) -> Option<usize> {
-        if let Ok(s) = std::str::from_utf8(data) {
-            for line in s.lines() {
-                let lower = line.to_lowercase();
-                if lower.starts_with("content-length:") {
-                    return line[15..].trim().parse().ok();
-                }
-            }
-        }
-        None
+        Self::find_raw_header(data, "content-length")
+            .and_then(|v| std::str::from_utf8(v).ok())
+            .and_then(|v| v.parse().ok())
     }
 
     /// Checks if transfer encoding is chunked
     pub fn is_chunked(data: &[u8]) -> bool {
-        if let Ok(s) = std::str::from_utf8(data) {
-            for line in s.lines() {
-                let lower = line.to_lowercase();
-                if lower.starts_with("transfer-encoding:") && lower.contains("chunked") {
-                    return true;
-                }
-            }
-        }
-        false
+        Self::find_raw_header(data, "transfer-encoding")
+            .map(|v| String::from_utf8_lossy(v).to_lowercase().contains("chunked"))
+            .unwrap_or(false)
     }
 
-    /// Checks if we have a complete request (headers + body)
-    pub fn is_complete(data: &[u8]) -> bool {
-        if !Self::has_complete_headers(data) {
-            return false;
+    /// Checks if we have a complete request (headers + body); a chunked body
+    /// over `max_body` bytes is complete so it can be rejected
+    pub fn is_complete(data: &[u8], max_body: usize) -> bool {
+        let header_end = match Self::find_header_end(data) {
+            Some(pos) => pos,
+            None => return false,
+        };
+        let body_start = header_end + 4;
+
+        // Ambiguous framing is complete as-is so it can be rejected right away
+        if Self::find_raw_header(data, "transfer-encoding").is_some()
+            && Self::find_raw_header(data, "content-length").is_some()
+        {
+            return true;
         }
 
-        // Find header end
-        if let Ok(s) = std::str::from_utf8(data) {
-            if let Some(header_end) = s.find("\r\n\r\n") {
-                let body_start = header_end + 4;
-                
-                // Check content length
-                if let Some(content_length) = Self::get_content_length(data) {
-                    return data.len() >= body_start + content_length;
-                }
+        // Check content length
+        if let Some(content_length) = Self::get_content_length(data) {
+            return data.len() >= body_start + content_length;
+        }
 
-                // Check chunked encoding
-                if Self::is_chunked(data) {
-                    // Look for final chunk marker: 0\r\n\r\n
-                    return s[body_start..].contains("0\r\n\r\n");
-                }
+        // Check chunked encoding: complete once the last chunk and the trailer
+        // section are in; malformed framing is complete so it can be rejected
+        if Self::is_chunked(data) {
+            return !matches!(Self::chunked_end(&data[body_start..], max_body), Err(ServerError::Incomplete));
+        }
+
+        // No body expected
+        true
+    }
+
+    /// Finds where a chunked body ends, after the last chunk and trailers
+    /// Chunk sizes are client-supplied, so they are held to `max_body` in
+    /// total before any arithmetic, which is checked all the same
+    fn chunked_end(data: &[u8], max_body: usize) -> Result<usize> {
+        let mut pos = 0;
+        let mut total: usize = 0;
 
-                // No body expected
-                return true;
+        // Chunks, up to and including the zero-size last chunk
+        loop {
+            let (size, line_len) = Self::parse_chunk_size(&data[pos..])?;
+            pos += line_len;
+            if size == 0 {
+                break;
+            }
+            if size > max_body - total {
+                return Err(ServerError::BodyTooLarge);
+            }
+            total += size;
+
+            let chunk_end = pos
+                .checked_add(size)
+                .filter(|end| end.checked_add(2).is_some())
+                .ok_or_else(|| ServerError::Parse("Chunk size out of range".to_string()))?;
+            if data.len() < chunk_end + 2 {
+                return Err(ServerError::Incomplete);
+            }
+            if &data[chunk_end..chunk_end + 2] != b"\r\n" {
+                return Err(ServerError::Parse("Missing CRLF after chunk data".to_string()));
+            }
+            pos = chunk_end + 2;
+        }
+
+        // Trailer fields, terminated by an empty line
+        loop {
+            let line_end = Self::find_crlf(&data[pos..]).ok_or(ServerError::Incomplete)?;
+            pos += line_end + 2;
+            if line_end == 0 {
+                return Ok(pos);
             }
         }
+    }
+
+    /// Parses a chunk-size line (hex size, optional extensions)
+    /// Returns the chunk size and the length of the line including CRLF
+    fn parse_chunk_size(data: &[u8]) -> Result<(usize, usize)> {
+        let line_end = Self::find_crlf(data).ok_or(ServerError::Incomplete)?;
+
+        let line = std::str::from_utf8(&data[..line_end])
+            .map_err(|_| ServerError::Parse("Invalid chunk size".to_string()))?;
+        let size_str = line.split(';').next().unwrap_or("").trim();
 
-        false
+        let size = usize::from_str_radix(size_str, 16)
+            .map_err(|_| ServerError::Parse("Invalid chunk size".to_string()))?;
+
+        Ok((size, line_end + 2))
     }
 
-    /// Decodes chunked transfer encoding
-    pub fn decode_chunked(data: &[u8]) -> Result<Vec<u8>> {
-        let mut result = Vec::new();
+    /// Decodes chunked transfer encoding, with the body held to `max_body`
+    /// bytes; returns the body and any trailer fields after the last chunk
+    pub fn decode_chunked(data: &[u8], max_body: usize) -> Result<DecodedChunked> {
+        // Validates the framing, so the offsets below stay in bounds
+        let end = Self::chunked_end(data, max_body)?;
+        let mut body = Vec::new();
         let mut pos = 0;
 
-        while pos < data.len() {
-            // Find chunk size line
-            let line_end = Self::find_crlf(&data[pos..])
-                .ok_or_else(|| ServerError::Parse("Invalid chunked encoding".to_string()))?;
-            
-            let size_str = std::str::from_utf8(&data[pos..pos + line_end])
-                .map_err(|_| ServerError::Parse("Invalid chunk size".to_string()))?;
-            
-            let chunk_size = usize::from_str_radix(size_str.trim(), 16)
-                .map_err(|_| ServerError::Parse("Invalid chunk size".to_string()))?;
-
-            pos += line_end + 2; // Skip size line and CRLF
-
-            if chunk_size == 0 {
-                break; // Final chunk
+        loop {
+            let (size, line_len) = Self::parse_chunk_size(&data[pos..])?;
+            pos += line_len;
+            if size == 0 {
+                break;
             }
+            body.extend_from_slice(&data[pos..pos + size]);
+            pos += size + 2; // Skip chunk data and trailing CRLF
+        }
 
-            // Read chunk data
-            if pos + chunk_size > data.len() {
-                return Err(ServerError::Parse("Incomplete chunk".to_string()));
+        let mut trailers = Vec::new();
+        for line in data[pos..end].split(|&b| b == b'\n').map(Self::trim_cr) {
+            if !line.is_empty() {
+                trailers.push(Self::parse_header_line(line)?);
             }
-
-            result.extend_from_slice(&data[pos..pos + chunk_size]);
-            pos += chunk_size + 2; // Skip chunk data and trailing CRLF
         }
 
-        Ok(result)
+        Ok((body, trailers))
     }
 
     /// Finds CRLF in data
     fn find_crlf(data: &[u8]) -> Option<usize> {
-        for i in 0..data.len().saturating_sub(1) {
-            if data[i] == b'\r' && data[i + 1] == b'\n' {
-                return Some(i);
-            }
+        data.windows(2).position(|pair| pair == b"\r\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_target(target: &str) -> Result<Request> {
+        RequestParser::parse(format!("GET {} HTTP/1.1\r\nHost: x\r\n\r\n", target).as_bytes())
+    }
+
+    #[test]
+    fn chunked_body_with_trailers() {
+        let data = b"POST /a HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n\
+            5\r\nhello\r\n6\r\n world\r\n0\r\nX-Checksum: abc\r\nContent-Length: 3\r\n\r\n";
+
+        assert!(RequestParser::is_complete(data, usize::MAX));
+        let request = RequestParser::parse(data).unwrap();
+        assert_eq!(request.body, b"hello world");
+        assert_eq!(request.headers.get("x-checksum"), Some("abc"));
+        // A forbidden trailer never reaches the headers
+        assert_eq!(request.headers.get("content-length"), None);
+    }
+
+    #[test]
+    fn overflowing_chunk_size() {
+        let huge = b"ffffffffffffffff\r\nab\r\n0\r\n\r\n";
+        assert!(matches!(RequestParser::decode_chunked(huge, 1024), Err(ServerError::BodyTooLarge)));
+
+        // Two chunks whose sizes add up past usize::MAX
+        let wrapping = b"1\r\na\r\nffffffffffffffff\r\nab\r\n0\r\n\r\n";
+        assert!(matches!(RequestParser::decode_chunked(wrapping, usize::MAX), Err(ServerError::BodyTooLarge)));
+
+        // A size that would run the offset past usize::MAX
+        assert!(matches!(RequestParser::decode_chunked(huge, usize::MAX), Err(ServerError::Parse(_))));
+
+        // Too many digits to fit at all
+        let long = b"10000000000000000\r\nab\r\n0\r\n\r\n";
+        assert!(RequestParser::decode_chunked(long, usize::MAX).is_err());
+    }
+
+    #[test]
+    fn chunked_body_over_limit_is_complete() {
+        let data = b"POST /a HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n400\r\n";
+        assert!(RequestParser::is_complete(data, 16));
+        assert!(!RequestParser::is_complete(data, usize::MAX));
+    }
+
+    #[test]
+    fn path_is_decoded_once() {
+        assert!(matches!(parse_target("/%2e%2e/etc/passwd"), Err(ServerError::BadRequest(_))));
+        assert!(matches!(parse_target("/..%2fetc/passwd"), Err(ServerError::BadRequest(_))));
+        // Double-encoded: decodes to "%2e%2e", which is refused rather than
+        // left for something downstream to decode again
+        assert!(matches!(parse_target("/%252e%252e/etc/passwd"), Err(ServerError::BadRequest(_))));
+        assert_eq!(parse_target("/a%20b").unwrap().path, "/a b");
+    }
+
+    #[test]
+    fn control_characters_in_path() {
+        for target in ["/a%0d%0aSet-Cookie:%20x=1", "/a%00", "/a%7f", "/a%09b"] {
+            assert!(matches!(parse_target(target), Err(ServerError::BadRequest(_))), "{}", target);
+        }
+        assert_eq!(parse_target("/caf%C3%A9").unwrap().path, "/café");
+    }
+
+    #[test]
+    fn absolute_form_target() {
+        let request = RequestParser::parse(
+            b"GET http://example.com:8080/docs/a%20b?x=1 HTTP/1.1\r\nHost: other.example\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(request.path, "/docs/a b");
+        assert_eq!(request.query_param("x"), Some("1"));
+        // The target's authority replaces the Host header
+        assert_eq!(request.host(), Some("example.com:8080"));
+
+        let request = RequestParser::parse(b"GET HTTPS://example.com HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+        assert_eq!(request.path, "/");
+        assert_eq!(request.host(), Some("example.com"));
+
+        assert!(matches!(parse_target("ftp://example.com/"), Err(ServerError::BadRequest(_))));
+        assert!(matches!(parse_target("http://user@example.com/"), Err(ServerError::BadRequest(_))));
+        assert!(matches!(parse_target("http:///path"), Err(ServerError::BadRequest(_))));
+    }
+
+    #[test]
+    fn malformed_header_lines() {
+        let parse = |headers: &[u8]| {
+            let mut data = b"GET / HTTP/1.1\r\nHost: x\r\n".to_vec();
+            data.extend_from_slice(headers);
+            data.extend_from_slice(b"\r\n\r\n");
+            RequestParser::parse(&data)
+        };
+
+        for bad in [
+            &b"X-A: one\r\n  folded"[..],
+            b"X-A: one\r\n\tfolded",
+            b"No colon here",
+            b": empty name",
+            b"Bad Name: x",
+            b"X-A: a\x00b",
+            b"X-A: a\x1bb",
+        ] {
+            assert!(matches!(parse(bad), Err(ServerError::Parse(_))), "{:?}", String::from_utf8_lossy(bad));
+        }
+
+        // obs-text in a value is kept (lossily), tabs are allowed, and a
+        // binary body never has to be text
+        let request = RequestParser::parse(
+            b"POST / HTTP/1.1\r\nHost: x\r\nX-A: caf\xe9\tau lait \r\nContent-Length: 3\r\n\r\n\xff\x00\xfe",
+        )
+        .unwrap();
+        assert_eq!(request.headers.get("x-a"), Some("caf\u{fffd}\tau lait"));
+        assert_eq!(request.body, b"\xff\x00\xfe");
+    }
+
+    #[test]
+    fn ambiguous_body_framing() {
+        let both = b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 4\r\nTransfer-Encoding: chunked\r\n\r\n";
+        // Complete as soon as the headers are in, so it is refused without
+        // waiting on a body whose length can't be trusted
+        assert!(RequestParser::is_complete(both, usize::MAX));
+        assert!(matches!(RequestParser::parse(both), Err(ServerError::BadRequest(_))));
+
+        for lengths in ["Content-Length: 4\r\nContent-Length: 5", "Content-Length: 4, 5", "Content-Length: -4", "Content-Length: 0x4"] {
+            let data = format!("POST / HTTP/1.1\r\nHost: x\r\n{}\r\n\r\nbody", lengths);
+            assert!(matches!(RequestParser::parse(data.as_bytes()), Err(ServerError::BadRequest(_))), "{}", lengths);
+        }
+
+        // Repeating the same length is harmless
+        let data = b"POST / HTTP/1.1\r\nHost: x\r\nContent-Length: 4\r\nContent-Length: 4, 4\r\n\r\nbody";
+        assert_eq!(RequestParser::parse(data).unwrap().body, b"body");
+    }
+
+    #[test]
+    fn duplicate_host_headers() {
+        for hosts in ["Host: a\r\nHost: a", "Host: a\r\nhost: b"] {
+            let data = format!("GET / HTTP/1.1\r\n{}\r\n\r\n", hosts);
+            assert!(matches!(RequestParser::parse(data.as_bytes()), Err(ServerError::BadRequest(_))), "{}", hosts);
+        }
+        assert_eq!(RequestParser::parse(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n").unwrap().host(), Some("a"));
+    }
+
+    #[test]
+    fn body_methods_need_a_length() {
+        for method in ["POST", "PUT"] {
+            let data = format!("{} /a HTTP/1.1\r\nHost: x\r\n\r\n", method);
+            assert!(matches!(RequestParser::parse(data.as_bytes()), Err(ServerError::LengthRequired)), "{}", method);
         }
-        None
+        assert!(RequestParser::parse(b"POST /a HTTP/1.1\r\nHost: x\r\nContent-Length: 0\r\n\r\n").is_ok());
+        assert!(RequestParser::parse(b"POST /a HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n").is_ok());
+        // Methods without a body don't have to declare one
+        assert!(RequestParser::parse(b"DELETE /a HTTP/1.1\r\nHost: x\r\n\r\n").is_ok());
     }
 }
@@ -1,6 +1,10 @@
+use super::body::SpooledBody;
 use super::headers::Headers;
 use super::method::Method;
 use std::collections::HashMap;
+use std::io::{self, Read};
+use std::net::SocketAddr;
+use std::sync::Arc;
 
 /// Represents an HTTP request
 #[derive(Debug, Clone)]
@@ -9,78 +13,154 @@ pub struct Request {
     pub method: Method,
     /// Request URI path
     pub path: String,
-    /// Query string parameters
-    pub query: HashMap<String, String>,
+    /// Request URI path as received, still percent-encoded
+    pub raw_path: String,
+    /// Query string parameters (all values, in order of appearance)
+    pub query: HashMap<String, Vec<String>>,
+    /// Raw query string as received (without the leading '?')
+    pub raw_query: String,
     /// HTTP version (e.g., "HTTP/1.1")
     pub version: String,
     /// Request headers
     pub headers: Headers,
     /// Request body
     pub body: Vec<u8>,
+    /// The body once spooled to a temp file, leaving `body` empty
+    pub body_file: Option<Arc<SpooledBody>>,
+    /// Client address, when the request came over a connection
+    pub remote_addr: Option<SocketAddr>,
+    /// Local address the request arrived on
+    pub local_addr: Option<SocketAddr>,
 }
 
 impl Request {
+    /// Returns the size in bytes of the serialized header fields
+    pub fn header_bytes(&self) -> usize {
+        self.headers.byte_len()
+    }
+
+    /// Returns the request ID, as sent by the client or assigned on arrival
+    pub fn request_id(&self) -> Option<&str> {
+        self.headers.get("x-request-id")
+    }
+
     /// Creates a new Request with the given method and path
+    /// The path is percent-decoded exactly once (see `decode_path`)
     pub fn new(method: Method, path: &str) -> Self {
-        let (path, query) = Self::parse_path_and_query(path);
+        let (raw_path, query, raw_query) = Self::parse_path_and_query(path);
+        let path = Self::decode_path(&raw_path).unwrap_or_else(|| raw_path.clone());
         Request {
             method,
             path,
+            raw_path,
             query,
+            raw_query,
             version: "HTTP/1.1".to_string(),
             headers: Headers::new(),
             body: Vec::new(),
+            body_file: None,
+            remote_addr: None,
+            local_addr: None,
         }
     }
 
-    /// Parses path and query string from URI
-    fn parse_path_and_query(uri: &str) -> (String, HashMap<String, String>) {
+    /// Parses path, query parameters and raw query string from URI
+    fn parse_path_and_query(uri: &str) -> (String, HashMap<String, Vec<String>>, String) {
         let mut query = HashMap::new();
         
         if let Some(pos) = uri.find('?') {
             let path = uri[..pos].to_string();
             let query_str = &uri[pos + 1..];
             
-            for pair in query_str.split('&') {
-                if let Some(eq_pos) = pair.find('=') {
-                    let key = Self::url_decode(&pair[..eq_pos]);
-                    let value = Self::url_decode(&pair[eq_pos + 1..]);
-                    query.insert(key, value);
-                } else if !pair.is_empty() {
-                    query.insert(Self::url_decode(pair), String::new());
-                }
+            for (key, value) in Self::parse_urlencoded(query_str) {
+                query.entry(key).or_insert_with(Vec::new).push(value);
             }
             
-            (path, query)
+            (path, query, query_str.to_string())
         } else {
-            (uri.to_string(), query)
+            (uri.to_string(), query, String::new())
         }
     }
 
-    /// URL decodes a string
+    /// Parses `key=value&...` pairs, URL decoding keys and values
+    fn parse_urlencoded(s: &str) -> Vec<(String, String)> {
+        let mut pairs = Vec::new();
+
+        for pair in s.split('&') {
+            if let Some(eq_pos) = pair.find('=') {
+                let key = Self::url_decode(&pair[..eq_pos]);
+                let value = Self::url_decode(&pair[eq_pos + 1..]);
+                pairs.push((key, value));
+            } else if !pair.is_empty() {
+                pairs.push((Self::url_decode(pair), String::new()));
+            }
+        }
+
+        pairs
+    }
+
+    /// Percent-decodes a request path in a single pass
+    ///
+    /// Decoding is never repeated: "%252e" becomes the literal "%2e", not
+    /// ".". Unlike query strings, '+' is kept as is. None when the decoded
+    /// bytes aren't UTF-8
+    pub fn decode_path(path: &str) -> Option<String> {
+        let bytes = path.as_bytes();
+        let mut decoded = Vec::with_capacity(bytes.len());
+        let mut i = 0;
+
+        while i < bytes.len() {
+            let escaped = match (bytes[i], bytes.get(i + 1..i + 3)) {
+                (b'%', Some(hex)) => std::str::from_utf8(hex).ok()
+                    .and_then(|h| u8::from_str_radix(h, 16).ok()),
+                _ => None,
+            };
+            match escaped {
+                Some(byte) => {
+                    decoded.push(byte);
+                    i += 3;
+                }
+                None => {
+                    decoded.push(bytes[i]);
+                    i += 1;
+                }
+            }
+        }
+
+        String::from_utf8(decoded).ok()
+    }
+
+    /// URL decodes a string, with '+' as a space
+    /// Escapes decode to bytes, so multi-byte UTF-8 sequences come out as
+    /// one character; invalid sequences become U+FFFD
     fn url_decode(s: &str) -> String {
-        let mut result = String::new();
-        let mut chars = s.chars().peekable();
-        
-        while let Some(c) = chars.next() {
-            if c == '%' {
-                let hex: String = chars.by_ref().take(2).collect();
-                if hex.len() == 2 {
-                    if let Ok(byte) = u8::from_str_radix(&hex, 16) {
-                        result.push(byte as char);
-                        continue;
-                    }
+        let bytes = s.as_bytes();
+        let mut decoded = Vec::with_capacity(bytes.len());
+        let mut i = 0;
+
+        while i < bytes.len() {
+            let escaped = match (bytes[i], bytes.get(i + 1..i + 3)) {
+                (b'%', Some(hex)) => std::str::from_utf8(hex).ok()
+                    .and_then(|h| u8::from_str_radix(h, 16).ok()),
+                _ => None,
+            };
+            match (escaped, bytes[i]) {
+                (Some(byte), _) => {
+                    decoded.push(byte);
+                    i += 3;
+                }
+                (None, b'+') => {
+                    decoded.push(b' ');
+                    i += 1;
+                }
+                (None, byte) => {
+                    decoded.push(byte);
+                    i += 1;
                 }
-                result.push('%');
-                result.push_str(&hex);
-            } else if c == '+' {
-                result.push(' ');
-            } else {
-                result.push(c);
             }
         }
-        
-        result
+
+        String::from_utf8_lossy(&decoded).into_owned()
     }
 
     /// Returns the Host header value
@@ -103,14 +183,66 @@ impl Request {
         self.headers.is_chunked()
     }
 
-    /// Checks if connection should be kept alive
+    /// Checks if the request carries a body (any bytes, a non-zero
+    /// Content-Length, or chunked framing)
+    pub fn has_body(&self) -> bool {
+        !self.body.is_empty() || self.body_file.is_some() || self.content_length().unwrap_or(0) > 0 || self.is_chunked()
+    }
+
+    /// Checks if the client wants the connection kept open: HTTP/1.1 unless
+    /// it sent `Connection: close`, HTTP/1.0 only with `Connection: keep-alive`
     pub fn keep_alive(&self) -> bool {
-        self.headers.keep_alive()
+        let options = self.headers.get_list("connection");
+        let has = |option: &str| options.iter().any(|o| o.eq_ignore_ascii_case(option));
+        if self.version == "HTTP/1.0" {
+            has("keep-alive")
+        } else {
+            !has("close")
+        }
     }
 
-    /// Gets a query parameter by name
+    /// Gets the first value of a query parameter by name
     pub fn query_param(&self, name: &str) -> Option<&str> {
-        self.query.get(name).map(|s| s.as_str())
+        self.query
+            .get(name)
+            .and_then(|v| v.first())
+            .map(|s| s.as_str())
+    }
+
+    /// Gets all values of a query parameter by name
+    pub fn query_params_all(&self, name: &str) -> &[String] {
+        self.query
+            .get(name)
+            .map(|v| v.as_slice())
+            .unwrap_or(&[])
+    }
+
+    /// Parses an application/x-www-form-urlencoded body into fields
+    /// Repeated keys keep their first value, matching `query_param`
+    pub fn form_params(&self) -> HashMap<String, String> {
+        let mut params = HashMap::new();
+
+        let is_form = self
+            .content_type()
+            .map(|ct| {
+                ct.split(';')
+                    .next()
+                    .unwrap_or("")
+                    .trim()
+                    .eq_ignore_ascii_case("application/x-www-form-urlencoded")
+            })
+            .unwrap_or(false);
+
+        if !is_form {
+            return params;
+        }
+
+        let body = String::from_utf8_lossy(&self.body);
+        for (key, value) in Self::parse_urlencoded(body.trim_end_matches(['\r', '\n'])) {
+            params.entry(key).or_insert(value);
+        }
+
+        params
     }
 
     /// Gets a cookie value by name
@@ -128,8 +260,71 @@ impl Request {
         })
     }
 
+    /// Size of the body in bytes, wherever it is kept
+    pub fn body_len(&self) -> usize {
+        match &self.body_file {
+            Some(file) => file.len() as usize,
+            None => self.body.len(),
+        }
+    }
+
+    /// Reads the body from its temp file when spooled, or from memory
+    pub fn body_reader(&self) -> io::Result<Box<dyn Read + '_>> {
+        match &self.body_file {
+            Some(file) => Ok(Box::new(file.open()?)),
+            None => Ok(Box::new(&self.body[..])),
+        }
+    }
+
     /// Returns the body as a string (if valid UTF-8)
     pub fn body_str(&self) -> Option<&str> {
         std::str::from_utf8(&self.body).ok()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::http::RequestParser;
+
+    #[test]
+    fn form_body_fields() {
+        let raw = b"POST /submit HTTP/1.1\r\nHost: x\r\nContent-Type: application/x-www-form-urlencoded; charset=utf-8\r\n\
+            Content-Length: 38\r\n\r\nname=Ada+Lovelace&lang=%C3%A9&name=Bob";
+        let request = RequestParser::parse(raw).unwrap();
+        let form = request.form_params();
+        assert_eq!(form.get("name").map(String::as_str), Some("Ada Lovelace"));
+        assert_eq!(form.get("lang").map(String::as_str), Some("é"));
+        assert_eq!(request.body_str(), Some("name=Ada+Lovelace&lang=%C3%A9&name=Bob"));
+
+        let raw = b"POST /submit HTTP/1.1\r\nHost: x\r\nContent-Type: text/plain\r\nContent-Length: 3\r\n\r\na=b";
+        assert!(RequestParser::parse(raw).unwrap().form_params().is_empty());
+    }
+
+    #[test]
+    fn repeated_query_parameters() {
+        let request = RequestParser::parse(b"GET /search?x=1&y=a&x=2&x= HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+        assert_eq!(request.query_params_all("x"), ["1", "2", ""]);
+        assert_eq!(request.query_param("x"), Some("1"));
+        assert_eq!(request.query_params_all("y"), ["a"]);
+        assert!(request.query_params_all("z").is_empty());
+    }
+
+
+    #[test]
+    fn header_byte_count() {
+        let request = RequestParser::parse(b"GET / HTTP/1.1\r\nHost: x\r\nAccept: */*\r\n\r\n").unwrap();
+        // "host: x\r\n" + "accept: */*\r\n"
+        assert_eq!(request.header_bytes(), 9 + 13);
+        assert_eq!(request.header_bytes(), request.headers.to_http_string().len());
+    }
+
+    #[test]
+    fn keep_alive_by_version() {
+        let keep_alive = |raw: &[u8]| RequestParser::parse(raw).unwrap().keep_alive();
+        assert!(keep_alive(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n"));
+        assert!(!keep_alive(b"GET / HTTP/1.1\r\nHost: x\r\nConnection: Close\r\n\r\n"));
+        assert!(!keep_alive(b"GET / HTTP/1.1\r\nHost: x\r\nConnection: TE, close\r\n\r\n"));
+        assert!(!keep_alive(b"GET / HTTP/1.0\r\n\r\n"));
+        assert!(keep_alive(b"GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n"));
+    }
+}
@@ -1,5 +1,44 @@
+use super::date::format_http_date;
 use super::headers::Headers;
 use super::status::StatusCode;
+use crate::session::Cookie;
+use std::cell::RefCell;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::rc::Rc;
+use std::time::SystemTime;
+
+/// A response body that is read in bounded chunks while writing
+///
+/// Clones share the source and its read position, so only one of them
+/// should be sent
+#[derive(Clone)]
+pub enum StreamBody {
+    /// An open file on disk (sent with sendfile(2) on Linux)
+    File(Rc<File>),
+    /// Any other byte source
+    Reader(Rc<RefCell<Box<dyn Read>>>),
+}
+
+impl StreamBody {
+    /// Reads the next chunk of the body into the buffer
+    pub fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            StreamBody::File(file) => (&**file).read(buf),
+            StreamBody::Reader(reader) => reader.borrow_mut().read(buf),
+        }
+    }
+}
+
+impl fmt::Debug for StreamBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StreamBody::File(file) => f.debug_tuple("File").field(file).finish(),
+            StreamBody::Reader(_) => f.write_str("Reader(..)"),
+        }
+    }
+}
 
 /// Represents an HTTP response
 #[derive(Debug, Clone)]
@@ -8,10 +47,14 @@ pub struct Response {
     pub version: String,
     /// Status code
     pub status: StatusCode,
+    /// Raw status code and reason phrase, overriding `status` on the wire
+    pub raw_status: Option<(u16, String)>,
     /// Response headers
     pub headers: Headers,
     /// Response body
     pub body: Vec<u8>,
+    /// Streamed body, sent after `body` (used for large files)
+    pub stream: Option<StreamBody>,
 }
 
 impl Response {
@@ -19,13 +62,33 @@ impl Response {
     pub fn new(status: StatusCode) -> Self {
         let mut headers = Headers::new();
         headers.set("Server", "localhost/0.1.0");
+        headers.set("Date", &format_http_date(SystemTime::now()));
         
         Response {
             version: "HTTP/1.1".to_string(),
             status,
+            raw_status: None,
             headers,
             body: Vec::new(),
+            stream: None,
+        }
+    }
+
+    /// Returns the numeric status code sent on the wire
+    pub fn status_code(&self) -> u16 {
+        match self.raw_status {
+            Some((code, _)) => code,
+            None => self.status.code(),
+        }
+    }
+
+    /// Sets a raw status code and reason phrase (for codes outside StatusCode)
+    pub fn raw_status(mut self, code: u16, reason: &str) -> Self {
+        if let Some(status) = StatusCode::from_code(code) {
+            self.status = status;
         }
+        self.raw_status = Some((code, reason.to_string()));
+        self
     }
 
     /// Creates a 200 OK response
@@ -43,6 +106,11 @@ impl Response {
         Self::new(StatusCode::BadRequest)
     }
 
+    /// Creates a 401 Unauthorized response
+    pub fn unauthorized() -> Self {
+        Self::new(StatusCode::Unauthorized)
+    }
+
     /// Creates a 403 Forbidden response
     pub fn forbidden() -> Self {
         Self::new(StatusCode::Forbidden)
@@ -63,6 +131,11 @@ impl Response {
         Self::new(StatusCode::InternalServerError)
     }
 
+    /// Creates a 503 Service Unavailable response
+    pub fn service_unavailable() -> Self {
+        Self::new(StatusCode::ServiceUnavailable)
+    }
+
     /// Creates a redirect response
     pub fn redirect(location: &str, permanent: bool) -> Self {
         let status = if permanent {
@@ -88,6 +161,26 @@ impl Response {
         self
     }
 
+    /// Streams the body from a file of known length
+    pub fn stream_file(mut self, file: File, len: u64) -> Self {
+        self.headers.set("Content-Length", &len.to_string());
+        self.body.clear();
+        self.stream = Some(StreamBody::File(Rc::new(file)));
+        self
+    }
+
+    /// Streams a body of unknown length that ends when the connection closes
+    /// (the HTTP/1.0 fallback, readable by any client): no Content-Length or
+    /// chunking is sent, and `Connection: close` ends the connection after it
+    pub fn stream_until_close(mut self, reader: Box<dyn Read>) -> Self {
+        self.headers.remove("Content-Length");
+        self.headers.remove("Transfer-Encoding");
+        self.headers.set("Connection", "close");
+        self.body.clear();
+        self.stream = Some(StreamBody::Reader(Rc::new(RefCell::new(reader))));
+        self
+    }
+
     /// Sets the body from a string
     pub fn body_str(self, body: &str) -> Self {
         self.body(body.as_bytes().to_vec())
@@ -121,22 +214,58 @@ impl Response {
         self
     }
 
+    /// Adds a Set-Cookie header built from a `Cookie`
+    pub fn cookie(mut self, cookie: Cookie) -> Self {
+        self.headers.add("Set-Cookie", &cookie.to_header_value());
+        self
+    }
+
+    /// Records that the response varies on a request header (e.g. after
+    /// negotiating on Accept-Encoding), extending any existing Vary list
+    pub fn add_vary(&mut self, header: &str) {
+        let existing = self.headers.get("Vary").unwrap_or("").to_string();
+        let mut names: Vec<&str> = existing
+            .split(',')
+            .map(|n| n.trim())
+            .filter(|n| !n.is_empty())
+            .collect();
+
+        // "*" already covers every header
+        if names.contains(&"*") {
+            return;
+        }
+
+        if header == "*" {
+            names = vec!["*"];
+        } else if !names.iter().any(|n| n.eq_ignore_ascii_case(header)) {
+            names.push(header);
+        }
+
+        let value = names.join(", ");
+        self.headers.set("Vary", &value);
+    }
+
     /// Sets the Connection header
     pub fn connection(mut self, value: &str) -> Self {
         self.headers.set("Connection", value);
         self
     }
 
+    /// Returns the size in bytes of the serialized header fields
+    pub fn header_bytes(&self) -> usize {
+        self.headers.byte_len()
+    }
+
     /// Serializes the response to bytes for sending
+    /// A streamed body is not included and must be written separately
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut result = Vec::new();
         
         // Status line
-        let status_line = format!(
-            "{} {}\r\n",
-            self.version,
-            self.status
-        );
+        let status_line = match self.raw_status {
+            Some((code, ref reason)) => format!("{} {} {}\r\n", self.version, code, reason),
+            None => format!("{} {}\r\n", self.version, self.status),
+        };
         result.extend_from_slice(status_line.as_bytes());
         
         // Headers
@@ -180,3 +309,92 @@ pub fn mime_type(path: &str) -> &'static str {
         _ => "application/octet-stream",
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads a streamed body to the end
+    fn drain(stream: &mut StreamBody) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut buf = [0u8; 7];
+        loop {
+            let n = stream.read(&mut buf).unwrap();
+            if n == 0 {
+                return out;
+            }
+            out.extend_from_slice(&buf[..n]);
+        }
+    }
+
+    #[test]
+    fn streamed_file_body() {
+        let path = std::env::temp_dir().join(format!("localhost-stream-{}", std::process::id()));
+        let contents: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &contents).unwrap();
+
+        let response = Response::ok().body(b"dropped".to_vec()).stream_file(File::open(&path).unwrap(), 10_000);
+        std::fs::remove_file(&path).unwrap();
+
+        // The head goes out first; the body comes from the stream, not to_bytes
+        let head = String::from_utf8(response.to_bytes()).unwrap();
+        assert!(head.contains("\r\ncontent-length: 10000\r\n"), "{}", head);
+        assert!(head.ends_with("\r\n\r\n"));
+
+        let copy = response.clone();
+        assert_eq!(drain(&mut copy.stream.unwrap()), contents);
+        // The clone shared the source, so nothing is left for the original
+        assert!(drain(&mut response.stream.unwrap()).is_empty());
+    }
+
+    #[test]
+    fn stream_until_close_drops_framing() {
+        let source: Box<dyn Read> = Box::new(&b"unbounded output"[..]);
+        let response = Response::ok().text("x").stream_until_close(source);
+
+        let head = String::from_utf8(response.to_bytes()).unwrap();
+        assert!(!head.contains("content-length"), "{}", head);
+        assert!(head.contains("\r\nconnection: close\r\n"));
+        assert_eq!(drain(&mut response.stream.unwrap()), b"unbounded output");
+    }
+
+    #[test]
+    fn vary_list_is_merged() {
+        let mut response = Response::ok();
+        response.add_vary("Accept-Encoding");
+        response.add_vary("Origin");
+        response.add_vary("accept-encoding");
+        assert_eq!(response.headers.get("Vary"), Some("Accept-Encoding, Origin"));
+
+        // An existing header set by hand is extended, not replaced
+        let mut response = Response::ok();
+        response.headers.set("Vary", "Cookie");
+        response.add_vary("Accept");
+        assert_eq!(response.headers.get("Vary"), Some("Cookie, Accept"));
+
+        response.add_vary("*");
+        response.add_vary("Origin");
+        assert_eq!(response.headers.get("Vary"), Some("*"));
+    }
+
+
+    #[test]
+    fn cookie_builder_becomes_set_cookie() {
+        use crate::session::SameSite;
+
+        let response = Response::ok()
+            .cookie(Cookie::new("theme", "dark").max_age(3600).secure(true).same_site(SameSite::Strict))
+            .cookie(Cookie::new("lang", "en").http_only(false).same_site(SameSite::None))
+            .set_cookie("legacy", "1", Some("Path=/old"));
+
+        let cookies: Vec<&str> = response.headers.get_all("set-cookie").unwrap().iter().map(String::as_str).collect();
+        assert_eq!(
+            cookies,
+            [
+                "theme=dark; Path=/; Max-Age=3600; Secure; HttpOnly; SameSite=Strict",
+                "lang=en; Path=/; SameSite=None",
+                "legacy=1; Path=/old",
+            ]
+        );
+    }
+}
@@ -3,6 +3,9 @@ use std::fmt;
 /// HTTP status codes
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum StatusCode {
+    // 1xx Informational
+    EarlyHints = 103,
+
     // 2xx Success
     Ok = 200,
     Created = 201,
@@ -17,17 +20,22 @@ pub enum StatusCode {
 
     // 4xx Client Errors
     BadRequest = 400,
+    Unauthorized = 401,
     Forbidden = 403,
     NotFound = 404,
     MethodNotAllowed = 405,
+    NotAcceptable = 406,
     RequestTimeout = 408,
+    LengthRequired = 411,
     PayloadTooLarge = 413,
+    RequestHeaderFieldsTooLarge = 431,
 
     // 5xx Server Errors
     InternalServerError = 500,
     NotImplemented = 501,
     BadGateway = 502,
     ServiceUnavailable = 503,
+    HttpVersionNotSupported = 505,
 }
 
 impl StatusCode {
@@ -39,6 +47,7 @@ impl StatusCode {
     /// Returns the reason phrase for the status code
     pub fn reason(&self) -> &'static str {
         match self {
+            StatusCode::EarlyHints => "Early Hints",
             StatusCode::Ok => "OK",
             StatusCode::Created => "Created",
             StatusCode::NoContent => "No Content",
@@ -48,21 +57,27 @@ impl StatusCode {
             StatusCode::TemporaryRedirect => "Temporary Redirect",
             StatusCode::PermanentRedirect => "Permanent Redirect",
             StatusCode::BadRequest => "Bad Request",
+            StatusCode::Unauthorized => "Unauthorized",
             StatusCode::Forbidden => "Forbidden",
             StatusCode::NotFound => "Not Found",
             StatusCode::MethodNotAllowed => "Method Not Allowed",
+            StatusCode::NotAcceptable => "Not Acceptable",
             StatusCode::RequestTimeout => "Request Timeout",
+            StatusCode::LengthRequired => "Length Required",
             StatusCode::PayloadTooLarge => "Payload Too Large",
+            StatusCode::RequestHeaderFieldsTooLarge => "Request Header Fields Too Large",
             StatusCode::InternalServerError => "Internal Server Error",
             StatusCode::NotImplemented => "Not Implemented",
             StatusCode::BadGateway => "Bad Gateway",
             StatusCode::ServiceUnavailable => "Service Unavailable",
+            StatusCode::HttpVersionNotSupported => "HTTP Version Not Supported",
         }
     }
 
     /// Creates a StatusCode from a numeric code
     pub fn from_code(code: u16) -> Option<Self> {
         match code {
+            103 => Some(StatusCode::EarlyHints),
             200 => Some(StatusCode::Ok),
             201 => Some(StatusCode::Created),
             204 => Some(StatusCode::NoContent),
@@ -72,15 +87,20 @@ impl StatusCode {
             307 => Some(StatusCode::TemporaryRedirect),
             308 => Some(StatusCode::PermanentRedirect),
             400 => Some(StatusCode::BadRequest),
+            401 => Some(StatusCode::Unauthorized),
             403 => Some(StatusCode::Forbidden),
             404 => Some(StatusCode::NotFound),
             405 => Some(StatusCode::MethodNotAllowed),
+            406 => Some(StatusCode::NotAcceptable),
             408 => Some(StatusCode::RequestTimeout),
+            411 => Some(StatusCode::LengthRequired),
             413 => Some(StatusCode::PayloadTooLarge),
+            431 => Some(StatusCode::RequestHeaderFieldsTooLarge),
             500 => Some(StatusCode::InternalServerError),
             501 => Some(StatusCode::NotImplemented),
             502 => Some(StatusCode::BadGateway),
             503 => Some(StatusCode::ServiceUnavailable),
+            505 => Some(StatusCode::HttpVersionNotSupported),
             _ => None,
         }
     }
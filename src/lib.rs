@@ -0,0 +1,16 @@
+//! A lightweight HTTP/1.1 server
+//!
+//! The `localhost` binary runs it from a configuration file; embedders can
+//! build a `router::Handler`, register custom route handlers and middleware
+//! on it, and serve it with `server::Server::run_with_handler`
+
+#[macro_use]
+pub mod log;
+
+pub mod cgi;
+pub mod config;
+pub mod error;
+pub mod http;
+pub mod router;
+pub mod server;
+pub mod session;
@@ -0,0 +1,141 @@
+use crate::config::{Config, Route, ServerConfig};
+use crate::http::format_http_date;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+
+/// Default access log line format
+pub const DEFAULT_FORMAT: &str =
+    "$remote_addr - [$time] \"$method $uri $protocol\" $status $body_bytes_sent $request_id";
+
+/// Values available to an access log line
+pub struct AccessEntry<'a> {
+    pub remote_addr: SocketAddr,
+    pub method: &'a str,
+    pub uri: &'a str,
+    pub protocol: &'a str,
+    pub host: &'a str,
+    pub user_agent: &'a str,
+    pub status: u16,
+    pub body_bytes_sent: usize,
+    pub request_header_bytes: usize,
+    pub response_header_bytes: usize,
+    pub request_time: Duration,
+    pub request_id: &'a str,
+}
+
+/// Writes access log lines to the destinations configured per server
+pub struct AccessLog {
+    /// Open log files by configured path
+    files: HashMap<String, File>,
+}
+
+impl AccessLog {
+    /// Opens every access log file named in the configuration
+    pub fn new(config: &Config) -> Self {
+        let mut files = HashMap::new();
+
+        for server in &config.servers {
+            if let Some(ref path) = server.access_log {
+                if path == "stdout" || files.contains_key(path) {
+                    continue;
+                }
+                match OpenOptions::new().create(true).append(true).open(path) {
+                    Ok(file) => {
+                        files.insert(path.clone(), file);
+                    }
+                    Err(e) => log_error!("Failed to open access log '{}': {}", path, e),
+                }
+            }
+        }
+
+        AccessLog { files }
+    }
+
+    /// Logs a request unless the server, route or filters exclude it
+    pub fn log(&self, server: &ServerConfig, route: Option<&Route>, entry: &AccessEntry) {
+        let target = match server.access_log {
+            Some(ref t) => t,
+            None => return,
+        };
+
+        if !Self::should_log(server, route, entry) {
+            return;
+        }
+
+        let format = server.log_format.as_deref().unwrap_or(DEFAULT_FORMAT);
+        let line = Self::format(format, entry);
+
+        if target == "stdout" {
+            println!("{}", line);
+        } else if let Some(mut file) = self.files.get(target) {
+            let _ = writeln!(file, "{}", line);
+        }
+    }
+
+    /// Applies the per-location switch and the status/IP exclusion filters
+    fn should_log(server: &ServerConfig, route: Option<&Route>, entry: &AccessEntry) -> bool {
+        if route.map(|r| !r.access_log).unwrap_or(false) {
+            return false;
+        }
+
+        let status_class = format!("{}xx", entry.status / 100);
+        if server.access_log_exclude_status.contains(&status_class) {
+            return false;
+        }
+
+        let ip = entry.remote_addr.ip().to_string();
+        !server.access_log_exclude_ip.contains(&ip)
+    }
+
+    /// Substitutes `$variables` in the format with the entry's values
+    fn format(format: &str, entry: &AccessEntry) -> String {
+        let mut line = String::new();
+        let mut chars = format.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            if c != '$' {
+                line.push(c);
+                continue;
+            }
+
+            let mut name = String::new();
+            while let Some(&ch) = chars.peek() {
+                if ch.is_ascii_alphanumeric() || ch == '_' {
+                    name.push(ch);
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+
+            match name.as_str() {
+                "remote_addr" => line.push_str(&entry.remote_addr.ip().to_string()),
+                "remote_port" => line.push_str(&entry.remote_addr.port().to_string()),
+                "time" => line.push_str(&format_http_date(SystemTime::now())),
+                "method" => line.push_str(entry.method),
+                "uri" => line.push_str(entry.uri),
+                "protocol" => line.push_str(entry.protocol),
+                "host" => line.push_str(entry.host),
+                "user_agent" => line.push_str(entry.user_agent),
+                "status" => line.push_str(&entry.status.to_string()),
+                "body_bytes_sent" => line.push_str(&entry.body_bytes_sent.to_string()),
+                "request_header_bytes" => line.push_str(&entry.request_header_bytes.to_string()),
+                "response_header_bytes" => line.push_str(&entry.response_header_bytes.to_string()),
+                "request_id" => line.push_str(entry.request_id),
+                "request_time" => {
+                    line.push_str(&format!("{:.3}", entry.request_time.as_secs_f64()))
+                }
+                _ => {
+                    // Unknown variables are kept verbatim
+                    line.push('$');
+                    line.push_str(&name);
+                }
+            }
+        }
+
+        line
+    }
+}
@@ -1,58 +1,109 @@
-mod cgi;
-mod config;
-mod error;
-mod http;
-mod router;
-mod server;
-mod session;
-
-use config::Config;
-use server::Server;
+#[macro_use]
+extern crate localhost;
+
+use localhost::config::Config;
+use localhost::log::{self, Level};
+use localhost::server::Server;
 use std::env;
 use std::process;
 
 fn main() {
     // Parse command line arguments
-    let args: Vec<String> = env::args().collect();
-    
-    let config_path = if args.len() > 1 {
-        &args[1]
-    } else {
-        "config/default.conf"
+    let mut args = env::args().skip(1);
+    let mut test_only = false;
+    let mut cli_level: Option<Level> = None;
+    let mut config_path = "config/default.conf".to_string();
+    let mut config_string: Option<String> = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            // Only check the configuration, like `nginx -t`
+            "-t" | "--test" => test_only = true,
+            "-l" | "--log-level" => {
+                let value = args.next().unwrap_or_default();
+                match value.parse() {
+                    Ok(level) => cli_level = Some(level),
+                    Err(_) => {
+                        eprintln!("Invalid log level: '{}' (expected error, warn, info or debug)", value);
+                        process::exit(1);
+                    }
+                }
+            }
+            // The configuration text itself, instead of a file
+            "--config-string" => match args.next() {
+                Some(text) => config_string = Some(text),
+                None => {
+                    eprintln!("--config-string needs the configuration text");
+                    process::exit(1);
+                }
+            },
+            // `-` reads the configuration from stdin
+            _ => config_path = arg,
+        }
+    }
+
+    if let Some(level) = cli_level {
+        log::set_level(level);
+    }
+
+    let source = match (&config_string, config_path.as_str()) {
+        (Some(_), _) => "command line".to_string(),
+        (None, "-") => "stdin".to_string(),
+        (None, path) => path.to_string(),
     };
 
-    println!("localhost HTTP Server v0.1.0");
-    println!("Loading configuration from: {}", config_path);
+    if !test_only {
+        log_info!("localhost HTTP Server v0.1.0");
+        log_info!("Loading configuration from: {}", source);
+    }
 
     // Load configuration
-    let config = match Config::load(config_path) {
+    let loaded = match &config_string {
+        Some(text) => Config::load_string(text),
+        None => Config::load(&config_path),
+    };
+    let config = match loaded {
         Ok(c) => c,
         Err(e) => {
-            eprintln!("Error loading configuration: {}", e);
+            log_error!("Error loading configuration: {}", e);
             process::exit(1);
         }
     };
 
+    // The command line flag takes precedence over the log_level directive,
+    // which already applies to the warnings validation prints
+    if cli_level.is_none() {
+        if let Some(level) = config.log_level {
+            log::set_level(level);
+        }
+    }
+
     // Validate configuration
     if let Err(e) = config.validate() {
-        eprintln!("Configuration error: {}", e);
+        log_error!("Configuration error: {}", e);
         process::exit(1);
     }
 
+    if test_only {
+        println!("configuration file {} syntax is ok", source);
+        println!("configuration file {} test is successful", source);
+        return;
+    }
+
     // Print server info
-    println!("\nServer configuration:");
+    log_info!("\nServer configuration:");
     for server in &config.servers {
-        println!("  - Host: {}", server.host);
-        println!("    Ports: {:?}", server.ports);
-        println!("    Root: {}", server.root);
-        println!("    Routes: {}", server.routes.len());
+        log_info!("  - Host: {}", server.host);
+        log_info!("    Ports: {:?}", server.ports);
+        log_info!("    Root: {}", server.root);
+        log_info!("    Routes: {}", server.routes.len());
     }
 
-    println!("\nStarting server...");
+    log_info!("\nStarting server...");
 
     // Run the server
     if let Err(e) = Server::run(config) {
-        eprintln!("Server error: {}", e);
+        log_error!("Server error: {}", e);
         process::exit(1);
     }
 }
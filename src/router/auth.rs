@@ -0,0 +1,80 @@
+use crate::config::Route;
+use crate::http::{Request, Response, StatusCode};
+use crate::session::signing::constant_time_eq;
+
+/// Handles HTTP Basic authentication
+pub struct BasicAuth;
+
+impl BasicAuth {
+    /// Authenticates a request against the route's credentials
+    /// Returns the username if the credentials are valid
+    pub fn authenticate(request: &Request, route: &Route) -> Option<String> {
+        let header = request.headers.get("authorization")?;
+        let (scheme, encoded) = header.split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return None;
+        }
+
+        let decoded = Self::decode_base64(encoded.trim())?;
+        let credentials = String::from_utf8(decoded).ok()?;
+        let (user, password) = credentials.split_once(':')?;
+
+        // Compared in constant time, and both halves always, so timing
+        // reveals neither a valid user name nor a password prefix
+        route
+            .auth_users
+            .iter()
+            .find(|(u, p)| {
+                constant_time_eq(u.as_bytes(), user.as_bytes()) & constant_time_eq(p.as_bytes(), password.as_bytes())
+            })
+            .map(|(u, _)| u.clone())
+    }
+
+    /// Creates a 401 response challenging the client for credentials
+    pub fn challenge(realm: &str) -> Response {
+        let mut response = Response::new(StatusCode::Unauthorized);
+        response.headers.set(
+            "WWW-Authenticate",
+            &format!("Basic realm=\"{}\", charset=\"UTF-8\"", realm.replace('"', "")),
+        );
+        response.html(
+            "<!DOCTYPE html>\n\
+            <html>\n\
+            <head><title>401 Unauthorized</title></head>\n\
+            <body>\n\
+            <h1>401 Unauthorized</h1>\n\
+            </body>\n\
+            </html>",
+        )
+    }
+
+    /// Decodes a standard base64 string
+    fn decode_base64(input: &str) -> Option<Vec<u8>> {
+        let input = input.trim_end_matches('=');
+        let mut result = Vec::with_capacity(input.len() * 3 / 4);
+        let mut buffer: u32 = 0;
+        let mut bits = 0;
+
+        for c in input.bytes() {
+            let value = match c {
+                b'A'..=b'Z' => c - b'A',
+                b'a'..=b'z' => c - b'a' + 26,
+                b'0'..=b'9' => c - b'0' + 52,
+                b'+' => 62,
+                b'/' => 63,
+                _ => return None,
+            };
+
+            buffer = (buffer << 6) | value as u32;
+            bits += 6;
+
+            if bits >= 8 {
+                bits -= 8;
+                result.push((buffer >> bits) as u8);
+                buffer &= (1 << bits) - 1;
+            }
+        }
+
+        Some(result)
+    }
+}
@@ -0,0 +1,62 @@
+use crate::config::Route;
+use crate::http::{Method, Request, Response, StatusCode};
+
+/// Cross-origin resource sharing (CORS) headers for a route
+pub struct Cors;
+
+impl Cors {
+    /// Checks if a request is a CORS preflight: an OPTIONS request carrying
+    /// Origin and Access-Control-Request-Method
+    pub fn is_preflight(request: &Request) -> bool {
+        request.method == Method::Options
+            && request.headers.get("Origin").is_some()
+            && request.headers.get("Access-Control-Request-Method").is_some()
+    }
+
+    /// Answers a preflight with 204 and the route's CORS headers
+    /// The route's own methods are advertised; a disallowed origin gets the
+    /// 204 without any Access-Control headers, which the browser rejects
+    pub fn preflight(request: &Request, route: &Route) -> Response {
+        let mut response = Response::new(StatusCode::NoContent);
+        if Self::apply(request, route, &mut response) {
+            let mut methods: Vec<&str> = route.methods.iter().map(|m| m.as_str()).collect();
+            if route.is_method_allowed(&Method::Get) && !methods.contains(&"HEAD") {
+                methods.push("HEAD");
+            }
+            response.headers.set("Access-Control-Allow-Methods", &methods.join(", "));
+
+            let headers = route.cors_allow_headers.as_deref()
+                .or_else(|| request.headers.get("Access-Control-Request-Headers"));
+            if let Some(headers) = headers {
+                response.headers.set("Access-Control-Allow-Headers", headers);
+            }
+            if let Some(max_age) = route.cors_max_age {
+                response.headers.set("Access-Control-Max-Age", &max_age.to_string());
+            }
+        }
+        response
+    }
+
+    /// Adds Access-Control-Allow-Origin when the route enables CORS and the
+    /// request's origin is allowed; returns whether it did
+    pub fn apply(request: &Request, route: &Route, response: &mut Response) -> bool {
+        let allowed = match &route.cors_allow_origin {
+            Some(allowed) => allowed,
+            None => return false,
+        };
+        let origin = match request.headers.get("Origin") {
+            Some(origin) => origin,
+            None => return false,
+        };
+
+        if allowed == "*" {
+            response.headers.set("Access-Control-Allow-Origin", "*");
+        } else if allowed.split_whitespace().any(|o| o == origin) {
+            response.headers.set("Access-Control-Allow-Origin", origin);
+            response.add_vary("Origin");
+        } else {
+            return false;
+        }
+        true
+    }
+}
@@ -1,27 +1,47 @@
-use crate::http::Response;
+use crate::error::{Result, ServerError};
+use crate::http::{format_http_date, parse_http_date, Response, StatusCode};
 use std::fs;
 use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
 
 /// Generates directory listings
 pub struct DirectoryListing;
 
 impl DirectoryListing {
     /// Generates an HTML directory listing for the given path
-    pub fn generate(dir_path: &str, request_path: &str) -> Response {
+    /// An empty directory yields a listing with only the parent link; errors
+    /// reading the directory are returned for the caller to map to a status.
+    /// Unless `follow_symlinks` is set, symlinks are listed as such, unfollowed.
+    /// The listing carries the directory's mtime as Last-Modified, and an
+    /// `if_modified_since` at or after it yields 304 without re-rendering
+    pub fn generate(
+        dir_path: &str,
+        request_path: &str,
+        follow_symlinks: bool,
+        if_modified_since: Option<&str>,
+    ) -> Result<Response> {
         let path = Path::new(dir_path);
 
         if !path.is_dir() {
-            return Response::not_found()
-                .html("<h1>404 Not Found</h1>");
+            return Err(ServerError::NotFound);
         }
 
-        let entries = match fs::read_dir(path) {
-            Ok(e) => e,
-            Err(_) => {
-                return Response::forbidden()
-                    .html("<h1>403 Forbidden</h1>");
+        // HTTP dates have whole-second precision
+        let modified = fs::metadata(path)?
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| UNIX_EPOCH + Duration::from_secs(d.as_secs()));
+
+        if let (Some(modified), Some(since)) = (modified, if_modified_since.and_then(parse_http_date)) {
+            if modified <= since {
+                let mut response = Response::new(StatusCode::NotModified);
+                response.headers.set("Last-Modified", &format_http_date(modified));
+                return Ok(response);
             }
-        };
+        }
+
+        let entries = fs::read_dir(path)?;
 
         let mut html = String::new();
         html.push_str("<!DOCTYPE html>\n<html>\n<head>\n");
@@ -57,9 +77,17 @@ impl DirectoryListing {
             .filter_map(|e| e.ok())
             .collect();
         
+        let is_dir = |entry: &fs::DirEntry| {
+            if follow_symlinks {
+                entry.path().is_dir()
+            } else {
+                entry.file_type().map(|t| t.is_dir()).unwrap_or(false)
+            }
+        };
+
         items.sort_by(|a, b| {
-            let a_is_dir = a.path().is_dir();
-            let b_is_dir = b.path().is_dir();
+            let a_is_dir = is_dir(a);
+            let b_is_dir = is_dir(b);
             
             // Directories first, then alphabetically
             match (a_is_dir, b_is_dir) {
@@ -72,8 +100,8 @@ impl DirectoryListing {
         // Generate entries
         for entry in items {
             let name = entry.file_name().to_string_lossy().to_string();
-            let entry_path = entry.path();
-            let is_dir = entry_path.is_dir();
+            let is_link = entry.file_type().map(|t| t.is_symlink()).unwrap_or(false);
+            let is_dir = is_dir(&entry);
             
             let href = if request_path.ends_with('/') {
                 format!("{}{}", request_path, name)
@@ -81,7 +109,9 @@ impl DirectoryListing {
                 format!("{}/{}", request_path, name)
             };
 
-            let (size_str, type_str) = if is_dir {
+            let (size_str, type_str) = if is_link && !follow_symlinks {
+                ("-".to_string(), "Symlink")
+            } else if is_dir {
                 ("-".to_string(), "Directory")
             } else {
                 let size = entry.metadata()
@@ -108,7 +138,11 @@ impl DirectoryListing {
         html.push_str("<p><em>localhost server</em></p>\n");
         html.push_str("</body>\n</html>");
 
-        Response::ok().html(&html)
+        let mut response = Response::ok().html(&html);
+        if let Some(modified) = modified {
+            response.headers.set("Last-Modified", &format_http_date(modified));
+        }
+        Ok(response)
     }
 
     /// Formats a file size in human-readable format
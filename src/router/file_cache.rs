@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+/// A cached file's contents and the metadata they were read with
+struct CachedFile {
+    contents: Vec<u8>,
+    modified: SystemTime,
+    last_used: u64,
+}
+
+/// In-memory LRU cache of small static files, bounded by entry count and
+/// total bytes
+///
+/// Entries are validated against the file's mtime and length on every hit,
+/// so a changed file is re-read rather than served stale
+pub struct FileCache {
+    max_entries: usize,
+    max_bytes: usize,
+    entries: HashMap<String, CachedFile>,
+    used_bytes: usize,
+    /// Monotonic use counter for LRU ordering
+    clock: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl FileCache {
+    /// Creates a cache holding at most `max_entries` files and `max_bytes` bytes
+    pub fn new(max_entries: usize, max_bytes: usize) -> Self {
+        FileCache {
+            max_entries,
+            max_bytes,
+            entries: HashMap::new(),
+            used_bytes: 0,
+            clock: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Checks if the cache can hold anything at all
+    pub fn is_enabled(&self) -> bool {
+        self.max_entries > 0 && self.max_bytes > 0
+    }
+
+    /// Returns the cached contents of a file if they are still current
+    pub fn get(&mut self, path: &str, modified: SystemTime, len: u64) -> Option<Vec<u8>> {
+        self.clock += 1;
+
+        let fresh = match self.entries.get_mut(path) {
+            Some(entry) if entry.modified == modified && entry.contents.len() as u64 == len => {
+                entry.last_used = self.clock;
+                self.hits += 1;
+                return Some(entry.contents.clone());
+            }
+            Some(_) => false,
+            None => true,
+        };
+
+        // Stale: drop it so the caller's fresh read replaces it
+        if !fresh {
+            self.remove(path);
+        }
+        self.misses += 1;
+        None
+    }
+
+    /// Stores a file's contents, evicting least recently used entries to fit
+    /// Files larger than the whole cache are not stored
+    pub fn insert(&mut self, path: &str, contents: Vec<u8>, modified: SystemTime) {
+        if !self.is_enabled() || contents.len() > self.max_bytes {
+            return;
+        }
+
+        self.remove(path);
+        while self.entries.len() >= self.max_entries || self.used_bytes + contents.len() > self.max_bytes {
+            if !self.evict_oldest() {
+                break;
+            }
+        }
+
+        self.clock += 1;
+        self.used_bytes += contents.len();
+        self.entries.insert(path.to_string(), CachedFile {
+            contents,
+            modified,
+            last_used: self.clock,
+        });
+    }
+
+    /// Number of lookups answered from memory
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// Number of lookups that had to read from disk
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+
+    /// Removes an entry, if present
+    fn remove(&mut self, path: &str) {
+        if let Some(entry) = self.entries.remove(path) {
+            self.used_bytes -= entry.contents.len();
+        }
+    }
+
+    /// Evicts the least recently used entry; false when the cache is empty
+    fn evict_oldest(&mut self) -> bool {
+        let oldest = self.entries
+            .iter()
+            .min_by_key(|(_, entry)| entry.last_used)
+            .map(|(path, _)| path.clone());
+
+        match oldest {
+            Some(path) => {
+                self.remove(&path);
+                true
+            }
+            None => false,
+        }
+    }
+}
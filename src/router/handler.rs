@@ -1,271 +1,1319 @@
+use super::auth::BasicAuth;
+use super::cors::Cors;
 use super::directory::DirectoryListing;
+use super::file_cache::FileCache;
+use super::landing::LandingPage;
 use super::redirect::Redirect;
 use super::static_files::StaticFiles;
 use crate::cgi::CgiExecutor;
-use crate::config::{Config, ServerConfig};
-use crate::http::{Method, Request, Response};
-use crate::session::SessionStore;
+use crate::config::{Config, CookiePolicy, RedirectPolicy, Route, ServerConfig, SessionStorage};
+use crate::error::{pages, Result, ServerError};
+use crate::http::compression::EncodingChoice;
+use crate::http::{
+    accept, compression, multipart, Method, MultipartReader, Request, RequestParser, Response, SpooledBody, StatusCode,
+};
+use crate::log::{AccessEntry, AccessLog};
+use crate::server::stats::BUCKET_LABELS;
+use crate::server::ConnectionStats;
+use crate::session::{Cookie, FileSessionStore, SameSite, SessionBackend, SessionStore};
+use std::cell::{Cell, RefCell};
 use std::fs;
-use std::path::Path;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// What to do with a request whose headers arrived before its body
+pub enum HeaderCheck {
+    /// Answer right away without waiting for the body
+    Respond(Response),
+    /// Write the body to a temp file in this directory as it arrives
+    Spool(PathBuf),
+    /// Keep reading the body into memory
+    Continue,
+}
+
+/// A custom Rust endpoint registered against a path
+pub trait RouteHandler {
+    /// Produces the response for a request to the registered path
+    fn handle(&self, request: &Request) -> Response;
+}
+
+impl<F> RouteHandler for F
+where
+    F: Fn(&Request) -> Response,
+{
+    fn handle(&self, request: &Request) -> Response {
+        self(request)
+    }
+}
+
+/// Hooks run around the core dispatch of every request, in registration order
+pub trait Middleware {
+    /// Inspects a request before dispatch; returning a response short-circuits it
+    fn before(&self, _request: &Request) -> Option<Response> {
+        None
+    }
+
+    /// Transforms the response after dispatch
+    fn after(&self, _request: &Request, response: Response) -> Response {
+        response
+    }
+}
 
 /// Main request handler
 pub struct Handler {
     config: Config,
-    sessions: Arc<Mutex<SessionStore>>,
+    sessions: Arc<Mutex<Box<dyn SessionBackend>>>,
+    access_log: AccessLog,
+    /// Registered custom handlers (path, handler), checked before routes
+    custom_handlers: Vec<(String, Box<dyn RouteHandler>)>,
+    /// Registered middleware, run in order around dispatch
+    middleware: Vec<Box<dyn Middleware>>,
+    /// Open connections, as last reported by the event loop
+    active_connections: Cell<usize>,
+    /// Connection reuse counters, as last reported by the event loop
+    connection_stats: Cell<ConnectionStats>,
+    /// Small static files shared by every server with `open_file_cache` on
+    file_cache: RefCell<FileCache>,
+}
+
+/// An upload's temp file, always removed when dropped: once it is linked
+/// into place the link is the only name left, and a failed write, an error
+/// return or an unwind never leaves a partial file behind
+struct TempUpload {
+    path: PathBuf,
+}
+
+impl TempUpload {
+    /// Links the finished file into `dir` under `filename`, or under
+    /// "stem_1.ext", "stem_2.ext", ... when that name is taken, and returns
+    /// the name used. Linking fails rather than replacing an existing file,
+    /// so two uploads of the same name can't overwrite each other
+    fn persist(self, dir: &Path, filename: &str) -> Result<String> {
+        let (stem, ext) = match filename.rfind('.') {
+            Some(pos) if pos > 0 => filename.split_at(pos),
+            _ => (filename, ""),
+        };
+
+        let mut name = filename.to_string();
+        for n in 1.. {
+            match fs::hard_link(&self.path, dir.join(&name)) {
+                Ok(()) => break,
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    name = format!("{}_{}{}", stem, n, ext);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(name)
+    }
+}
+
+impl Drop for TempUpload {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
 }
 
 impl Handler {
     /// Creates a new handler with the given configuration
     pub fn new(config: Config) -> Self {
+        let access_log = AccessLog::new(&config);
+
+        // One cache serves all servers, sized to the largest configured
+        let cache_servers = config.servers.iter().filter(|s| s.open_file_cache_max > 0);
+        let file_cache = FileCache::new(
+            cache_servers.clone().map(|s| s.open_file_cache_max).max().unwrap_or(0),
+            cache_servers.map(|s| s.open_file_cache_size).max().unwrap_or(0),
+        );
+
+        let sessions: Box<dyn SessionBackend> = match &config.session_store {
+            SessionStorage::Memory => Box::new(SessionStore::new()),
+            SessionStorage::File(dir) => Box::new(FileSessionStore::new(dir)),
+        };
+
         Handler { 
             config,
-            sessions: Arc::new(Mutex::new(SessionStore::new())),
+            sessions: Arc::new(Mutex::new(sessions)),
+            access_log,
+            custom_handlers: Vec::new(),
+            middleware: Vec::new(),
+            active_connections: Cell::new(0),
+            connection_stats: Cell::new(ConnectionStats::default()),
+            file_cache: RefCell::new(file_cache),
         }
     }
 
-    /// Handles an incoming request
-    pub fn handle(&self, request: &Request, server: &ServerConfig) -> Response {
-        // Get or create session
-        let session_cookie = request.cookie("session_id");
-        let (session_id, is_new_session) = {
-            let mut sessions = self.sessions.lock().unwrap();
-            match session_cookie {
-                Some(ref id) if sessions.get(id).is_some() => (id.clone(), false),
-                _ => (sessions.create(), true),
+    /// Records the number of open connections, used for load shedding
+    pub fn set_active_connections(&self, count: usize) {
+        self.active_connections.set(count);
+    }
+
+    /// Records the event loop's connection counters for the status page
+    pub fn set_connection_stats(&self, stats: ConnectionStats) {
+        self.connection_stats.set(stats);
+    }
+
+    /// Adds a middleware; `before` hooks run in registration order and
+    /// `after` hooks in reverse, so the first one added wraps all others
+    pub fn add_middleware<M: Middleware + 'static>(&mut self, middleware: M) {
+        self.middleware.push(Box::new(middleware));
+    }
+
+    /// Dispatches a request through the middleware chain
+    /// When a `before` hook short-circuits, only the middleware that already
+    /// ran gets its `after` hook applied
+    fn dispatch(&self, request: &Request, server: &ServerConfig, interim: &mut dyn FnMut(&Response)) -> Response {
+        let mut entered = 0;
+        let mut response = None;
+
+        for middleware in &self.middleware {
+            if let Some(early) = middleware.before(request) {
+                response = Some(early);
+                break;
+            }
+            entered += 1;
+        }
+
+        let mut response = response.unwrap_or_else(|| self.handle(request, server, interim));
+        for middleware in self.middleware[..entered].iter().rev() {
+            response = middleware.after(request, response);
+        }
+        response
+    }
+
+    /// Registers a custom handler for a path and everything below it
+    /// Custom handlers run before static file and CGI resolution
+    pub fn register<H: RouteHandler + 'static>(&mut self, path: &str, handler: H) {
+        self.custom_handlers.push((path.to_string(), Box::new(handler)));
+    }
+
+    /// Finds the registered custom handler for a request path, if any
+    fn find_custom_handler(&self, path: &str) -> Option<&dyn RouteHandler> {
+        self.custom_handlers
+            .iter()
+            .filter(|(prefix, _)| {
+                path == prefix
+                    || path.strip_prefix(prefix.as_str())
+                        .map(|rest| prefix.ends_with('/') || rest.starts_with('/'))
+                        .unwrap_or(false)
+            })
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, handler)| handler.as_ref())
+    }
+
+    /// Runs the full pipeline on raw request bytes received on `local` from
+    /// `peer`: parsing, virtual host selection, body limits and dispatch.
+    /// On TLS connections `sni` is the server name from the handshake; `body`
+    /// is the request body when it was spooled to disk while being read.
+    /// `interim` is handed any 1xx response (103 Early Hints) to send ahead
+    /// of the final one
+    pub fn respond(
+        &self,
+        data: &[u8],
+        body: Option<SpooledBody>,
+        local: SocketAddr,
+        peer: SocketAddr,
+        sni: Option<&str>,
+        interim: &mut dyn FnMut(&Response),
+    ) -> Response {
+        let started = Instant::now();
+        let port = local.port();
+
+        // Header limits come from the port's default server, as the Host
+        // header isn't known until the headers are parsed
+        let max_headers = self.config.find_server(port, None)
+            .map(|s| s.max_headers)
+            .unwrap_or(crate::http::parser::DEFAULT_MAX_HEADERS);
+
+        // A chunked body is held to the largest limit on the port while it is
+        // decoded; the route's own limit is checked below
+        let max_body = self.config.max_body_size_on(port);
+
+        let mut request = match RequestParser::parse_with_limits(data, max_headers, max_body) {
+            Ok(r) => r,
+            Err(ServerError::UnsupportedVersion(_)) => {
+                return Response::new(StatusCode::HttpVersionNotSupported)
+                    .connection("close")
+                    .html("<h1>505 HTTP Version Not Supported</h1>");
+            }
+            Err(ServerError::BodyTooLarge) => {
+                // The rest of the chunked body is never read, so close
+                return Response::payload_too_large()
+                    .connection("close")
+                    .html("<h1>413 Payload Too Large</h1>");
+            }
+            Err(ServerError::LengthRequired) => {
+                // Any body that follows can't be framed, so close after answering
+                return Response::new(StatusCode::LengthRequired)
+                    .connection("close")
+                    .html("<h1>411 Length Required</h1>");
+            }
+            Err(ServerError::TooManyHeaders) => {
+                return Response::new(StatusCode::RequestHeaderFieldsTooLarge)
+                    .connection("close")
+                    .html("<h1>431 Request Header Fields Too Large</h1>");
+            }
+            Err(_) => {
+                // Unrecoverable: the stream can't be resynchronized, so close
+                return Response::bad_request()
+                    .connection("close")
+                    .html("<h1>400 Bad Request</h1>");
+            }
+        };
+
+        request.remote_addr = Some(peer);
+        request.local_addr = Some(local);
+        request.body_file = body.map(Arc::new);
+        Self::assign_request_id(&mut request);
+
+        // Find the right server config using Host header for virtual hosting
+        let host_header = request.host().unwrap_or("localhost");
+        let server = match self.config.find_server_by_sni(sni, host_header, port)
+            .or_else(|| self.config.servers.first())
+        {
+            Some(s) => s,
+            None => {
+                return Response::internal_error()
+                    .html("<h1>500 Internal Server Error</h1>");
+            }
+        };
+
+        // Behind a trusted proxy, everything downstream sees the real client
+        let peer = Self::client_addr(&request, server, peer);
+        request.remote_addr = Some(peer);
+
+        if server.method_override {
+            Self::override_method(&mut request);
+        }
+
+        // Check body size
+        let mut response = if let Some(response) = self.check_maintenance(&request, server, peer) {
+            response
+        } else if let Some(response) = self.check_overload(&request, server) {
+            response
+        } else if let Some(response) = self.upgrade_insecure(&request, server, port) {
+            response
+        } else if server.strict_bodies && request.has_body() && !server.body_methods.contains(&request.method) {
+            // Ambiguous framing: don't trust the rest of the stream
+            self.error_response(&request, server, 400).connection("close")
+        } else if !server.is_body_size_allowed(&request.path, request.body_len()) {
+            Response::payload_too_large()
+                .html("<h1>413 Payload Too Large</h1>")
+        } else if let Err(e) = Self::spool_body(&mut request, server) {
+            self.error_page(&request, server, 500, Some(&format!("Failed to spool request body: {}", e)))
+        } else {
+            self.dispatch(&request, server, interim)
+        };
+        response = self.limit_cookies(&request, response, server);
+        response = self.check_redirect(&request, response, server);
+        Self::strip_head_body(&request, &mut response);
+
+        // With keep-alive off every connection serves a single request, as
+        // does one the client asked to close
+        if !server.keepalive || !request.keep_alive() {
+            response.headers.set("Connection", "close");
+        }
+        if server.keepalive_header && response.headers.keep_alive() {
+            response.headers.set("Keep-Alive", &format!("timeout={}", server.timeout));
+        }
+        if let Some(id) = request.request_id() {
+            response.headers.set("X-Request-Id", id);
+        }
+
+        self.log_access(&request, &response, server, peer, started);
+        response
+    }
+
+    /// Drops the body of a response to HEAD, keeping the headers (Content-Length
+    /// included) a GET would get. Handlers build the full response so the
+    /// headers match; this is the one place the body goes, and dropping a
+    /// streamed body closes its file or stops its CGI script
+    fn strip_head_body(request: &Request, response: &mut Response) {
+        if request.method == Method::Head {
+            response.body.clear();
+            response.stream = None;
+        }
+    }
+
+    /// Applies the Set-Cookie size and count limits, logging a warning when a
+    /// response exceeds them. Cookies are kept in order while they fit
+    fn limit_cookies(&self, request: &Request, mut response: Response, server: &ServerConfig) -> Response {
+        let max_size = if server.set_cookie_max_size == 0 { usize::MAX } else { server.set_cookie_max_size };
+        let max_count = if server.set_cookie_max_count == 0 { usize::MAX } else { server.set_cookie_max_count };
+
+        let cookies = match response.headers.get_all("set-cookie") {
+            Some(cookies) => cookies.clone(),
+            None => return response,
+        };
+        // Serialized as "Set-Cookie: <value>\r\n"
+        let sizes: Vec<usize> = cookies.iter().map(|c| "set-cookie".len() + c.len() + 4).collect();
+        let total: usize = sizes.iter().sum();
+        if total <= max_size && cookies.len() <= max_count {
+            return response;
+        }
+
+        log_warn!(
+            "{} {}: {} Set-Cookie headers ({} bytes) exceed the limit",
+            request.method, request.path, cookies.len(), total
+        );
+
+        match server.set_cookie_policy {
+            CookiePolicy::Warn => response,
+            CookiePolicy::Reject => self.error_response(request, server, 500),
+            CookiePolicy::Drop => {
+                response.headers.remove("set-cookie");
+                let mut used = 0;
+                let mut kept = 0;
+                for (cookie, size) in cookies.iter().zip(sizes) {
+                    if kept < max_count && used + size <= max_size {
+                        response.headers.add("Set-Cookie", cookie);
+                        used += size;
+                        kept += 1;
+                    }
+                }
+                response
+            }
+        }
+    }
+
+    /// Holds redirects to hosts off `redirect_allow_hosts`, whether the
+    /// Location came from a `return`, the Host header or a CGI script, so a
+    /// request can't bounce visitors to an arbitrary site. Paths on this
+    /// server always pass
+    fn check_redirect(&self, request: &Request, response: Response, server: &ServerConfig) -> Response {
+        let host = match response.headers.get("location").and_then(Redirect::external_host) {
+            Some(host) => host,
+            None => return response,
+        };
+        if server.is_redirect_host_allowed(&host) {
+            return response;
+        }
+
+        log_warn!(
+            "{} {}: redirect to off-list host '{}'",
+            request.method, request.path, host
+        );
+
+        match server.redirect_policy {
+            RedirectPolicy::Log => response,
+            RedirectPolicy::Block => self.error_response(request, server, 500),
+        }
+    }
+
+    /// Gives the request an ID for tracing, keeping a well-formed X-Request-Id
+    /// from the client (e.g. set by a proxy) and generating one otherwise;
+    /// CGI scripts see it as HTTP_X_REQUEST_ID
+    fn assign_request_id(request: &mut Request) {
+        let valid = request.request_id()
+            .map(|id| !id.is_empty() && id.len() <= 128 && id.bytes().all(|b| b.is_ascii_graphic()))
+            .unwrap_or(false);
+        if !valid {
+            request.headers.set("X-Request-Id", &SessionStore::generate_id());
+        }
+    }
+
+    /// Moves a body read into memory (e.g. a chunked one) to a temp file when
+    /// the route spools bodies of its size, releasing the memory before a CGI
+    /// script or upload reads it
+    fn spool_body(request: &mut Request, server: &ServerConfig) -> io::Result<()> {
+        if request.body_file.is_some() {
+            return Ok(());
+        }
+        let dir = match server.find_route(&request.path).and_then(|route| Self::spool_dir(route, request, request.body.len())) {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+        let spooled = SpooledBody::create(&dir, &request.body)?;
+        log_debug!("{} body of {} bytes spooled to {}", request.path, spooled.len(), spooled.path().display());
+        request.body = Vec::new();
+        request.body_file = Some(Arc::new(spooled));
+        Ok(())
+    }
+
+    /// The directory a route spools a body of `len` bytes to, if it does:
+    /// as configured, and for any upload too large for one multipart buffer
+    fn spool_dir(route: &Route, request: &Request, len: usize) -> Option<PathBuf> {
+        let large_upload = request.method == Method::Post
+            && route.upload_dir.is_some()
+            && len > multipart::DEFAULT_BUFFER_SIZE;
+        if !route.spools_body(len) && !large_upload {
+            return None;
+        }
+        Some(route.client_body_temp_path.as_deref().map_or_else(std::env::temp_dir, PathBuf::from))
+    }
+
+    /// Routes a POST as the PUT or DELETE named by X-HTTP-Method-Override or,
+    /// failing that, a `_method` form field, for clients that can only send
+    /// GET and POST. Other methods and other targets are left as they are
+    fn override_method(request: &mut Request) {
+        if request.method != Method::Post {
+            return;
+        }
+        let target = match request.headers.get("x-http-method-override") {
+            Some(value) => value.parse::<Method>().ok(),
+            None => request.form_params().get("_method").and_then(|v| v.parse::<Method>().ok()),
+        };
+        if let Some(method @ (Method::Put | Method::Delete)) = target {
+            log_debug!("{} overridden to {}", request.path, method);
+            request.method = method;
+        }
+    }
+
+    /// Finds the client address behind trusted proxies: when the peer is in
+    /// `set_real_ip_from`, X-Forwarded-For is walked from the right past
+    /// trusted entries, and the first untrusted one is the client. Anything
+    /// from an untrusted peer could be spoofed, so its header is ignored
+    fn client_addr(request: &Request, server: &ServerConfig, peer: SocketAddr) -> SocketAddr {
+        if !server.is_trusted_proxy(peer.ip()) {
+            return peer;
+        }
+        let mut client = peer.ip();
+        for entry in request.headers.get_list("x-forwarded-for").into_iter().rev() {
+            let ip = match entry.parse::<IpAddr>().or_else(|_| entry.parse::<SocketAddr>().map(|a| a.ip())) {
+                Ok(ip) => ip,
+                Err(_) => break,
+            };
+            client = ip;
+            if !server.is_trusted_proxy(ip) {
+                break;
+            }
+        }
+        SocketAddr::new(client, peer.port())
+    }
+
+    /// Returns a 503 response while the server is in maintenance, unless the
+    /// client IP is allow-listed
+    fn check_maintenance(&self, request: &Request, server: &ServerConfig, peer: SocketAddr) -> Option<Response> {
+        if !server.in_maintenance() {
+            return None;
+        }
+
+        let ip = peer.ip().to_string();
+        if server.maintenance_allow.contains(&ip) {
+            return None;
+        }
+
+        let mut response = self.error_response(request, server, 503);
+        response.headers.set("Retry-After", &server.maintenance_retry_after.to_string());
+        Some(response)
+    }
+
+    /// Redirects a browser asking for `Upgrade-Insecure-Requests` over plain
+    /// HTTP to the https URL of the same resource
+    fn upgrade_insecure(&self, request: &Request, server: &ServerConfig, port: u16) -> Option<Response> {
+        if !server.upgrade_insecure_requests
+            || request.headers.get("upgrade-insecure-requests").map(str::trim) != Some("1")
+            || self.config.is_ssl_address(&server.host, port)
+        {
+            return None;
+        }
+
+        // Drop the port, keeping an IPv6 literal's brackets
+        let host = request.host().unwrap_or(&server.server_name);
+        let host = match host.find(']') {
+            Some(end) if host.starts_with('[') => &host[..=end],
+            _ => host.split(':').next().unwrap_or(host),
+        };
+        let https_port = self.config.https_port_for(&server.server_name);
+        let authority = if https_port == 443 { host.to_string() } else { format!("{}:{}", host, https_port) };
+        let query = if request.raw_query.is_empty() { String::new() } else { format!("?{}", request.raw_query) };
+
+        // The target as received stays percent-encoded, so a decoded CR or
+        // LF can't end up in the Location header
+        let location = format!("https://{}{}{}", authority, request.raw_path, query);
+        let mut response = Redirect::temporary_preserve_method(&location);
+        response.add_vary("Upgrade-Insecure-Requests");
+        Some(response)
+    }
+
+    /// Returns a 503 response while more connections are open than the soft
+    /// limit, with a Retry-After growing by a second per 10% over the limit
+    fn check_overload(&self, request: &Request, server: &ServerConfig) -> Option<Response> {
+        let soft = server.max_connections_soft;
+        let active = self.active_connections.get();
+        if soft == 0 || active <= soft {
+            return None;
+        }
+
+        let retry_after = (1 + (active - soft) * 10 / soft).min(120);
+        let mut response = self.error_response(request, server, 503);
+        response.headers.set("Retry-After", &retry_after.to_string());
+        Some(response)
+    }
+
+    /// Builds the 103 Early Hints response announcing a GET route's `preload`
+    /// assets; None when there are none or the client speaks HTTP/1.0, which
+    /// can't take interim responses
+    fn early_hints(request: &Request, route: &Route) -> Option<Response> {
+        if route.preload.is_empty() || request.method != Method::Get || request.version == "HTTP/1.0" {
+            return None;
+        }
+
+        let mut response = Response::new(StatusCode::EarlyHints);
+        for (url, kind) in &route.preload {
+            let link = match kind {
+                Some(kind) => format!("<{}>; rel=preload; as={}", url, kind),
+                None => format!("<{}>; rel=preload", url),
+            };
+            response.headers.add("Link", &link);
+        }
+        Some(response)
+    }
+
+    /// Checks a request whose headers have arrived but whose body hasn't:
+    /// an early error response (e.g. 413 for an oversized Content-Length), or
+    /// where to spool a body the route keeps on disk
+    pub fn check_headers(&self, data: &[u8], port: u16, peer: SocketAddr, sni: Option<&str>) -> HeaderCheck {
+        let started = Instant::now();
+        let mut request = match RequestParser::parse(data) {
+            Ok(request) => request,
+            Err(_) => return HeaderCheck::Continue,
+        };
+        Self::assign_request_id(&mut request);
+
+        let host_header = request.host().unwrap_or("localhost");
+        let server = match self.config.find_server_by_sni(sni, host_header, port)
+            .or_else(|| self.config.servers.first())
+        {
+            Some(server) => server,
+            None => return HeaderCheck::Continue,
+        };
+        let peer = Self::client_addr(&request, server, peer);
+
+        let mut response = if let Some(response) = self.check_maintenance(&request, server, peer) {
+            response
+        } else {
+            match request.content_length() {
+                Some(len) if !server.is_body_size_allowed(&request.path, len) => {
+                    Response::payload_too_large()
+                        .html("<h1>413 Payload Too Large</h1>")
+                }
+                Some(len) => {
+                    return match server.find_route(&request.path).and_then(|route| Self::spool_dir(route, &request, len)) {
+                        Some(dir) => HeaderCheck::Spool(dir),
+                        None => HeaderCheck::Continue,
+                    };
+                }
+                None => return HeaderCheck::Continue,
             }
         };
+
+        if !server.keepalive || !request.keep_alive() {
+            response.headers.set("Connection", "close");
+        }
+        Self::strip_head_body(&request, &mut response);
+        if let Some(id) = request.request_id() {
+            response.headers.set("X-Request-Id", id);
+        }
+        self.log_access(&request, &response, server, peer, started);
+        HeaderCheck::Respond(response)
+    }
+
+    /// Writes the access log line for a handled request
+    fn log_access(
+        &self,
+        request: &Request,
+        response: &Response,
+        server: &ServerConfig,
+        peer: SocketAddr,
+        started: Instant,
+    ) {
+        // A HEAD answer keeps the Content-Length a GET would get, but no body goes out
+        let body_bytes_sent = if request.method == Method::Head {
+            0
+        } else {
+            response.headers.content_length().unwrap_or(response.body.len())
+        };
+        let entry = AccessEntry {
+            remote_addr: peer,
+            method: request.method.as_str(),
+            uri: &request.path,
+            protocol: &request.version,
+            host: request.host().unwrap_or("-"),
+            user_agent: request.headers.get("user-agent").unwrap_or("-"),
+            status: response.status_code(),
+            body_bytes_sent,
+            request_header_bytes: request.header_bytes(),
+            response_header_bytes: response.header_bytes(),
+            request_time: started.elapsed(),
+            request_id: request.request_id().unwrap_or("-"),
+        };
+        self.access_log.log(server, server.find_route(&request.path), &entry);
+    }
+
+    /// Handles a raw request and returns the serialized response, as if it
+    /// arrived on the first configured port. Independent of the event loop,
+    /// so it can drive tests, benchmarks and fuzzing without sockets
+    pub fn dispatch_bytes(&self, data: &[u8]) -> Vec<u8> {
+        let port = self.config.servers
+            .first()
+            .and_then(|s| s.ports.first().copied())
+            .unwrap_or(8080);
+
+        let local = SocketAddr::from(([127, 0, 0, 1], port));
+        let peer = SocketAddr::from(([127, 0, 0, 1], 0));
+        let mut bytes = Vec::new();
+        let mut response = self.respond(data, None, local, peer, None, &mut |hints| bytes.extend(hints.to_bytes()));
+        bytes.extend(response.to_bytes());
+
+        // Inline any streamed body so the result is self-contained
+        if let Some(mut stream) = response.stream.take() {
+            let mut buf = [0u8; 8192];
+            while let Ok(n) = stream.read(&mut buf) {
+                if n == 0 {
+                    break;
+                }
+                bytes.extend_from_slice(&buf[..n]);
+            }
+        }
+
+        bytes
+    }
+
+    /// Handles an incoming request; `interim` is handed an Early Hints
+    /// response once the request has passed the route's checks
+    pub fn handle(&self, request: &Request, server: &ServerConfig, interim: &mut dyn FnMut(&Response)) -> Response {
+        // OPTIONS * asks about the server as a whole, not a resource
+        if request.method == Method::Options && request.path == "*" {
+            let mut response = Response::new(StatusCode::NoContent);
+            response.headers.set("Allow", &Self::server_methods(server).join(", "));
+            return response;
+        }
+
+        // Registered Rust handlers take precedence over configured routes
+        if let Some(custom) = self.find_custom_handler(&request.path) {
+            return custom.handle(request);
+        }
+
+        // The health endpoint and status page answer before any route
+        if let Some(response) = self.health_response(request, server) {
+            return response;
+        }
+        if let Some(response) = self.status_response(request, server) {
+            return response;
+        }
+        if let Some(response) = self.session_admin_response(request, server) {
+            return response;
+        }
+
+        // A configured favicon or robots.txt answers before any route
+        if let Some(response) = self.well_known_response(request, server) {
+            return response;
+        }
+
+        // Get or create session
+        let (session_id, cookie_max_age) = self.session(request, server);
         // Find matching route
         let route = match server.find_route(&request.path) {
             Some(r) => r,
-            None => return self.error_response(server, 404),
+            None => return self.error_response(request, server, 404),
         };
         
 
+        // A CORS preflight carries no credentials and touches no resource, so
+        // it is answered before the method and auth checks, and never reaches
+        // CGI or the filesystem
+        if Cors::is_preflight(request)
+            && (route.cors_preflight || route.is_method_allowed(&Method::Options))
+        {
+            return Cors::preflight(request, route);
+        }
+
         // Check if method is allowed
         if !route.is_method_allowed(&request.method) {
-            return self.error_response(server, 405);
+            return self.error_response(request, server, 405);
         }
 
+        // Check Basic authentication
+        let remote_user = match &route.auth_basic {
+            Some(realm) => match BasicAuth::authenticate(request, route) {
+                Some(user) => Some(user),
+                None => return BasicAuth::challenge(realm),
+            },
+            None => None,
+        };
+
         // Handle redirects
         if let Some((location, permanent)) = &route.redirect {
             return Redirect::to(location, *permanent);
         }
 
+        // Let the client preload assets while the response is built
+        if let Some(hints) = Self::early_hints(request, route) {
+            interim(&hints);
+        }
+
         // Resolve file path (use server root if route has no root)
         let file_path = match route.resolve_path_with_root(&request.path, &server.root) {
             Some(p) => p,
-            None => return self.error_response(server, 404),
+            None => return self.error_response(request, server, 404),
         };
         
 
-        let path = Path::new(&file_path);
+        // Without symlink following, the resolved path must stay inside the
+        // route's root (a missing file still gets its 404 below)
+        if !route.follow_symlinks && !StaticFiles::is_within(route.root_dir(&server.root), &file_path) {
+            return self.error_response(request, server, 403);
+        }
 
-        // Check for CGI
-        if let Some(cgi_handler) = route.get_cgi_handler(&file_path) {
-            return self.handle_cgi(request, &file_path, cgi_handler, server);
+        // Check for CGI. Everything that can reject the request (method, auth,
+        // redirect) runs before this point, so a rejected request never spawns
+        // a process; a CGI script also takes precedence over uploads
+        if let Some(cgi_handler) = route.cgi_handler_for(&file_path) {
+            if !route.cgi_allows_method(&file_path, &request.method) {
+                return self.error_response(request, server, 405);
+            }
+            let response = self.handle_cgi(request, &file_path, cgi_handler, remote_user.as_deref(), route, server);
+            let mut response = self.check_acceptable(request, response, route, server);
+            Cors::apply(request, route, &mut response);
+            return response;
         }
 
         // Handle based on method
         let mut response = match request.method {
             Method::Get | Method::Head => {
-                self.handle_get(request, &file_path, route.autoindex, route.index.as_deref(), server)
+                self.handle_get(request, &file_path, route, server)
             }
             Method::Post => {
-                self.handle_post(request, &file_path, route.upload_dir.as_deref(), server)
+                self.handle_post(request, route, server)
             }
             Method::Delete => {
-                self.handle_delete(&file_path, server)
+                self.handle_delete(request, &file_path, server)
             }
-            _ => self.error_response(server, 405),
+            _ => self.error_response(request, server, 405),
         };
+        response = self.check_acceptable(request, response, route, server);
+        Cors::apply(request, route, &mut response);
         
-        // Set session cookie if new session
-        if is_new_session {
-            response.headers.add("Set-Cookie", 
-                &format!("session_id={}; Path=/; HttpOnly; SameSite=Strict", session_id));
+        // Set session cookie if new session, or renew it before it expires
+        if let Some(max_age) = cookie_max_age {
+            let value = match &server.session_secret {
+                Some(secret) => Cookie::sign(&session_id, secret),
+                None => session_id,
+            };
+            response = response.cookie(
+                Cookie::new("session_id", &value)
+                    .max_age(max_age.as_secs())
+                    .same_site(SameSite::Strict),
+            );
         }
         
         response
     }
 
+    /// Finds the request's session or starts one; returns its ID and, when
+    /// the cookie must be sent (a new session, or one past the renewal
+    /// threshold of its cookie's lifetime), the cookie's Max-Age
+    fn session(&self, request: &Request, server: &ServerConfig) -> (String, Option<Duration>) {
+        // With a secret, a cookie that fails verification counts as no session
+        let session_cookie = request.cookie("session_id").and_then(|value| match &server.session_secret {
+            Some(secret) => Cookie::verify(&value, secret),
+            None => Some(value),
+        });
+
+        let mut sessions = self.sessions.lock().unwrap();
+        let existing = session_cookie.as_deref().and_then(|id| sessions.get(id)).map(|session| {
+            let due = server
+                .session_renew_threshold
+                .is_some_and(|percent| session.cookie_due(percent));
+            (session.id.clone(), session.expires_in, due)
+        });
+
+        match existing {
+            Some((id, _, false)) => (id, None),
+            Some((id, max_age, true)) => {
+                if let Some(session) = sessions.get_mut(&id) {
+                    session.cookie_issued_at = Instant::now();
+                }
+                sessions.save(&id);
+                (id, Some(max_age))
+            }
+            None => {
+                let id = sessions.create();
+                let max_age = sessions.get(&id).map(|s| s.expires_in).unwrap_or_default();
+                (id, Some(max_age))
+            }
+        }
+    }
+
+    /// Replaces a successful GET/HEAD response with 406 Not Acceptable when
+    /// the route negotiates strictly and Accept rules out its Content-Type
+    fn check_acceptable(&self, request: &Request, response: Response, route: &Route, server: &ServerConfig) -> Response {
+        if !route.strict_accept
+            || (request.method != Method::Get && request.method != Method::Head)
+            || !(200..300).contains(&response.status_code())
+        {
+            return response;
+        }
+
+        match response.headers.get("content-type") {
+            Some(content_type) if !accept::accepts(&request.headers, content_type) => {
+                self.error_response(request, server, 406)
+            }
+            _ => response,
+        }
+    }
+
+    /// Serves the configured favicon and robots.txt; None leaves the
+    /// request to normal routing
+    fn well_known_response(&self, request: &Request, server: &ServerConfig) -> Option<Response> {
+        if request.method != Method::Get && request.method != Method::Head {
+            return None;
+        }
+
+        let response = match request.path.as_str() {
+            "/favicon.ico" => {
+                let file = server.favicon.as_ref()?;
+                StaticFiles::serve(file).unwrap_or_else(|e| self.file_error_response(request, server, e))
+            }
+            "/robots.txt" => match (&server.robots_text, &server.robots) {
+                (Some(text), _) => Response::ok().text(text),
+                (None, Some(file)) => StaticFiles::serve(file)
+                    .map(|r| r.content_type("text/plain; charset=utf-8"))
+                    .unwrap_or_else(|e| self.file_error_response(request, server, e)),
+                (None, None) => return None,
+            },
+            _ => return None,
+        };
+        Some(response)
+    }
+
+    /// Answers the health endpoint: 200 when every check passes, otherwise
+    /// 503 listing the failures; None leaves the request to normal routing
+    fn health_response(&self, request: &Request, server: &ServerConfig) -> Option<Response> {
+        if server.health_check_uri.as_deref() != Some(request.path.as_str())
+            || (request.method != Method::Get && request.method != Method::Head)
+        {
+            return None;
+        }
+
+        let problems = Config::health_problems(server);
+        let mut response = if problems.is_empty() {
+            Response::ok().json("{\"status\":\"ok\"}")
+        } else {
+            log_warn!("Health check failed: {}", problems.join("; "));
+            let reasons: Vec<String> = problems.iter().map(|p| json_string(p)).collect();
+            Response::new(StatusCode::ServiceUnavailable)
+                .json(&format!("{{\"status\":\"unavailable\",\"problems\":[{}]}}", reasons.join(",")))
+        };
+        response.headers.set("Cache-Control", "no-store");
+        Some(response)
+    }
+
+    /// Answers the status page with the connection and cache counters;
+    /// None leaves the request to normal routing
+    fn status_response(&self, request: &Request, server: &ServerConfig) -> Option<Response> {
+        if server.status_page.as_deref() != Some(request.path.as_str())
+            || (request.method != Method::Get && request.method != Method::Head)
+        {
+            return None;
+        }
+
+        let stats = self.connection_stats.get();
+        let mut text = format!(
+            "Active connections: {}\nAccepted connections: {}\nClosed connections: {}\nRequests: {}\nRequests per connection: {:.2}\n",
+            self.active_connections.get(),
+            stats.accepted,
+            stats.closed(),
+            stats.requests,
+            stats.requests_per_accepted(),
+        );
+        text.push_str("Closed connections by requests served:\n");
+        for (label, count) in BUCKET_LABELS.iter().zip(stats.requests_per_connection) {
+            text.push_str(&format!("  {}: {}\n", label, count));
+        }
+        let cache = self.file_cache.borrow();
+        if cache.is_enabled() {
+            text.push_str(&format!("File cache: {} hits, {} misses\n", cache.hits(), cache.misses()));
+        }
+
+        let mut response = Response::ok().text(&text);
+        response.headers.set("Cache-Control", "no-store");
+        Some(response)
+    }
+
+    /// Answers the session admin endpoint: GET lists session IDs, DELETE
+    /// destroys all sessions, those whose `key` data equals `value`, or the
+    /// one named by the path suffix
+    fn session_admin_response(&self, request: &Request, server: &ServerConfig) -> Option<Response> {
+        let base = server.session_admin.as_deref()?;
+        let target = match request.path.strip_prefix(base)? {
+            "" | "/" => None,
+            rest => Some(rest.strip_prefix('/')?),
+        };
+
+        let allowed = request.remote_addr.is_some_and(|addr| {
+            if server.session_admin_allow.is_empty() {
+                addr.ip().to_canonical().is_loopback()
+            } else {
+                server.session_admin_allow.iter().any(|range| range.contains(addr.ip()))
+            }
+        });
+        if !allowed {
+            return Some(self.error_response(request, server, 403));
+        }
+
+        let mut sessions = self.sessions.lock().unwrap();
+        let mut response = match (&request.method, target) {
+            (Method::Get | Method::Head, None) => {
+                let mut ids: Vec<String> = sessions.iter_ids().collect();
+                ids.sort();
+                let mut text = format!("Sessions: {}\n", ids.len());
+                for id in ids {
+                    text.push_str(&id);
+                    text.push('\n');
+                }
+                Response::ok().text(&text)
+            }
+            (Method::Delete, None) => {
+                let destroyed = match (request.query_param("key"), request.query_param("value")) {
+                    (Some(key), Some(value)) => {
+                        sessions.destroy_matching(&|session| session.get(key).is_some_and(|v| v == value))
+                    }
+                    (None, None) => sessions.destroy_all(),
+                    _ => return Some(self.error_response(request, server, 400)),
+                };
+                Response::ok().text(&format!("Destroyed {} sessions\n", destroyed))
+            }
+            (Method::Delete, Some(id)) => {
+                let destroyed = sessions.destroy_matching(&|session| session.id == id);
+                if destroyed == 0 {
+                    return Some(self.error_response(request, server, 404));
+                }
+                Response::new(StatusCode::NoContent)
+            }
+            (_, None) => {
+                let mut response = self.error_response(request, server, 405);
+                response.headers.set("Allow", "GET, HEAD, DELETE");
+                response
+            }
+            (_, Some(_)) => {
+                let mut response = self.error_response(request, server, 405);
+                response.headers.set("Allow", "DELETE");
+                response
+            }
+        };
+        response.headers.set("Cache-Control", "no-store");
+        Some(response)
+    }
+
+    /// Renders the landing page, from `landing_page_template` when set
+    fn landing_page(&self, request: &Request, server: &ServerConfig) -> Response {
+        let template = match &server.landing_page_template {
+            Some(path) => match fs::read_to_string(path) {
+                Ok(template) => Some(template),
+                Err(e) => {
+                    let detail = format!("Cannot read landing_page_template {}: {}", path, e);
+                    log_warn!("{}", detail);
+                    return self.error_page(request, server, 500, Some(&detail));
+                }
+            },
+            None => None,
+        };
+        Response::ok().html(&LandingPage::render(server, template.as_deref()))
+    }
+
+    /// Lists every method accepted by at least one of the server's routes
+    fn server_methods(server: &ServerConfig) -> Vec<&'static str> {
+        let allowed = |method: Method| server.routes.iter().any(|r| r.is_method_allowed(&method));
+        let candidates = [Method::Get, Method::Head, Method::Post, Method::Put, Method::Delete];
+        let mut methods: Vec<&'static str> = candidates
+            .into_iter()
+            .filter(|m| allowed(*m) || (*m == Method::Head && allowed(Method::Get)))
+            .map(|m| m.as_str())
+            .collect();
+        methods.push(Method::Options.as_str());
+        methods
+    }
+
     /// Handles GET requests
     fn handle_get(
         &self,
         request: &Request,
         file_path: &str,
-        autoindex: bool,
-        index: Option<&str>,
+        route: &Route,
         server: &ServerConfig,
     ) -> Response {
+        // Clean URLs: a missing extensionless path falls back to its .html file
+        let clean_path = Self::clean_url_path(request, file_path, route);
+        let file_path = clean_path.as_deref().unwrap_or(file_path);
         let path = Path::new(file_path);
 
+        // Refuse anything reached through a symlink unless links are followed
+        let root = route.root_dir(&server.root);
+        if !route.follow_symlinks && StaticFiles::contains_symlink(root, file_path) {
+            return self.error_response(request, server, 403);
+        }
+
         // If it's a directory
         if path.is_dir() {
             // Try index file first
-            if let Some(index_file) = index {
+            if let Some(index_file) = route.index.as_deref() {
                 let index_path = path.join(index_file);
-                if index_path.exists() && index_path.is_file() {
-                    return match StaticFiles::serve(index_path.to_str().unwrap_or(file_path)) {
+                let linked = !route.follow_symlinks
+                    && StaticFiles::contains_symlink(root, index_path.to_str().unwrap_or(file_path));
+                if index_path.exists() && index_path.is_file() && !linked {
+                    return match self.serve_file(index_path.to_str().unwrap_or(file_path), server) {
                         Ok(response) => response,
-                        Err(_) => self.error_response(server, 500),
+                        Err(e) => self.file_error_response(request, server, e),
                     };
                 }
             }
 
             // Directory listing if enabled
-            if autoindex {
-                return DirectoryListing::generate(file_path, &request.path);
+            if route.autoindex {
+                return match DirectoryListing::generate(
+                    file_path,
+                    &request.path,
+                    route.follow_symlinks,
+                    request.headers.get("if-modified-since"),
+                ) {
+                    Ok(response) => response,
+                    Err(e) => self.file_error_response(request, server, e),
+                };
+            }
+
+            // At the site root, a generated landing page can stand in
+            if server.landing_page && request.path == "/" {
+                return self.landing_page(request, server);
             }
 
-            return self.error_response(server, 403);
+            // No index and listing disabled
+            return self.error_response(request, server, route.autoindex_missing);
         }
 
         // Serve static file
-        match StaticFiles::serve(file_path) {
+        match self.serve_file(file_path, server) {
             Ok(mut response) => {
-                // For HEAD requests, remove body but keep headers
-                if request.method == Method::Head {
-                    response.body.clear();
+                // A 204 carries neither a body nor Content-Length/Content-Type
+                let empty = response.stream.is_none() && response.body.is_empty();
+                if empty && route.empty_as_no_content {
+                    response.status = StatusCode::NoContent;
+                    response.headers.remove("Content-Length");
+                    response.headers.remove("Content-Type");
+                }
+                if let Some(disposition) = route.disposition_for(file_path) {
+                    let filename = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+                    response.headers.set("Content-Disposition", &disposition.header_value(filename));
                 }
                 response
             }
-            Err(crate::error::ServerError::NotFound) => self.error_response(server, 404),
-            Err(crate::error::ServerError::Forbidden) => self.error_response(server, 403),
-            Err(_) => self.error_response(server, 500),
+            Err(e) => self.file_error_response(request, server, e),
         }
     }
 
-    /// Handles POST requests (file uploads)
-    fn handle_post(
-        &self,
+    /// Finds the `.html` file a clean URL stands for; None unless clean URLs
+    /// are on and the path has no extension, no trailing slash and doesn't
+    /// exist, so real files and directories (with their index) always win
+    fn clean_url_path(request: &Request, file_path: &str, route: &Route) -> Option<String> {
+        let last_segment = request.path.rsplit('/').next().unwrap_or("");
+        if !route.clean_urls || last_segment.is_empty() || last_segment.contains('.') || Path::new(file_path).exists() {
+            return None;
+        }
+
+        let candidate = format!("{}.html", file_path);
+        Path::new(&candidate).is_file().then_some(candidate)
+    }
+
+    /// Serves a static file, through the file cache when the server enables it
+    fn serve_file(&self, file_path: &str, server: &ServerConfig) -> Result<Response> {
+        if server.open_file_cache_max > 0 {
+            StaticFiles::serve_cached(file_path, &mut self.file_cache.borrow_mut())
+        } else {
+            StaticFiles::serve(file_path)
+        }
+    }
+
+    /// Handles POST requests (file uploads)
+    fn handle_post(
+        &self,
         request: &Request,
-        file_path: &str,
-        upload_dir: Option<&str>,
+        route: &Route,
         server: &ServerConfig,
     ) -> Response {
         // Check for upload directory
-        let upload_path = match upload_dir {
+        let upload_path = match route.upload_dir.as_deref() {
             Some(dir) => dir,
-            None => return self.error_response(server, 403),
+            None => return self.error_response(request, server, 403),
         };
+        let tmp_path = route.upload_tmp_dir.as_deref().unwrap_or(upload_path);
 
-        // Create upload directory if it doesn't exist
-        if let Err(_) = fs::create_dir_all(upload_path) {
-            return self.error_response(server, 500);
+        // Create upload directories if they don't exist
+        if fs::create_dir_all(upload_path).is_err() || fs::create_dir_all(tmp_path).is_err() {
+            return self.error_response(request, server, 500);
         }
 
         // Parse multipart form data or save raw body
         let content_type = request.content_type().unwrap_or("");
         
         if content_type.starts_with("multipart/form-data") {
-            self.handle_multipart_upload(request, upload_path, server)
+            self.handle_multipart_upload(request, route, upload_path, tmp_path, server)
         } else {
             // Save raw body as file
-            let filename = format!("upload_{}", std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .map(|d| d.as_secs())
-                .unwrap_or(0));
-            
-            let target_path = Path::new(upload_path).join(&filename);
-            
-            match fs::write(&target_path, &request.body) {
-                Ok(_) => Response::ok()
+            let copy_body = |file: &mut fs::File| {
+                io::copy(&mut request.body_reader()?, file)?;
+                Ok(())
+            };
+            let filename = format!("upload_{}", Self::unique_suffix());
+            match Self::store_upload(tmp_path, upload_path, &filename, copy_body) {
+                Ok(filename) => Response::ok()
                     .json(&format!("{{\"status\":\"ok\",\"file\":\"{}\"}}", filename)),
-                Err(_) => self.error_response(server, 500),
+                Err(_) => self.error_response(request, server, 500),
             }
         }
     }
 
     /// Handles multipart form data uploads
+    /// Parts are streamed to disk one buffer at a time; a file larger than
+    /// `upload_max_file_size`, or parts adding up to more than the body
+    /// limit, get 413 and nothing of that file is kept
     fn handle_multipart_upload(
         &self,
         request: &Request,
+        route: &Route,
         upload_path: &str,
+        tmp_path: &str,
         server: &ServerConfig,
     ) -> Response {
         let content_type = request.content_type().unwrap_or("");
-        
-        // Extract boundary
-        let boundary = content_type
-            .split("boundary=")
-            .nth(1)
-            .map(|b| b.trim_matches('"'));
 
-        let boundary = match boundary {
-            Some(b) => format!("--{}", b),
-            None => return self.error_response(server, 400),
+        let boundary = match MultipartReader::<&[u8]>::boundary(content_type) {
+            Some(b) => b,
+            None => return self.error_response(request, server, 400),
         };
 
-        // Parse multipart data (simplified)
-        let body = match std::str::from_utf8(&request.body) {
-            Ok(s) => s,
-            Err(_) => return self.error_response(server, 400),
+        let part_limit = route.upload_max_file_size.map_or(u64::MAX, |n| n as u64);
+        let total_limit = server.max_body_size_for(&request.path) as u64;
+        let body = match request.body_reader() {
+            Ok(body) => body,
+            Err(_) => return self.error_response(request, server, 500),
         };
+        let mut reader = MultipartReader::new(body, boundary, multipart::DEFAULT_BUFFER_SIZE)
+            .with_total_limit(total_limit);
 
         let mut uploaded_files = Vec::new();
+        let mut stored = Vec::new();
 
-        for part in body.split(&boundary) {
-            if part.trim().is_empty() || part.trim() == "--" {
-                continue;
-            }
-
-            // Find filename in Content-Disposition header
-            if let Some(filename) = Self::extract_filename(part) {
-                // Find content (after double newline)
-                if let Some(content_start) = part.find("\r\n\r\n") {
-                    let content = &part[content_start + 4..];
-                    let content = content.trim_end_matches("\r\n");
-                    
-                    let target_path = Path::new(upload_path).join(&filename);
-                    
-                    if fs::write(&target_path, content.as_bytes()).is_ok() {
-                        uploaded_files.push(filename);
-                    }
+        let failure = loop {
+            let part = match reader.next_part() {
+                Ok(Some(part)) => part,
+                Ok(None) => break None,
+                Err(e) => break Some(e),
+            };
+
+            // Only file parts are stored; other form fields are skipped
+            let filename = match part.filename.as_deref().and_then(Self::sanitize_filename) {
+                Some(name) => name,
+                None => continue,
+            };
+
+            match Self::store_upload(tmp_path, upload_path, &filename, |file| {
+                reader.copy_part(file, part_limit).map(|_| ())
+            }) {
+                Ok(filename) => {
+                    stored.push(Path::new(upload_path).join(&filename));
+                    uploaded_files.push(filename);
                 }
+                Err(e) => break Some(e),
+            }
+        };
+
+        // The upload succeeds or fails as a whole: a part that fails takes
+        // the files stored from earlier parts with it
+        if let Some(e) = failure {
+            for path in &stored {
+                let _ = fs::remove_file(path);
             }
+            return self.upload_error_response(request, server, &e);
         }
 
         if uploaded_files.is_empty() {
-            self.error_response(server, 400)
+            self.error_response(request, server, 400)
         } else {
             Response::ok()
                 .json(&format!("{{\"status\":\"ok\",\"files\":{:?}}}", uploaded_files))
         }
     }
 
-    /// Extracts filename from multipart part
-    fn extract_filename(part: &str) -> Option<String> {
-        for line in part.lines() {
-            if line.to_lowercase().contains("content-disposition") {
-                if let Some(start) = line.find("filename=\"") {
-                    let rest = &line[start + 10..];
-                    if let Some(end) = rest.find('"') {
-                        return Some(rest[..end].to_string());
-                    }
-                }
-            }
+    /// Maps a failed upload to its error page: 413 for size limits, 400 for
+    /// malformed bodies, 500 for storage errors
+    fn upload_error_response(&self, request: &Request, server: &ServerConfig, error: &ServerError) -> Response {
+        let status = match error {
+            ServerError::BodyTooLarge => 413,
+            ServerError::BadRequest(_) => 400,
+            _ => 500,
+        };
+        self.error_response(request, server, status)
+    }
+
+    /// Writes an upload to a temp file via `write`, syncs it, then links it
+    /// into `upload_dir` under a free name based on `filename`, which it
+    /// returns. The target never holds a partial file, and the temp file is
+    /// removed on every way out
+    fn store_upload<F>(tmp_dir: &str, upload_dir: &str, filename: &str, write: F) -> Result<String>
+    where
+        F: FnOnce(&mut fs::File) -> Result<()>,
+    {
+        let path = Path::new(tmp_dir).join(format!(".upload-{}.tmp", Self::unique_suffix()));
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        let tmp_file = TempUpload { path };
+
+        write(&mut file)?;
+        file.sync_all()?;
+        drop(file);
+        tmp_file.persist(Path::new(upload_dir), filename)
+    }
+
+    /// Generates a name suffix unique across concurrent uploads:
+    /// the time in nanoseconds plus a process-wide sequence number
+    fn unique_suffix() -> String {
+        static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0);
+        let seq = SEQUENCE.fetch_add(1, Ordering::Relaxed);
+        format!("{}_{}", nanos, seq)
+    }
+
+    /// Reduces a client-supplied filename to its base name so it can't escape
+    /// the upload directory; rejects names that are empty, "." or ".."
+    fn sanitize_filename(name: &str) -> Option<String> {
+        let base = name.rsplit(['/', '\\']).next().unwrap_or("");
+        let base: String = base.chars().filter(|c| !c.is_control()).collect();
+        let base = base.trim();
+
+        match base {
+            "" | "." | ".." => None,
+            _ => Some(base.to_string()),
         }
-        None
     }
 
     /// Handles DELETE requests
-    fn handle_delete(&self, file_path: &str, server: &ServerConfig) -> Response {
+    fn handle_delete(&self, request: &Request, file_path: &str, server: &ServerConfig) -> Response {
         let path = Path::new(file_path);
 
         if !path.exists() {
-            return self.error_response(server, 404);
+            return self.error_response(request, server, 404);
         }
 
         // Security: don't allow deleting directories
         if path.is_dir() {
-            return self.error_response(server, 403);
+            return self.error_response(request, server, 403);
         }
 
         match fs::remove_file(path) {
             Ok(_) => Response::ok()
                 .json("{\"status\":\"ok\",\"message\":\"File deleted\"}"),
-            Err(_) => self.error_response(server, 500),
+            Err(_) => self.error_response(request, server, 500),
         }
     }
 
@@ -275,24 +1323,114 @@ impl Handler {
         request: &Request,
         script_path: &str,
         interpreter: &str,
+        remote_user: Option<&str>,
+        route: &Route,
         server: &ServerConfig,
     ) -> Response {
-        match CgiExecutor::execute(request, script_path, interpreter) {
-            Ok(response) => response,
-            Err(_) => self.error_response(server, 500),
+        let execute = if route.cgi_buffering { CgiExecutor::execute } else { CgiExecutor::execute_streaming };
+        let result = execute(
+            request,
+            script_path,
+            interpreter,
+            remote_user,
+            route.cgi_pass_authorization,
+            route.cgi_max_output,
+            route.cgi_working_dir.as_deref(),
+        );
+
+        match result {
+            Ok(mut response) => {
+                // A script honoring REQUEST_METHOD=HEAD sends no body, so the
+                // length of the GET body is unknown
+                if request.method == Method::Head && response.body.is_empty() {
+                    response.headers.remove("Content-Length");
+                }
+                self.compress(request, response, server)
+            }
+            Err(ServerError::CgiOutputTooLarge) => self.error_response(request, server, 502),
+            Err(ServerError::NotFound) => self.error_response(request, server, 404),
+            Err(ServerError::Forbidden) => self.error_response(request, server, 403),
+            Err(e) => self.error_page(request, server, 500, Some(&e.to_string())),
+        }
+    }
+
+    /// Compresses a buffered response with Brotli or gzip when enabled, the
+    /// client accepts it, and the body is large enough and of a compressible
+    /// type; the client's preferred coding wins, then Brotli. A client that
+    /// refuses identity and every enabled coding gets 406
+    fn compress(&self, request: &Request, mut response: Response, server: &ServerConfig) -> Response {
+        let content_type = response.headers.get("content-type").unwrap_or("");
+        let encodings: Vec<&str> = [(server.brotli, "br"), (server.gzip, "gzip")]
+            .into_iter()
+            .filter_map(|(enabled, coding)| enabled.then_some(coding))
+            .collect();
+        let eligible = !encodings.is_empty()
+            && response.stream.is_none()
+            && response.body.len() >= server.gzip_min_length
+            && !response.headers.contains("content-encoding")
+            && compression::is_compressible(content_type, &server.gzip_types);
+
+        // The representation depends on Accept-Encoding either way
+        if eligible {
+            response.add_vary("Accept-Encoding");
+        }
+
+        let supported: &[&str] = if eligible { &encodings } else { &[] };
+        let (coding, compressed) = match compression::choose_encoding(request, supported) {
+            EncodingChoice::Coding("br") => ("br", compression::brotli(&response.body, server.brotli_comp_level)),
+            EncodingChoice::Coding(_) => ("gzip", compression::gzip(&response.body)),
+            EncodingChoice::Identity => return response,
+            EncodingChoice::NotAcceptable => {
+                // Only a successful body the client can't take is refused;
+                // errors and script-encoded bodies go out as they are
+                let refuse = (200..300).contains(&response.status_code())
+                    && !response.body.is_empty()
+                    && !response.headers.contains("content-encoding");
+                return if refuse { self.error_response(request, server, 406) } else { response };
+            }
+        };
+        response.headers.set("Content-Encoding", coding);
+        response.body(compressed)
+    }
+
+    /// Maps a filesystem error to an error response: missing files are 404,
+    /// permission problems 403, and anything else a genuine 500
+    fn file_error_response(&self, request: &Request, server: &ServerConfig, error: ServerError) -> Response {
+        match error {
+            ServerError::NotFound => self.error_response(request, server, 404),
+            ServerError::Forbidden => self.error_response(request, server, 403),
+            ServerError::Io(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                self.error_response(request, server, 404)
+            }
+            ServerError::Io(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                self.error_response(request, server, 403)
+            }
+            _ => self.error_response(request, server, 500),
         }
     }
 
     /// Generates an error response
-    fn error_response(&self, server: &ServerConfig, status_code: u16) -> Response {
-        use crate::http::StatusCode;
-        
-        // Try custom error page
-        if let Some(error_page) = server.get_error_page(status_code) {
+    fn error_response(&self, request: &Request, server: &ServerConfig, status_code: u16) -> Response {
+        self.error_page(request, server, status_code, None)
+    }
+
+    /// Generates an error response, with request context and the internal
+    /// error detail shown only when `debug_errors` is on
+    fn error_page(
+        &self,
+        request: &Request,
+        server: &ServerConfig,
+        status_code: u16,
+        detail: Option<&str>,
+    ) -> Response {
+        // Try custom error page (debug mode always renders the built-in page
+        // so the context is visible)
+        if let Some(error_page) = server.get_error_page(status_code).filter(|_| !server.debug_errors) {
             let error_path = format!("{}/{}", server.root, error_page.trim_start_matches('/'));
             if let Ok(mut response) = StaticFiles::serve(&error_path) {
-                // Set correct status code for error page
-                response.status = StatusCode::from_code(status_code).unwrap_or(StatusCode::InternalServerError);
+                // Set correct status code for error page (or its configured override)
+                let code = server.error_page_status(status_code);
+                response.status = StatusCode::from_code(code).unwrap_or(StatusCode::InternalServerError);
                 return response;
             }
         }
@@ -300,22 +1438,1525 @@ impl Handler {
         // Default error pages
         let (response, message) = match status_code {
             400 => (Response::bad_request(), "Bad Request"),
+            401 => (Response::unauthorized(), "Unauthorized"),
             403 => (Response::forbidden(), "Forbidden"),
             404 => (Response::not_found(), "Not Found"),
             405 => (Response::method_not_allowed(), "Method Not Allowed"),
+            406 => (Response::new(StatusCode::NotAcceptable), "Not Acceptable"),
             413 => (Response::payload_too_large(), "Payload Too Large"),
+            502 => (Response::new(StatusCode::BadGateway), "Bad Gateway"),
+            503 => (Response::service_unavailable(), "Service Unavailable"),
             _ => (Response::internal_error(), "Internal Server Error"),
         };
 
-        response.html(&format!(
-            "<!DOCTYPE html>\n\
-            <html>\n\
-            <head><title>{} {}</title></head>\n\
-            <body>\n\
-            <h1>{} {}</h1>\n\
-            </body>\n\
-            </html>",
-            status_code, message, status_code, message
+        response.html(&pages::render(status_code, message, request, detail, server.debug_errors))
+    }
+}
+
+/// Quotes text as a JSON string
+fn json_string(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handler(config: &str) -> Handler {
+        Handler::new(Config::load_string(config).unwrap())
+    }
+
+    /// Dispatches a GET and returns the serialized response as text
+    fn get(handler: &Handler, target: &str) -> String {
+        let raw = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", target);
+        String::from_utf8_lossy(&handler.dispatch_bytes(raw.as_bytes())).into_owned()
+    }
+
+    const SITE: &str = "server {\n    listen 8080\n    root /nonexistent\n    location / {\n        methods GET\n    }\n}\n";
+
+    /// A fresh directory under the system temp dir, removed on drop
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("localhost-handler-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+
+        fn path(&self) -> String {
+            self.0.to_string_lossy().into_owned()
+        }
+
+        fn write(&self, rel: &str, content: &str) {
+            let path = self.0.join(rel);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+
+        /// Writes an executable script
+        #[cfg(unix)]
+        fn script(&self, rel: &str, content: &str) {
+            use std::os::unix::fs::PermissionsExt;
+            self.write(rel, content);
+            fs::set_permissions(self.0.join(rel), fs::Permissions::from_mode(0o755)).unwrap();
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Splits a serialized response into its status line, lowercased header
+    /// lines and body
+    fn split_response(response: &[u8]) -> (String, Vec<(String, String)>, Vec<u8>) {
+        let end = response.windows(4).position(|w| w == b"\r\n\r\n").expect("no end of headers");
+        let head = String::from_utf8(response[..end].to_vec()).unwrap();
+        let mut lines = head.split("\r\n");
+        let status = lines.next().unwrap().to_string();
+        let headers = lines
+            .map(|line| {
+                let (name, value) = line.split_once(": ").expect("malformed header line");
+                (name.to_lowercase(), value.to_string())
+            })
+            .collect();
+        (status, headers, response[end + 4..].to_vec())
+    }
+
+    fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+        headers.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+
+    #[test]
+    fn dispatch_bytes_serializes_a_full_response() {
+        let root = TempDir::new("dispatch");
+        root.write("index.html", "<h1>home</h1>");
+        let handler = handler(&format!(
+            "server {{\n    listen 8080\n    root {}\n    location / {{\n        methods GET\n        index index.html\n    }}\n}}\n",
+            root.path()
+        ));
+
+        let bytes = handler.dispatch_bytes(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let (status, headers, body) = split_response(&bytes);
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(body, b"<h1>home</h1>");
+        assert_eq!(header(&headers, "content-length"), Some("13"));
+        assert!(header(&headers, "content-type").unwrap().starts_with("text/html"));
+        assert!(header(&headers, "date").unwrap().ends_with(" GMT"));
+
+        // Garbage still gets a well-formed answer rather than nothing
+        let (status, headers, body) = split_response(&handler.dispatch_bytes(b"NOT HTTP\r\n\r\n"));
+        assert!(status.starts_with("HTTP/1.1 400"), "{}", status);
+        assert_eq!(header(&headers, "content-length"), Some(body.len().to_string().as_str()));
+    }
+
+    #[test]
+    fn file_cache_reads_once_until_the_file_changes() {
+        let root = TempDir::new("file-cache");
+        root.write("page.html", "first");
+        let handler = handler(&format!(
+            "server {{\n    listen 8080\n    root {}\n    open_file_cache max=10\n    location / {{\n        methods GET\n    }}\n}}\n",
+            root.path()
+        ));
+        let counts = || {
+            let cache = handler.file_cache.borrow();
+            (cache.hits(), cache.misses())
+        };
+
+        assert!(get(&handler, "/page.html").ends_with("\r\n\r\nfirst"));
+        assert!(get(&handler, "/page.html").ends_with("\r\n\r\nfirst"));
+        assert_eq!(counts(), (1, 1));
+
+        // Same length, so only the new mtime gives the change away
+        let path = root.0.join("page.html");
+        fs::write(&path, "again").unwrap();
+        let later = std::time::SystemTime::now() + Duration::from_secs(10);
+        fs::File::options().write(true).open(&path).unwrap().set_modified(later).unwrap();
+
+        assert!(get(&handler, "/page.html").ends_with("\r\n\r\nagain"));
+        assert!(get(&handler, "/page.html").ends_with("\r\n\r\nagain"));
+        assert_eq!(counts(), (2, 2));
+    }
+
+    #[test]
+    fn registered_closure_handles_its_path() {
+        let mut handler = handler(SITE);
+        handler.register("/ping", |_: &Request| Response::ok().text("pong"));
+
+        for target in ["/ping", "/ping/deeper"] {
+            let response = get(&handler, target);
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+            assert!(response.ends_with("\r\n\r\npong"), "{}", response);
+        }
+        assert!(get(&handler, "/pingpong").starts_with("HTTP/1.1 404"));
+    }
+
+    struct PoweredBy;
+
+    impl Middleware for PoweredBy {
+        fn after(&self, _request: &Request, mut response: Response) -> Response {
+            response.headers.set("X-Powered-By", "localhost");
+            response
+        }
+    }
+
+    /// Lets through only requests carrying the token
+    struct RequireToken;
+
+    impl Middleware for RequireToken {
+        fn before(&self, request: &Request) -> Option<Response> {
+            match request.headers.get("X-Token") {
+                Some("letmein") => None,
+                _ => Some(Response::unauthorized().text("no token")),
+            }
+        }
+    }
+
+    #[test]
+    fn middleware_adds_header_to_every_response() {
+        let mut handler = handler(SITE);
+        handler.register("/ping", |_: &Request| Response::ok().text("pong"));
+        handler.add_middleware(PoweredBy);
+
+        for (target, status) in [("/ping", "200"), ("/missing", "404")] {
+            let response = get(&handler, target);
+            assert!(response.starts_with(&format!("HTTP/1.1 {}", status)), "{}", response);
+            assert!(response.contains("\r\nx-powered-by: localhost\r\n"), "{}", response);
+        }
+    }
+
+    #[test]
+    fn middleware_short_circuits_unauthorized_requests() {
+        let mut handler = handler(SITE);
+        let called = std::rc::Rc::new(Cell::new(0));
+        let counter = called.clone();
+        handler.register("/ping", move |_: &Request| {
+            counter.set(counter.get() + 1);
+            Response::ok().text("pong")
+        });
+        handler.add_middleware(PoweredBy);
+        handler.add_middleware(RequireToken);
+
+        let response = get(&handler, "/ping");
+        assert!(response.starts_with("HTTP/1.1 401"), "{}", response);
+        assert!(response.ends_with("no token"));
+        // Middleware that ran before the short circuit still sees the response
+        assert!(response.contains("\r\nx-powered-by: localhost\r\n"));
+        assert_eq!(called.get(), 0);
+
+        let raw = b"GET /ping HTTP/1.1\r\nHost: localhost\r\nX-Token: letmein\r\n\r\n";
+        let response = String::from_utf8_lossy(&handler.dispatch_bytes(raw)).into_owned();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert_eq!(called.get(), 1);
+    }
+
+    fn session_admin_site(allow: &str) -> Handler {
+        handler(&format!(
+            "server {{\n    listen 8080\n    root /nonexistent\n    session_admin /admin/sessions\n    {}\n    location / {{\n        methods GET\n    }}\n}}\n",
+            allow
+        ))
+    }
+
+    #[test]
+    fn session_admin_refuses_other_addresses() {
+        // dispatch_bytes requests come from 127.0.0.1
+        let handler = session_admin_site("session_admin_allow 10.0.0.0/8");
+        handler.sessions.lock().unwrap().create();
+
+        assert!(get(&handler, "/admin/sessions").starts_with("HTTP/1.1 403"));
+        let response = handler.dispatch_bytes(b"DELETE /admin/sessions HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(response.starts_with(b"HTTP/1.1 403"));
+        assert_eq!(handler.sessions.lock().unwrap().count(), 1);
+    }
+
+    #[test]
+    fn session_admin_lists_and_destroys() {
+        let handler = session_admin_site("");
+        let ids: Vec<String> = (0..3).map(|_| handler.sessions.lock().unwrap().create()).collect();
+
+        let listing = get(&handler, "/admin/sessions");
+        assert!(listing.starts_with("HTTP/1.1 200"), "{}", listing);
+        assert!(listing.contains("Sessions: 3\n"));
+        assert!(ids.iter().all(|id| listing.contains(id.as_str())));
+
+        let response = handler.dispatch_bytes(b"DELETE /admin/sessions HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(String::from_utf8_lossy(&response).ends_with("Destroyed 3 sessions\n"));
+        assert_eq!(handler.sessions.lock().unwrap().count(), 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn cgi_gets_the_query_string_as_sent() {
+        let root = TempDir::new("query-string");
+        root.script("cgi-bin/query.sh", "#!/bin/sh\nprintf 'Content-Type: text/plain\\r\\n\\r\\n%s' \"$QUERY_STRING\"\n");
+        let handler = handler(&format!(
+            "server {{\n    listen 8080\n    root {}\n    location /cgi-bin {{\n        methods GET\n        cgi .sh *\n    }}\n}}\n",
+            root.path()
+        ));
+
+        // Order, repeats, escapes and empty values all reach the script untouched
+        let query = "z=1&a=2&z=3&name=J%C3%BCrgen+K&empty=&flag";
+        let response = get(&handler, &format!("/cgi-bin/query.sh?{}", query));
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with(&format!("\r\n\r\n{}", query)), "{}", response);
+
+        assert!(get(&handler, "/cgi-bin/query.sh").ends_with("\r\n\r\n"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn cgi_sees_the_authenticated_user() {
+        let root = TempDir::new("remote-user");
+        root.write("users", "alice:secret\nbob:hunter2\n");
+        root.script("cgi-bin/whoami.sh", "#!/bin/sh\nprintf 'Content-Type: text/plain\\r\\n\\r\\n%s %s' \"$REMOTE_USER\" \"$AUTH_TYPE\"\n");
+        let handler = handler(&format!(
+            "server {{\n    listen 8080\n    root {0}\n    location /cgi-bin {{\n        methods GET\n        cgi .sh *\n        \
+             auth_basic \"Staff\"\n        auth_basic_user_file {0}/users\n    }}\n}}\n",
+            root.path()
+        ));
+        let request = |credentials: &str| {
+            let raw = format!(
+                "GET /cgi-bin/whoami.sh HTTP/1.1\r\nHost: localhost\r\nAuthorization: Basic {}\r\n\r\n",
+                credentials
+            );
+            String::from_utf8_lossy(&handler.dispatch_bytes(raw.as_bytes())).into_owned()
+        };
+
+        // alice:secret
+        let response = request("YWxpY2U6c2VjcmV0");
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with("\r\n\r\nalice Basic"), "{}", response);
+
+        // alice:hunter2 is bob's password, not hers
+        assert!(request("YWxpY2U6aHVudGVyMg==").starts_with("HTTP/1.1 401"));
+        assert!(get(&handler, "/cgi-bin/whoami.sh").starts_with("HTTP/1.1 401"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn cors_preflight_on_a_get_post_route() {
+        let root = TempDir::new("cors");
+        root.script("api/data.sh", "#!/bin/sh\nprintf 'Content-Type: text/plain\\r\\n\\r\\n'\nseq 1 1000\n");
+        let handler = handler(&format!(
+            "server {{\n    listen 8080\n    root {}\n    gzip on\n    location /api {{\n        methods GET POST\n        cgi .sh *\n        \
+             cors_allow_origin https://app.example\n        cors_max_age 600\n        cors_preflight on\n    }}\n}}\n",
+            root.path()
+        ));
+
+        let preflight = b"OPTIONS /api/data.sh HTTP/1.1\r\nHost: localhost\r\nOrigin: https://app.example\r\n\
+            Access-Control-Request-Method: POST\r\nAccess-Control-Request-Headers: Content-Type\r\n\r\n";
+        let (status, headers, body) = split_response(&handler.dispatch_bytes(preflight));
+        assert_eq!(status, "HTTP/1.1 204 No Content");
+        assert!(body.is_empty());
+        assert_eq!(header(&headers, "access-control-allow-origin"), Some("https://app.example"));
+        assert_eq!(header(&headers, "access-control-allow-methods"), Some("GET, POST, HEAD"));
+        assert_eq!(header(&headers, "access-control-allow-headers"), Some("Content-Type"));
+        assert_eq!(header(&headers, "access-control-max-age"), Some("600"));
+
+        // A plain OPTIONS is still not allowed on the route
+        let (status, _, _) = split_response(&handler.dispatch_bytes(b"OPTIONS /api/data.sh HTTP/1.1\r\nHost: localhost\r\n\r\n"));
+        assert!(status.starts_with("HTTP/1.1 405"), "{}", status);
+
+        // The actual request adds Origin to the Vary line compression started
+        let request = b"GET /api/data.sh HTTP/1.1\r\nHost: localhost\r\nOrigin: https://app.example\r\nAccept-Encoding: gzip\r\n\r\n";
+        let (status, headers, _) = split_response(&handler.dispatch_bytes(request));
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        let vary: Vec<&str> = headers.iter().filter(|(n, _)| n == "vary").map(|(_, v)| v.as_str()).collect();
+        assert_eq!(vary, ["Accept-Encoding, Origin"]);
+    }
+
+    #[test]
+    fn upgrade_insecure_requests_redirects_to_https() {
+        let handler = handler(
+            "server {\n    listen 8080\n    listen 8443 ssl\n    server_name localhost\n    root /nonexistent\n    \
+             upgrade_insecure_requests on\n    location / {\n        methods GET\n    }\n}\n",
+        );
+        let request = |host: &str| {
+            let raw = format!(
+                "GET /a%20b/c?x=1&y=%0d HTTP/1.1\r\nHost: {}\r\nUpgrade-Insecure-Requests: 1\r\n\r\n",
+                host
+            );
+            split_response(&handler.dispatch_bytes(raw.as_bytes()))
+        };
+
+        let (status, headers, _) = request("localhost:8080");
+        assert_eq!(status, "HTTP/1.1 307 Temporary Redirect");
+        assert_eq!(header(&headers, "location"), Some("https://localhost:8443/a%20b/c?x=1&y=%0d"));
+        let vary: Vec<&str> = headers.iter().filter(|(n, _)| n == "vary").map(|(_, v)| v.as_str()).collect();
+        assert_eq!(vary, ["Upgrade-Insecure-Requests"]);
+
+        let (_, headers, _) = request("[::1]:8080");
+        assert_eq!(header(&headers, "location"), Some("https://[::1]:8443/a%20b/c?x=1&y=%0d"));
+
+        // Without the header the request is served as it came
+        assert!(get(&handler, "/").starts_with("HTTP/1.1 404"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn cgi_html_is_gzipped_for_accepting_clients() {
+        let root = TempDir::new("gzip");
+        root.script(
+            "cgi-bin/table.sh",
+            "#!/bin/sh\nprintf 'Content-Type: text/html\\r\\n\\r\\n<table>\\n'\n\
+             for i in $(seq 1 2000); do printf '<tr><td>%s</td><td>row %s</td></tr>\\n' $i $((i * 7)); done\nprintf '</table>\\n'\n",
+        );
+        let handler = handler(&format!(
+            "server {{\n    listen 8080\n    root {}\n    gzip on\n    location /cgi-bin {{\n        methods GET\n        cgi .sh *\n    }}\n}}\n",
+            root.path()
+        ));
+
+        let (status, headers, plain) = split_response(&handler.dispatch_bytes(
+            b"GET /cgi-bin/table.sh HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        ));
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(header(&headers, "content-encoding"), None);
+        assert!(plain.len() > 50_000);
+
+        let (status, headers, body) = split_response(&handler.dispatch_bytes(
+            b"GET /cgi-bin/table.sh HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: br;q=0, gzip\r\n\r\n",
+        ));
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(header(&headers, "content-encoding"), Some("gzip"));
+        assert_eq!(header(&headers, "content-length"), Some(body.len().to_string().as_str()));
+        assert!(body.len() < plain.len() / 4);
+
+        let mut decoded = Vec::new();
+        io::Read::read_to_end(&mut flate2::read::GzDecoder::new(&body[..]), &mut decoded).unwrap();
+        assert!(decoded == plain);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn disallowed_cgi_method_never_runs_the_script() {
+        let root = TempDir::new("cgi-methods");
+        let marker = root.0.join("ran");
+        root.script(
+            "cgi-bin/touch.sh",
+            &format!("#!/bin/sh\ntouch {}\nprintf 'Content-Type: text/plain\\r\\n\\r\\nran'\n", marker.display()),
+        );
+        let handler = handler(&format!(
+            "server {{\n    listen 8080\n    root {}\n    location /cgi-bin {{\n        methods GET POST DELETE\n        cgi .sh *\n        \
+             cgi_methods .sh GET\n    }}\n}}\n",
+            root.path()
+        ));
+
+        for method in ["POST", "DELETE"] {
+            let raw = format!("{} /cgi-bin/touch.sh HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\r\n", method);
+            let response = handler.dispatch_bytes(raw.as_bytes());
+            assert!(response.starts_with(b"HTTP/1.1 405"), "{}", String::from_utf8_lossy(&response));
+            assert!(!marker.exists(), "{} ran the script", method);
+        }
+
+        let response = get(&handler, "/cgi-bin/touch.sh");
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with("ran"));
+        assert!(marker.exists());
+    }
+
+    /// A multipart POST carrying one file part
+    fn upload(handler: &Handler, filename: &str, content: &str) -> String {
+        let body = format!(
+            "--XyZ\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\n\r\n{}\r\n--XyZ--\r\n",
+            filename, content
+        );
+        let raw = format!(
+            "POST /uploads HTTP/1.1\r\nHost: localhost\r\nContent-Type: multipart/form-data; boundary=XyZ\r\n\
+             Content-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        String::from_utf8_lossy(&handler.dispatch_bytes(raw.as_bytes())).into_owned()
+    }
+
+    fn upload_site(root: &TempDir) -> Handler {
+        handler(&format!(
+            "server {{\n    listen 8080\n    root {0}/www\n    location /uploads {{\n        methods POST\n        \
+             upload_dir {0}/www/uploads\n    }}\n}}\n",
+            root.path()
         ))
     }
+
+    #[test]
+    fn upload_names_cannot_escape_the_upload_dir() {
+        let root = TempDir::new("upload-escape");
+        let handler = upload_site(&root);
+
+        for name in ["../../evil", "..\\..\\evil", "/etc/evil"] {
+            let response = upload(&handler, name, "pwned");
+            assert!(response.starts_with("HTTP/1.1 200"), "{}: {}", name, response);
+        }
+        assert!(!root.0.join("evil").exists());
+        assert!(!root.0.join("www/evil").exists());
+        assert_eq!(fs::read_to_string(root.0.join("www/uploads/evil")).unwrap(), "pwned");
+
+        // Names that are nothing but traversal are refused
+        assert!(upload(&handler, "..", "x").starts_with("HTTP/1.1 400"));
+    }
+
+    #[test]
+    fn uploads_with_the_same_name_keep_both_files() {
+        let root = TempDir::new("upload-collide");
+        let handler = upload_site(&root);
+
+        assert!(upload(&handler, "report.txt", "first").contains("\"report.txt\""));
+        assert!(upload(&handler, "report.txt", "second").contains("\"report_1.txt\""));
+        assert!(upload(&handler, "report.txt", "third").contains("\"report_2.txt\""));
+
+        let uploads = root.0.join("www/uploads");
+        assert_eq!(fs::read_to_string(uploads.join("report.txt")).unwrap(), "first");
+        assert_eq!(fs::read_to_string(uploads.join("report_1.txt")).unwrap(), "second");
+        assert_eq!(fs::read_to_string(uploads.join("report_2.txt")).unwrap(), "third");
+        // No temp files are left behind
+        assert_eq!(fs::read_dir(&uploads).unwrap().count(), 3);
+    }
+
+    #[test]
+    fn persist_never_replaces_an_existing_file() {
+        let root = TempDir::new("persist");
+        root.write("taken.bin", "original");
+        root.write(".upload.tmp", "new");
+
+        let temp = TempUpload { path: root.0.join(".upload.tmp") };
+        assert_eq!(temp.persist(&root.0, "taken.bin").unwrap(), "taken_1.bin");
+        assert_eq!(fs::read_to_string(root.0.join("taken.bin")).unwrap(), "original");
+        assert_eq!(fs::read_to_string(root.0.join("taken_1.bin")).unwrap(), "new");
+        assert!(!root.0.join(".upload.tmp").exists());
+    }
+
+    #[test]
+    fn alias_replaces_the_location_prefix() {
+        let root = TempDir::new("alias");
+        root.write("site/index.html", "site");
+        root.write("site/static/app.css", "wrong tree");
+        root.write("assets/app.css", "body {}");
+        root.write("assets/css/print.css", "@media print {}");
+        let handler = handler(&format!(
+            "server {{\n    listen 8080\n    root {0}/site\n    location / {{\n        methods GET\n    }}\n    \
+             location /static {{\n        methods GET\n        alias {0}/assets/\n    }}\n}}\n",
+            root.path()
+        ));
+
+        assert!(get(&handler, "/static/app.css").ends_with("\r\n\r\nbody {}"));
+        assert!(get(&handler, "/static/css/print.css").ends_with("\r\n\r\n@media print {}"));
+        // Other locations still resolve against the server root
+        assert!(get(&handler, "/index.html").ends_with("\r\n\r\nsite"));
+    }
+
+    #[test]
+    fn too_many_headers_get_431() {
+        let handler = handler(&SITE.replace("root /nonexistent", "root /nonexistent\n    max_headers 3"));
+        let request = |count: usize| {
+            let headers: String = (1..count).map(|i| format!("X-H{}: v\r\n", i)).collect();
+            let raw = format!("GET /missing HTTP/1.1\r\nHost: localhost\r\n{}\r\n", headers);
+            String::from_utf8_lossy(&handler.dispatch_bytes(raw.as_bytes())).into_owned()
+        };
+
+        // Host counts toward the limit
+        assert!(request(3).starts_with("HTTP/1.1 404"));
+        let response = request(4);
+        assert!(response.starts_with("HTTP/1.1 431"), "{}", response);
+        assert!(response.contains("\r\nconnection: close\r\n"));
+    }
+
+    #[test]
+    fn access_log_lines_and_filters() {
+        let root = TempDir::new("access-log");
+        root.write("site/index.html", "home");
+        root.write("site/quiet/page.html", "quiet");
+        let site = |filters: &str| {
+            handler(&format!(
+                "server {{\n    listen 8080\n    root {0}/site\n    access_log {0}/access.log\n    \
+                 log_format \"$remote_addr $method $uri $status $body_bytes_sent\"\n    {1}\n    \
+                 location / {{\n        methods GET HEAD\n        index index.html\n    }}\n    \
+                 location /quiet {{\n        methods GET\n        access_log off\n    }}\n}}\n",
+                root.path(),
+                filters
+            ))
+        };
+        let log = || fs::read_to_string(root.0.join("access.log")).unwrap_or_default();
+
+        let handler = site("access_log_exclude_status 3xx 4xx");
+        get(&handler, "/");
+        get(&handler, "/missing");
+        get(&handler, "/quiet/page.html");
+        handler.dispatch_bytes(b"HEAD /index.html HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert_eq!(log(), "127.0.0.1 GET / 200 4\n127.0.0.1 HEAD /index.html 200 0\n");
+
+        let handler = site("access_log_exclude_ip 10.0.0.1 127.0.0.1");
+        get(&handler, "/");
+        assert_eq!(log().lines().count(), 2);
+    }
+
+    #[test]
+    fn single_file_root_answers_every_path() {
+        let root = TempDir::new("file-root");
+        root.write("down.html", "<h1>Back soon</h1>");
+        let handler = handler(&format!(
+            "server {{\n    listen 8080\n    root {}/down.html\n    location / {{\n        methods GET\n    }}\n}}\n",
+            root.path()
+        ));
+
+        for target in ["/", "/down.html", "/a/b/c.png", "/?x=1"] {
+            let response = get(&handler, target);
+            assert!(response.starts_with("HTTP/1.1 200"), "{}: {}", target, response);
+            assert!(response.contains("\r\ncontent-type: text/html"), "{}", response);
+            assert!(response.ends_with("\r\n\r\n<h1>Back soon</h1>"));
+        }
+    }
+
+    #[test]
+    fn maintenance_file_toggles_503() {
+        let root = TempDir::new("maintenance");
+        root.write("site/index.html", "home");
+        let site = |allow: &str| {
+            handler(&format!(
+                "server {{\n    listen 8080\n    root {0}/site\n    maintenance_file {0}/down\n    maintenance_retry_after 120\n    {1}\n    \
+                 location / {{\n        methods GET\n        index index.html\n    }}\n}}\n",
+                root.path(),
+                allow
+            ))
+        };
+        let handler = site("");
+
+        assert!(get(&handler, "/").starts_with("HTTP/1.1 200"));
+        root.write("down", "");
+        let (status, headers, _) = split_response(get(&handler, "/").as_bytes());
+        assert!(status.starts_with("HTTP/1.1 503"), "{}", status);
+        assert_eq!(header(&headers, "retry-after"), Some("120"));
+
+        // dispatch_bytes requests come from 127.0.0.1
+        assert!(get(&site("maintenance_allow 127.0.0.1"), "/").starts_with("HTTP/1.1 200"));
+
+        fs::remove_file(root.0.join("down")).unwrap();
+        assert!(get(&handler, "/").starts_with("HTTP/1.1 200"));
+    }
+
+    #[test]
+    fn location_body_limit_overrides_the_server() {
+        let mut handler = handler(
+            "server {\n    listen 8080\n    root /nonexistent\n    client_max_body_size 10\n    \
+             location / {\n        methods POST\n    }\n    \
+             location /upload {\n        methods POST\n        client_max_body_size 1k\n    }\n}\n",
+        );
+        for path in ["/form", "/upload"] {
+            handler.register(path, |request: &Request| Response::ok().text(&request.body_len().to_string()));
+        }
+        let post = |path: &str, size: usize| {
+            let raw = format!("POST {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}", path, size, "x".repeat(size));
+            String::from_utf8_lossy(&handler.dispatch_bytes(raw.as_bytes())).into_owned()
+        };
+
+        assert!(post("/form", 10).ends_with("\r\n\r\n10"));
+        assert!(post("/form", 11).starts_with("HTTP/1.1 413"));
+        assert!(post("/upload", 1024).ends_with("\r\n\r\n1024"));
+        assert!(post("/upload", 1025).starts_with("HTTP/1.1 413"));
+    }
+
+    #[test]
+    fn empty_directory_lists_and_disabled_listing_is_refused() {
+        let root = TempDir::new("listing");
+        fs::create_dir_all(root.0.join("files/empty")).unwrap();
+        fs::create_dir_all(root.0.join("closed/sub")).unwrap();
+        let handler = handler(&format!(
+            "server {{\n    listen 8080\n    root {}\n    location /files {{\n        methods GET\n        autoindex on\n    }}\n    \
+             location /closed {{\n        methods GET\n    }}\n}}\n",
+            root.path()
+        ));
+
+        // An empty directory is a listing with nothing but the parent link
+        let response = get(&handler, "/files/empty/");
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.contains("<h1>Index of /files/empty/</h1>"));
+        assert_eq!(response.matches("<a href=").count(), 1);
+        assert!(response.contains(">..</a>"));
+
+        assert!(get(&handler, "/closed/sub/").starts_with("HTTP/1.1 403"));
+
+        // A directory that can't be read is refused, not a server error
+        #[cfg(unix)]
+        if unsafe { libc::geteuid() } != 0 {
+            use std::os::unix::fs::PermissionsExt;
+            let locked = root.0.join("files/locked");
+            fs::create_dir(&locked).unwrap();
+            fs::set_permissions(&locked, fs::Permissions::from_mode(0o000)).unwrap();
+            let response = get(&handler, "/files/locked/");
+            fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+            assert!(response.starts_with("HTTP/1.1 403"), "{}", response);
+        }
+    }
+
+    #[test]
+    fn keep_alive_header_advertises_the_timeout() {
+        let site = |directives: &str| handler(&SITE.replace("root /nonexistent", &format!("root /nonexistent\n    timeout 15\n    {}", directives)));
+
+        let handler = site("keepalive_header on");
+        let (_, headers, _) = split_response(get(&handler, "/missing").as_bytes());
+        assert_eq!(header(&headers, "keep-alive"), Some("timeout=15"));
+
+        // Not on a response that ends the connection
+        let (_, headers, _) = split_response(&handler.dispatch_bytes(b"BAD\r\n\r\n"));
+        assert_eq!(header(&headers, "keep-alive"), None);
+        let (_, headers, _) = split_response(&handler.dispatch_bytes(b"GET /missing HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n"));
+        assert_eq!(header(&headers, "connection"), Some("close"));
+        assert_eq!(header(&headers, "keep-alive"), None);
+
+        let (_, headers, _) = split_response(get(&site(""), "/missing").as_bytes());
+        assert_eq!(header(&headers, "keep-alive"), None);
+    }
+
+    #[test]
+    fn options_asterisk_reports_server_methods() {
+        let handler = handler(
+            "server {\n    listen 8080\n    root /nonexistent\n    location / {\n        methods GET\n    }\n    \
+             location /api {\n        methods POST DELETE\n    }\n}\n",
+        );
+
+        let (status, headers, body) = split_response(&handler.dispatch_bytes(b"OPTIONS * HTTP/1.1\r\nHost: localhost\r\n\r\n"));
+        assert_eq!(status, "HTTP/1.1 204 No Content");
+        assert_eq!(header(&headers, "allow"), Some("GET, HEAD, POST, DELETE, OPTIONS"));
+        assert!(body.is_empty());
+
+        // The asterisk form means nothing to other methods
+        assert!(handler.dispatch_bytes(b"GET * HTTP/1.1\r\nHost: localhost\r\n\r\n").starts_with(b"HTTP/1.1 400"));
+    }
+
+    #[test]
+    fn http2_preface_gets_505() {
+        let handler = handler(SITE);
+        for raw in [&b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n"[..], b"GET / HTTP/3\r\nHost: localhost\r\n\r\n", b"GET / HTTP/1.2\r\nHost: localhost\r\n\r\n"] {
+            let (status, headers, _) = split_response(&handler.dispatch_bytes(raw));
+            assert_eq!(status, "HTTP/1.1 505 HTTP Version Not Supported", "{}", String::from_utf8_lossy(raw));
+            assert_eq!(header(&headers, "connection"), Some("close"));
+        }
+
+        // Not a version at all is plain malformed
+        assert!(handler.dispatch_bytes(b"GET / FTP/1.1\r\nHost: localhost\r\n\r\n").starts_with(b"HTTP/1.1 400"));
+        assert!(get(&handler, "/").starts_with("HTTP/1.1 404"));
+    }
+
+    #[test]
+    fn uploads_go_through_the_temp_dir() {
+        let root = TempDir::new("upload-tmp");
+        fs::create_dir_all(root.0.join("www/uploads")).unwrap();
+        fs::create_dir_all(root.0.join("spool")).unwrap();
+        let handler = handler(&format!(
+            "server {{\n    listen 8080\n    root {0}/www\n    location /uploads {{\n        methods POST\n        \
+             upload_dir {0}/www/uploads\n        upload_tmp_dir {0}/spool\n    }}\n}}\n",
+            root.path()
+        ));
+
+        assert!(upload(&handler, "notes.txt", "kept").starts_with("HTTP/1.1 200"));
+        assert_eq!(fs::read_to_string(root.0.join("www/uploads/notes.txt")).unwrap(), "kept");
+        assert_eq!(fs::read_dir(root.0.join("spool")).unwrap().count(), 0);
+
+        // Both directories are created on demand
+        fs::remove_dir_all(root.0.join("www/uploads")).unwrap();
+        fs::remove_dir_all(root.0.join("spool")).unwrap();
+        assert!(upload(&handler, "again.txt", "back").starts_with("HTTP/1.1 200"));
+        assert_eq!(fs::read_to_string(root.0.join("www/uploads/again.txt")).unwrap(), "back");
+        assert_eq!(fs::read_dir(root.0.join("spool")).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn debug_errors_show_escaped_request_context() {
+        let target = "/missing?q=<script>";
+        let response = get(&handler(&SITE.replace("root /nonexistent", "root /nonexistent\n    debug_errors on")), target);
+        assert!(response.starts_with("HTTP/1.1 404"));
+        assert!(response.contains("Method: GET\n"), "{}", response);
+        assert!(response.contains("Path: /missing\n"));
+        assert!(response.contains("Query: q=&lt;script&gt;\n"));
+        assert!(!response.contains("<script>"));
+
+        let response = get(&handler(SITE), target);
+        assert!(response.starts_with("HTTP/1.1 404"));
+        assert!(!response.contains("Method:") && !response.contains("script"), "{}", response);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn cgi_dir_runs_every_file_as_a_script() {
+        let root = TempDir::new("cgi-dir");
+        root.write("www/index.html", "home");
+        root.script("scripts/hello", "#!/bin/sh\nprintf 'Content-Type: text/plain\\r\\n\\r\\nhello from %s' \"$SCRIPT_NAME\"\n");
+        // Not executable: its extension's interpreter runs it
+        root.write("scripts/tool.sh", "printf 'Content-Type: text/plain\\r\\n\\r\\ninterpreted'\n");
+        let handler = handler(&format!(
+            "server {{\n    listen 8080\n    root {0}/www\n    location / {{\n        methods GET\n    }}\n    \
+             location /cgi-bin {{\n        methods GET\n        cgi_dir {0}/scripts\n        cgi .sh /bin/sh\n    }}\n}}\n",
+            root.path()
+        ));
+
+        let response = get(&handler, "/cgi-bin/hello");
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with("\r\n\r\nhello from /cgi-bin/hello"), "{}", response);
+        assert!(get(&handler, "/cgi-bin/tool.sh").ends_with("\r\n\r\ninterpreted"));
+        assert!(get(&handler, "/cgi-bin/nothing").starts_with("HTTP/1.1 404"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn runaway_cgi_output_gets_502() {
+        let root = TempDir::new("cgi-max-output");
+        root.script("cgi-bin/endless.sh", "#!/bin/sh\nprintf 'Content-Type: text/plain\\r\\n\\r\\n'\nexec yes\n");
+        root.script("cgi-bin/small.sh", "#!/bin/sh\nprintf 'Content-Type: text/plain\\r\\n\\r\\nsmall'\n");
+        let handler = handler(&format!(
+            "server {{\n    listen 8080\n    root {}\n    location /cgi-bin {{\n        methods GET\n        cgi .sh *\n        \
+             cgi_max_output 64k\n    }}\n}}\n",
+            root.path()
+        ));
+
+        let started = std::time::Instant::now();
+        let response = get(&handler, "/cgi-bin/endless.sh");
+        assert!(response.starts_with("HTTP/1.1 502"), "{}", &response[..response.len().min(200)]);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(get(&handler, "/cgi-bin/small.sh").ends_with("\r\n\r\nsmall"));
+    }
+
+    #[test]
+    fn overload_503_grows_retry_after_with_load() {
+        let handler = handler(&SITE.replace("root /nonexistent", "root /nonexistent\n    max_connections_soft 10"));
+        let retry_after = |active: usize| {
+            handler.set_active_connections(active);
+            let (status, headers, _) = split_response(get(&handler, "/missing").as_bytes());
+            (status[9..12].to_string(), header(&headers, "retry-after").map(str::to_string))
+        };
+
+        assert_eq!(retry_after(10), ("404".to_string(), None));
+        assert_eq!(retry_after(11), ("503".to_string(), Some("2".to_string())));
+        assert_eq!(retry_after(20), ("503".to_string(), Some("11".to_string())));
+        assert_eq!(retry_after(10_000), ("503".to_string(), Some("120".to_string())));
+    }
+
+    #[test]
+    fn favicon_and_robots_answer_before_routing() {
+        let root = TempDir::new("well-known");
+        fs::write(root.0.join("icon.ico"), b"\x00\x00\x01\x00icon").unwrap();
+        // The only route takes POST, so these never reach it
+        let handler = handler(&format!(
+            "server {{\n    listen 8080\n    root /nonexistent\n    favicon {}/icon.ico\n    \
+             robots \"User-agent: *\\nDisallow: /private\"\n    location / {{\n        methods POST\n    }}\n}}\n",
+            root.path()
+        ));
+
+        let (status, headers, body) = split_response(&handler.dispatch_bytes(b"GET /favicon.ico HTTP/1.1\r\nHost: localhost\r\n\r\n"));
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(body, b"\x00\x00\x01\x00icon");
+        assert_eq!(header(&headers, "content-length"), Some("8"));
+
+        let response = get(&handler, "/robots.txt");
+        assert!(response.contains("\r\ncontent-type: text/plain"), "{}", response);
+        assert!(response.ends_with("\r\n\r\nUser-agent: *\nDisallow: /private"), "{}", response);
+
+        let response = handler.dispatch_bytes(b"HEAD /robots.txt HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(response.starts_with(b"HTTP/1.1 200") && response.ends_with(b"\r\n\r\n"));
+        assert!(get(&handler, "/other.txt").starts_with("HTTP/1.1 405"));
+    }
+
+    #[test]
+    fn directory_listing_revalidates_with_last_modified() {
+        let root = TempDir::new("listing-304");
+        root.write("files/a.txt", "a");
+        let handler = handler(&format!(
+            "server {{\n    listen 8080\n    root {}\n    location /files {{\n        methods GET\n        autoindex on\n    }}\n}}\n",
+            root.path()
+        ));
+        let get_since = |since: &str| {
+            let raw = format!("GET /files/ HTTP/1.1\r\nHost: localhost\r\nIf-Modified-Since: {}\r\n\r\n", since);
+            split_response(&handler.dispatch_bytes(raw.as_bytes()))
+        };
+
+        let (status, headers, _) = split_response(get(&handler, "/files/").as_bytes());
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        let modified = header(&headers, "last-modified").unwrap().to_string();
+
+        let (status, headers, body) = get_since(&modified);
+        assert_eq!(status, "HTTP/1.1 304 Not Modified");
+        assert_eq!(header(&headers, "last-modified"), Some(modified.as_str()));
+        assert!(body.is_empty());
+
+        assert!(get_since("Thu, 01 Jan 1970 00:00:00 GMT").0.starts_with("HTTP/1.1 200"));
+        assert!(get_since("not a date").0.starts_with("HTTP/1.1 200"));
+
+        // A new entry bumps the directory's mtime
+        let later = std::time::SystemTime::now() + Duration::from_secs(10);
+        root.write("files/b.txt", "b");
+        fs::File::open(root.0.join("files")).unwrap().set_modified(later).unwrap();
+        let (status, _, body) = get_since(&modified);
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert!(String::from_utf8_lossy(&body).contains("b.txt"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn cgi_working_dir_sets_the_script_cwd() {
+        let root = TempDir::new("cgi-cwd");
+        root.write("data/greeting.txt", "from data");
+        root.script("www/cgi-bin/pwd.sh", "#!/bin/sh\nprintf 'Content-Type: text/plain\\r\\n\\r\\n%s|' \"$(pwd -P)\"\ncat greeting.txt 2>/dev/null || true\n");
+        let site = |working_dir: &str| {
+            handler(&format!(
+                "server {{\n    listen 8080\n    root {0}/www\n    location /cgi-bin {{\n        methods GET\n        cgi .sh *\n        {1}\n    }}\n}}\n",
+                root.path(),
+                working_dir
+            ))
+        };
+        let real = |rel: &str| fs::canonicalize(root.0.join(rel)).unwrap().display().to_string();
+
+        // By default the script runs in its own directory
+        let response = get(&site(""), "/cgi-bin/pwd.sh");
+        assert!(response.ends_with(&format!("\r\n\r\n{}|", real("www/cgi-bin"))), "{}", response);
+
+        let response = get(&site(&format!("cgi_working_dir {}/data", root.path())), "/cgi-bin/pwd.sh");
+        assert!(response.ends_with(&format!("\r\n\r\n{}|from data", real("data"))), "{}", response);
+
+        let response = get(&site("cgi_working_dir /nonexistent/cwd"), "/cgi-bin/pwd.sh");
+        assert!(response.starts_with("HTTP/1.1 5"), "{}", response);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn error_pages_shared_by_several_codes() {
+        let root = TempDir::new("error-pages");
+        root.write("50x.html", "<h1>Our fault</h1>");
+        root.write("empty.html", "nothing here");
+        root.script("cgi-bin/fail.sh", "#!/bin/sh\nexit 1\n");
+        root.script("cgi-bin/flood.sh", "#!/bin/sh\nprintf 'Content-Type: text/plain\\r\\n\\r\\n'\nexec yes\n");
+        let handler = handler(&format!(
+            "server {{\n    listen 8080\n    root {}\n    error_page 500 502 /50x.html\n    error_page 404 =200 /empty.html\n    \
+             location / {{\n        methods GET\n    }}\n    \
+             location /cgi-bin {{\n        methods GET\n        cgi .sh *\n        cgi_max_output 1k\n    }}\n}}\n",
+            root.path()
+        ));
+
+        for (target, status) in [("/cgi-bin/fail.sh", "500"), ("/cgi-bin/flood.sh", "502")] {
+            let response = get(&handler, target);
+            assert!(response.starts_with(&format!("HTTP/1.1 {}", status)), "{}: {}", target, response);
+            assert!(response.ends_with("\r\n\r\n<h1>Our fault</h1>"), "{}", response);
+        }
+
+        let response = get(&handler, "/missing");
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with("\r\n\r\nnothing here"));
+    }
+
+
+    #[test]
+    fn strict_bodies_rejects_a_get_body() {
+        let root = TempDir::new("strict-bodies");
+        root.write("index.html", "home");
+        let config = |strict: &str| {
+            format!(
+                "server {{\n    listen 8080\n    root {}\n    strict_bodies {}\n    \
+                 location / {{\n        methods GET DELETE\n        index index.html\n    }}\n}}\n",
+                root.path(),
+                strict
+            )
+        };
+        let with_body = b"GET / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 4\r\n\r\nabcd";
+
+        let strict = handler(&config("on"));
+        let (status, headers, _) = split_response(&strict.dispatch_bytes(with_body));
+        assert!(status.starts_with("HTTP/1.1 400"), "{}", status);
+        assert_eq!(header(&headers, "connection"), Some("close"));
+        let (status, _, _) = split_response(&strict.dispatch_bytes(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n"));
+        assert!(status.starts_with("HTTP/1.1 200"), "{}", status);
+
+        let (status, _, body) = split_response(&handler(&config("off")).dispatch_bytes(with_body));
+        assert!(status.starts_with("HTTP/1.1 200"), "{}", status);
+        assert_eq!(body, b"home");
+    }
+
+
+    #[cfg(unix)]
+    #[test]
+    fn cgi_sees_connection_endpoints() {
+        let root = TempDir::new("cgi-endpoints");
+        root.script(
+            "env.sh",
+            "#!/bin/sh\nprintf 'Content-Type: text/plain\\r\\n\\r\\n'\n\
+             echo \"$SERVER_ADDR:$SERVER_PORT $REMOTE_ADDR:$REMOTE_PORT\"\n",
+        );
+        let handler = handler(&format!(
+            "server {{\n    listen 8080\n    root {}\n    location / {{\n        methods GET\n        cgi .sh /bin/sh\n    }}\n}}\n",
+            root.path()
+        ));
+
+        let local = SocketAddr::from(([127, 0, 0, 1], 8080));
+        let peer = SocketAddr::from(([10, 1, 2, 3], 51234));
+        let raw = b"GET /env.sh HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let response = handler.respond(raw, None, local, peer, None, &mut |_| {});
+        let (status, _, body) = split_response(&response.to_bytes());
+        assert!(status.starts_with("HTTP/1.1 200"), "{}", status);
+        assert_eq!(String::from_utf8_lossy(&body), "127.0.0.1:8080 10.1.2.3:51234\n");
+    }
+
+
+    #[test]
+    fn zero_byte_files() {
+        let root = TempDir::new("empty-files");
+        root.write("empty.txt", "");
+        root.write("quiet/empty.txt", "");
+        let handler = handler(&format!(
+            "server {{\n    listen 8080\n    root {}\n    location / {{\n        methods GET HEAD\n    }}\n    \
+             location /quiet {{\n        methods GET\n        empty_as_no_content on\n    }}\n}}\n",
+            root.path()
+        ));
+
+        for method in ["GET", "HEAD"] {
+            let raw = format!("{} /empty.txt HTTP/1.1\r\nHost: localhost\r\n\r\n", method);
+            let (status, headers, body) = split_response(&handler.dispatch_bytes(raw.as_bytes()));
+            assert!(status.starts_with("HTTP/1.1 200"), "{}: {}", method, status);
+            assert_eq!(header(&headers, "content-length"), Some("0"));
+            assert!(header(&headers, "content-type").unwrap().starts_with("text/plain"));
+            assert!(body.is_empty());
+        }
+
+        let (status, headers, body) = split_response(&handler.dispatch_bytes(b"GET /quiet/empty.txt HTTP/1.1\r\nHost: localhost\r\n\r\n"));
+        assert!(status.starts_with("HTTP/1.1 204"), "{}", status);
+        assert_eq!(header(&headers, "content-length"), None);
+        assert_eq!(header(&headers, "content-type"), None);
+        assert!(body.is_empty());
+    }
+
+
+    #[test]
+    fn request_id_is_echoed_and_logged() {
+        let root = TempDir::new("request-id");
+        root.write("site/index.html", "home");
+        let handler = handler(&format!(
+            "server {{\n    listen 8080\n    root {0}/site\n    access_log {0}/access.log\n    \
+             location / {{\n        methods GET\n        index index.html\n    }}\n}}\n",
+            root.path()
+        ));
+        let logged_ids = || {
+            fs::read_to_string(root.0.join("access.log"))
+                .unwrap()
+                .lines()
+                .map(|line| line.rsplit(' ').next().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        let (_, headers, _) = split_response(get(&handler, "/").as_bytes());
+        let generated = header(&headers, "x-request-id").unwrap().to_string();
+        assert!(!generated.is_empty());
+        let (_, headers, _) = split_response(get(&handler, "/").as_bytes());
+        assert_ne!(header(&headers, "x-request-id"), Some(generated.as_str()));
+
+        // A client's well-formed ID is kept; an unusable one is replaced
+        let (_, headers, _) = split_response(&handler.dispatch_bytes(b"GET / HTTP/1.1\r\nHost: localhost\r\nX-Request-Id: trace-42\r\n\r\n"));
+        assert_eq!(header(&headers, "x-request-id"), Some("trace-42"));
+        let long = format!("GET / HTTP/1.1\r\nHost: localhost\r\nX-Request-Id: {}\r\n\r\n", "a".repeat(200));
+        let (_, headers, _) = split_response(&handler.dispatch_bytes(long.as_bytes()));
+        let replaced = header(&headers, "x-request-id").unwrap().to_string();
+        assert_ne!(replaced, "a".repeat(200));
+
+        let ids = logged_ids();
+        assert_eq!(ids.len(), 4);
+        assert_eq!(ids[0], generated);
+        assert_eq!(ids[2], "trace-42");
+        assert_eq!(ids[3], replaced);
+    }
+
+
+    #[cfg(unix)]
+    #[test]
+    fn set_cookie_limits_and_policies() {
+        let root = TempDir::new("cookie-limits");
+        root.script(
+            "cookies.sh",
+            &format!(
+                "#!/bin/sh\nprintf 'Content-Type: text/plain\\r\\nSet-Cookie: a=1\\r\\nSet-Cookie: b={}\\r\\nSet-Cookie: c=3\\r\\n\\r\\nok'\n",
+                "x".repeat(300)
+            ),
+        );
+        let cookies = |limits: &str| {
+            let handler = handler(&format!(
+                "server {{\n    listen 8080\n    root {}\n    {}\n    location / {{\n        methods GET\n        cgi .sh /bin/sh\n    }}\n}}\n",
+                root.path(),
+                limits
+            ));
+            let (status, headers, _) = split_response(&handler.dispatch_bytes(b"GET /cookies.sh HTTP/1.1\r\nHost: localhost\r\n\r\n"));
+            let names: Vec<String> = headers
+                .iter()
+                .filter(|(name, _)| name == "set-cookie")
+                .map(|(_, value)| value[..1].to_string())
+                .collect();
+            (status[9..12].to_string(), names.join(""))
+        };
+
+        assert_eq!(cookies(""), ("200".to_string(), "abc".to_string()));
+        // Cookies that fit are kept in order, skipping the oversized one
+        assert_eq!(cookies("set_cookie_max_size 100"), ("200".to_string(), "ac".to_string()));
+        assert_eq!(cookies("set_cookie_max_count 2"), ("200".to_string(), "ab".to_string()));
+        assert_eq!(cookies("set_cookie_max_size 100\n    set_cookie_policy warn"), ("200".to_string(), "abc".to_string()));
+        assert_eq!(cookies("set_cookie_max_size 100\n    set_cookie_policy reject"), ("500".to_string(), String::new()));
+    }
+
+
+    #[test]
+    fn strict_accept_answers_406() {
+        let root = TempDir::new("strict-accept");
+        root.write("api/page.html", "<p>html only</p>");
+        root.write("page.html", "<p>html only</p>");
+        let handler = handler(&format!(
+            "server {{\n    listen 8080\n    root {}\n    location / {{\n        methods GET\n    }}\n    \
+             location /api {{\n        methods GET\n        strict_accept on\n    }}\n}}\n",
+            root.path()
+        ));
+        let status = |target: &str, accept: &str| {
+            let raw = format!("GET {} HTTP/1.1\r\nHost: localhost\r\nAccept: {}\r\n\r\n", target, accept);
+            split_response(&handler.dispatch_bytes(raw.as_bytes())).0[9..12].to_string()
+        };
+
+        assert_eq!(status("/api/page.html", "application/json"), "406");
+        assert_eq!(status("/api/page.html", "application/json, text/html;q=0.2"), "200");
+        assert_eq!(status("/api/page.html", "*/*"), "200");
+        assert_eq!(status("/api/missing.html", "application/json"), "404");
+        // Routes without strict_accept ignore the header
+        assert_eq!(status("/page.html", "application/json"), "200");
+    }
+
+
+    #[test]
+    fn clean_urls_serve_html_files() {
+        let root = TempDir::new("clean-urls");
+        root.write("about.html", "about page");
+        root.write("blog/index.html", "blog index");
+        root.write("blog.html", "blog file");
+        root.write("notes", "extensionless file");
+        root.write("notes.html", "notes page");
+        let site = |clean: &str| {
+            handler(&format!(
+                "server {{\n    listen 8080\n    root {}\n    location / {{\n        methods GET\n        index index.html\n        clean_urls {}\n    }}\n}}\n",
+                root.path(),
+                clean
+            ))
+        };
+
+        let handler = site("on");
+        let response = get(&handler, "/about");
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with("\r\n\r\nabout page"));
+        assert!(get(&handler, "/about.html").ends_with("\r\n\r\nabout page"));
+        // Real files and directories are not shadowed
+        assert!(get(&handler, "/notes").ends_with("\r\n\r\nextensionless file"));
+        assert!(get(&handler, "/blog/").ends_with("\r\n\r\nblog index"));
+        assert!(get(&handler, "/contact").starts_with("HTTP/1.1 404"));
+
+        assert!(get(&site("off"), "/about").starts_with("HTTP/1.1 404"));
+    }
+
+
+    #[cfg(unix)]
+    #[test]
+    fn missing_files_are_404_and_escapes_403() {
+        let root = TempDir::new("containment");
+        root.write("site/page.html", "page");
+        root.write("secret.txt", "secret");
+        std::os::unix::fs::symlink(root.0.join("secret.txt"), root.0.join("site/leak.txt")).unwrap();
+        std::os::unix::fs::symlink(&root.0, root.0.join("site/outside")).unwrap();
+        let handler = handler(&format!(
+            "server {{\n    listen 8080\n    root {}/site\n    location / {{\n        methods GET\n        follow_symlinks off\n    }}\n}}\n",
+            root.path()
+        ));
+        let status = |target: &str| get(&handler, target)[9..12].to_string();
+
+        assert_eq!(status("/page.html"), "200");
+        assert_eq!(status("/missing.html"), "404");
+        assert_eq!(status("/no/such/dir/missing.html"), "404");
+        // Symlinks out of the root are refused, existing target or not
+        assert_eq!(status("/leak.txt"), "403");
+        assert_eq!(status("/outside/secret.txt"), "403");
+        assert_eq!(status("/outside/missing.txt"), "403");
+        let traversal = status("/../secret.txt");
+        assert!(traversal != "200" && traversal != "500", "{}", traversal);
+    }
+
+
+    #[test]
+    fn health_endpoint_checks_roots_and_paths() {
+        let root = TempDir::new("health");
+        root.write("site/index.html", "home");
+        root.write("docs/index.html", "docs");
+        root.write("state/ready", "");
+        let handler = handler(&format!(
+            "server {{\n    listen 8080\n    root {0}/site\n    health_check /healthz\n    health_check_path {0}/state/ready\n    \
+             location / {{\n        methods GET\n    }}\n    location /docs {{\n        methods GET\n        root {0}/docs\n    }}\n}}\n",
+            root.path()
+        ));
+
+        let (status, headers, body) = split_response(get(&handler, "/healthz").as_bytes());
+        assert!(status.starts_with("HTTP/1.1 200"), "{}", status);
+        assert_eq!(header(&headers, "cache-control"), Some("no-store"));
+        assert_eq!(body, b"{\"status\":\"ok\"}");
+
+        fs::remove_dir_all(root.0.join("docs")).unwrap();
+        fs::remove_file(root.0.join("state/ready")).unwrap();
+        let (status, headers, body) = split_response(get(&handler, "/healthz").as_bytes());
+        assert!(status.starts_with("HTTP/1.1 503"), "{}", status);
+        assert!(header(&headers, "content-type").unwrap().starts_with("application/json"));
+        let body = String::from_utf8(body).unwrap();
+        assert!(body.starts_with("{\"status\":\"unavailable\",\"problems\":["), "{}", body);
+        assert!(body.contains(&format!("{}/docs", root.path())), "{}", body);
+        assert!(body.contains(&format!("{}/state/ready", root.path())), "{}", body);
+    }
+
+
+    #[test]
+    fn forwarded_for_trusted_only_from_proxies() {
+        let root = TempDir::new("real-ip");
+        root.write("site/index.html", "home");
+        let handler = handler(&format!(
+            "server {{\n    listen 8080\n    root {0}/site\n    access_log {0}/access.log\n    log_format \"$remote_addr\"\n    \
+             set_real_ip_from 10.0.0.0/8\n    set_real_ip_from 192.168.1.1\n    \
+             location / {{\n        methods GET\n        index index.html\n    }}\n}}\n",
+            root.path()
+        ));
+        let local = SocketAddr::from(([127, 0, 0, 1], 8080));
+        let client_ip = |peer: [u8; 4], forwarded: &str| {
+            let raw = format!("GET / HTTP/1.1\r\nHost: localhost\r\nX-Forwarded-For: {}\r\n\r\n", forwarded);
+            handler.respond(raw.as_bytes(), None, local, SocketAddr::from((peer, 4000)), None, &mut |_| {});
+            fs::read_to_string(root.0.join("access.log")).unwrap().lines().last().unwrap().to_string()
+        };
+
+        // An untrusted peer's header could be forged
+        assert_eq!(client_ip([203, 0, 113, 9], "198.51.100.7"), "203.0.113.9");
+        assert_eq!(client_ip([10, 1, 2, 3], "198.51.100.7"), "198.51.100.7");
+        // Walked from the right past trusted proxies, stopping at the first untrusted entry
+        assert_eq!(client_ip([10, 1, 2, 3], "1.1.1.1, 198.51.100.7, 192.168.1.1, 10.9.9.9"), "198.51.100.7");
+        assert_eq!(client_ip([10, 1, 2, 3], "garbage, 10.2.2.2"), "10.2.2.2");
+    }
+
+
+    #[cfg(unix)]
+    #[test]
+    fn head_to_cgi_has_headers_and_no_body() {
+        let root = TempDir::new("cgi-head");
+        root.script(
+            "page.sh",
+            "#!/bin/sh\nprintf 'Content-Type: text/plain\\r\\nX-Method: %s\\r\\n\\r\\n' \"$REQUEST_METHOD\"\n\
+             [ \"$REQUEST_METHOD\" = HEAD ] || printf 'the body'\n",
+        );
+        root.script("ignores-head.sh", "#!/bin/sh\nprintf 'Content-Type: text/plain\\r\\n\\r\\nthe body'\n");
+        let handler = handler(&format!(
+            "server {{\n    listen 8080\n    root {}\n    location / {{\n        methods GET HEAD\n        cgi .sh /bin/sh\n    }}\n}}\n",
+            root.path()
+        ));
+        let head = |target: &str| {
+            let raw = format!("HEAD {} HTTP/1.1\r\nHost: localhost\r\n\r\n", target);
+            split_response(&handler.dispatch_bytes(raw.as_bytes()))
+        };
+
+        // The script sees HEAD and skips its body
+        let (status, headers, body) = head("/page.sh");
+        assert!(status.starts_with("HTTP/1.1 200"), "{}", status);
+        assert_eq!(header(&headers, "x-method"), Some("HEAD"));
+        assert_eq!(header(&headers, "content-length"), None);
+        assert!(header(&headers, "content-type").unwrap().starts_with("text/plain"));
+        assert!(body.is_empty());
+
+        // A body sent anyway is dropped, keeping the length a GET would get
+        let (status, headers, body) = head("/ignores-head.sh");
+        assert!(status.starts_with("HTTP/1.1 200"), "{}", status);
+        assert_eq!(header(&headers, "content-length"), Some("8"));
+        assert!(body.is_empty());
+    }
+
+
+    #[test]
+    fn autoindex_missing_hides_directories() {
+        let root = TempDir::new("autoindex-missing");
+        root.write("hidden/file.txt", "x");
+        root.write("shown/file.txt", "x");
+        let handler = handler(&format!(
+            "server {{\n    listen 8080\n    root {}\n    location / {{\n        methods GET\n    }}\n    \
+             location /hidden {{\n        methods GET\n        autoindex_missing 404\n    }}\n}}\n",
+            root.path()
+        ));
+
+        assert!(get(&handler, "/hidden/").starts_with("HTTP/1.1 404"));
+        assert!(get(&handler, "/hidden/file.txt").starts_with("HTTP/1.1 200"));
+        assert!(get(&handler, "/shown/").starts_with("HTTP/1.1 403"));
+
+        let config = "server {\n    listen 8080\n    location / {\n        autoindex_missing 401\n    }\n}\n";
+        assert!(Config::load_string(config).is_err());
+    }
+
+
+    #[test]
+    fn preload_links_go_out_as_early_hints() {
+        let root = TempDir::new("early-hints");
+        root.write("index.html", "home");
+        let handler = handler(&format!(
+            "server {{\n    listen 8080\n    root {}\n    location / {{\n        methods GET\n        index index.html\n        \
+             preload /style.css style\n        preload /app.js\n    }}\n}}\n",
+            root.path()
+        ));
+
+        let bytes = handler.dispatch_bytes(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let (status, headers, rest) = split_response(&bytes);
+        assert_eq!(status, "HTTP/1.1 103 Early Hints");
+        let links: Vec<&str> = headers.iter().filter(|(name, _)| name == "link").map(|(_, v)| v.as_str()).collect();
+        assert_eq!(links, ["</style.css>; rel=preload; as=style", "</app.js>; rel=preload"]);
+        // The final response follows the interim one
+        let (status, _, body) = split_response(&rest);
+        assert!(status.starts_with("HTTP/1.1 200"), "{}", status);
+        assert_eq!(body, b"home");
+
+        for raw in [&b"GET / HTTP/1.0\r\n\r\n"[..], b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\r\n"] {
+            let bytes = handler.dispatch_bytes(raw);
+            assert!(!bytes.starts_with(b"HTTP/1.1 103"), "{}", String::from_utf8_lossy(raw));
+        }
+    }
+
+
+    #[test]
+    fn memory_and_file_session_backends_behave_alike() {
+        let dir = TempDir::new("session-backends");
+        for store in ["session_store memory".to_string(), format!("session_store file {}", dir.path())] {
+            let handler = handler(&format!(
+                "{}\nserver {{\n    listen 8080\n    root /nonexistent\n    session_admin /admin/sessions\n    \
+                 location / {{\n        methods GET\n    }}\n}}\n",
+                store
+            ));
+            let ids: Vec<String> = (0..2).map(|_| handler.sessions.lock().unwrap().create()).collect();
+            {
+                let mut sessions = handler.sessions.lock().unwrap();
+                sessions.get_mut(&ids[0]).unwrap().set("user", "ada");
+                sessions.save(&ids[0]);
+                assert_eq!(sessions.get(&ids[0]).unwrap().get("user").map(String::as_str), Some("ada"), "{}", store);
+                assert!(sessions.get("no-such-session").is_none(), "{}", store);
+            }
+
+            let listing = get(&handler, "/admin/sessions");
+            assert!(listing.contains("Sessions: 2\n"), "{}: {}", store, listing);
+            assert!(ids.iter().all(|id| listing.contains(id.as_str())), "{}", store);
+
+            let response = handler.dispatch_bytes(b"DELETE /admin/sessions HTTP/1.1\r\nHost: localhost\r\n\r\n");
+            assert!(String::from_utf8_lossy(&response).ends_with("Destroyed 2 sessions\n"), "{}", store);
+            assert_eq!(handler.sessions.lock().unwrap().count(), 0, "{}", store);
+        }
+        assert_eq!(fs::read_dir(&dir.0).unwrap().count(), 0);
+    }
+
+
+    #[test]
+    fn session_cookie_renewed_past_the_threshold() {
+        let site = |threshold: &str| handler(&SITE.replace("root /nonexistent", &format!("root /nonexistent\n    {}", threshold)));
+        let session_cookie = |handler: &Handler, id: Option<&str>| {
+            let raw = match id {
+                Some(id) => format!("GET / HTTP/1.1\r\nHost: localhost\r\nCookie: session_id={}\r\n\r\n", id),
+                None => "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n".to_string(),
+            };
+            let (_, headers, _) = split_response(&handler.dispatch_bytes(raw.as_bytes()));
+            header(&headers, "set-cookie").map(str::to_string)
+        };
+        let backdate = |handler: &Handler, id: &str| {
+            let mut sessions = handler.sessions.lock().unwrap();
+            let session = sessions.get_mut(id).unwrap();
+            session.cookie_issued_at = Instant::now() - session.expires_in * 6 / 10;
+        };
+
+        let handler = site("session_renew_threshold 50");
+        let first = session_cookie(&handler, None).unwrap();
+        let id = first["session_id=".len()..].split(';').next().unwrap().to_string();
+        let max_age = first.split("; ").find(|a| a.starts_with("Max-Age=")).unwrap().to_string();
+        assert_eq!(session_cookie(&handler, Some(&id)), None);
+
+        // Past half its lifetime the same cookie goes out again, once
+        backdate(&handler, &id);
+        let renewed = session_cookie(&handler, Some(&id)).unwrap();
+        assert!(renewed.starts_with(&format!("session_id={};", id)), "{}", renewed);
+        assert!(renewed.contains(&max_age), "{}", renewed);
+        assert_eq!(session_cookie(&handler, Some(&id)), None);
+
+        let handler = site("session_renew_threshold off");
+        let first = session_cookie(&handler, None).unwrap();
+        let id = first["session_id=".len()..].split(';').next().unwrap().to_string();
+        backdate(&handler, &id);
+        assert_eq!(session_cookie(&handler, Some(&id)), None);
+    }
+
+
+    #[test]
+    fn off_list_redirects_are_blocked() {
+        let site = |policy: &str| {
+            handler(&format!(
+                "server {{\n    listen 8080\n    server_name localhost\n    root /nonexistent\n    \
+                 redirect_allow_hosts docs.example *.cdn.example\n    {}\n    \
+                 location /evil {{\n        return https://evil.example/phish\n    }}\n    \
+                 location /sneaky {{\n        return /\\evil.example/\n    }}\n    \
+                 location /docs {{\n        return https://docs.example/guide\n    }}\n    \
+                 location /assets {{\n        return //img.cdn.example/a.png\n    }}\n    \
+                 location /self {{\n        return https://localhost/home\n    }}\n    \
+                 location /moved {{\n        return /new-place\n    }}\n}}\n",
+                policy
+            ))
+        };
+
+        let handler = site("");
+        for target in ["/evil", "/sneaky"] {
+            let (status, headers, _) = split_response(get(&handler, target).as_bytes());
+            assert!(status.starts_with("HTTP/1.1 500"), "{}: {}", target, status);
+            assert_eq!(header(&headers, "location"), None);
+        }
+        for target in ["/docs", "/assets", "/self", "/moved"] {
+            let status = get(&handler, target)[9..12].to_string();
+            assert!(status.starts_with('3'), "{}: {}", target, status);
+        }
+
+        // log only warns
+        let (status, headers, _) = split_response(get(&site("redirect_policy log"), "/evil").as_bytes());
+        assert!(status.starts_with("HTTP/1.1 3"), "{}", status);
+        assert_eq!(header(&headers, "location"), Some("https://evil.example/phish"));
+    }
+
+
+    #[test]
+    fn landing_page_stands_in_for_a_missing_index() {
+        let root = TempDir::new("landing");
+        root.write("site/docs/readme.txt", "docs");
+        root.write("landing.html", "<h1>Welcome to $server_name</h1><ul>$links</ul>");
+        let site = |directives: &str| {
+            handler(&format!(
+                "server {{\n    listen 8080\n    server_name example\n    root {0}/site\n    {1}\n    \
+                 location / {{\n        methods GET\n    }}\n    location /docs {{\n        methods GET\n    }}\n    \
+                 location /upload {{\n        methods POST\n    }}\n}}\n",
+                root.path(),
+                directives
+            ))
+        };
+
+        assert!(get(&site(""), "/").starts_with("HTTP/1.1 403"));
+
+        let handler = site("landing_page on");
+        let response = get(&handler, "/");
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.contains("<h1>example</h1>"));
+        assert!(response.contains("<li><a href=\"/docs\">/docs</a></li>"));
+        // Only locations that answer GET are linked
+        assert!(!response.contains("/upload"));
+        // Other directories keep their 403
+        assert!(get(&handler, "/docs/").starts_with("HTTP/1.1 403"));
+
+        let templated = format!("landing_page on\n    landing_page_template {}/landing.html", root.path());
+        let response = get(&site(&templated), "/");
+        assert!(response.ends_with("\r\n\r\n<h1>Welcome to example</h1><ul><li><a href=\"/docs\">/docs</a></li>\n</ul>"), "{}", response);
+    }
+
+
+    #[test]
+    fn method_override_turns_a_post_into_delete() {
+        let root = TempDir::new("method-override");
+        let site = |directives: &str| {
+            handler(&format!(
+                "server {{\n    listen 8080\n    root {}\n    {}\n    location / {{\n        methods GET DELETE\n    }}\n}}\n",
+                root.path(),
+                directives
+            ))
+        };
+        let post = |handler: &Handler, extra: &str, body: &str| {
+            let raw = format!(
+                "POST /note.txt HTTP/1.1\r\nHost: localhost\r\n{}Content-Length: {}\r\n\r\n{}",
+                extra,
+                body.len(),
+                body
+            );
+            split_response(&handler.dispatch_bytes(raw.as_bytes())).0[9..12].to_string()
+        };
+        let exists = || root.0.join("note.txt").exists();
+
+        root.write("note.txt", "x");
+        assert_eq!(post(&site(""), "X-HTTP-Method-Override: DELETE\r\n", ""), "405");
+        assert!(exists());
+
+        let handler = site("method_override on");
+        // Only PUT and DELETE can be asked for
+        assert_eq!(post(&handler, "X-HTTP-Method-Override: GET\r\n", ""), "405");
+        assert!(post(&handler, "X-HTTP-Method-Override: DELETE\r\n", "").starts_with('2'));
+        assert!(!exists());
+
+        root.write("note.txt", "x");
+        let form = "Content-Type: application/x-www-form-urlencoded\r\n";
+        assert!(post(&handler, form, "_method=DELETE").starts_with('2'));
+        assert!(!exists());
+    }
+
+
+    #[cfg(unix)]
+    #[test]
+    fn spooled_body_reaches_cgi_stdin() {
+        let root = TempDir::new("spooled-cgi");
+        root.write("spool/.keep", "");
+        root.script(
+            "echo.sh",
+            "#!/bin/sh\nspooled=$(ls -A \"$(dirname \"$0\")/spool\" | grep -c '^\\.body-')\n\
+             printf 'Content-Type: application/octet-stream\\r\\nX-Spooled: %s\\r\\n\\r\\n' \"$spooled\"\ncat\n",
+        );
+        let handler = handler(&format!(
+            "server {{\n    listen 8080\n    root {0}\n    client_max_body_size 1M\n    \
+             location / {{\n        methods POST\n        cgi .sh /bin/sh\n        \
+             client_body_buffer_size 1k\n        client_body_temp_path {0}/spool\n    }}\n}}\n",
+            root.path()
+        ));
+        let body: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let mut raw = format!("POST /echo.sh HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n", body.len()).into_bytes();
+        raw.extend(&body);
+
+        let (status, headers, echoed) = split_response(&handler.dispatch_bytes(&raw));
+        assert!(status.starts_with("HTTP/1.1 200"), "{}", status);
+        assert_eq!(header(&headers, "x-spooled"), Some("1"));
+        assert!(echoed == body);
+        // The temp file is gone once the request is done
+        assert_eq!(fs::read_dir(root.0.join("spool")).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn post_without_a_length_is_411() {
+        let handler = handler(SITE);
+        let (status, headers, _) = split_response(&handler.dispatch_bytes(b"POST / HTTP/1.1\r\nHost: localhost\r\n\r\n"));
+        assert!(status.starts_with("HTTP/1.1 411"), "{}", status);
+        assert_eq!(header(&headers, "connection"), Some("close"));
+    }
+
+    #[test]
+    fn types_disposition_picks_attachment_or_inline() {
+        let root = TempDir::new("disposition");
+        root.write("report.CSV", "a,b\n");
+        root.write("manual.pdf", "%PDF-1.4");
+        root.write("page.html", "<p>hi</p>");
+        root.write("docs/manual.pdf", "%PDF-1.4");
+        let handler = handler(&format!(
+            "server {{\n    listen 8080\n    root {}\n    types_disposition {{ attachment: [csv, bin]; inline: [pdf]; }}\n    \
+             location / {{\n        methods GET\n    }}\n    \
+             location /docs {{\n        methods GET\n        types_disposition {{ attachment pdf; }}\n    }}\n}}\n",
+            root.path()
+        ));
+
+        let disposition = |target: &str| {
+            let raw = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", target);
+            let (status, headers, _) = split_response(&handler.dispatch_bytes(raw.as_bytes()));
+            assert!(status.starts_with("HTTP/1.1 200"), "{}: {}", target, status);
+            header(&headers, "content-disposition").map(str::to_string)
+        };
+        assert_eq!(disposition("/report.CSV").as_deref(), Some("attachment; filename=\"report.CSV\""));
+        assert_eq!(disposition("/manual.pdf").as_deref(), Some("inline; filename=\"manual.pdf\""));
+        assert_eq!(disposition("/page.html"), None);
+        // A location's own mapping wins over the server's
+        assert_eq!(disposition("/docs/manual.pdf").as_deref(), Some("attachment; filename=\"manual.pdf\""));
+    }
 }
@@ -0,0 +1,40 @@
+use crate::config::ServerConfig;
+use crate::error::pages::escape_html;
+use crate::http::Method;
+
+/// Template used when no `landing_page_template` is configured
+const DEFAULT_TEMPLATE: &str = "<!DOCTYPE html>\n\
+    <html>\n\
+    <head><title>$server_name</title></head>\n\
+    <body>\n\
+    <h1>$server_name</h1>\n\
+    <ul>\n\
+    $links\
+    </ul>\n\
+    </body>\n\
+    </html>";
+
+/// Generates a server homepage linking the configured locations, shown at
+/// the site root when it has no index file and listing is off
+pub struct LandingPage;
+
+impl LandingPage {
+    /// Renders the page from a template (the built-in one when None),
+    /// replacing `$server_name` and `$links`, the latter a list item per
+    /// location that answers GET
+    pub fn render(server: &ServerConfig, template: Option<&str>) -> String {
+        let mut links = String::new();
+        for route in &server.routes {
+            if route.path == "/" || !route.is_method_allowed(&Method::Get) {
+                continue;
+            }
+            let path = escape_html(&route.path);
+            links.push_str(&format!("<li><a href=\"{}\">{}</a></li>\n", path, path));
+        }
+
+        template
+            .unwrap_or(DEFAULT_TEMPLATE)
+            .replace("$server_name", &escape_html(&server.server_name))
+            .replace("$links", &links)
+    }
+}
@@ -1,9 +1,13 @@
+pub mod auth;
+pub mod cors;
 pub mod directory;
+pub mod file_cache;
 pub mod handler;
+pub mod landing;
 pub mod redirect;
 pub mod static_files;
 
 pub use directory::DirectoryListing;
-pub use handler::Handler;
+pub use handler::{Handler, HeaderCheck, Middleware, RouteHandler};
 pub use redirect::Redirect;
 pub use static_files::StaticFiles;
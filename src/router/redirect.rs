@@ -1,3 +1,4 @@
+use crate::error::pages::escape_html;
 use crate::http::{Response, StatusCode};
 
 /// Handles HTTP redirects
@@ -16,6 +17,7 @@ impl Redirect {
         response.headers.set("Location", location);
         
         // Add a simple HTML body for clients that don't follow redirects
+        let escaped = escape_html(location);
         let html = format!(
             "<!DOCTYPE html>\n\
             <html>\n\
@@ -28,16 +30,46 @@ impl Redirect {
             <p>Redirecting to <a href=\"{}\">{}</a></p>\n\
             </body>\n\
             </html>",
-            location,
+            escaped,
             status.code(),
             status.reason(),
-            location,
-            location
+            escaped,
+            escaped
         );
 
         response.html(&html)
     }
 
+    /// Returns the host an absolute or protocol-relative redirect target
+    /// points at (empty for a scheme without one, like `javascript:`), or
+    /// None for a path on this server. Backslashes count as slashes, as
+    /// browsers read `/\evil.com` as `//evil.com`
+    pub fn external_host(location: &str) -> Option<String> {
+        let location = location.trim().replace('\\', "/");
+
+        let rest = if let Some(rest) = location.strip_prefix("//") {
+            rest
+        } else if location.starts_with('/') {
+            return None;
+        } else {
+            // A scheme is a colon before any path, query or fragment
+            let end = location.find(['/', '?', '#']).unwrap_or(location.len());
+            let colon = location[..end].find(':')?;
+            match location[colon + 1..].strip_prefix("//") {
+                Some(rest) => rest,
+                None => return Some(String::new()),
+            }
+        };
+
+        let authority = rest.split(['/', '?', '#']).next().unwrap_or("");
+        let host_port = authority.rsplit('@').next().unwrap_or("");
+        let host = match host_port.strip_prefix('[') {
+            Some(v6) => v6.split(']').next().unwrap_or(""),
+            None => host_port.split(':').next().unwrap_or(""),
+        };
+        Some(host.trim_end_matches('.').to_lowercase())
+    }
+
     /// Creates a 301 Moved Permanently redirect
     pub fn permanent(location: &str) -> Response {
         Self::to(location, true)
@@ -52,7 +84,8 @@ impl Redirect {
     pub fn temporary_preserve_method(location: &str) -> Response {
         let mut response = Response::new(StatusCode::TemporaryRedirect);
         response.headers.set("Location", location);
-        
+
+        let escaped = escape_html(location);
         let html = format!(
             "<!DOCTYPE html>\n\
             <html>\n\
@@ -62,7 +95,7 @@ impl Redirect {
             <p>Redirecting to <a href=\"{}\">{}</a></p>\n\
             </body>\n\
             </html>",
-            location, location
+            escaped, escaped
         );
 
         response.html(&html)
@@ -72,7 +105,8 @@ impl Redirect {
     pub fn permanent_preserve_method(location: &str) -> Response {
         let mut response = Response::new(StatusCode::PermanentRedirect);
         response.headers.set("Location", location);
-        
+
+        let escaped = escape_html(location);
         let html = format!(
             "<!DOCTYPE html>\n\
             <html>\n\
@@ -82,9 +116,26 @@ impl Redirect {
             <p>Redirecting to <a href=\"{}\">{}</a></p>\n\
             </body>\n\
             </html>",
-            location, location
+            escaped, escaped
         );
 
         response.html(&html)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn external_hosts() {
+        assert_eq!(Redirect::external_host("/local/path"), None);
+        assert_eq!(Redirect::external_host("https://Example.COM./x"), Some("example.com".to_string()));
+        assert_eq!(Redirect::external_host("//cdn.example:8443/a"), Some("cdn.example".to_string()));
+        assert_eq!(Redirect::external_host("/\\evil.example"), Some("evil.example".to_string()));
+        assert_eq!(Redirect::external_host("http://user@[::1]:80/"), Some("::1".to_string()));
+        assert_eq!(Redirect::external_host("javascript:alert(1)"), Some(String::new()));
+        // A colon after the path starts is no scheme
+        assert_eq!(Redirect::external_host("page?next=a:b"), None);
+    }
+}
@@ -1,14 +1,29 @@
+use super::file_cache::FileCache;
 use crate::error::{Result, ServerError};
 use crate::http::{mime_type, Response};
 use std::fs;
+use std::io::Read;
 use std::path::{Path, PathBuf};
 
+/// Files larger than this are streamed instead of read into memory
+const STREAM_THRESHOLD: u64 = 1024 * 1024;
+
 /// Serves static files from the filesystem
 pub struct StaticFiles;
 
 impl StaticFiles {
     /// Serves a file from the given path
     pub fn serve(file_path: &str) -> Result<Response> {
+        Self::serve_from(file_path, None)
+    }
+
+    /// Serves a file, answering small files from the cache when it holds a
+    /// current copy and caching them after a read otherwise
+    pub fn serve_cached(file_path: &str, cache: &mut FileCache) -> Result<Response> {
+        Self::serve_from(file_path, Some(cache))
+    }
+
+    fn serve_from(file_path: &str, cache: Option<&mut FileCache>) -> Result<Response> {
         let path = Path::new(file_path);
 
         // Security: prevent path traversal
@@ -26,9 +41,37 @@ impl StaticFiles {
             return Err(ServerError::NotFound);
         }
 
-        // Read file contents
-        let contents = fs::read(path)?;
         let mime = mime_type(file_path);
+        let metadata = fs::metadata(path)?;
+        let len = metadata.len();
+        let modified = metadata.modified().ok();
+        let mut cache = cache.filter(|c| c.is_enabled() && len <= STREAM_THRESHOLD);
+
+        if let (Some(cache), Some(modified)) = (cache.as_deref_mut(), modified) {
+            if let Some(contents) = cache.get(file_path, modified, len) {
+                return Ok(Response::ok()
+                    .content_type(mime)
+                    .body(contents));
+            }
+        }
+
+        let file = fs::File::open(path)?;
+
+        // Stream large files in bounded chunks
+        if len > STREAM_THRESHOLD {
+            return Ok(Response::ok()
+                .content_type(mime)
+                .stream_file(file, len));
+        }
+
+        // Read file contents
+        let mut contents = Vec::with_capacity(len as usize);
+        let mut file = file;
+        file.read_to_end(&mut contents)?;
+
+        if let (Some(cache), Some(modified)) = (cache, modified) {
+            cache.insert(file_path, contents.clone(), modified);
+        }
 
         Ok(Response::ok()
             .content_type(mime)
@@ -50,6 +93,61 @@ impl StaticFiles {
         Self::serve(file_path)
     }
 
+    /// Checks that a path, with symlinks resolved, stays inside a root
+    /// A path that doesn't exist is judged by its deepest existing ancestor,
+    /// so a missing file is still a 404 rather than a failed check
+    pub fn is_within(root: &str, path: &str) -> bool {
+        let root = match fs::canonicalize(root) {
+            Ok(root) => root,
+            Err(_) => return false,
+        };
+        Self::canonicalize_existing(Path::new(path))
+            .map(|resolved| resolved.starts_with(&root))
+            .unwrap_or(false)
+    }
+
+    /// Canonicalizes the deepest existing ancestor of a path and appends the
+    /// missing components to it; None when a missing component is ".."
+    fn canonicalize_existing(path: &Path) -> Option<PathBuf> {
+        let mut existing = path;
+        let mut missing = Vec::new();
+
+        loop {
+            if let Ok(mut resolved) = fs::canonicalize(existing) {
+                resolved.extend(missing.iter().rev());
+                return Some(resolved);
+            }
+            missing.push(existing.file_name()?);
+            existing = match existing.parent()? {
+                parent if parent.as_os_str().is_empty() => Path::new("."),
+                parent => parent,
+            };
+        }
+    }
+
+    /// Checks if any component of a path below `root` is a symbolic link
+    /// The root itself may sit behind links; only what the request added is
+    /// checked
+    pub fn contains_symlink(root: &str, path: &str) -> bool {
+        let (mut current, below) = match Path::new(path).strip_prefix(root) {
+            Ok(below) => (PathBuf::from(root), below),
+            Err(_) => (PathBuf::new(), Path::new(path)),
+        };
+        for component in below.components() {
+            current.push(component);
+            if matches!(component, std::path::Component::CurDir) {
+                continue;
+            }
+            if fs::symlink_metadata(&current)
+                .map(|m| m.file_type().is_symlink())
+                .unwrap_or(false)
+            {
+                return true;
+            }
+        }
+        false
+    }
+
     /// Checks if a path is safe (no path traversal)
     fn is_safe_path(path: &str) -> bool {
         // Reject paths with ..
@@ -57,10 +155,6 @@ impl StaticFiles {
             return false;
         }
 
-        // Reject paths starting with /
-        // (they should be resolved relative to root)
-        let normalized = path.replace('\\', "/");
-        
         // Check for null bytes
         if path.contains('\0') {
             return false;
@@ -95,3 +189,63 @@ pub struct FileInfo {
     pub is_dir: bool,
     pub is_file: bool,
 }
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    /// A fresh directory under the system temp dir, removed on drop
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("localhost-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+
+        fn path(&self, rel: &str) -> String {
+            self.0.join(rel).to_string_lossy().into_owned()
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn symlinked_root_is_allowed() {
+        let tmp = TempDir::new("symlinked-root");
+        fs::create_dir(tmp.path("real")).unwrap();
+        fs::write(tmp.path("real/index.html"), "hi").unwrap();
+        symlink(tmp.path("real"), tmp.path("www")).unwrap();
+
+        let root = tmp.path("www");
+        let file = tmp.path("www/index.html");
+        assert!(!StaticFiles::contains_symlink(&root, &file));
+        assert!(StaticFiles::is_within(&root, &file));
+        assert!(StaticFiles::is_within(&root, &tmp.path("www/missing.html")));
+    }
+
+    #[test]
+    fn links_below_root_are_detected() {
+        let tmp = TempDir::new("inner-link");
+        fs::create_dir(tmp.path("www")).unwrap();
+        fs::write(tmp.path("secret"), "x").unwrap();
+        symlink(tmp.path("secret"), tmp.path("www/escape")).unwrap();
+        fs::write(tmp.path("www/page"), "x").unwrap();
+        symlink(tmp.path("www/page"), tmp.path("www/alias")).unwrap();
+
+        let root = tmp.path("www");
+        assert!(StaticFiles::contains_symlink(&root, &tmp.path("www/escape")));
+        assert!(!StaticFiles::is_within(&root, &tmp.path("www/escape")));
+
+        // A link that stays inside the root is still a link
+        assert!(StaticFiles::contains_symlink(&root, &tmp.path("www/alias")));
+        assert!(StaticFiles::is_within(&root, &tmp.path("www/alias")));
+    }
+}
@@ -1,9 +1,21 @@
 use crate::error::{Result, ServerError};
-use crate::http::{Request, RequestParser, Response};
+use crate::config::TcpKeepalive;
+use crate::http::{Request, RequestParser, Response, SpoolWriter, SpooledBody, StreamBody};
 use std::io::{Read, Write};
-use std::net::{SocketAddr, TcpStream};
-use std::os::windows::io::AsRawSocket;
-use std::time::Instant;
+use super::stream::ClientStream;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Default size of each socket read
+pub const DEFAULT_READ_BUFFER_SIZE: usize = 8192;
+
+/// Default size of each chunk sent from a streamed response body
+pub const DEFAULT_WRITE_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Largest unread request body drained to keep a connection alive after an
+/// early response; anything larger (or of unknown size) closes the connection
+const MAX_DRAIN_SIZE: usize = 1024 * 1024;
 
 /// Connection state
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -18,14 +30,27 @@ pub enum ConnectionState {
     Closed,
 }
 
+/// Outcome of a socket read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadOutcome {
+    /// Bytes were added to the read buffer
+    Data(usize),
+    /// Nothing to read yet; the connection stays as it is
+    WouldBlock,
+    /// The peer shut down its sending side or closed the connection
+    Eof,
+}
+
 /// Represents a client connection
 pub struct Connection {
-    /// The TCP stream
-    pub stream: TcpStream,
+    /// The client socket
+    pub stream: ClientStream,
     /// Client address
     pub addr: SocketAddr,
     /// Server port this connection came from
     pub server_port: u16,
+    /// Local address the connection was accepted on
+    pub local_addr: SocketAddr,
     /// Current state
     pub state: ConnectionState,
     /// Read buffer
@@ -34,99 +59,294 @@ pub struct Connection {
     pub write_buffer: Vec<u8>,
     /// Bytes written so far
     pub bytes_written: usize,
+    /// Remaining streamed response body, refilled into the write buffer
+    pub body_stream: Option<StreamBody>,
     /// When the connection was created
     pub created_at: Instant,
     /// Last activity time
     pub last_activity: Instant,
     /// Keep connection alive
     pub keep_alive: bool,
+    /// Headers of the pending request were already checked for early errors
+    pub headers_checked: bool,
+    /// Bytes of an unread request body to discard before the next request
+    pub discard_remaining: usize,
+    /// Body of the pending request, written to a temp file as it arrives
+    /// instead of to the read buffer
+    body_spool: Option<SpoolWriter>,
+    /// The pending request's body went to a temp file rather than the read
+    /// buffer, so none of it is left on the socket once complete
+    body_spooled: bool,
+    /// Scratch buffer each socket read goes into
+    read_chunk: Vec<u8>,
+    /// Size of each chunk sent from a streamed response body
+    write_chunk_size: usize,
+    /// Largest chunked request body taken in before it is rejected
+    max_body_size: usize,
+    /// Number of socket reads that returned data
+    pub read_calls: u64,
+    /// Number of responses queued on this connection
+    pub requests: u64,
+    /// Part of an interim (1xx) response the socket didn't take yet
+    interim: Vec<u8>,
+    /// When the connection entered its current state, or last moved bytes
+    /// while writing
+    state_since: Instant,
 }
 
 impl Connection {
     /// Creates a new connection
-    pub fn new(stream: TcpStream, addr: SocketAddr, server_port: u16) -> Self {
+    pub fn new(stream: ClientStream, addr: SocketAddr, server_port: u16) -> Self {
         let now = Instant::now();
+        let local_addr = stream.local_addr()
+            .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], server_port)));
         Connection {
             stream,
             addr,
             server_port,
+            local_addr,
             state: ConnectionState::Reading,
-            read_buffer: Vec::with_capacity(8192),
+            read_buffer: Vec::with_capacity(DEFAULT_READ_BUFFER_SIZE),
             write_buffer: Vec::new(),
             bytes_written: 0,
+            body_stream: None,
             created_at: now,
             last_activity: now,
             keep_alive: true,
+            headers_checked: false,
+            discard_remaining: 0,
+            body_spool: None,
+            body_spooled: false,
+            read_chunk: vec![0; DEFAULT_READ_BUFFER_SIZE],
+            write_chunk_size: DEFAULT_WRITE_BUFFER_SIZE,
+            max_body_size: usize::MAX,
+            read_calls: 0,
+            requests: 0,
+            interim: Vec::new(),
+            state_since: now,
         }
     }
 
+    /// Sets how much each socket read takes in and how large each chunk of
+    /// a streamed response body is
+    pub fn with_buffer_sizes(mut self, read_size: usize, write_size: usize) -> Self {
+        self.read_chunk = vec![0; read_size];
+        self.read_buffer = Vec::with_capacity(read_size);
+        self.write_chunk_size = write_size;
+        self
+    }
+
+    /// Sets the largest chunked request body taken in before it is rejected
+    pub fn with_max_body_size(mut self, max_body_size: usize) -> Self {
+        self.max_body_size = max_body_size;
+        self
+    }
+
     /// Returns the raw socket descriptor
     pub fn fd(&self) -> u64 {
-        self.stream.as_raw_socket() as u64
+        self.stream.fd()
     }
 
     /// Reads available data from the socket (non-blocking)
-    pub fn read(&mut self) -> Result<usize> {
-        let mut buf = [0u8; 8192];
-        
-        match self.stream.read(&mut buf) {
-            Ok(0) => {
-                // Connection closed by peer
-                self.state = ConnectionState::Closed;
-                Ok(0)
-            }
-            Ok(n) => {
-                self.read_buffer.extend_from_slice(&buf[..n]);
-                self.last_activity = Instant::now();
-                Ok(n)
-            }
-            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                Ok(0)
-            }
-            Err(e) => {
-                self.state = ConnectionState::Closed;
-                Err(ServerError::Io(e))
-            }
+    ///
+    /// Only a read returning 0 bytes is end-of-stream; `WouldBlock` (e.g. a
+    /// readable event another read already drained, or a TLS record still
+    /// incomplete) leaves the connection untouched. On end-of-stream the
+    /// connection is closed: reads only happen in `Reading`, so any request
+    /// still buffered is unfinished and can never complete
+    pub fn read(&mut self) -> Result<ReadOutcome> {
+        loop {
+            return match self.stream.read(&mut self.read_chunk) {
+                Ok(0) => {
+                    self.set_state(ConnectionState::Closed);
+                    Ok(ReadOutcome::Eof)
+                }
+                Ok(n) => {
+                    // Drop leftover body bytes of a request that was answered early
+                    let skip = n.min(self.discard_remaining);
+                    self.discard_remaining -= skip;
+                    let data = &self.read_chunk[skip..n];
+                    match self.body_spool.as_mut() {
+                        Some(spool) => {
+                            if let Err(e) = spool.write(data) {
+                                self.body_spool = None;
+                                self.set_state(ConnectionState::Closed);
+                                return Err(ServerError::Io(e));
+                            }
+                        }
+                        None => self.read_buffer.extend_from_slice(data),
+                    }
+                    self.read_calls += 1;
+                    self.last_activity = Instant::now();
+                    Ok(ReadOutcome::Data(n))
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => Ok(ReadOutcome::WouldBlock),
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    self.set_state(ConnectionState::Closed);
+                    Err(ServerError::Io(e))
+                }
+            };
         }
     }
 
     /// Writes data to the socket (non-blocking)
+    ///
+    /// Keeps writing the current buffer until it drains or the socket buffer
+    /// fills up (`WouldBlock`); a partial write is not an error and leaves the
+    /// connection in `Writing` so the next writable event resumes it
     pub fn write(&mut self) -> Result<usize> {
-        if self.bytes_written >= self.write_buffer.len() {
-            return Ok(0);
+        let mut total = 0;
+
+        while self.bytes_written < self.write_buffer.len() {
+            let remaining = &self.write_buffer[self.bytes_written..];
+
+            match self.stream.write(remaining) {
+                Ok(0) => {
+                    self.set_state(ConnectionState::Closed);
+                    return Ok(total);
+                }
+                Ok(n) => {
+                    self.bytes_written += n;
+                    total += n;
+                    self.last_activity = Instant::now();
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    return Ok(total);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    self.set_state(ConnectionState::Closed);
+                    return Err(ServerError::Io(e));
+                }
+            }
         }
 
-        let remaining = &self.write_buffer[self.bytes_written..];
-        
-        match self.stream.write(remaining) {
-            Ok(0) => {
-                self.state = ConnectionState::Closed;
-                Ok(0)
+        // Continue with the streamed body once the buffer is drained; one chunk
+        // goes out per writable event so other connections get a turn
+        if self.body_stream.is_some() {
+            total += self.send_stream()?;
+        }
+
+        if self.is_write_complete() {
+            // TLS may still hold encrypted output; finish sending it first
+            match self.stream.flush() {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(total),
+                Err(e) => {
+                    self.set_state(ConnectionState::Closed);
+                    return Err(ServerError::Io(e));
+                }
             }
-            Ok(n) => {
-                self.bytes_written += n;
+
+            if self.keep_alive {
+                // Reset for next request
+                self.read_buffer.clear();
+                self.write_buffer.clear();
+                self.bytes_written = 0;
+                self.headers_checked = false;
+                self.body_spooled = false;
+                self.set_state(ConnectionState::Reading);
+            } else {
+                self.set_state(ConnectionState::Closed);
+            }
+        } else if total > 0 {
+            // Still sending: progress keeps the watchdog away
+            self.state_since = Instant::now();
+        }
+
+        Ok(total)
+    }
+
+    /// Sends the next chunk of a file body straight from the kernel with
+    /// sendfile(2), skipping the copy through the write buffer; other bodies
+    /// are refilled into the buffer for the next write
+    #[cfg(target_os = "linux")]
+    fn send_stream(&mut self) -> Result<usize> {
+        use std::os::unix::io::AsRawFd;
+
+        // TLS must encrypt the body, so it can't go to the socket directly
+        let file_fd = match &self.body_stream {
+            Some(StreamBody::File(file)) if !self.stream.is_tls() => file.as_raw_fd(),
+            _ => {
+                self.refill_from_stream()?;
+                return Ok(0);
+            }
+        };
+
+        let mut total = 0;
+        while total < self.write_chunk_size {
+            // A null offset reads from (and advances) the file's own position
+            let n = unsafe {
+                libc::sendfile(
+                    self.fd() as libc::c_int,
+                    file_fd,
+                    std::ptr::null_mut(),
+                    self.write_chunk_size - total,
+                )
+            };
+
+            if n > 0 {
+                total += n as usize;
                 self.last_activity = Instant::now();
+                continue;
+            }
+            if n == 0 {
+                self.body_stream = None;
+                break;
+            }
 
-                // Check if we've written everything
-                if self.bytes_written >= self.write_buffer.len() {
-                    if self.keep_alive {
-                        // Reset for next request
-                        self.read_buffer.clear();
-                        self.write_buffer.clear();
-                        self.bytes_written = 0;
-                        self.state = ConnectionState::Reading;
-                    } else {
-                        self.state = ConnectionState::Closed;
-                    }
+            let err = std::io::Error::last_os_error();
+            match err.kind() {
+                std::io::ErrorKind::WouldBlock => break,
+                std::io::ErrorKind::Interrupted => {}
+                _ => {
+                    self.body_stream = None;
+                    self.set_state(ConnectionState::Closed);
+                    return Err(ServerError::Io(err));
                 }
+            }
+        }
+
+        Ok(total)
+    }
+
+    /// Refills the write buffer from the streamed body for the next write
+    #[cfg(not(target_os = "linux"))]
+    fn send_stream(&mut self) -> Result<usize> {
+        self.refill_from_stream()?;
+        Ok(0)
+    }
 
-                Ok(n)
+    /// Loads the next chunk of the streamed body into the write buffer
+    fn refill_from_stream(&mut self) -> Result<()> {
+        let stream = match self.body_stream.as_mut() {
+            Some(s) => s,
+            None => return Ok(()),
+        };
+
+        self.write_buffer.resize(self.write_chunk_size, 0);
+        self.bytes_written = 0;
+
+        match stream.read(&mut self.write_buffer) {
+            Ok(0) => {
+                self.write_buffer.clear();
+                self.body_stream = None;
+                Ok(())
+            }
+            Ok(n) => {
+                self.write_buffer.truncate(n);
+                Ok(())
             }
             Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                Ok(0)
+                // Nothing ready yet; try again on the next writable event
+                self.write_buffer.clear();
+                Ok(())
             }
             Err(e) => {
-                self.state = ConnectionState::Closed;
+                self.write_buffer.clear();
+                self.body_stream = None;
+                self.set_state(ConnectionState::Closed);
                 Err(ServerError::Io(e))
             }
         }
@@ -134,7 +354,85 @@ impl Connection {
 
     /// Checks if we have a complete request
     pub fn has_complete_request(&self) -> bool {
-        RequestParser::is_complete(&self.read_buffer)
+        match &self.body_spool {
+            Some(spool) => spool.remaining() == 0,
+            None => RequestParser::is_complete(&self.read_buffer, self.max_body_size),
+        }
+    }
+
+    /// Writes the pending request's body to a temp file in `dir` from now on,
+    /// moving the body bytes already buffered; the read buffer keeps only the
+    /// headers. Only bodies with a Content-Length are spooled this way
+    pub fn start_spool(&mut self, dir: &Path) -> Result<()> {
+        let body_start = match RequestParser::body_offset(&self.read_buffer) {
+            Some(pos) => pos,
+            None => return Ok(()),
+        };
+        let len = match RequestParser::get_content_length(&self.read_buffer) {
+            Some(len) => len,
+            None => return Ok(()),
+        };
+
+        let mut spool = SpoolWriter::create(dir, len as u64)?;
+        spool.write(&self.read_buffer[body_start..])?;
+        self.read_buffer.truncate(body_start);
+        self.body_spool = Some(spool);
+        self.body_spooled = true;
+        Ok(())
+    }
+
+    /// Takes the spooled body of a complete request
+    pub fn take_spooled_body(&mut self) -> Option<SpooledBody> {
+        self.body_spool.take().map(SpoolWriter::finish)
+    }
+
+    /// Checks if the request headers have been received
+    pub fn has_complete_headers(&self) -> bool {
+        RequestParser::has_complete_headers(&self.read_buffer)
+    }
+
+    /// Returns how many declared body bytes haven't been received yet,
+    /// or None when that can't be known (chunked body still in flight)
+    fn unread_body_len(&self) -> Option<usize> {
+        if let Some(spool) = &self.body_spool {
+            return Some(spool.remaining() as usize);
+        }
+        if self.body_spooled {
+            return Some(0);
+        }
+
+        let body_start = match RequestParser::body_offset(&self.read_buffer) {
+            Some(pos) => pos,
+            None => return Some(0),
+        };
+
+        if RequestParser::is_complete(&self.read_buffer, self.max_body_size) {
+            return Some(0);
+        }
+
+        if RequestParser::is_chunked(&self.read_buffer) {
+            return None;
+        }
+
+        let received = self.read_buffer.len() - body_start;
+        let declared = RequestParser::get_content_length(&self.read_buffer).unwrap_or(0);
+        Some(declared.saturating_sub(received))
+    }
+
+    /// Checks if the client is waiting for 100 Continue before sending its body
+    fn expects_continue(&self) -> bool {
+        RequestParser::parse(&self.read_buffer)
+            .ok()
+            .and_then(|r| r.headers.get("expect").map(|v| v.eq_ignore_ascii_case("100-continue")))
+            .unwrap_or(false)
+    }
+
+    /// Checks if the buffered data can never become a valid request
+    pub fn has_malformed_request(&self) -> bool {
+        !matches!(
+            RequestParser::check_partial(&self.read_buffer),
+            Err(ServerError::Incomplete)
+        )
     }
 
     /// Parses the request from the read buffer
@@ -142,21 +440,313 @@ impl Connection {
         RequestParser::parse(&self.read_buffer)
     }
 
+    /// Marks the connection closed and releases its pending response,
+    /// including any open file being streamed
+    pub fn close(&mut self) {
+        self.set_state(ConnectionState::Closed);
+        self.body_stream = None;
+        self.body_spool = None;
+        self.write_buffer = Vec::new();
+        self.read_buffer = Vec::new();
+    }
+
+    /// Aborts the connection with a TCP RST instead of a graceful FIN
+    ///
+    /// Sets SO_LINGER to zero so the close that follows discards unsent data
+    /// and resets the connection
+    pub fn reset(&mut self) -> Result<()> {
+        set_linger_zero(self.fd())?;
+        self.set_state(ConnectionState::Closed);
+        Ok(())
+    }
+
+    /// Turns on TCP keepalive probes so a peer that vanished without closing
+    /// is detected on an idle keep-alive connection; a no-op on Unix sockets
+    pub fn set_tcp_keepalive(&self, keepalive: &TcpKeepalive) -> Result<()> {
+        if self.stream.is_unix() {
+            return Ok(());
+        }
+        set_keepalive(self.fd(), keepalive)
+    }
+
     /// Sets the response to send
-    pub fn set_response(&mut self, response: Response) {
-        self.write_buffer = response.to_bytes();
+    pub fn set_response(&mut self, mut response: Response) {
+        // A response sent before the whole body arrived leaves unread bytes on
+        // the socket: drain them to keep the connection in sync, or close when
+        // draining isn't possible or safe
+        match self.unread_body_len() {
+            Some(0) => {}
+            Some(n) if n <= MAX_DRAIN_SIZE && !self.expects_continue() => {
+                self.discard_remaining = n;
+            }
+            _ => {
+                response.headers.set("Connection", "close");
+            }
+        }
+        // A body the response didn't wait for is no longer wanted
+        self.body_spool = None;
+
+        self.requests += 1;
+        self.body_stream = response.stream.take();
+        self.write_buffer = std::mem::take(&mut self.interim);
+        self.write_buffer.extend_from_slice(&response.to_bytes());
         self.bytes_written = 0;
-        self.state = ConnectionState::Writing;
+        self.set_state(ConnectionState::Writing);
         self.keep_alive = response.headers.keep_alive();
     }
 
+    /// Sends an interim (1xx) response ahead of the final one, right away as
+    /// far as the socket takes it; the rest goes out before the final response
+    pub fn send_interim(&mut self, response: &Response) {
+        let bytes = response.to_bytes();
+        let mut sent = 0;
+        while sent < bytes.len() {
+            match self.stream.write(&bytes[sent..]) {
+                Ok(0) => break,
+                Ok(n) => sent += n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(_) => break,
+            }
+        }
+        // TLS holds the record until flushed
+        let _ = self.stream.flush();
+        self.interim.extend_from_slice(&bytes[sent..]);
+    }
+
     /// Checks if the connection has timed out
     pub fn is_timed_out(&self, timeout_secs: u64) -> bool {
         self.last_activity.elapsed().as_secs() > timeout_secs
     }
 
+    /// Earliest time `is_timed_out` can become true, given no further activity
+    pub fn timeout_deadline(&self, timeout_secs: u64) -> Instant {
+        self.last_activity + Duration::from_secs(timeout_secs + 1)
+    }
+
+    /// Moves the connection to a state, restarting the time spent in it
+    /// when the state changes
+    pub fn set_state(&mut self, state: ConnectionState) {
+        if self.state != state {
+            self.state = state;
+            self.state_since = Instant::now();
+        }
+    }
+
+    /// Returns how long the connection has been in its current state; a
+    /// response still being written counts from the last bytes sent
+    pub fn time_in_state(&self) -> Duration {
+        self.state_since.elapsed()
+    }
+
     /// Checks if writing is complete
     pub fn is_write_complete(&self) -> bool {
-        self.bytes_written >= self.write_buffer.len()
+        self.bytes_written >= self.write_buffer.len() && self.body_stream.is_none()
+    }
+}
+
+/// Sets SO_LINGER with a zero timeout on a socket
+#[cfg(windows)]
+fn set_linger_zero(socket: u64) -> Result<()> {
+    #[repr(C)]
+    struct Linger {
+        l_onoff: u16,
+        l_linger: u16,
+    }
+
+    #[link(name = "ws2_32")]
+    extern "system" {
+        fn setsockopt(s: usize, level: i32, optname: i32, optval: *const u8, optlen: i32) -> i32;
+    }
+
+    const SOL_SOCKET: i32 = 0xffff;
+    const SO_LINGER: i32 = 0x0080;
+
+    let linger = Linger { l_onoff: 1, l_linger: 0 };
+    let ret = unsafe {
+        setsockopt(
+            socket as usize,
+            SOL_SOCKET,
+            SO_LINGER,
+            &linger as *const Linger as *const u8,
+            std::mem::size_of::<Linger>() as i32,
+        )
+    };
+
+    if ret != 0 {
+        return Err(ServerError::Io(std::io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+/// Sets SO_LINGER with a zero timeout on a socket
+#[cfg(not(windows))]
+fn set_linger_zero(fd: u64) -> Result<()> {
+    let linger = libc::linger { l_onoff: 1, l_linger: 0 };
+    let ret = unsafe {
+        libc::setsockopt(
+            fd as libc::c_int,
+            libc::SOL_SOCKET,
+            libc::SO_LINGER,
+            &linger as *const libc::linger as *const libc::c_void,
+            std::mem::size_of::<libc::linger>() as libc::socklen_t,
+        )
+    };
+
+    if ret != 0 {
+        return Err(ServerError::Io(std::io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+/// Enables SO_KEEPALIVE on a socket (probe timing isn't set on Windows)
+#[cfg(windows)]
+fn set_keepalive(socket: u64, _keepalive: &TcpKeepalive) -> Result<()> {
+    #[link(name = "ws2_32")]
+    extern "system" {
+        fn setsockopt(s: usize, level: i32, optname: i32, optval: *const u8, optlen: i32) -> i32;
+    }
+
+    const SOL_SOCKET: i32 = 0xffff;
+    const SO_KEEPALIVE: i32 = 0x0008;
+
+    let on: i32 = 1;
+    let ret = unsafe {
+        setsockopt(
+            socket as usize,
+            SOL_SOCKET,
+            SO_KEEPALIVE,
+            &on as *const i32 as *const u8,
+            std::mem::size_of::<i32>() as i32,
+        )
+    };
+
+    if ret != 0 {
+        return Err(ServerError::Io(std::io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+/// Enables SO_KEEPALIVE on a socket, plus the probe timing on Linux
+#[cfg(not(windows))]
+fn set_keepalive(fd: u64, keepalive: &TcpKeepalive) -> Result<()> {
+    set_int_option(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+
+    #[cfg(target_os = "linux")]
+    {
+        let timing = [
+            (libc::TCP_KEEPIDLE, keepalive.idle),
+            (libc::TCP_KEEPINTVL, keepalive.interval),
+            (libc::TCP_KEEPCNT, keepalive.count),
+        ];
+        for (option, value) in timing {
+            if let Some(value) = value {
+                set_int_option(fd, libc::IPPROTO_TCP, option, value as libc::c_int)?;
+            }
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = keepalive;
+
+    Ok(())
+}
+
+/// Sets an integer socket option
+#[cfg(not(windows))]
+fn set_int_option(fd: u64, level: libc::c_int, option: libc::c_int, value: libc::c_int) -> Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd as libc::c_int,
+            level,
+            option,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+
+    if ret != 0 {
+        return Err(ServerError::Io(std::io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+
+    /// Reads a 48 KiB request through a connection with the given read size
+    /// and returns how many socket reads it took
+    fn reads_for_request(read_size: usize) -> u64 {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        server.set_nonblocking(true).unwrap();
+        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        let mut conn = Connection::new(ClientStream::Unix(server), addr, 8080)
+            .with_buffer_sizes(read_size, DEFAULT_WRITE_BUFFER_SIZE);
+
+        let body = vec![b'x'; 48 * 1024];
+        let head = format!("POST /upload HTTP/1.1\r\nHost: x\r\nContent-Length: {}\r\n\r\n", body.len());
+        client.write_all(head.as_bytes()).unwrap();
+        client.write_all(&body).unwrap();
+
+        while let ReadOutcome::Data(_) = conn.read().unwrap() {}
+        assert_eq!(conn.read_buffer.len(), head.len() + body.len());
+        conn.read_calls
+    }
+
+    #[test]
+    fn larger_read_buffer_needs_fewer_reads() {
+        let small = reads_for_request(DEFAULT_READ_BUFFER_SIZE);
+        let large = reads_for_request(64 * 1024);
+        assert!(small >= 6, "{}", small);
+        assert_eq!(large, 1);
+    }
+
+
+    /// Reads an integer socket option
+    fn int_option(fd: u64, level: libc::c_int, option: libc::c_int) -> libc::c_int {
+        let mut value: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(fd as libc::c_int, level, option, &mut value as *mut libc::c_int as *mut libc::c_void, &mut len)
+        };
+        assert_eq!(ret, 0, "{}", std::io::Error::last_os_error());
+        value
+    }
+
+    #[test]
+    fn tcp_keepalive_is_set_on_accepted_sockets() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, addr) = listener.accept().unwrap();
+        let conn = Connection::new(ClientStream::Tcp(stream), addr, 8080);
+        assert_eq!(int_option(conn.fd(), libc::SOL_SOCKET, libc::SO_KEEPALIVE), 0);
+
+        let keepalive = TcpKeepalive { idle: Some(60), interval: Some(10), count: Some(5) };
+        conn.set_tcp_keepalive(&keepalive).unwrap();
+        assert_ne!(int_option(conn.fd(), libc::SOL_SOCKET, libc::SO_KEEPALIVE), 0);
+        #[cfg(target_os = "linux")]
+        {
+            assert_eq!(int_option(conn.fd(), libc::IPPROTO_TCP, libc::TCP_KEEPIDLE), 60);
+            assert_eq!(int_option(conn.fd(), libc::IPPROTO_TCP, libc::TCP_KEEPINTVL), 10);
+            assert_eq!(int_option(conn.fd(), libc::IPPROTO_TCP, libc::TCP_KEEPCNT), 5);
+        }
+    }
+
+
+    #[test]
+    fn would_block_is_not_end_of_stream() {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        server.set_nonblocking(true).unwrap();
+        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        let mut conn = Connection::new(ClientStream::Unix(server), addr, 8080);
+
+        assert!(matches!(conn.read().unwrap(), ReadOutcome::WouldBlock));
+        assert_eq!(conn.state, ConnectionState::Reading);
+
+        client.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        assert!(matches!(conn.read().unwrap(), ReadOutcome::Data(16)));
+        assert!(matches!(conn.read().unwrap(), ReadOutcome::Eof));
+        assert_eq!(conn.state, ConnectionState::Closed);
     }
 }
@@ -48,11 +48,12 @@ impl Poller {
 
     /// Modifies the event type for a registered socket
     pub fn modify(&mut self, fd: u64, event_type: EventType) -> Result<()> {
-        if self.registered.contains_key(&fd) {
-            self.registered.insert(fd, event_type);
-            Ok(())
-        } else {
-            Err(ServerError::Internal("Socket not registered".to_string()))
+        match self.registered.get_mut(&fd) {
+            Some(registered) => {
+                *registered = event_type;
+                Ok(())
+            }
+            None => Err(ServerError::Internal("Socket not registered".to_string())),
         }
     }
 
@@ -66,9 +67,6 @@ impl Poller {
     /// This is the ONLY poll call per event loop iteration as required by spec
     /// On Windows, we use a polling approach since libc doesn't expose select properly
     pub fn wait(&mut self, timeout: Option<Duration>) -> Result<Vec<Event>> {
-        use std::io::{Read, Write};
-        use std::net::TcpStream;
-        
         if self.registered.is_empty() {
             if let Some(t) = timeout {
                 std::thread::sleep(t.min(Duration::from_millis(100)));
@@ -1,12 +1,19 @@
-use super::connection::{Connection, ConnectionState};
+use super::connection::{Connection, ConnectionState, ReadOutcome, DEFAULT_READ_BUFFER_SIZE, DEFAULT_WRITE_BUFFER_SIZE};
 use super::epoll::{EventType, Poller};
 use super::listener::Listener;
+use super::stats::ConnectionStats;
 use crate::config::Config;
-use crate::error::Result;
+use crate::error::{Result, ServerError};
 use crate::http::Response;
-use crate::router::Handler;
-use std::collections::HashMap;
-use std::time::Duration;
+use crate::router::{Handler, HeaderCheck};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// Set by SIGINT/SIGTERM so the loop exits and listeners clean up
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
 
 /// Main event loop for the server
 pub struct EventLoop {
@@ -18,8 +25,21 @@ pub struct EventLoop {
     listeners: HashMap<u64, Listener>,
     /// Active connections (fd -> Connection)
     connections: HashMap<u64, Connection>,
+    /// Idle deadlines (earliest first), checked lazily against each
+    /// connection's last activity when they come due
+    timers: BinaryHeap<Reverse<(Instant, u64)>>,
+    /// Idle timeout in seconds
+    timeout: u64,
+    /// Abort timed out connections with a RST
+    reset_timedout: bool,
+    /// Seconds a connection may stay in one state (0 = no watchdog)
+    watchdog_timeout: u64,
+    /// When the watchdog last checked the connections
+    last_watchdog: Instant,
     /// Request handler
     handler: Handler,
+    /// Connection reuse counters
+    stats: ConnectionStats,
     /// Running flag
     running: bool,
 }
@@ -27,15 +47,31 @@ pub struct EventLoop {
 impl EventLoop {
     /// Creates a new event loop with the given configuration
     pub fn new(config: Config) -> Result<Self> {
-        let poller = Poller::new()?;
         let handler = Handler::new(config.clone());
+        Self::with_handler(config, handler)
+    }
+
+    /// Creates a new event loop dispatching to a prepared handler
+    /// (e.g. one with custom route handlers registered)
+    pub fn with_handler(config: Config, handler: Handler) -> Result<Self> {
+        let poller = Poller::new()?;
+
+        let (timeout, reset_timedout, watchdog_timeout) = config.servers.first()
+            .map(|s| (s.timeout, s.reset_timedout_connection, s.watchdog_timeout))
+            .unwrap_or((60, false, 0));
 
         Ok(EventLoop {
             config,
             poller,
             listeners: HashMap::new(),
             connections: HashMap::new(),
+            timers: BinaryHeap::new(),
+            timeout,
+            reset_timedout,
+            watchdog_timeout,
+            last_watchdog: Instant::now(),
             handler,
+            stats: ConnectionStats::default(),
             running: false,
         })
     }
@@ -47,19 +83,26 @@ impl EventLoop {
         // Create listeners for all configured addresses (deduplicated)
         let addresses = self.config.get_listen_addresses();
         for (host, port) in addresses {
-            println!("Listening on {}:{}", host, port);
-            let listener = Listener::bind(&host, port)?;
+            let mut listener = Listener::bind(&host, port)?;
+            let ssl = self.config.is_ssl_address(&host, port);
+            if ssl {
+                listener = self.enable_tls(listener, &host, port)?;
+            }
+            log_info!("Listening on {}:{}{}", host, port, if ssl { " (ssl)" } else { "" });
             let fd = listener.fd();
             self.poller.register(fd, EventType::Read)?;
             self.listeners.insert(fd, listener);
         }
 
-        println!("Server started, waiting for connections...");
+        self.bind_unix_sockets()?;
+
+        log_info!("Server started, waiting for connections...");
 
         // Main event loop
-        while self.running {
+        while self.running && !SHUTDOWN.load(Ordering::SeqCst) {
             // Poll for events with 100ms timeout
             let events = self.poller.wait(Some(Duration::from_millis(100)))?;
+            let mut touched = Vec::new();
 
             for event in events {
                 if self.listeners.contains_key(&event.fd) {
@@ -68,6 +111,8 @@ impl EventLoop {
                         self.accept_connection(event.fd)?;
                     }
                 } else if self.connections.contains_key(&event.fd) {
+                    touched.push(event.fd);
+
                     // Get connection state first
                     let state = self.connections.get(&event.fd)
                         .map(|c| c.state)
@@ -84,12 +129,68 @@ impl EventLoop {
             }
 
             // Check for timeouts and process pending requests
-            self.process_connections()?;
+            self.process_connections(&touched)?;
+        }
+
+        Ok(())
+    }
+
+    /// Sets up TLS on an `ssl` listener, with certificates chosen by SNI
+    #[cfg(feature = "tls")]
+    fn enable_tls(&self, listener: Listener, host: &str, port: u16) -> Result<Listener> {
+        let tls = super::tls::Tls::server_config(&self.config, host, port)?;
+        Ok(listener.with_tls(tls))
+    }
+
+    #[cfg(not(feature = "tls"))]
+    fn enable_tls(&self, _listener: Listener, host: &str, port: u16) -> Result<Listener> {
+        Err(ServerError::Config(format!(
+            "{}:{} uses ssl, but TLS support is not built in (build with --features tls)",
+            host, port
+        )))
+    }
+
+    /// Binds every configured Unix domain socket
+    /// Their connections are matched to servers by the server's `unix_port`
+    #[cfg(unix)]
+    fn bind_unix_sockets(&mut self) -> Result<()> {
+        let mut bound = false;
+
+        for server in &self.config.servers {
+            let port = server.unix_port;
+            for path in &server.unix_sockets {
+                log_info!("Listening on unix:{}", path);
+                let listener = Listener::bind_unix(path, port, server.unix_socket_mode)?;
+                let fd = listener.fd();
+                self.poller.register(fd, EventType::Read)?;
+                self.listeners.insert(fd, listener);
+                bound = true;
+            }
+        }
+
+        // Exit the loop on a signal so the socket files get removed
+        if bound {
+            extern "C" fn on_signal(_: libc::c_int) {
+                SHUTDOWN.store(true, Ordering::SeqCst);
+            }
+            let handler = on_signal as extern "C" fn(libc::c_int) as *const () as libc::sighandler_t;
+            unsafe {
+                libc::signal(libc::SIGINT, handler);
+                libc::signal(libc::SIGTERM, handler);
+            }
         }
 
         Ok(())
     }
 
+    #[cfg(not(unix))]
+    fn bind_unix_sockets(&mut self) -> Result<()> {
+        if self.config.servers.iter().any(|s| !s.unix_sockets.is_empty()) {
+            return Err(ServerError::Config("Unix domain sockets are not supported on this platform".to_string()));
+        }
+        Ok(())
+    }
+
     /// Accepts a new connection
     fn accept_connection(&mut self, listener_fd: u64) -> Result<()> {
         let listener = match self.listeners.get(&listener_fd) {
@@ -99,14 +200,40 @@ impl EventLoop {
 
         let port = listener.port();
 
+        let server = self.config.find_server(port, None);
+        let max_connections = server.map(|s| s.max_connections).unwrap_or(0);
+        let (read_size, write_size) = server
+            .map(|s| (s.read_buffer_size, s.write_buffer_size))
+            .unwrap_or((DEFAULT_READ_BUFFER_SIZE, DEFAULT_WRITE_BUFFER_SIZE));
+        let so_keepalive = server.and_then(|s| s.so_keepalive);
+        let max_body_size = self.config.max_body_size_on(port);
+
         // Accept all pending connections
         while let Some((stream, addr)) = listener.accept()? {
-            let conn = Connection::new(stream, addr, port);
+            // Over the hard cap: close right away
+            if max_connections > 0 && self.connections.len() >= max_connections {
+                log_warn!("Connection limit reached, refusing {}", addr);
+                drop(stream);
+                continue;
+            }
+
+            let conn = Connection::new(stream, addr, port)
+                .with_buffer_sizes(read_size, write_size)
+                .with_max_body_size(max_body_size);
             let fd = conn.fd();
+            log_debug!("[{}] accepted {} on port {}", fd, addr, port);
+
+            if let Some(keepalive) = &so_keepalive {
+                if let Err(e) = conn.set_tcp_keepalive(keepalive) {
+                    log_warn!("[{}] failed to enable TCP keepalive: {}", fd, e);
+                }
+            }
 
             // Register for read events
             self.poller.register(fd, EventType::Read)?;
+            self.timers.push(Reverse((conn.timeout_deadline(self.timeout), fd)));
             self.connections.insert(fd, conn);
+            self.stats.accepted += 1;
         }
 
         Ok(())
@@ -119,12 +246,54 @@ impl EventLoop {
             None => return Ok(()),
         };
 
-        // Read available data
-        conn.read()?;
-
-        // Check if we have a complete request
-        if conn.has_complete_request() {
-            conn.state = ConnectionState::Processing;
+        // Read available data; a failed socket only ends this connection
+        let n = match conn.read() {
+            Ok(ReadOutcome::Data(n)) => n,
+            // Spurious wakeup: nothing changed, so there is nothing to re-check
+            Ok(ReadOutcome::WouldBlock) => return Ok(()),
+            Ok(ReadOutcome::Eof) => {
+                if conn.read_buffer.is_empty() {
+                    log_debug!("[{}] peer closed", fd);
+                } else {
+                    log_debug!("[{}] peer closed mid-request ({} bytes buffered)", fd, conn.read_buffer.len());
+                }
+                return Ok(());
+            }
+            Err(e) => {
+                Self::log_connection_error(fd, "read", &e);
+                conn.close();
+                return Ok(());
+            }
+        };
+        log_debug!("[{}] read {} bytes ({} buffered)", fd, n, conn.read_buffer.len());
+
+        // Check if we have a complete request, or one that can never complete
+        // (the latter gets a 400 and the connection is closed)
+        if conn.has_complete_request() || conn.has_malformed_request() {
+            log_debug!("[{}] Reading -> Processing", fd);
+            conn.set_state(ConnectionState::Processing);
+        } else if conn.has_complete_headers() && !conn.headers_checked {
+            // Reject early (e.g. oversized Content-Length) without waiting for the body
+            conn.headers_checked = true;
+            match self.handler.check_headers(&conn.read_buffer, conn.server_port, conn.addr, conn.stream.server_name()) {
+                HeaderCheck::Respond(response) => {
+                    conn.set_response(response);
+                    self.poller.modify(fd, EventType::Write)?;
+                }
+                HeaderCheck::Spool(dir) => match conn.start_spool(&dir) {
+                    Ok(()) if conn.has_complete_request() => {
+                        log_debug!("[{}] Reading -> Processing", fd);
+                        conn.set_state(ConnectionState::Processing);
+                    }
+                    Ok(()) => log_debug!("[{}] spooling request body to {}", fd, dir.display()),
+                    Err(e) => {
+                        log_error!("[{}] failed to spool request body: {}", fd, e);
+                        conn.set_response(Response::internal_error().html("<h1>500 Internal Server Error</h1>"));
+                        self.poller.modify(fd, EventType::Write)?;
+                    }
+                },
+                HeaderCheck::Continue => {}
+            }
         }
 
         Ok(())
@@ -137,16 +306,30 @@ impl EventLoop {
             None => return Ok(()),
         };
 
-        // Write data
-        conn.write()?;
+        // Write data; a failed socket only ends this connection
+        let n = match conn.write() {
+            Ok(n) => n,
+            Err(e) => {
+                Self::log_connection_error(fd, "write", &e);
+                conn.close();
+                return Ok(());
+            }
+        };
+        log_debug!("[{}] wrote {} bytes", fd, n);
 
         // Update poller based on new state
         match conn.state {
             ConnectionState::Reading => {
+                log_debug!("[{}] Writing -> Reading (keep-alive)", fd);
                 self.poller.modify(fd, EventType::Read)?;
             }
             ConnectionState::Closed => {
                 // Will be cleaned up in process_connections
+                log_debug!("[{}] Writing -> Closed", fd);
+            }
+            ConnectionState::Writing if !conn.is_write_complete() => {
+                // Partial write: stay armed for the next writable event
+                self.poller.modify(fd, EventType::Write)?;
             }
             _ => {}
         }
@@ -154,22 +337,116 @@ impl EventLoop {
         Ok(())
     }
 
-    /// Processes all connections (timeouts, pending requests)
-    fn process_connections(&mut self) -> Result<()> {
-        let mut to_remove = Vec::new();
-        let mut to_process = Vec::new();
+    /// Logs a connection I/O failure: a client that went away is routine,
+    /// anything else is worth a warning
+    fn log_connection_error(fd: u64, operation: &str, error: &ServerError) {
+        let disconnected = matches!(
+            error,
+            ServerError::Io(e) if matches!(
+                e.kind(),
+                ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe | ErrorKind::UnexpectedEof
+            )
+        );
+
+        if disconnected {
+            log_debug!("[{}] client disconnected during {}: {}", fd, operation, error);
+        } else {
+            log_warn!("[{}] {} failed: {}", fd, operation, error);
+        }
+    }
+
+    /// Pops the idle deadlines that have come due and returns the
+    /// connections that really timed out
+    ///
+    /// A connection active since its deadline was scheduled is rescheduled
+    /// instead, so only due entries are examined rather than every connection
+    fn expire_timeouts(&mut self) -> Vec<u64> {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+
+        while let Some(&Reverse((deadline, fd))) = self.timers.peek() {
+            if deadline > now {
+                break;
+            }
+            self.timers.pop();
+
+            let conn = match self.connections.get(&fd) {
+                Some(c) => c,
+                None => continue,
+            };
+
+            // Left over from an earlier connection that reused this fd
+            if deadline < conn.created_at + Duration::from_secs(self.timeout + 1) {
+                continue;
+            }
+
+            if conn.is_timed_out(self.timeout) {
+                expired.push(fd);
+            } else {
+                self.timers.push(Reverse((conn.timeout_deadline(self.timeout), fd)));
+            }
+        }
 
-        // Find connections to process or remove
+        expired
+    }
+
+    /// Returns the connections stuck in one state past the watchdog timeout,
+    /// or left Closed without being removed. The idle timeout only catches
+    /// connections that go quiet; this catches any a bug leaves wedged
+    /// (never processed, or trickling forever). Runs at most once a second
+    fn watchdog(&mut self) -> Vec<u64> {
+        if self.watchdog_timeout == 0 || self.last_watchdog.elapsed() < Duration::from_secs(1) {
+            return Vec::new();
+        }
+        self.last_watchdog = Instant::now();
+
+        let limit = Duration::from_secs(self.watchdog_timeout);
+        let mut stuck = Vec::new();
         for (&fd, conn) in &self.connections {
-            // Check for timeout
-            let timeout = self.config.servers.first()
-                .map(|s| s.timeout)
-                .unwrap_or(60);
+            let held = conn.time_in_state();
+            // A Closed connection should be gone by the next check
+            let leaked = conn.state == ConnectionState::Closed && held >= Duration::from_secs(1);
+            if leaked || held > limit {
+                log_warn!(
+                    "[{}] watchdog: connection from {} stuck {:?} for {}s, closing",
+                    fd, conn.addr, conn.state, held.as_secs()
+                );
+                stuck.push(fd);
+            }
+        }
+        stuck
+    }
+
+    /// Processes timed out connections and those with new events
+    fn process_connections(&mut self, touched: &[u64]) -> Result<()> {
+        let mut to_remove = Vec::new();
+        let mut to_reset = Vec::new();
+        let mut to_process = Vec::new();
 
-            if conn.is_timed_out(timeout) {
+        for fd in self.expire_timeouts() {
+            if let Some(conn) = self.connections.get(&fd) {
+                log_warn!("[{}] connection from {} timed out", fd, conn.addr);
+            }
+            if self.reset_timedout {
+                to_reset.push(fd);
+            }
+            to_remove.push(fd);
+        }
+        for fd in self.watchdog() {
+            if !to_remove.contains(&fd) {
                 to_remove.push(fd);
+            }
+        }
+
+        // Only connections that saw events can have changed state
+        for &fd in touched {
+            if to_remove.contains(&fd) {
                 continue;
             }
+            let conn = match self.connections.get(&fd) {
+                Some(c) => c,
+                None => continue,
+            };
 
             // Check for closed connections
             if conn.state == ConnectionState::Closed {
@@ -184,43 +461,62 @@ impl EventLoop {
         }
 
         // Process pending requests
+        self.handler.set_active_connections(self.connections.len());
+        self.stats.requests += to_process.len() as u64;
+        self.handler.set_connection_stats(self.stats);
         for fd in to_process {
+            let mut send_now = false;
             if let Some(conn) = self.connections.get_mut(&fd) {
-                let response = match conn.parse_request() {
-                    Ok(request) => {
-                        // Find the right server config using Host header for virtual hosting
-                        let host_header = request.host().unwrap_or("localhost");
-                        let server_config = self.config.find_server_by_host(host_header, conn.server_port)
-                            .or_else(|| self.config.servers.first());
-
-                        if let Some(server) = server_config {
-                            // Check body size
-                            if !server.is_body_size_allowed(request.body.len()) {
-                                Response::payload_too_large()
-                                    .html("<h1>413 Payload Too Large</h1>")
-                            } else {
-                                self.handler.handle(&request, server)
-                            }
-                        } else {
-                            Response::internal_error()
-                                .html("<h1>500 Internal Server Error</h1>")
-                        }
-                    }
-                    Err(_) => {
-                        Response::bad_request()
-                            .html("<h1>400 Bad Request</h1>")
-                    }
-                };
+                let body = conn.take_spooled_body();
+                let (local, peer) = (conn.local_addr, conn.addr);
+                let sni = conn.stream.server_name().map(str::to_string);
+                // The request bytes are lent out so interim responses can be
+                // written to the connection meanwhile
+                let data = std::mem::take(&mut conn.read_buffer);
+                let response = self.handler.respond(&data, body, local, peer, sni.as_deref(), &mut |hints| {
+                    conn.send_interim(hints);
+                });
+                conn.read_buffer = data;
+                log_debug!("[{}] Processing -> Writing ({})", fd, response.status_code());
+
+                let threshold = self.config.find_server(conn.server_port, None)
+                    .map(|s| s.response_buffer_size)
+                    .unwrap_or(0);
 
                 conn.set_response(response);
                 self.poller.modify(fd, EventType::Write)?;
+
+                // Small, fully buffered responses go out right away in one write
+                send_now = conn.body_stream.is_none() && conn.write_buffer.len() <= threshold;
+            }
+
+            if send_now {
+                self.handle_write(fd)?;
+                if self.connections.get(&fd).map(|c| c.state) == Some(ConnectionState::Closed) {
+                    to_remove.push(fd);
+                }
+            }
+        }
+
+        // Abort timed out connections with a RST when configured to
+        for fd in to_reset {
+            if let Some(conn) = self.connections.get_mut(&fd) {
+                if let Err(e) = conn.reset() {
+                    log_warn!("[{}] failed to reset connection: {}", fd, e);
+                }
             }
         }
 
         // Remove closed/timed out connections
         for fd in to_remove {
+            let (reads, requests) = self.connections.get(&fd)
+                .map(|c| (c.read_calls, c.requests))
+                .unwrap_or((0, 0));
+            log_debug!("[{}] connection removed after {} reads, {} requests", fd, reads, requests);
             self.poller.unregister(fd)?;
-            self.connections.remove(&fd);
+            if self.connections.remove(&fd).is_some() {
+                self.stats.record_close(requests);
+            }
         }
 
         Ok(())
@@ -1,11 +1,38 @@
+use super::stream::ClientStream;
 use crate::error::{Result, ServerError};
 use std::net::{TcpListener, TcpStream, SocketAddr};
+
+#[cfg(windows)]
 use std::os::windows::io::AsRawSocket;
 
-/// TCP listener wrapper for accepting connections
+#[cfg(not(windows))]
+use std::os::unix::io::AsRawFd;
+
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+#[cfg(unix)]
+use std::path::PathBuf;
+
+#[cfg(feature = "tls")]
+use super::tls::Tls;
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+
+/// The underlying listening socket
+enum Socket {
+    Tcp(TcpListener),
+    /// A Unix domain socket and the path it was bound to
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+/// Listener wrapper for accepting connections
 pub struct Listener {
-    inner: TcpListener,
+    inner: Socket,
     addr: SocketAddr,
+    /// TLS settings for `ssl` ports; accepted connections start a handshake
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<rustls::ServerConfig>>,
 }
 
 impl Listener {
@@ -19,15 +46,64 @@ impl Listener {
         listener.set_nonblocking(true)?;
 
         Ok(Listener {
-            inner: listener,
+            inner: Socket::Tcp(listener),
             addr,
+            #[cfg(feature = "tls")]
+            tls: None,
+        })
+    }
+
+    /// Terminates TLS on every connection this listener accepts
+    #[cfg(feature = "tls")]
+    pub fn with_tls(mut self, config: Arc<rustls::ServerConfig>) -> Self {
+        self.tls = Some(config);
+        self
+    }
+
+    /// Creates a listener on a Unix domain socket
+    /// Connections are matched to servers as if they arrived on `port`.
+    /// A stale socket file left by an earlier run is replaced, and `mode`
+    /// sets the file's permissions when given
+    #[cfg(unix)]
+    pub fn bind_unix(path: &str, port: u16, mode: Option<u32>) -> Result<Self> {
+        use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+        let socket_path = PathBuf::from(path);
+        if let Ok(metadata) = std::fs::symlink_metadata(&socket_path) {
+            if !metadata.file_type().is_socket() {
+                return Err(ServerError::Config(format!("{} exists and is not a socket", path)));
+            }
+            std::fs::remove_file(&socket_path)?;
+        }
+
+        let listener = UnixListener::bind(&socket_path)?;
+        listener.set_nonblocking(true)?;
+
+        if let Some(mode) = mode {
+            std::fs::set_permissions(&socket_path, std::fs::Permissions::from_mode(mode))?;
+        }
+
+        Ok(Listener {
+            inner: Socket::Unix(listener, socket_path),
+            addr: SocketAddr::from(([0, 0, 0, 0], port)),
+            #[cfg(feature = "tls")]
+            tls: None,
         })
     }
 
     /// Accepts a new connection (non-blocking)
-    /// Returns None if no connection is pending
-    pub fn accept(&self) -> Result<Option<(TcpStream, SocketAddr)>> {
-        match self.inner.accept() {
+    /// Returns None if no connection is pending. Unix socket peers have no
+    /// address and are reported as 0.0.0.0:0
+    pub fn accept(&self) -> Result<Option<(ClientStream, SocketAddr)>> {
+        let accepted = match &self.inner {
+            Socket::Tcp(listener) => listener.accept()
+                .and_then(|(stream, addr)| Ok((self.wrap_tcp(stream)?, addr))),
+            #[cfg(unix)]
+            Socket::Unix(listener, _) => listener.accept()
+                .map(|(stream, _)| (ClientStream::Unix(stream), SocketAddr::from(([0, 0, 0, 0], 0)))),
+        };
+
+        match accepted {
             Ok((stream, addr)) => {
                 stream.set_nonblocking(true)?;
                 Ok(Some((stream, addr)))
@@ -39,9 +115,34 @@ impl Listener {
         }
     }
 
+    /// Wraps an accepted TCP socket, in a TLS session on `ssl` ports
+    #[cfg(feature = "tls")]
+    fn wrap_tcp(&self, stream: TcpStream) -> std::io::Result<ClientStream> {
+        match &self.tls {
+            Some(config) => Ok(ClientStream::Tls(Box::new(Tls::accept(config, stream)?))),
+            None => Ok(ClientStream::Tcp(stream)),
+        }
+    }
+
+    #[cfg(not(feature = "tls"))]
+    fn wrap_tcp(&self, stream: TcpStream) -> std::io::Result<ClientStream> {
+        Ok(ClientStream::Tcp(stream))
+    }
+
     /// Returns the raw socket descriptor
+    #[cfg(windows)]
     pub fn fd(&self) -> u64 {
-        self.inner.as_raw_socket() as u64
+        match &self.inner {
+            Socket::Tcp(listener) => listener.as_raw_socket() as u64,
+        }
+    }
+
+    #[cfg(not(windows))]
+    pub fn fd(&self) -> u64 {
+        match &self.inner {
+            Socket::Tcp(listener) => listener.as_raw_fd() as u64,
+            Socket::Unix(listener, _) => listener.as_raw_fd() as u64,
+        }
     }
 
     /// Returns the bound address
@@ -54,3 +155,13 @@ impl Listener {
         self.addr.port()
     }
 }
+
+#[cfg(unix)]
+impl Drop for Listener {
+    /// Removes a Unix socket's file on shutdown
+    fn drop(&mut self) {
+        if let Socket::Unix(_, path) = &self.inner {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
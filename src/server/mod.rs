@@ -2,14 +2,20 @@ pub mod connection;
 pub mod epoll;
 pub mod event_loop;
 pub mod listener;
+pub mod stats;
+pub mod stream;
+#[cfg(feature = "tls")]
+pub mod tls;
 
 pub use connection::{Connection, ConnectionState};
 pub use epoll::{Event, EventType, Poller};
 pub use event_loop::EventLoop;
 pub use listener::Listener;
+pub use stats::ConnectionStats;
 
 use crate::config::Config;
 use crate::error::Result;
+use crate::router::Handler;
 
 /// Main server struct
 pub struct Server;
@@ -20,4 +26,11 @@ impl Server {
         let mut event_loop = EventLoop::new(config)?;
         event_loop.run()
     }
+
+    /// Runs the server with a prepared handler, e.g. one with custom
+    /// route handlers registered
+    pub fn run_with_handler(config: Config, handler: Handler) -> Result<()> {
+        let mut event_loop = EventLoop::with_handler(config, handler)?;
+        event_loop.run()
+    }
 }
@@ -0,0 +1,58 @@
+/// Upper bounds of the requests-per-connection buckets; the last bucket
+/// takes everything above the final bound
+const BUCKET_BOUNDS: [u64; 5] = [0, 1, 5, 10, 100];
+
+/// Labels of the requests-per-connection buckets
+pub const BUCKET_LABELS: [&str; 6] = ["0", "1", "2-5", "6-10", "11-100", "101+"];
+
+/// Connection reuse counters kept by the event loop
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConnectionStats {
+    /// Connections accepted since startup
+    pub accepted: u64,
+    /// Requests handed to the handler since startup
+    pub requests: u64,
+    /// Closed connections, counted by how many requests each served
+    pub requests_per_connection: [u64; 6],
+}
+
+impl ConnectionStats {
+    /// Records a closed connection that served `requests` requests
+    pub fn record_close(&mut self, requests: u64) {
+        let bucket = BUCKET_BOUNDS
+            .iter()
+            .position(|&bound| requests <= bound)
+            .unwrap_or(BUCKET_BOUNDS.len());
+        self.requests_per_connection[bucket] += 1;
+    }
+
+    /// Number of connections closed so far
+    pub fn closed(&self) -> u64 {
+        self.requests_per_connection.iter().sum()
+    }
+
+    /// Average requests served per accepted connection
+    pub fn requests_per_accepted(&self) -> f64 {
+        if self.accepted == 0 {
+            0.0
+        } else {
+            self.requests as f64 / self.accepted as f64
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn closes_land_in_buckets() {
+        let mut stats = ConnectionStats { accepted: 5, requests: 12, ..Default::default() };
+        for requests in [0, 1, 5, 6, 101] {
+            stats.record_close(requests);
+        }
+        assert_eq!(stats.requests_per_connection, [1, 1, 1, 1, 0, 1]);
+        assert_eq!(stats.closed(), 5);
+        assert_eq!(stats.requests_per_accepted(), 2.4);
+    }
+}
@@ -0,0 +1,139 @@
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+#[cfg(feature = "tls")]
+use super::tls::TlsStream;
+
+#[cfg(windows)]
+use std::os::windows::io::AsRawSocket;
+
+#[cfg(not(windows))]
+use std::os::unix::io::AsRawFd;
+
+/// A client connection's socket: TCP, TLS over TCP, or a Unix domain
+/// socket on Unix
+pub enum ClientStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<TlsStream>),
+}
+
+impl ClientStream {
+    /// Returns the raw socket descriptor
+    #[cfg(windows)]
+    pub fn fd(&self) -> u64 {
+        match self {
+            ClientStream::Tcp(stream) => stream.as_raw_socket() as u64,
+            #[cfg(feature = "tls")]
+            ClientStream::Tls(stream) => stream.sock.as_raw_socket() as u64,
+        }
+    }
+
+    #[cfg(not(windows))]
+    pub fn fd(&self) -> u64 {
+        match self {
+            ClientStream::Tcp(stream) => stream.as_raw_fd() as u64,
+            ClientStream::Unix(stream) => stream.as_raw_fd() as u64,
+            #[cfg(feature = "tls")]
+            ClientStream::Tls(stream) => stream.sock.as_raw_fd() as u64,
+        }
+    }
+
+    /// Sets the socket to non-blocking mode
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            ClientStream::Tcp(stream) => stream.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => stream.set_nonblocking(nonblocking),
+            #[cfg(feature = "tls")]
+            ClientStream::Tls(stream) => stream.sock.set_nonblocking(nonblocking),
+        }
+    }
+
+    /// Returns the local address for TCP connections
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match self {
+            ClientStream::Tcp(stream) => stream.local_addr().ok(),
+            #[cfg(unix)]
+            ClientStream::Unix(_) => None,
+            #[cfg(feature = "tls")]
+            ClientStream::Tls(stream) => stream.sock.local_addr().ok(),
+        }
+    }
+
+    /// Checks if the connection is a Unix domain socket rather than TCP
+    #[cfg(unix)]
+    pub fn is_unix(&self) -> bool {
+        matches!(self, ClientStream::Unix(_))
+    }
+
+    #[cfg(not(unix))]
+    pub fn is_unix(&self) -> bool {
+        false
+    }
+
+    /// Checks if the connection is encrypted, so bytes must pass through
+    /// the TLS layer rather than go to the socket directly (e.g. sendfile)
+    #[cfg(feature = "tls")]
+    pub fn is_tls(&self) -> bool {
+        matches!(self, ClientStream::Tls(_))
+    }
+
+    #[cfg(not(feature = "tls"))]
+    pub fn is_tls(&self) -> bool {
+        false
+    }
+
+    /// Returns the server name the client asked for in the TLS handshake (SNI)
+    #[cfg(feature = "tls")]
+    pub fn server_name(&self) -> Option<&str> {
+        match self {
+            ClientStream::Tls(stream) => stream.conn.server_name(),
+            _ => None,
+        }
+    }
+
+    #[cfg(not(feature = "tls"))]
+    pub fn server_name(&self) -> Option<&str> {
+        None
+    }
+}
+
+impl Read for ClientStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ClientStream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => stream.read(buf),
+            #[cfg(feature = "tls")]
+            ClientStream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for ClientStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            ClientStream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => stream.write(buf),
+            #[cfg(feature = "tls")]
+            ClientStream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            ClientStream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => stream.flush(),
+            #[cfg(feature = "tls")]
+            ClientStream::Tls(stream) => stream.flush(),
+        }
+    }
+}
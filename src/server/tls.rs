@@ -0,0 +1,96 @@
+use crate::config::Config;
+use crate::error::{Result, ServerError};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{ServerConnection, StreamOwned};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::TcpStream;
+use std::sync::Arc;
+
+/// A TLS session over a client socket
+pub type TlsStream = StreamOwned<ServerConnection, TcpStream>;
+
+/// Picks the certificate for a handshake by the SNI server name, falling
+/// back to the port's default server
+#[derive(Debug)]
+struct SniResolver {
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+    fallback: Option<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        client_hello
+            .server_name()
+            .and_then(|name| self.by_name.get(&name.to_lowercase()))
+            .or(self.fallback.as_ref())
+            .cloned()
+    }
+}
+
+/// TLS termination with rustls
+pub struct Tls;
+
+impl Tls {
+    /// Builds the TLS settings for a listening address from the certificates
+    /// of every `ssl` server on it
+    pub fn server_config(config: &Config, host: &str, port: u16) -> Result<Arc<rustls::ServerConfig>> {
+        let mut resolver = SniResolver {
+            by_name: HashMap::new(),
+            fallback: None,
+        };
+
+        let servers = config.servers.iter().filter(|s| s.host == host && s.ssl_ports.contains(&port));
+        for server in servers {
+            let (cert, key) = match (&server.ssl_certificate, &server.ssl_certificate_key) {
+                (Some(cert), Some(key)) => (cert, key),
+                _ => continue,
+            };
+            let certified = Arc::new(Self::load_certified_key(cert, key)?);
+
+            if resolver.fallback.is_none() || server.is_default_for(port) {
+                resolver.fallback = Some(certified.clone());
+            }
+            resolver.by_name.entry(server.server_name.to_lowercase()).or_insert(certified);
+        }
+
+        let mut tls = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(resolver));
+        tls.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(Arc::new(tls))
+    }
+
+    /// Starts a server-side TLS session on an accepted socket; the handshake
+    /// runs as the connection is read and written
+    pub fn accept(config: &Arc<rustls::ServerConfig>, stream: TcpStream) -> io::Result<TlsStream> {
+        let conn = ServerConnection::new(config.clone()).map_err(io::Error::other)?;
+        Ok(StreamOwned::new(conn, stream))
+    }
+
+    /// Loads a PEM certificate chain and private key
+    fn load_certified_key(cert_path: &str, key_path: &str) -> Result<CertifiedKey> {
+        let open = |path: &str| {
+            File::open(path)
+                .map(BufReader::new)
+                .map_err(|e| ServerError::Config(format!("Cannot read {}: {}", path, e)))
+        };
+
+        let certs = rustls_pemfile::certs(&mut open(cert_path)?)
+            .collect::<io::Result<Vec<_>>>()
+            .map_err(|e| ServerError::Config(format!("Invalid certificate {}: {}", cert_path, e)))?;
+        if certs.is_empty() {
+            return Err(ServerError::Config(format!("No certificate found in {}", cert_path)));
+        }
+
+        let key = rustls_pemfile::private_key(&mut open(key_path)?)
+            .map_err(|e| ServerError::Config(format!("Invalid private key {}: {}", key_path, e)))?
+            .ok_or_else(|| ServerError::Config(format!("No private key found in {}", key_path)))?;
+        let signing_key = rustls::crypto::ring::sign::any_supported_type(&key)
+            .map_err(|e| ServerError::Config(format!("Unsupported private key {}: {}", key_path, e)))?;
+
+        Ok(CertifiedKey::new(certs, signing_key))
+    }
+}
@@ -1,3 +1,4 @@
+use super::signing::{constant_time_eq, hmac_sha256};
 use std::collections::HashMap;
 
 /// Represents an HTTP cookie
@@ -87,6 +88,32 @@ impl Cookie {
         Cookie::new(name, "").max_age(0)
     }
 
+    /// Signs a cookie value with a server secret, appending the hex
+    /// HMAC-SHA256 of the value after a `.`
+    pub fn sign(value: &str, secret: &str) -> String {
+        format!("{}.{}", value, Self::signature(value, secret))
+    }
+
+    /// Checks a value made by `sign` and returns the original value, or None
+    /// if it was tampered with or signed with another secret
+    pub fn verify(signed: &str, secret: &str) -> Option<String> {
+        let (value, signature) = signed.rsplit_once('.')?;
+        let expected = Self::signature(value, secret);
+        if constant_time_eq(signature.as_bytes(), expected.as_bytes()) {
+            Some(value.to_string())
+        } else {
+            None
+        }
+    }
+
+    /// Hex HMAC-SHA256 of a value
+    fn signature(value: &str, secret: &str) -> String {
+        hmac_sha256(secret.as_bytes(), value.as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
     /// Converts the cookie to a Set-Cookie header value
     pub fn to_header_value(&self) -> String {
         let mut parts = vec![format!("{}={}", self.name, self.value)];
@@ -139,3 +166,31 @@ pub fn parse_cookies(header_value: &str) -> HashMap<String, String> {
 
     cookies
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signed_value_round_trip() {
+        let signed = Cookie::sign("user=alice", "s3cret");
+        assert_eq!(Cookie::verify(&signed, "s3cret").as_deref(), Some("user=alice"));
+        assert_eq!(Cookie::verify(&signed, "other"), None);
+    }
+
+    #[test]
+    fn tampered_signed_cookie() {
+        let signed = Cookie::sign("user=alice", "s3cret");
+        let (_, signature) = signed.rsplit_once('.').unwrap();
+
+        assert_eq!(Cookie::verify(&format!("user=admin.{}", signature), "s3cret"), None);
+
+        let mut flipped = signed.clone().into_bytes();
+        let last = flipped.last_mut().unwrap();
+        *last = if *last == b'0' { b'1' } else { b'0' };
+        assert_eq!(Cookie::verify(&String::from_utf8(flipped).unwrap(), "s3cret"), None);
+
+        assert_eq!(Cookie::verify(&signed[..signed.len() - 1], "s3cret"), None);
+        assert_eq!(Cookie::verify("user=alice", "s3cret"), None);
+    }
+}
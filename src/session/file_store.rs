@@ -0,0 +1,317 @@
+use super::store::{Session, SessionBackend, SessionStore};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How often expired session files are swept from the directory
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How stale a stored access time may get before a read writes it back; a
+/// session can expire at most this much early
+const TOUCH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Session store keeping one file per session in a directory, so sessions
+/// survive a restart
+///
+/// A new session stays in memory until it holds data, so a visitor who never
+/// stores anything costs no disk writes
+pub struct FileSessionStore {
+    dir: PathBuf,
+    /// Default session expiry
+    default_expiry: Duration,
+    /// New sessions with no data yet, not written to disk
+    pending: HashMap<String, Session>,
+    /// The session last read, kept so a reference to it can be handed out
+    loaded: Option<Session>,
+    last_cleanup: Instant,
+}
+
+impl FileSessionStore {
+    /// Creates a store over an existing directory
+    pub fn new(dir: &str) -> Self {
+        FileSessionStore {
+            dir: PathBuf::from(dir),
+            default_expiry: Duration::from_secs(3600),
+            pending: HashMap::new(),
+            loaded: None,
+            last_cleanup: Instant::now(),
+        }
+    }
+
+    /// Path of a session's file; IDs come from the client's cookie, so
+    /// anything but the hex IDs this server generates is refused
+    fn path(&self, id: &str) -> Option<PathBuf> {
+        if id.is_empty() || !id.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+        Some(self.dir.join(format!("{}.session", id)))
+    }
+
+    /// Reads a live session from disk, removing its file once expired
+    fn load(&self, id: &str) -> Option<Session> {
+        let path = self.path(id)?;
+        let stored = Self::read(id, &path)?;
+
+        if stored.is_expired() {
+            let _ = fs::remove_file(&path);
+            return None;
+        }
+        Some(stored.into_session())
+    }
+
+    /// Writes a session to disk through a temp file, so a reader never sees
+    /// it half-written
+    fn write(&self, session: &Session) {
+        let path = match self.path(&session.id) {
+            Some(path) => path,
+            None => return,
+        };
+        let tmp = path.with_extension("tmp");
+
+        let result = fs::write(&tmp, StoredSession::serialize(session)).and_then(|_| fs::rename(&tmp, &path));
+        if let Err(e) = result {
+            let _ = fs::remove_file(&tmp);
+            log_warn!("Failed to store session in {}: {}", path.display(), e);
+        }
+    }
+
+    /// Every session file in the directory, with its session ID
+    fn session_files(&self) -> Vec<(String, PathBuf)> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(_) => return Vec::new(),
+        };
+        entries
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().and_then(|e| e.to_str()) == Some("session"))
+            .filter_map(|path| {
+                let id = path.file_stem()?.to_str()?.to_string();
+                Some((id, path))
+            })
+            .collect()
+    }
+
+    /// Reads a session file whether or not it has expired
+    fn read(id: &str, path: &Path) -> Option<StoredSession> {
+        let content = fs::read_to_string(path).ok()?;
+        StoredSession::parse(id, &content)
+    }
+
+    /// Loads a session into `loaded` and refreshes its access time, writing
+    /// it back once the stored time is `TOUCH_INTERVAL` old
+    fn load_touched(&mut self, id: &str) {
+        self.loaded = self.load(id).map(|mut session| {
+            let stale = session.last_accessed.elapsed() >= TOUCH_INTERVAL;
+            session.touch();
+            if stale {
+                self.write(&session);
+            }
+            session
+        });
+    }
+
+    /// A live pending session, refreshed; an expired one is dropped
+    fn pending_touched(&mut self, id: &str) -> Option<&mut Session> {
+        if self.pending.get(id).is_some_and(|session| session.is_expired()) {
+            self.pending.remove(id);
+        }
+        let session = self.pending.get_mut(id)?;
+        session.touch();
+        Some(session)
+    }
+}
+
+impl SessionBackend for FileSessionStore {
+    fn create(&mut self) -> String {
+        if self.last_cleanup.elapsed() >= CLEANUP_INTERVAL {
+            self.cleanup();
+        }
+
+        let id = SessionStore::generate_id();
+        let mut session = Session::new(&id);
+        session.expires_in = self.default_expiry;
+        self.pending.insert(id.clone(), session);
+        id
+    }
+
+    fn get(&mut self, id: &str) -> Option<&Session> {
+        if self.pending.contains_key(id) {
+            return self.pending_touched(id).map(|session| &*session);
+        }
+        self.load_touched(id);
+        self.loaded.as_ref()
+    }
+
+    fn get_mut(&mut self, id: &str) -> Option<&mut Session> {
+        if self.pending.contains_key(id) {
+            return self.pending_touched(id);
+        }
+        self.load_touched(id);
+        self.loaded.as_mut()
+    }
+
+    fn save(&mut self, id: &str) {
+        // A pending session goes to disk once it has something to keep
+        if self.pending.get(id).is_some_and(|session| !session.data.is_empty()) {
+            if let Some(session) = self.pending.remove(id) {
+                self.write(&session);
+            }
+            return;
+        }
+        if let Some(session) = self.loaded.as_ref().filter(|s| s.id == id) {
+            self.write(session);
+        }
+    }
+
+    fn destroy(&mut self, id: &str) {
+        self.pending.remove(id);
+        if self.loaded.as_ref().is_some_and(|s| s.id == id) {
+            self.loaded = None;
+        }
+        if let Some(path) = self.path(id) {
+            let _ = fs::remove_file(path);
+        }
+    }
+
+    fn cleanup(&mut self) {
+        self.last_cleanup = Instant::now();
+        self.pending.retain(|_, session| !session.is_expired());
+
+        for (id, path) in self.session_files() {
+            if Self::read(&id, &path).is_none_or(|stored| stored.is_expired()) {
+                let _ = fs::remove_file(&path);
+            }
+        }
+    }
+
+    fn iter_ids(&self) -> Box<dyn Iterator<Item = String> + '_> {
+        Box::new(self.pending.keys().cloned().chain(self.session_files().into_iter().map(|(id, _)| id)))
+    }
+
+    fn destroy_matching(&mut self, predicate: &dyn Fn(&Session) -> bool) -> usize {
+        self.loaded = None;
+
+        let before = self.pending.len();
+        self.pending.retain(|_, session| !predicate(session));
+        let mut destroyed = before - self.pending.len();
+        for (id, path) in self.session_files() {
+            let matches = Self::read(&id, &path).is_some_and(|stored| predicate(&stored.into_session()));
+            if matches && fs::remove_file(&path).is_ok() {
+                destroyed += 1;
+            }
+        }
+        destroyed
+    }
+}
+
+/// A session as written to disk, with wall-clock times in Unix seconds:
+/// `created`, `accessed`, `issued` and `expires` lines and a `data` line
+struct StoredSession {
+    id: String,
+    created: u64,
+    accessed: u64,
+    issued: u64,
+    expires: u64,
+    data: HashMap<String, String>,
+}
+
+impl StoredSession {
+    /// Serializes a session
+    fn serialize(session: &Session) -> String {
+        format!(
+            "created {}\naccessed {}\nissued {}\nexpires {}\ndata {}\n",
+            unix_time(session.created_at),
+            unix_time(session.last_accessed),
+            unix_time(session.cookie_issued_at),
+            session.expires_in.as_secs(),
+            session.serialize_data()
+        )
+    }
+
+    /// Parses a session file, or None if it is malformed
+    fn parse(id: &str, content: &str) -> Option<Self> {
+        let mut stored = StoredSession {
+            id: id.to_string(),
+            created: 0,
+            accessed: 0,
+            issued: 0,
+            expires: 0,
+            data: HashMap::new(),
+        };
+        let mut lines = content.lines();
+        for field in [&mut stored.created, &mut stored.accessed, &mut stored.issued, &mut stored.expires] {
+            let line = lines.next()?;
+            *field = line.split_once(' ')?.1.parse().ok()?;
+        }
+        let data = lines.next()?.strip_prefix("data")?;
+        stored.data = Session::deserialize_data(data.trim_start())?;
+        Some(stored)
+    }
+
+    /// Checks expiry against the wall clock, so time spent while the server
+    /// was down counts
+    fn is_expired(&self) -> bool {
+        now_unix().saturating_sub(self.accessed) > self.expires
+    }
+
+    /// Converts back to a session, mapping the stored times onto `Instant`s
+    fn into_session(self) -> Session {
+        let mut session = Session::new(&self.id);
+        session.data = self.data;
+        session.created_at = instant_at(self.created);
+        session.last_accessed = instant_at(self.accessed);
+        session.cookie_issued_at = instant_at(self.issued);
+        session.expires_in = Duration::from_secs(self.expires);
+        session
+    }
+}
+
+/// Current time in Unix seconds
+fn now_unix() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Wall-clock Unix seconds of a past `Instant`
+fn unix_time(instant: Instant) -> u64 {
+    now_unix().saturating_sub(instant.elapsed().as_secs())
+}
+
+/// The `Instant` of past Unix seconds (clamped to now if the monotonic clock
+/// doesn't reach back that far)
+fn instant_at(secs: u64) -> Instant {
+    let age = Duration::from_secs(now_unix().saturating_sub(secs));
+    Instant::now().checked_sub(age).unwrap_or_else(Instant::now)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn destroy_all_covers_pending_and_stored_sessions() {
+        let dir = std::env::temp_dir().join(format!("localhost-sessions-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let mut store = FileSessionStore::new(&dir.to_string_lossy());
+
+        let ids: Vec<String> = (0..4).map(|_| store.create()).collect();
+        // Two get data and go to disk; the others stay pending
+        for id in &ids[..2] {
+            store.get_mut(id).unwrap().set("user", "alice");
+            store.save(id);
+        }
+        assert_eq!(store.session_files().len(), 2);
+        assert_eq!(store.count(), 4);
+
+        assert_eq!(store.destroy_all(), 4);
+        assert_eq!(store.count(), 0);
+        assert!(store.session_files().is_empty());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
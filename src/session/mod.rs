@@ -1,5 +1,8 @@
 pub mod cookie;
+pub mod file_store;
+pub mod signing;
 pub mod store;
 
 pub use cookie::{parse_cookies, Cookie, SameSite};
-pub use store::{Session, SessionStore};
+pub use file_store::FileSessionStore;
+pub use store::{Session, SessionBackend, SessionStore};
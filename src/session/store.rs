@@ -1,6 +1,45 @@
 use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::{Duration, Instant};
 
+/// Returns `len` random bytes, hex-encoded, for IDs that must not be guessed
+pub fn random_hex(len: usize) -> String {
+    let mut bytes = vec![0u8; len];
+    fill_random(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Fills `buf` from the OS random source. Where there is none (or it fails),
+/// falls back to hashing the time and a per-process counter with std's
+/// randomly keyed hasher, so IDs still never repeat
+fn fill_random(buf: &mut [u8]) {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+
+    #[cfg(unix)]
+    {
+        use std::io::Read;
+        let read = std::fs::File::open("/dev/urandom").and_then(|mut f| f.read_exact(buf));
+        if read.is_ok() {
+            return;
+        }
+    }
+
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    for chunk in buf.chunks_mut(8) {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_nanos())
+                .unwrap_or(0),
+        );
+        hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+        chunk.copy_from_slice(&hasher.finish().to_le_bytes()[..chunk.len()]);
+    }
+}
+
 /// Session data storage
 #[derive(Debug, Clone)]
 pub struct Session {
@@ -12,6 +51,8 @@ pub struct Session {
     pub created_at: Instant,
     /// Last access time
     pub last_accessed: Instant,
+    /// When the session cookie was last sent
+    pub cookie_issued_at: Instant,
     /// Session expiry duration
     pub expires_in: Duration,
 }
@@ -25,6 +66,7 @@ impl Session {
             data: HashMap::new(),
             created_at: now,
             last_accessed: now,
+            cookie_issued_at: now,
             expires_in: Duration::from_secs(3600), // 1 hour default
         }
     }
@@ -40,6 +82,39 @@ impl Session {
         self.last_accessed = Instant::now();
     }
 
+    /// Gets a value parsed as `T`, or None if it is missing or doesn't parse
+    pub fn get_as<T: FromStr>(&self, key: &str) -> Option<T> {
+        self.data.get(key).and_then(|value| value.parse().ok())
+    }
+
+    /// Sets a value from anything with a string form
+    pub fn set_as<T: ToString>(&mut self, key: &str, value: T) {
+        self.set(key, &value.to_string());
+    }
+
+    /// Serializes the data as `key=value` pairs joined by `&`, sorted by key
+    /// and percent-encoded down to unreserved characters, so the result is
+    /// safe in a cookie value or on a single line of a file
+    pub fn serialize_data(&self) -> String {
+        let mut pairs: Vec<_> = self.data.iter().collect();
+        pairs.sort();
+        pairs
+            .iter()
+            .map(|(key, value)| format!("{}={}", encode_component(key), encode_component(value)))
+            .collect::<Vec<_>>()
+            .join("&")
+    }
+
+    /// Parses data written by `serialize_data`, or None if it is malformed
+    pub fn deserialize_data(s: &str) -> Option<HashMap<String, String>> {
+        let mut data = HashMap::new();
+        for pair in s.split('&').filter(|p| !p.is_empty()) {
+            let (key, value) = pair.split_once('=')?;
+            data.insert(decode_component(key)?, decode_component(value)?);
+        }
+        Some(data)
+    }
+
     /// Removes a value from the session
     pub fn remove(&mut self, key: &str) -> Option<String> {
         self.last_accessed = Instant::now();
@@ -51,12 +126,58 @@ impl Session {
         self.last_accessed.elapsed() > self.expires_in
     }
 
+    /// Checks if the cookie has used up `percent` of its lifetime, so it
+    /// should be sent again to keep an active session alive
+    pub fn cookie_due(&self, percent: u8) -> bool {
+        self.cookie_issued_at.elapsed() >= self.expires_in * u32::from(percent) / 100
+    }
+
     /// Touches the session (updates last accessed time)
     pub fn touch(&mut self) {
         self.last_accessed = Instant::now();
     }
 }
 
+/// Storage for sessions, so the in-memory and file stores (and any
+/// future backend) are interchangeable behind the handler
+pub trait SessionBackend: Send {
+    /// Creates a new session and returns its ID
+    fn create(&mut self) -> String;
+
+    /// Gets a live session by ID, refreshing its last access time
+    fn get(&mut self, id: &str) -> Option<&Session>;
+
+    /// Gets a live session by ID for changes, which `save` writes back
+    fn get_mut(&mut self, id: &str) -> Option<&mut Session>;
+
+    /// Writes back changes made through `get_mut`; backends that hand out
+    /// their stored sessions directly have nothing to do
+    fn save(&mut self, _id: &str) {}
+
+    /// Destroys a session
+    fn destroy(&mut self, id: &str);
+
+    /// Removes expired sessions
+    fn cleanup(&mut self);
+
+    /// IDs of every stored session
+    fn iter_ids(&self) -> Box<dyn Iterator<Item = String> + '_>;
+
+    /// Number of stored sessions
+    fn count(&self) -> usize {
+        self.iter_ids().count()
+    }
+
+    /// Destroys every session the predicate matches (e.g. those of a user
+    /// whose password changed) and returns how many there were
+    fn destroy_matching(&mut self, predicate: &dyn Fn(&Session) -> bool) -> usize;
+
+    /// Destroys every session and returns how many there were
+    fn destroy_all(&mut self) -> usize {
+        self.destroy_matching(&|_| true)
+    }
+}
+
 /// In-memory session store
 pub struct SessionStore {
     sessions: HashMap<String, Session>,
@@ -81,21 +202,34 @@ impl SessionStore {
         }
     }
 
-    /// Generates a new session ID
+    /// Generates a new session ID: 128 random bits, hex-encoded
     pub fn generate_id() -> String {
-        use std::time::{SystemTime, UNIX_EPOCH};
-        
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .map(|d| d.as_nanos())
-            .unwrap_or(0);
-        
-        // Simple ID generation (in production, use a proper random generator)
-        format!("{:x}{:x}", timestamp, timestamp.wrapping_mul(0x5DEECE66D))
+        random_hex(16)
     }
 
-    /// Creates a new session and returns its ID
-    pub fn create(&mut self) -> String {
+    /// Gets or creates a session
+    pub fn get_or_create(&mut self, id: Option<&str>) -> &mut Session {
+        let session_id = match id {
+            Some(existing_id) if self.sessions.contains_key(existing_id) => {
+                if let Some(session) = self.sessions.get(existing_id) {
+                    if !session.is_expired() {
+                        existing_id.to_string()
+                    } else {
+                        self.create()
+                    }
+                } else {
+                    self.create()
+                }
+            }
+            _ => self.create(),
+        };
+
+        self.sessions.get_mut(&session_id).unwrap()
+    }
+}
+
+impl SessionBackend for SessionStore {
+    fn create(&mut self) -> String {
         let id = Self::generate_id();
         let mut session = Session::new(&id);
         session.expires_in = self.default_expiry;
@@ -103,10 +237,9 @@ impl SessionStore {
         id
     }
 
-    /// Gets a session by ID
-    pub fn get(&mut self, id: &str) -> Option<&Session> {
+    fn get(&mut self, id: &str) -> Option<&Session> {
         // Clean up expired sessions occasionally
-        self.cleanup_expired();
+        self.cleanup();
         
         if let Some(session) = self.sessions.get_mut(id) {
             if !session.is_expired() {
@@ -119,8 +252,7 @@ impl SessionStore {
         None
     }
 
-    /// Gets a mutable session by ID
-    pub fn get_mut(&mut self, id: &str) -> Option<&mut Session> {
+    fn get_mut(&mut self, id: &str) -> Option<&mut Session> {
         if let Some(session) = self.sessions.get_mut(id) {
             if !session.is_expired() {
                 session.touch();
@@ -131,33 +263,11 @@ impl SessionStore {
         None
     }
 
-    /// Gets or creates a session
-    pub fn get_or_create(&mut self, id: Option<&str>) -> &mut Session {
-        let session_id = match id {
-            Some(existing_id) if self.sessions.contains_key(existing_id) => {
-                if let Some(session) = self.sessions.get(existing_id) {
-                    if !session.is_expired() {
-                        existing_id.to_string()
-                    } else {
-                        self.create()
-                    }
-                } else {
-                    self.create()
-                }
-            }
-            _ => self.create(),
-        };
-
-        self.sessions.get_mut(&session_id).unwrap()
-    }
-
-    /// Destroys a session
-    pub fn destroy(&mut self, id: &str) {
+    fn destroy(&mut self, id: &str) {
         self.sessions.remove(id);
     }
 
-    /// Cleans up expired sessions
-    pub fn cleanup_expired(&mut self) {
+    fn cleanup(&mut self) {
         let expired: Vec<String> = self.sessions
             .iter()
             .filter(|(_, s)| s.is_expired())
@@ -169,10 +279,19 @@ impl SessionStore {
         }
     }
 
-    /// Returns the number of active sessions
-    pub fn count(&self) -> usize {
+    fn iter_ids(&self) -> Box<dyn Iterator<Item = String> + '_> {
+        Box::new(self.sessions.keys().cloned())
+    }
+
+    fn count(&self) -> usize {
         self.sessions.len()
     }
+
+    fn destroy_matching(&mut self, predicate: &dyn Fn(&Session) -> bool) -> usize {
+        let before = self.sessions.len();
+        self.sessions.retain(|_, session| !predicate(session));
+        before - self.sessions.len()
+    }
 }
 
 impl Default for SessionStore {
@@ -180,3 +299,85 @@ impl Default for SessionStore {
         Self::new()
     }
 }
+
+/// Percent-encodes everything but unreserved characters
+fn encode_component(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
+
+/// Reverses `encode_component`, or None on a bad escape or invalid UTF-8
+fn decode_component(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn typed_values_and_serialization() {
+        let mut session = Session::new("abc");
+        session.set_as("visits", 42u32);
+        session.set("name", "Ada & Charles=1; x");
+        session.set("empty", "");
+
+        assert_eq!(session.get_as::<u32>("visits"), Some(42));
+        assert_eq!(session.get_as::<i64>("visits"), Some(42));
+        assert_eq!(session.get_as::<u32>("name"), None);
+        assert_eq!(session.get_as::<u32>("missing"), None);
+
+        let serialized = session.serialize_data();
+        assert!(!serialized.contains([' ', ';', '\n']), "{}", serialized);
+        assert_eq!(Session::deserialize_data(&serialized), Some(session.data.clone()));
+        assert_eq!(Session::deserialize_data("novalue"), None);
+
+        assert_eq!(session.remove("visits").as_deref(), Some("42"));
+        assert_eq!(session.get_as::<u32>("visits"), None);
+    }
+
+    #[test]
+    fn get_or_create_replaces_expired_sessions() {
+        let mut store = SessionStore::with_expiry(0);
+        let id = store.get_or_create(None).id.clone();
+        std::thread::sleep(Duration::from_millis(5));
+        assert_ne!(store.get_or_create(Some(&id)).id, id);
+
+        let mut store = SessionStore::new();
+        let id = store.get_or_create(None).id.clone();
+        assert_eq!(store.get_or_create(Some(&id)).id, id);
+        assert_ne!(store.get_or_create(Some("unknown")).id, "unknown");
+    }
+
+    #[test]
+    fn destroy_all_sessions() {
+        let mut store = SessionStore::new();
+        for _ in 0..5 {
+            store.create();
+        }
+        assert_eq!(store.count(), 5);
+        assert_eq!(store.destroy_all(), 5);
+        assert_eq!(store.count(), 0);
+        assert_eq!(store.iter_ids().count(), 0);
+    }
+}
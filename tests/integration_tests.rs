@@ -0,0 +1,645 @@
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A server process on a free port, serving a temp root; killed on drop
+struct TestServer {
+    child: Child,
+    port: u16,
+    root: PathBuf,
+}
+
+impl TestServer {
+    fn start(name: &str, directives: &str) -> Self {
+        Self::with_config(name, |port, root| {
+            format!(
+                "server {{\n    listen {}\n    host 127.0.0.1\n    root {}\n    {}\n    location / {{\n        methods GET\n        index index.html\n    }}\n}}\n",
+                port,
+                root.display(),
+                directives
+            )
+        })
+    }
+
+    /// Starts a server on the configuration `config` builds for a port and
+    /// root directory
+    fn with_config(name: &str, config: impl Fn(u16, &Path) -> String) -> Self {
+        let root = std::env::temp_dir().join(format!("localhost-it-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("index.html"), "hello").unwrap();
+
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let child = Command::new(env!("CARGO_BIN_EXE_localhost"))
+            .arg("--config-string")
+            .arg(config(port, &root))
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+
+        let server = TestServer { child, port, root };
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while TcpStream::connect(("127.0.0.1", port)).is_err() {
+            assert!(Instant::now() < deadline, "server did not start");
+            thread::sleep(Duration::from_millis(50));
+        }
+        server
+    }
+
+    fn connect(&self) -> TcpStream {
+        let stream = TcpStream::connect(("127.0.0.1", self.port)).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
+
+/// Reads one response off a keep-alive connection, returning its status
+/// code and body
+fn read_response(reader: &mut impl BufRead) -> (u16, Vec<u8>) {
+    let mut status_line = String::new();
+    reader.read_line(&mut status_line).unwrap();
+    let status = status_line
+        .split(' ')
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .unwrap_or_else(|| panic!("bad status line: {:?}", status_line));
+
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap();
+            }
+        }
+    }
+
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).unwrap();
+    (status, body)
+}
+
+/// Runs the binary to completion with the given arguments
+fn run(args: &[&str]) -> std::process::Output {
+    Command::new(env!("CARGO_BIN_EXE_localhost")).args(args).output().unwrap()
+}
+
+#[test]
+fn test_flag_checks_the_configuration() {
+    let root = std::env::temp_dir().to_string_lossy().into_owned();
+    let good = format!("server {{\n    listen 8080\n    root {}\n}}\n", root);
+    let output = run(&["-t", "--config-string", &good]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("syntax is ok"), "{}", stdout);
+    assert!(stdout.contains("test is successful"), "{}", stdout);
+
+    // Nothing is bound, so a port in use doesn't matter
+    let busy = TcpListener::bind("127.0.0.1:0").unwrap();
+    let config = format!("server {{\n    listen {}\n    host 127.0.0.1\n    root {}\n}}\n", busy.local_addr().unwrap().port(), root);
+    assert!(run(&["--test", "--config-string", &config]).status.success());
+
+    for bad in ["server {\n    listen 8080\n    root /nonexistent/localhost-test\n}\n", "server {\n    listen none\n}\n"] {
+        let output = run(&["-t", "--config-string", bad]);
+        assert_eq!(output.status.code(), Some(1), "{}", bad);
+        assert!(output.stdout.is_empty());
+        assert!(!output.stderr.is_empty());
+    }
+}
+
+#[test]
+fn configuration_read_from_stdin() {
+    let root = std::env::temp_dir().join(format!("localhost-it-stdin-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root).unwrap();
+    fs::write(root.join("index.html"), "from stdin").unwrap();
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let config = format!(
+        "server {{\n    listen {}\n    host 127.0.0.1\n    root {}\n    location / {{\n        methods GET\n        index index.html\n    }}\n}}\n",
+        port,
+        root.display()
+    );
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_localhost"))
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    // Closing stdin ends the configuration
+    child.stdin.take().unwrap().write_all(config.as_bytes()).unwrap();
+    let server = TestServer { child, port, root };
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let mut stream = loop {
+        match TcpStream::connect(("127.0.0.1", port)) {
+            Ok(stream) => break stream,
+            Err(_) => {
+                assert!(Instant::now() < deadline, "server did not start");
+                thread::sleep(Duration::from_millis(50));
+            }
+        }
+    };
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    assert_eq!(read_response(&mut BufReader::new(stream)), (200, b"from stdin".to_vec()));
+    drop(server);
+
+    // An unparsable configuration on stdin fails at startup
+    let mut child = Command::new(env!("CARGO_BIN_EXE_localhost"))
+        .args(["-t", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(b"server {\n    listen none\n}\n").unwrap();
+    assert_eq!(child.wait().unwrap().code(), Some(1));
+}
+
+#[test]
+fn log_level_quiets_warnings() {
+    let config = |level: &str| format!("{}\nlenient on\nserver {{\n    listen 8080\n    root /nonexistent/localhost-test\n}}\n", level);
+
+    let output = run(&["-t", "--config-string", &config("")]);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("/nonexistent/localhost-test"));
+
+    for (args, level) in [(&["-l", "error"][..], ""), (&[], "log_level error"), (&["--log-level", "error"], "log_level debug")] {
+        let text = config(level);
+        let output = run(&[args, &["-t", "--config-string", &text]].concat());
+        assert!(output.status.success());
+        assert!(output.stderr.is_empty(), "{:?} {}: {}", args, level, String::from_utf8_lossy(&output.stderr));
+    }
+
+    let output = run(&["-l", "loud", "-t", "--config-string", &config("")]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Invalid log level"));
+}
+
+#[test]
+fn busy_keep_alive_connection_survives_watchdog() {
+    let server = TestServer::start("watchdog", "watchdog_timeout 1");
+    let mut stream = server.connect();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+    // Well past the watchdog timeout in total, but never idle for long
+    let started = Instant::now();
+    let mut requests = 0;
+    while started.elapsed() < Duration::from_millis(2500) {
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let (status, body) = read_response(&mut reader);
+        assert_eq!(status, 200, "request {} failed", requests);
+        assert_eq!(body, b"hello");
+        requests += 1;
+        thread::sleep(Duration::from_millis(200));
+    }
+    assert!(requests >= 8);
+}
+
+#[test]
+fn watchdog_closes_stuck_connection() {
+    let server = TestServer::start("watchdog-stuck", "watchdog_timeout 1");
+    let mut stream = server.connect();
+
+    // Headers that never finish keep the connection in one state
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n").unwrap();
+    let mut buf = [0; 1024];
+    let started = Instant::now();
+    let closed = matches!(stream.read(&mut buf), Ok(0) | Err(_));
+    assert!(closed);
+    assert!(started.elapsed() < Duration::from_secs(4));
+}
+
+#[test]
+fn timed_out_connection_is_reset() {
+    for (reset, name) in [(true, "reset-on"), (false, "reset-off")] {
+        let directive = format!("timeout 1\n    reset_timedout_connection {}", if reset { "on" } else { "off" });
+        let server = TestServer::start(name, &directive);
+        let mut stream = server.connect();
+        stream.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+
+        let mut buf = [0; 64];
+        match stream.read(&mut buf) {
+            Err(e) if reset => assert_eq!(e.kind(), std::io::ErrorKind::ConnectionReset),
+            Ok(0) if !reset => {}
+            other => panic!("reset {}: {:?}", reset, other),
+        }
+    }
+}
+
+#[test]
+fn idle_connections_time_out_and_busy_ones_stay() {
+    let server = TestServer::start("idle-timeout", "timeout 1");
+    let mut idle = server.connect();
+    let mut busy = server.connect();
+    let mut busy_reader = BufReader::new(busy.try_clone().unwrap());
+
+    let started = Instant::now();
+    while started.elapsed() < Duration::from_millis(3500) {
+        busy.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        assert_eq!(read_response(&mut busy_reader).0, 200);
+        thread::sleep(Duration::from_millis(300));
+    }
+
+    // Long closed by now, while the busy one kept its slot
+    idle.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+    let mut buf = [0; 64];
+    assert!(matches!(idle.read(&mut buf), Ok(0)));
+}
+
+#[test]
+fn malformed_request_gets_400_and_close() {
+    let server = TestServer::start("malformed", "");
+    let mut stream = server.connect();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    assert_eq!(read_response(&mut reader).0, 200);
+
+    // A bad request line is answered before the headers ever finish
+    stream.write_all(b"GET / HTTP/1.1 extra\r\nHost: localhost\r\n").unwrap();
+    let started = Instant::now();
+    assert_eq!(read_response(&mut reader).0, 400);
+    assert!(started.elapsed() < Duration::from_secs(2));
+
+    // The stream can't be resynchronized, so nothing else is read from it
+    let mut rest = Vec::new();
+    assert_eq!(reader.read_to_end(&mut rest).unwrap(), 0);
+}
+
+#[test]
+fn oversized_body_is_refused_before_it_arrives() {
+    let server = TestServer::start("early-413", "client_max_body_size 100");
+    let mut stream = server.connect();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+    // Answered on the headers alone; the body that follows is drained so the
+    // connection stays usable
+    stream.write_all(b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 1000\r\n\r\n").unwrap();
+    assert_eq!(read_response(&mut reader).0, 413);
+    stream.write_all(&[b'x'; 1000]).unwrap();
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    assert_eq!(read_response(&mut reader), (200, b"hello".to_vec()));
+
+    // Too much to drain: the connection is closed instead
+    let mut stream = server.connect();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    stream.write_all(b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 100000000\r\n\r\n").unwrap();
+    assert_eq!(read_response(&mut reader).0, 413);
+    let mut rest = Vec::new();
+    assert_eq!(reader.read_to_end(&mut rest).unwrap(), 0);
+}
+
+#[test]
+fn slow_reader_gets_everything_without_stalling_others() {
+    let server = TestServer::start("slow-reader", "");
+    // Under the streaming threshold, so it is written from one in-memory buffer
+    let contents: Vec<u8> = (0..900_000u32).map(|i| (i % 241) as u8).collect();
+    fs::write(server.root.join("page.bin"), &contents).unwrap();
+
+    let mut slow = server.connect();
+    slow.write_all(b"GET /page.bin HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    thread::sleep(Duration::from_millis(300));
+
+    // The socket buffers are full by now; another client is still served
+    let mut other = server.connect();
+    other.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    assert_eq!(read_response(&mut BufReader::new(other)), (200, b"hello".to_vec()));
+
+    let (status, body) = read_response(&mut BufReader::new(slow));
+    assert_eq!(status, 200);
+    assert!(body == contents);
+}
+
+#[test]
+fn connections_past_the_limit_are_closed() {
+    let server = TestServer::start("max-connections", "max_connections 1");
+
+    // The startup probes count until the server sees them close
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let (mut first, mut reader) = loop {
+        let mut stream = server.connect();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        if reader.fill_buf().map(|b| !b.is_empty()).unwrap_or(false) {
+            assert_eq!(read_response(&mut reader).0, 200);
+            break (stream, reader);
+        }
+        assert!(Instant::now() < deadline, "never got a connection slot");
+        thread::sleep(Duration::from_millis(50));
+    };
+
+    let mut second = server.connect();
+    let mut buf = [0; 64];
+    assert!(matches!(second.read(&mut buf), Ok(0) | Err(_)));
+
+    // The first connection is unaffected
+    first.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    assert_eq!(read_response(&mut reader).0, 200);
+}
+
+#[test]
+fn large_file_arrives_intact() {
+    let server = TestServer::start("large-file", "");
+    // Past the 1 MiB streaming threshold, and not a multiple of any buffer size
+    let contents: Vec<u8> = (0..3_000_017u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8).collect();
+    fs::write(server.root.join("big.bin"), &contents).unwrap();
+
+    let mut stream = server.connect();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    for _ in 0..2 {
+        stream.write_all(b"GET /big.bin HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let (status, body) = read_response(&mut reader);
+        assert_eq!(status, 200);
+        assert_eq!(body.len(), contents.len());
+        assert!(body == contents);
+    }
+}
+
+#[test]
+fn client_leaving_mid_response_leaves_the_server_running() {
+    let mut server = TestServer::start("disconnect", "");
+    fs::write(server.root.join("big.bin"), vec![b'x'; 8 * 1024 * 1024]).unwrap();
+
+    for _ in 0..3 {
+        let mut stream = server.connect();
+        stream.write_all(b"GET /big.bin HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut buf = [0; 4096];
+        assert!(stream.read(&mut buf).unwrap() > 0);
+        // Closing with unread data resets the connection under the writer
+        drop(stream);
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    assert!(server.child.try_wait().unwrap().is_none(), "server exited");
+    let mut stream = server.connect();
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    assert_eq!(read_response(&mut BufReader::new(stream)), (200, b"hello".to_vec()));
+}
+
+#[test]
+fn connections_close_when_asked_or_with_keepalive_off() {
+    let closes_after_one = |server: &TestServer, request: &[u8]| {
+        let mut stream = server.connect();
+        stream.write_all(request).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        assert_eq!(read_response(&mut reader).0, 200);
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        rest.is_empty()
+    };
+    let keep_alive = b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: keep-alive\r\n\r\n";
+
+    let server = TestServer::start("keepalive-off", "keepalive off");
+    assert!(closes_after_one(&server, keep_alive));
+
+    let server = TestServer::start("keepalive-on", "");
+    assert!(closes_after_one(&server, b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n"));
+    assert!(closes_after_one(&server, b"GET / HTTP/1.0\r\nHost: localhost\r\n\r\n"));
+
+    // Otherwise the connection stays open for the next request
+    let mut stream = server.connect();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    for _ in 0..2 {
+        stream.write_all(keep_alive).unwrap();
+        assert_eq!(read_response(&mut reader).0, 200);
+    }
+}
+
+#[test]
+fn aborted_upload_leaves_no_temp_file() {
+    let server = TestServer::with_config("aborted-upload", |port, root| {
+        fs::create_dir_all(root.join("spool")).unwrap();
+        format!(
+            "server {{\n    listen {}\n    host 127.0.0.1\n    root {}\n    client_max_body_size 10M\n    \
+             location /uploads {{\n        methods POST\n        upload_dir {root}/uploads\n        \
+             client_body_temp_path {root}/spool\n    }}\n}}\n",
+            port,
+            root.display(),
+            root = root.display()
+        )
+    });
+    let spooled = || fs::read_dir(server.root.join("spool")).unwrap().count();
+
+    let mut stream = server.connect();
+    let head = "POST /uploads HTTP/1.1\r\nHost: localhost\r\nContent-Type: multipart/form-data; boundary=XyZ\r\n\
+                Content-Length: 4000000\r\n\r\n";
+    stream.write_all(head.as_bytes()).unwrap();
+    stream.write_all(&vec![b'x'; 256 * 1024]).unwrap();
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while spooled() == 0 {
+        assert!(Instant::now() < deadline, "body was not spooled");
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    drop(stream);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while spooled() != 0 {
+        assert!(Instant::now() < deadline, "temp file left behind");
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert!(!server.root.join("uploads").exists() || fs::read_dir(server.root.join("uploads")).unwrap().count() == 0);
+}
+
+#[test]
+fn early_hints_arrive_before_the_response() {
+    let server = TestServer::with_config("early-hints", |port, root| {
+        format!(
+            "server {{\n    listen {}\n    host 127.0.0.1\n    root {}\n    location / {{\n        methods GET\n        \
+             index index.html\n        preload /style.css style\n    }}\n}}\n",
+            port,
+            root.display()
+        )
+    });
+
+    let mut stream = server.connect();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    for _ in 0..2 {
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        assert_eq!(read_response(&mut reader), (103, Vec::new()));
+        assert_eq!(read_response(&mut reader), (200, b"hello".to_vec()));
+    }
+}
+
+#[test]
+fn half_closed_clients_are_answered_then_closed() {
+    let server = TestServer::start("half-close", "");
+
+    // A whole request before the shutdown is still answered
+    let mut stream = server.connect();
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    stream.shutdown(std::net::Shutdown::Write).unwrap();
+    let mut reader = BufReader::new(stream);
+    assert_eq!(read_response(&mut reader), (200, b"hello".to_vec()));
+    let mut rest = Vec::new();
+    reader.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty());
+
+    // Half a request can never complete, so the connection just closes
+    let mut stream = server.connect();
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: loc").unwrap();
+    stream.shutdown(std::net::Shutdown::Write).unwrap();
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty(), "{}", String::from_utf8_lossy(&rest));
+}
+
+#[test]
+fn status_page_counts_connections_and_requests() {
+    let server = TestServer::start("status", "status_page /status");
+    let status = |reader: &mut BufReader<TcpStream>, stream: &mut TcpStream| {
+        stream.write_all(b"GET /status HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let (code, body) = read_response(reader);
+        assert_eq!(code, 200);
+        let text = String::from_utf8(body).unwrap();
+        let counter = |name: &str| -> u64 {
+            let line = text.lines().find(|l| l.starts_with(name)).unwrap_or_else(|| panic!("{}", text));
+            line[name.len()..].trim().parse().unwrap()
+        };
+        (counter("Accepted connections:"), counter("Requests:"))
+    };
+
+    let mut first = server.connect();
+    let mut first_reader = BufReader::new(first.try_clone().unwrap());
+    let (accepted, requests) = status(&mut first_reader, &mut first);
+
+    let mut second = server.connect();
+    let mut second_reader = BufReader::new(second.try_clone().unwrap());
+    for (stream, reader) in [(&mut first, &mut first_reader), (&mut second, &mut second_reader)] {
+        for _ in 0..3 {
+            stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+            assert_eq!(read_response(reader).0, 200);
+        }
+    }
+
+    // One more connection, and six pages plus the status request itself
+    assert_eq!(status(&mut second_reader, &mut second), (accepted + 1, requests + 7));
+}
+
+#[cfg(unix)]
+#[test]
+fn unbuffered_cgi_body_ends_at_close() {
+    let server = TestServer::with_config("cgi-stream", |port, root| {
+        let script = "#!/bin/sh\nprintf 'Content-Type: text/plain\\r\\n\\r\\n'\n\
+                      for i in 1 2 3; do echo \"part $i\"; sleep 0.1; done\n";
+        fs::write(root.join("stream.sh"), script).unwrap();
+        format!(
+            "server {{\n    listen {}\n    host 127.0.0.1\n    root {}\n    location / {{\n        methods GET\n        \
+             cgi .sh /bin/sh\n        cgi_buffering off\n    }}\n}}\n",
+            port,
+            root.display()
+        )
+    });
+
+    let mut stream = server.connect();
+    stream.write_all(b"GET /stream.sh HTTP/1.0\r\nHost: localhost\r\n\r\n").unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+
+    let response = String::from_utf8(response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+    let head = head.to_lowercase();
+    assert!(!head.contains("content-length"), "{}", head);
+    assert!(!head.contains("transfer-encoding"), "{}", head);
+    assert!(head.contains("connection: close"), "{}", head);
+    assert_eq!(body, "part 1\npart 2\npart 3\n");
+}
+
+/// A TLS client trusting the test CAs, connected to the server under `name`
+#[cfg(feature = "tls")]
+fn tls_client(server: &TestServer, name: &str) -> (rustls::ClientConnection, TcpStream) {
+    use std::sync::Arc;
+
+    let mut roots = rustls::RootCertStore::empty();
+    let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let mut ca = BufReader::new(fs::File::open(fixtures.join("ca.pem")).unwrap());
+    for cert in rustls_pemfile::certs(&mut ca) {
+        roots.add(cert.unwrap()).unwrap();
+    }
+    let config = rustls::ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+    let tls = rustls::ClientConnection::new(Arc::new(config), name.to_string().try_into().unwrap()).unwrap();
+    (tls, server.connect())
+}
+
+#[cfg(feature = "tls")]
+#[test]
+fn https_handshake_and_request() {
+    let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let server = TestServer::with_config("tls", |port, root| {
+        format!(
+            "server {{\n    listen {} ssl\n    host 127.0.0.1\n    server_name localhost\n    root {}\n    \
+             ssl_certificate {}\n    ssl_certificate_key {}\n    location / {{\n        methods GET\n        index index.html\n    }}\n}}\n",
+            port,
+            root.display(),
+            fixtures.join("cert.pem").display(),
+            fixtures.join("key.pem").display()
+        )
+    });
+
+    let (mut tls, mut socket) = tls_client(&server, "localhost");
+    let mut stream = rustls::Stream::new(&mut tls, &mut socket);
+
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    let (status, body) = read_response(&mut BufReader::new(stream));
+    assert_eq!(status, 200);
+    assert_eq!(body, b"hello");
+    assert!(!tls.is_handshaking());
+}
+
+#[cfg(feature = "tls")]
+#[test]
+fn sni_picks_the_certificate_and_server() {
+    let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let server = TestServer::with_config("sni", |port, root| {
+        fs::create_dir_all(root.join("other")).unwrap();
+        fs::write(root.join("other/index.html"), "other site").unwrap();
+        let site = |name: &str, root: &Path, cert: &str, key: &str| {
+            format!(
+                "server {{\n    listen {} ssl\n    host 127.0.0.1\n    server_name {}\n    root {}\n    \
+                 ssl_certificate {}\n    ssl_certificate_key {}\n    location / {{\n        methods GET\n        index index.html\n    }}\n}}\n",
+                port,
+                name,
+                root.display(),
+                fixtures.join(cert).display(),
+                fixtures.join(key).display()
+            )
+        };
+        site("localhost", root, "cert.pem", "key.pem") + &site("other.test", &root.join("other"), "other-cert.pem", "other-key.pem")
+    });
+
+    for (name, cert, body) in [("localhost", "cert.pem", &b"hello"[..]), ("other.test", "other-cert.pem", b"other site")] {
+        let (mut tls, mut socket) = tls_client(&server, name);
+        let mut stream = rustls::Stream::new(&mut tls, &mut socket);
+        // The SNI name decides, whatever the Host header says
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let (status, received) = read_response(&mut BufReader::new(stream));
+        assert_eq!(status, 200, "{}", name);
+        assert_eq!(received, body, "{}", name);
+
+        let mut pem = BufReader::new(fs::File::open(fixtures.join(cert)).unwrap());
+        let expected = rustls_pemfile::certs(&mut pem).next().unwrap().unwrap();
+        assert_eq!(tls.peer_certificates().unwrap()[0], expected, "{}", name);
+    }
+}